@@ -0,0 +1,17 @@
+//! Serves the engine's JSON-RPC interface over stdin and stdout, for use by editor plugins.
+//! See the `optra::rpc` module for the available methods.
+
+extern crate optra;
+
+use std::io;
+use std::process;
+use optra::rpc::RpcServer;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(error) = RpcServer::new().serve(&mut stdin.lock(), &mut stdout.lock()) {
+        eprintln!("optra-rpc: {}", error);
+        process::exit(1);
+    }
+}
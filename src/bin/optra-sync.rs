@@ -0,0 +1,505 @@
+//! Keeps a file synchronized with a copy of it held by another `optra-sync` process.
+//!
+//! ```text
+//! optra-sync <file> --site <id> (--listen <address> | --connect <address>)
+//!            [--state <state file>] [--interval <milliseconds>] [--policy keep|drop|markers|resurrect]
+//! ```
+//!
+//! One side listens for a connection and the other connects to it.  Each side checks its file
+//! for changes every `--interval` milliseconds (250 by default), sends them to the other side,
+//! and applies the changes it receives.  If the connection is lost, the connecting side keeps
+//! trying to reconnect and the listening side waits for it to come back.  Changes made in the
+//! meantime are sent once the two sides are connected again.
+//!
+//! A site that connects with no history of its own asks the other side for a snapshot, and
+//! its copy of the file is replaced by the other side's.
+//!
+//! `--policy` sets how an insert made inside text the other side deleted at the same time is
+//! settled: it is kept (the default), dropped, both versions of the lines involved are kept
+//! between conflict markers, or the deleted text around it is put back.  Both sides must use the
+//! same policy.
+//!
+//! If the two copies of the file stop matching, or a change from the other side can't be
+//! integrated, the two sides elect one copy to keep and rebuild the other side from it.  Edits
+//! that side had not had acknowledged yet are carried over, so nothing is lost.
+//!
+//! The process stops when its standard input is closed, saving its history to the state file
+//! (`<file>.optra` by default) so that it can pick up where it left off when it is started again.
+//! The state is also saved whenever the file changes, so little is lost if the process is killed.
+//!
+//! Since changes are found by polling, a change made to the file at the same moment that a
+//! remote change is being written to it can be lost.
+
+extern crate optra;
+extern crate rdiff;
+extern crate byteorder;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, Shutdown};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use byteorder::{NetworkEndian, ByteOrder};
+use rdiff::BlockHashes;
+use optra::{Engine, TimeStamper, ConflictPolicy};
+use optra::protocol::{Message, Event, Session, SessionError, SessionState};
+
+const BLOCK_SIZE: usize = 8;
+
+const USAGE: &str = "Usage: optra-sync <file> --site <id> (--listen <address> | --connect <address>) [--state <state file>] [--interval <milliseconds>] [--policy keep|drop|markers|resurrect]";
+
+enum Peer {
+    Listen(String),
+    Connect(String),
+}
+
+struct Options {
+    file: PathBuf,
+    state: PathBuf,
+    site_id: u32,
+    peer: Peer,
+    interval: Duration,
+    policy: Option<ConflictPolicy>,
+}
+
+/// The connection to the other side, if there is one.  Incoming data is read on its own thread
+/// so that the main loop never has to wait on the other side.  Anything written while there is
+/// no connection is thrown away, since the session will send it again after reconnecting.
+/// Carries the session's messages over TCP.  Everything written between two flushes is sent as
+/// one frame, prefixed with its length, and only whole frames are handed to the session.
+struct Connection {
+    stream: Option<TcpStream>,
+    incoming: Option<Receiver<Vec<u8>>>,
+    /// Whole frames that have arrived and not been read yet
+    buffer: Vec<u8>,
+    /// What has been written since the last flush
+    outgoing: Vec<u8>,
+}
+
+struct Daemon {
+    options: Options,
+    session: Session<Connection>,
+    hashes: BlockHashes,
+    /// The contents of the file as the session's history has left it
+    base: Vec<u8>,
+    listener: Option<TcpListener>,
+    /// True until a site that started with no history has received a snapshot
+    joining: bool,
+}
+
+fn main() {
+    let options = match parse_options(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut input = Vec::new();
+            let _ = io::stdin().read_to_end(&mut input);
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let result = Daemon::new(options).and_then(|mut daemon| daemon.run(&stop));
+    if let Err(error) = result {
+        eprintln!("optra-sync: {}", error);
+        process::exit(1);
+    }
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut file = None;
+    let mut state = None;
+    let mut site_id = None;
+    let mut peer = None;
+    let mut interval = Duration::from_millis(250);
+    let mut policy = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--site" | "--listen" | "--connect" | "--state" | "--interval" | "--policy" => {
+                let value = try!(args.next().ok_or(format!("{} needs a value", arg)));
+                match arg.as_ref() {
+                    "--site" => site_id = Some(try!(value.parse().map_err(|_| format!("Invalid site id {}", value)))),
+                    "--listen" => peer = Some(Peer::Listen(value)),
+                    "--connect" => peer = Some(Peer::Connect(value)),
+                    "--state" => state = Some(PathBuf::from(value)),
+                    "--policy" => policy = Some(match value.as_ref() {
+                        "keep" => ConflictPolicy::KeepInsert,
+                        "drop" => ConflictPolicy::DropInsert,
+                        "markers" => ConflictPolicy::Markers,
+                        "resurrect" => ConflictPolicy::Resurrect,
+                        _ => return Err(format!("Invalid policy {}", value)),
+                    }),
+                    _ => interval = Duration::from_millis(try!(value.parse().map_err(|_| format!("Invalid interval {}", value)))),
+                }
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if file.is_none() => file = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    let file = try!(file.ok_or("No file given".to_string()));
+    let state = state.unwrap_or_else(|| {
+        let mut name = file.clone().into_os_string();
+        name.push(".optra");
+        PathBuf::from(name)
+    });
+    Ok(Options {
+        file: file,
+        state: state,
+        site_id: try!(site_id.ok_or("No site id given".to_string())),
+        peer: try!(peer.ok_or("One of --listen or --connect must be given".to_string())),
+        interval: interval,
+        policy: policy,
+    })
+}
+
+impl Daemon {
+    fn new(options: Options) -> Result<Daemon, SessionError> {
+        if !options.file.exists() {
+            try!(File::create(&options.file));
+        }
+        let listener = match options.peer {
+            Peer::Listen(ref address) => {
+                let listener = try!(TcpListener::bind(&address[..]));
+                try!(listener.set_nonblocking(true));
+                Some(listener)
+            },
+            Peer::Connect(_) => None
+        };
+
+        let mut session;
+        let hashes;
+        let base;
+        let joining;
+        if options.state.exists() {
+            // rdiff expects reads to be filled completely, so read from memory rather than the file
+            let mut data = Vec::new();
+            try!(try!(File::open(&options.state)).read_to_end(&mut data));
+            let mut reader = &data[..];
+            let mut engine = try!(Engine::expand_from(&mut reader, options.site_id));
+            if let Some(policy) = options.policy {
+                engine.set_conflict_policy(policy);
+            }
+            let stamper = try!(TimeStamper::expand_from(&mut reader));
+            let mut count_buf = [0; 4];
+            try!(reader.read_exact(&mut count_buf));
+            let length = NetworkEndian::read_u32(&count_buf) as usize;
+            let mut contents = Vec::new();
+            if try!((&mut reader).take(length as u64).read_to_end(&mut contents)) < length {
+                return Err(SessionError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "The state file is truncated")));
+            }
+            hashes = try!(BlockHashes::new(&contents[..], BLOCK_SIZE));
+            base = contents;
+            try!(reader.read_exact(&mut count_buf));
+            session = Session::new(Connection::new(), options.site_id, engine, stamper);
+            for _ in 0..NetworkEndian::read_u32(&count_buf) {
+                match try!(Message::expand_from(&mut reader)) {
+                    Message::Transaction { transaction, lookup } => session.queue_pending(transaction, lookup),
+                    message => return Err(SessionError::Protocol(format!("Unexpected {:?} in the state file", message)))
+                }
+            }
+            joining = false;
+        } else {
+            // Anything already in the file will be found by the first check as one big insert
+            let mut engine = Engine::new(options.site_id);
+            engine.set_conflict_policy(options.policy.unwrap_or_default());
+            session = Session::new(Connection::new(), options.site_id, engine, TimeStamper::new());
+            hashes = BlockHashes::empty(BLOCK_SIZE);
+            base = Vec::new();
+            joining = match options.peer {
+                Peer::Listen(_) => false,
+                Peer::Connect(_) => true,
+            };
+        }
+        Ok(Daemon {
+            options: options,
+            session: session,
+            hashes: hashes,
+            base: base,
+            listener: listener,
+            joining: joining,
+        })
+    }
+
+    fn run(&mut self, stop: &AtomicBool) -> Result<(), SessionError> {
+        while !stop.load(Ordering::SeqCst) {
+            if !self.session.get_transport_mut().is_connected() {
+                try!(self.connect());
+            }
+            let mut changed = try!(self.check_file());
+            changed |= try!(self.receive());
+            if changed {
+                try!(self.save());
+            }
+            thread::sleep(self.options.interval);
+        }
+        self.session.get_transport_mut().detach();
+        self.save()
+    }
+
+    /// Tries once to connect to the other side, and starts a new session if it works
+    fn connect(&mut self) -> Result<(), SessionError> {
+        let stream = match self.listener {
+            Some(ref listener) => match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(SessionError::Io(error))
+            },
+            None => match self.options.peer {
+                Peer::Connect(ref address) => match TcpStream::connect(&address[..]) {
+                    Ok(stream) => stream,
+                    Err(_) => return Ok(())
+                },
+                Peer::Listen(_) => return Ok(())
+            }
+        };
+        try!(stream.set_nonblocking(false));
+        try!(self.session.get_transport_mut().attach(stream));
+        self.session.reset();
+        try!(self.session.start());
+        // Otherwise the session catches the other side up once it has said hello
+        if self.joining {
+            try!(self.session.request_snapshot());
+        }
+        Ok(())
+    }
+
+    /// Sends any changes made to the file since it was last checked.  Returns true if there were any.
+    fn check_file(&mut self) -> Result<bool, SessionError> {
+        if self.joining {
+            return Ok(false);
+        }
+        let mut document = Vec::new();
+        try!(try!(File::open(&self.options.file)).read_to_end(&mut document));
+        let diff = try!(self.hashes.diff_and_update(&document[..]));
+        if diff.is_empty() {
+            return Ok(false);
+        }
+        try!(self.session.send_diffs_with_base(diff, &self.base, &document));
+        self.base = document;
+        Ok(true)
+    }
+
+    /// Handles every complete message from the other side.  Returns true if the file or the
+    /// session's history changed.
+    fn receive(&mut self) -> Result<bool, SessionError> {
+        let mut changed = false;
+        if !self.session.get_transport_mut().poll() {
+            eprintln!("optra-sync: Disconnected");
+            self.disconnect();
+            return Ok(changed);
+        }
+        while self.session.get_transport_mut().has_message() {
+            let mut document = self.base.clone();
+            match self.session.process_next_with_document(&mut document) {
+                Ok(Event::Connected { site_id }) => eprintln!("optra-sync: Connected to site {}", site_id),
+                Ok(Event::Remote(_)) => {
+                    try!(self.write_file(&document));
+                    if self.session.get_state() == SessionState::Repairing {
+                        eprintln!("optra-sync: The file no longer matches the other side's copy, repairing it");
+                    }
+                    changed = true;
+                },
+                Ok(Event::Snapshot(document)) => {
+                    try!(self.write_file(&document));
+                    self.joining = false;
+                    changed = true;
+                },
+                Ok(Event::RepairRequested(state)) => {
+                    eprintln!("optra-sync: Site {} reports that the file has diverged", state.get_site_id());
+                    // Our copy may be the one that is kept, so take in any last changes first
+                    changed |= try!(self.check_file());
+                    try!(self.session.repair(&state, &self.base));
+                },
+                Ok(Event::Repaired(document)) => {
+                    eprintln!("optra-sync: Repaired the file from the other side's copy");
+                    try!(self.write_file(&document));
+                    changed = true;
+                },
+                Ok(Event::SnapshotRequested) => {
+                    // The snapshot must match our history, so take in any last changes first
+                    changed |= try!(self.check_file());
+                    try!(self.session.send_snapshot(&self.base));
+                },
+                Ok(Event::Acknowledged(_)) => changed = true,
+                Ok(Event::Closed(message)) => {
+                    eprintln!("optra-sync: The other side closed the connection: {}", message);
+                    self.disconnect();
+                    break;
+                },
+                Ok(Event::Duplicate) => {},
+                Err(error @ SessionError::Sync(_)) => {
+                    eprintln!("optra-sync: Could not integrate a change from the other side, repairing the file: {}", error);
+                    // The change is still pending on the other side, so the repair carries it over
+                    if self.session.get_state() != SessionState::Repairing {
+                        changed |= try!(self.check_file());
+                        try!(self.session.request_repair(&self.base));
+                    }
+                },
+                Err(error) => {
+                    eprintln!("optra-sync: {}", error);
+                    self.disconnect();
+                    break;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Replaces the contents of the file, without treating the new contents as a local change
+    fn write_file(&mut self, document: &[u8]) -> Result<(), SessionError> {
+        try!(try!(File::create(&self.options.file)).write_all(document));
+        self.hashes = try!(BlockHashes::new(document, BLOCK_SIZE));
+        self.base = document.to_vec();
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.session.get_transport_mut().detach();
+        self.session.reset();
+    }
+
+    /// Saves the history, the file's contents as the history left them and any unacknowledged
+    /// transactions to the state file
+    fn save(&self) -> Result<(), SessionError> {
+        let mut temp_name = self.options.state.clone().into_os_string();
+        temp_name.push(".tmp");
+        let temp_path = Path::new(&temp_name);
+        let mut writer = Vec::new();
+        try!(self.session.get_engine().compress_to(&mut writer));
+        try!(self.session.get_stamper().compress_to(&mut writer));
+        let mut count_buf = [0; 4];
+        NetworkEndian::write_u32(&mut count_buf, self.base.len() as u32);
+        try!(writer.write_all(&count_buf));
+        try!(writer.write_all(&self.base));
+        let pending = self.session.get_pending();
+        NetworkEndian::write_u32(&mut count_buf, pending.len() as u32);
+        try!(writer.write_all(&count_buf));
+        for (transaction, lookup) in pending {
+            let message = Message::Transaction {
+                transaction: transaction.clone(),
+                lookup: lookup.clone(),
+            };
+            try!(message.compress_to(&mut writer));
+        }
+        try!(try!(File::create(temp_path)).write_all(&writer));
+        try!(fs::rename(temp_path, &self.options.state));
+        Ok(())
+    }
+}
+
+impl Connection {
+    fn new() -> Connection {
+        Connection {
+            stream: None,
+            incoming: None,
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn attach(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = try!(stream.try_clone());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut length_buf = [0; 4];
+            while reader.read_exact(&mut length_buf).is_ok() {
+                // Grows with the data that arrives rather than trusting the length up front
+                let length = NetworkEndian::read_u32(&length_buf) as usize;
+                let mut frame = Vec::new();
+                match (&mut reader).take(length as u64).read_to_end(&mut frame) {
+                    Ok(read) if read == length => (),
+                    _ => break
+                }
+                if sender.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+        self.stream = Some(stream);
+        self.incoming = Some(receiver);
+        self.buffer.clear();
+        self.outgoing.clear();
+        Ok(())
+    }
+
+    fn detach(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.incoming = None;
+        self.outgoing.clear();
+    }
+
+    /// Collects any data that has arrived.  Returns false if the connection has been lost.
+    fn poll(&mut self) -> bool {
+        let receiver = match self.incoming {
+            Some(ref receiver) => receiver,
+            None => return true
+        };
+        loop {
+            match receiver.try_recv() {
+                Ok(data) => self.buffer.extend_from_slice(&data),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false
+            }
+        }
+    }
+
+    /// Checks whether a message has arrived.  The session flushes after every message, so the
+    /// buffer only ever holds whole ones.
+    fn has_message(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!((&self.buffer[..]).read(buf));
+        self.buffer.drain(..read);
+        Ok(read)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let outgoing = &self.outgoing;
+        let failed = match self.stream {
+            Some(ref mut stream) => {
+                let mut length_buf = [0; 4];
+                NetworkEndian::write_u32(&mut length_buf, outgoing.len() as u32);
+                stream.write_all(&length_buf).and_then(|_| stream.write_all(outgoing)).and_then(|_| stream.flush()).is_err()
+            },
+            None => false
+        };
+        self.outgoing.clear();
+        if failed {
+            // The reader thread will notice as well, and the main loop will reconnect
+            self.detach();
+        }
+        Ok(())
+    }
+}
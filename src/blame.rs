@@ -0,0 +1,191 @@
+use std::collections::linked_list::LinkedList;
+use engine::TimeStamper;
+use operations::{InsertOperation, DeleteOperation, Operation};
+use element::Element;
+use {OTError, ErrorKind as Kind, Position};
+
+/// A range of a document and the operation that inserted it, as found by `Engine::blame()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorship {
+    position: Position,
+    length: Position,
+    site_id: Option<u32>,
+    state: Option<(u32, u32)>,
+}
+
+/// A piece of the document while the history is being replayed.  The origin is the site id and
+/// local timestamp of the insert that produced it, if any.
+struct Run {
+    length: Position,
+    origin: Option<(u32, u32)>,
+}
+
+impl Authorship {
+    /// Gets the position of the range in the document
+    #[inline]
+    pub fn get_position(&self) -> Position {
+        self.position
+    }
+
+    /// Gets the length of the range
+    #[inline]
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+
+    /// Gets the id of the site that inserted the range, or `None` if it was already in the
+    /// document when the history began
+    #[inline]
+    pub fn get_site_id(&self) -> Option<u32> {
+        self.site_id
+    }
+
+    /// Gets the `(site id, timestamp)` of the transaction that inserted the range, or `None`
+    /// if it was already in the document when the history began
+    #[inline]
+    pub fn get_state(&self) -> Option<(u32, u32)> {
+        self.state
+    }
+}
+
+/// Replays a history in effect order over a document that is `length` bytes long once the
+/// history has been applied, keeping track of where each byte came from
+pub fn annotate<T: Element>(inserts: &LinkedList<InsertOperation<T>>, deletes: &LinkedList<DeleteOperation<T>>, length: Position, stamper: &TimeStamper) -> Result<Vec<Authorship>, OTError> {
+    // Whatever the history didn't insert must have been there to begin with
+    let inserted = inserts.iter().fold(0, |total, insert| total + insert.get_value().len() as Position);
+    let deleted = deletes.iter().fold(0, |total, delete| total + delete.get_length());
+    if length + deleted < inserted {
+        return Err(OTError::new(Kind::OutOfRange));
+    }
+    let mut runs = Vec::new();
+    if length + deleted > inserted {
+        runs.push(Run {
+            length: length + deleted - inserted,
+            origin: None,
+        });
+    }
+
+    for insert in inserts.iter() {
+        if insert.get_value().is_empty() {
+            continue;
+        }
+        let index = try!(split_at(&mut runs, insert.get_position()));
+        runs.insert(index, Run {
+            length: insert.get_value().len() as Position,
+            origin: Some((insert.get_site_id(), insert.get_timestamp())),
+        });
+    }
+    for delete in deletes.iter() {
+        if delete.get_length() == 0 {
+            continue;
+        }
+        let start = try!(split_at(&mut runs, delete.get_position()));
+        let end = try!(split_at(&mut runs, delete.get_position() + delete.get_length()));
+        runs.drain(start..end);
+    }
+
+    let mut authorship: Vec<Authorship> = Vec::new();
+    let mut position = 0;
+    for run in runs {
+        let state = run.origin.and_then(|(_, timestamp)| stamper.get_remote_timestamp_for(timestamp));
+        let site_id = run.origin.map(|(site_id, _)| site_id);
+        match authorship.last_mut() {
+            // Neighbouring pieces of the same insert are reported as one range
+            Some(ref mut last) if last.site_id == site_id && last.state == state => {
+                last.length += run.length;
+            },
+            _ => authorship.push(Authorship {
+                position: position,
+                length: run.length,
+                site_id: site_id,
+                state: state,
+            })
+        }
+        position += run.length;
+    }
+    Ok(authorship)
+}
+
+/// Splits the runs so that one of them starts at `position`, and returns its index
+fn split_at(runs: &mut Vec<Run>, position: Position) -> Result<usize, OTError> {
+    let mut start = 0;
+    for index in 0..runs.len() {
+        if start == position {
+            return Ok(index);
+        }
+        let end = start + runs[index].length;
+        if position < end {
+            let origin = runs[index].origin;
+            runs[index].length = position - start;
+            runs.insert(index + 1, Run {
+                length: end - position,
+                origin: origin,
+            });
+            return Ok(index + 1);
+        }
+        start = end;
+    }
+    if start == position {
+        Ok(runs.len())
+    } else {
+        Err(OTError::new(Kind::OutOfRange))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::{Engine, TransactionSequence, TimeStamper};
+    use test_utils::local_edit;
+    use std::collections::BTreeMap;
+    use Position;
+
+    fn integrate(engine: &mut Engine, stamper: &mut TimeStamper, document: &mut Vec<u8>, mut transaction: TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>) {
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            stamper.stamp_remote(site_id, timestamp);
+        }
+        engine.integrate_remote(&mut transaction, lookup, stamper).unwrap();
+        *document = transaction.apply_to_bytes(document);
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn ranges(engine: &Engine, document: &[u8], stamper: &TimeStamper) -> Vec<(Position, Position, Option<u32>, Option<(u32, u32)>)> {
+        engine.blame(document, stamper).unwrap().iter().map(|authorship| {
+            (authorship.get_position(), authorship.get_length(), authorship.get_site_id(), authorship.get_state())
+        }).collect()
+    }
+
+    #[test]
+    fn blame_follows_the_history() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut document1 = Vec::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        let mut document2 = Vec::new();
+
+        let (first, lookup) = local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(0, "Hello world")], vec![]);
+        integrate(&mut engine2, &mut stamper2, &mut document2, first, &lookup);
+        let (second, lookup) = local_edit(&mut engine2, &mut stamper2, 2, &mut document2, vec![(6, "big ")], vec![]);
+        // Made at the same time as the second edit
+        let (third, third_lookup) = local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![], vec![(0, 6)]);
+        integrate(&mut engine1, &mut stamper1, &mut document1, second, &lookup);
+        integrate(&mut engine2, &mut stamper2, &mut document2, third, &third_lookup);
+        assert_eq!(document1, b"big world");
+        assert_eq!(document2, document1);
+
+        let expected = vec![(0, 4, Some(2), Some((2, 1))), (4, 5, Some(1), Some((1, 0)))];
+        assert_eq!(ranges(&engine1, &document1, &stamper1), expected);
+        assert_eq!(ranges(&engine2, &document2, &stamper2), expected);
+    }
+
+    #[test]
+    fn text_from_before_the_history_has_no_author() {
+        let mut engine = Engine::new(1);
+        let mut stamper = TimeStamper::new();
+        let mut document = b"abc".to_vec();
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(1, "XY")], vec![(3, 1)]);
+        assert_eq!(document, b"aXYc");
+        assert_eq!(ranges(&engine, &document, &stamper), vec![(0, 1, None, None), (1, 2, Some(1), Some((1, 0))), (3, 1, None, None)]);
+        assert!(engine.blame(b"", &stamper).is_err());
+    }
+}
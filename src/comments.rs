@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, LinkedList};
+use std::io::{self, Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+use operations::{InsertOperation, DeleteOperation, AnchorOperation, Operation};
+use element::Element;
+use conflict;
+use metadata;
+use Position;
+
+/// One comment in a thread.
+///
+/// A comment is identified by the id of the site that wrote it and a number, which is given out
+/// by `Comments::next_id()` so that it is higher than the number of every comment and thread the
+/// site had seen.  Comments are sent between sites on their own with `compress_to()`, rather than
+/// in a transaction, since they don't change the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    id: (u32, u32),
+    thread: (u32, u32),
+    text: String,
+}
+
+/// A thread of comments anchored to a range of the document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thread {
+    id: (u32, u32),
+    /// Where the thread is anchored, once its anchor has been seen
+    range: Option<(Position, Position)>,
+    /// Whether every part of the range has been deleted
+    orphaned: bool,
+    /// The comments, in the order they were written
+    comments: Vec<Comment>,
+}
+
+/// The threads of comments on a document, kept anchored to the same text as the document changes.
+///
+/// A thread is anchored to a range by an [`AnchorOperation`](struct.AnchorOperation.html) sent
+/// along with a transaction, and its comments are sent on their own.  Either can arrive first: a
+/// thread whose comments have arrived before its anchor has no range yet.  Text inserted inside
+/// the range of a thread becomes part of it, while text inserted at either end doesn't.  Once all
+/// of the text in the range has been deleted, the thread is orphaned, and it stays where its text
+/// used to be even if new text is written there.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Comments {
+    threads: BTreeMap<(u32, u32), Thread>,
+    /// The highest number given to a comment or thread that has been seen
+    clock: u32,
+}
+
+impl Comment {
+    /// Creates the comment with the given id in the given thread
+    pub fn new(id: (u32, u32), thread: (u32, u32), text: &str) -> Comment {
+        Comment {
+            id: id,
+            thread: thread,
+            text: text.to_string(),
+        }
+    }
+
+    /// Gets the `(site id, number)` of this comment
+    #[inline]
+    pub fn get_id(&self) -> (u32, u32) {
+        self.id
+    }
+
+    /// Gets the id of the thread this comment is in
+    #[inline]
+    pub fn get_thread(&self) -> (u32, u32) {
+        self.thread
+    }
+
+    /// Gets the text of this comment
+    #[inline]
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+
+    /// Compress this comment and write to `writer`.  The output can then be expanded
+    /// back into an equivilent comment using `expand_from()`.
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        for &value in [self.id.0, self.id.1, self.thread.0, self.thread.1].iter() {
+            NetworkEndian::write_u32(&mut int_buf, value);
+            try!(writer.write_all(&int_buf));
+        }
+        metadata::write_string(writer, &self.text)
+    }
+
+    /// Expand a comment from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Comment> {
+        let mut int_buf = [0;4];
+        let mut values = [0;4];
+        for value in values.iter_mut() {
+            try!(reader.read_exact(&mut int_buf));
+            *value = NetworkEndian::read_u32(&int_buf);
+        }
+        Ok(Comment {
+            id: (values[0], values[1]),
+            thread: (values[2], values[3]),
+            text: try!(metadata::read_string(reader)),
+        })
+    }
+
+    /// Whether this comment was written after `other`
+    fn follows(&self, other: &Comment) -> bool {
+        self.id.1 > other.id.1 || self.id.1 == other.id.1 && self.id.0 > other.id.0
+    }
+}
+
+impl Thread {
+    fn new(id: (u32, u32)) -> Thread {
+        Thread {
+            id: id,
+            range: None,
+            orphaned: false,
+            comments: Vec::new(),
+        }
+    }
+
+    /// Gets the `(site id, number)` of this thread
+    #[inline]
+    pub fn get_id(&self) -> (u32, u32) {
+        self.id
+    }
+
+    /// Gets the start and end of the range this thread is anchored to, or `None` if its anchor
+    /// hasn't been seen yet
+    #[inline]
+    pub fn get_range(&self) -> Option<(Position, Position)> {
+        self.range
+    }
+
+    /// Whether all of the text this thread was anchored to has been deleted
+    #[inline]
+    pub fn is_orphaned(&self) -> bool {
+        self.orphaned
+    }
+
+    /// Gets the comments in this thread, in the order they were written
+    #[inline]
+    pub fn get_comments(&self) -> &[Comment] {
+        &self.comments
+    }
+}
+
+impl Comments {
+    /// Creates a store with no threads
+    pub fn new() -> Comments {
+        Comments::default()
+    }
+
+    /// Gets a new id for a comment or thread written at `site_id`
+    pub fn next_id(&mut self, site_id: u32) -> (u32, u32) {
+        self.clock += 1;
+        (site_id, self.clock)
+    }
+
+    /// Gets the thread with the given id, if anything about it has been seen
+    pub fn get_thread(&self, id: (u32, u32)) -> Option<&Thread> {
+        self.threads.get(&id)
+    }
+
+    /// Gets every thread, ordered by id
+    pub fn get_threads(&self) -> Vec<&Thread> {
+        self.threads.values().collect()
+    }
+
+    /// Gets the threads whose text has all been deleted
+    pub fn get_orphaned(&self) -> Vec<&Thread> {
+        self.threads.values().filter(|thread| thread.orphaned).collect()
+    }
+
+    /// Adds a comment, which may have been written at this site or sent from another one.
+    /// Returns `false` if the comment had already been added.
+    pub fn add_comment(&mut self, comment: Comment) -> bool {
+        self.see(comment.id);
+        let thread_id = comment.thread;
+        self.see(thread_id);
+        let thread = self.threads.entry(thread_id).or_insert_with(|| Thread::new(thread_id));
+        if thread.comments.iter().any(|other| other.id == comment.id) {
+            return false;
+        }
+        let index = thread.comments.iter().rposition(|other| comment.follows(other)).map_or(0, |index| index + 1);
+        thread.comments.insert(index, comment);
+        true
+    }
+
+    /// Anchors a thread to the range of `anchor`, which has been placed in the document as it
+    /// is now.  A thread is only anchored once, so a later anchor for the same thread is ignored.
+    pub fn anchor(&mut self, anchor: &AnchorOperation) {
+        let thread_id = anchor.get_thread();
+        self.see(thread_id);
+        let thread = self.threads.entry(thread_id).or_insert_with(|| Thread::new(thread_id));
+        if thread.range.is_none() {
+            let position = anchor.get_position();
+            thread.range = Some((position, position + anchor.get_length()));
+            thread.orphaned = anchor.get_length() == 0;
+        }
+    }
+
+    /// Moves every thread over the given inserts and deletes, which are applied to the document
+    /// in effect order, orphaning the threads whose text is deleted entirely
+    pub fn update<T: Element>(&mut self, inserts: &LinkedList<InsertOperation<T>>, deletes: &LinkedList<DeleteOperation<T>>) {
+        for thread in self.threads.values_mut() {
+            if let Some(range) = thread.range {
+                let (start, end) = if thread.orphaned {
+                    // Text written where the thread used to be doesn't bring it back
+                    let (start, _) = conflict::through_inserts(range, inserts.iter().filter(|insert| insert.get_position() != range.0));
+                    let (start, _) = conflict::through_deletes((start, start), deletes.iter());
+                    (start, start)
+                } else {
+                    let range = conflict::through_inserts(range, inserts.iter());
+                    conflict::through_deletes(range, deletes.iter())
+                };
+                thread.range = Some((start, end));
+                thread.orphaned = start == end;
+            }
+        }
+    }
+
+    /// Compress these comments and write to `writer`.  The output can then be expanded
+    /// back into equivilent comments using `expand_from()`.
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.clock);
+        try!(writer.write_all(&int_buf));
+        NetworkEndian::write_u32(&mut int_buf, self.threads.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for thread in self.threads.values() {
+            NetworkEndian::write_u32(&mut int_buf, thread.id.0);
+            try!(writer.write_all(&int_buf));
+            NetworkEndian::write_u32(&mut int_buf, thread.id.1);
+            try!(writer.write_all(&int_buf));
+            match thread.range {
+                Some((start, end)) => {
+                    try!(writer.write_all(&[if thread.orphaned { 2 } else { 1 }]));
+                    NetworkEndian::write_u64(&mut long_buf, start);
+                    try!(writer.write_all(&long_buf));
+                    NetworkEndian::write_u64(&mut long_buf, end);
+                    try!(writer.write_all(&long_buf));
+                },
+                None => {
+                    try!(writer.write_all(&[0]));
+                }
+            }
+            NetworkEndian::write_u32(&mut int_buf, thread.comments.len() as u32);
+            try!(writer.write_all(&int_buf));
+            for comment in thread.comments.iter() {
+                try!(comment.compress_to(writer));
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand comments from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Comments> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        let mut bool_buf = [0;1];
+        try!(reader.read_exact(&mut int_buf));
+        let clock = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let thread_len = NetworkEndian::read_u32(&int_buf);
+        let mut threads = BTreeMap::new();
+        for _ in 0..thread_len {
+            try!(reader.read_exact(&mut int_buf));
+            let site_id = NetworkEndian::read_u32(&int_buf);
+            try!(reader.read_exact(&mut int_buf));
+            let mut thread = Thread::new((site_id, NetworkEndian::read_u32(&int_buf)));
+            try!(reader.read_exact(&mut bool_buf));
+            if bool_buf[0] != 0 {
+                try!(reader.read_exact(&mut long_buf));
+                let start = NetworkEndian::read_u64(&long_buf);
+                try!(reader.read_exact(&mut long_buf));
+                thread.range = Some((start, NetworkEndian::read_u64(&long_buf)));
+                thread.orphaned = bool_buf[0] == 2;
+            }
+            try!(reader.read_exact(&mut int_buf));
+            let comment_len = NetworkEndian::read_u32(&int_buf);
+            for _ in 0..comment_len {
+                thread.comments.push(try!(Comment::expand_from(reader)));
+            }
+            threads.insert(thread.id, thread);
+        }
+        Ok(Comments {
+            threads: threads,
+            clock: clock,
+        })
+    }
+
+    /// Makes sure the ids this site gives out are higher than `id`
+    fn see(&mut self, id: (u32, u32)) {
+        if id.1 > self.clock {
+            self.clock = id.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::LinkedList;
+    use operations::{InsertOperation, DeleteOperation, AnchorOperation};
+    use super::{Comments, Comment};
+
+    #[test]
+    fn comments_are_ordered() {
+        let mut comments = Comments::new();
+        let thread = comments.next_id(1);
+        assert_eq!(thread, (1, 1));
+        let first = Comment::new(comments.next_id(1), thread, "Is this right?");
+        assert!(comments.add_comment(first.clone()));
+
+        // Replies that arrive out of order, or twice, end up in the same place
+        let reply = Comment::new((2, 3), thread, "I think so");
+        let concurrent = Comment::new((3, 3), thread, "Not sure");
+        assert!(comments.add_comment(concurrent.clone()));
+        assert!(comments.add_comment(reply.clone()));
+        assert!(!comments.add_comment(reply.clone()));
+        assert_eq!(comments.get_thread(thread).unwrap().get_comments(), &[first, reply, concurrent]);
+        assert_eq!(comments.get_thread(thread).unwrap().get_range(), None);
+        assert_eq!(comments.next_id(1), (1, 4));
+
+        let mut buffer = Vec::new();
+        comments.compress_to(&mut buffer).unwrap();
+        assert_eq!(Comments::expand_from(&mut &buffer[..]).unwrap(), comments);
+    }
+
+    #[test]
+    fn threads_are_orphaned() {
+        let mut comments = Comments::new();
+        comments.anchor(&AnchorOperation::new(4, 5, (1, 1), 0, 1));
+        comments.anchor(&AnchorOperation::new(10, 3, (1, 2), 0, 1));
+
+        // Text inserted inside the first thread joins it, while the second is deleted entirely
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(6, b"xx".to_vec(), 1, 2));
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(11, 5, 1, 2));
+        comments.update(&inserts, &deletes);
+        assert_eq!(comments.get_thread((1, 1)).unwrap().get_range(), Some((4, 11)));
+        assert_eq!(comments.get_thread((1, 2)).unwrap().get_range(), Some((11, 11)));
+        assert_eq!(comments.get_orphaned().iter().map(|thread| thread.get_id()).collect::<Vec<_>>(), vec![(1, 2)]);
+
+        // Writing where the orphaned thread used to be doesn't bring it back
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(11, b"new".to_vec(), 2, 2));
+        comments.update(&inserts, &LinkedList::<DeleteOperation>::new());
+        assert_eq!(comments.get_thread((1, 2)).unwrap().get_range(), Some((11, 11)));
+        assert!(comments.get_thread((1, 2)).unwrap().is_orphaned());
+    }
+}
@@ -0,0 +1,156 @@
+use operations::{InsertOperation, DeleteOperation, Operation};
+use element::Element;
+use Position;
+
+/// What kind of edits ran into each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Text was inserted inside a range that was deleted at the same time
+    InsertInsideDelete,
+    /// Two sites deleted ranges that overlap at the same time
+    OverlappingDeletes,
+}
+
+/// How the engine settled a conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The inserted text was kept, and the delete took out what was around it
+    InsertKept,
+    /// The inserted text was deleted along with what was around it
+    InsertDeleted,
+    /// The text both deletes covered was deleted once
+    DeletedOnce,
+}
+
+/// One of the operations involved in a conflict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictOperation<T: Element = u8> {
+    /// An insert
+    Insert(InsertOperation<T>),
+    /// A delete
+    Delete(DeleteOperation<T>),
+}
+
+impl<T: Element> ConflictOperation<T> {
+    /// Gets the id of the site the operation was first performed at
+    pub fn get_site_id(&self) -> u32 {
+        match *self {
+            ConflictOperation::Insert(ref insert) => insert.get_site_id(),
+            ConflictOperation::Delete(ref delete) => delete.get_site_id(),
+        }
+    }
+
+    /// Gets the local timestamp of the operation's transaction
+    pub fn get_timestamp(&self) -> u32 {
+        match *self {
+            ConflictOperation::Insert(ref insert) => insert.get_timestamp(),
+            ConflictOperation::Delete(ref delete) => delete.get_timestamp(),
+        }
+    }
+}
+
+/// A collision between a local operation and a concurrent remote one, found by
+/// `Engine::integrate_remote_with_conflicts()`.
+///
+/// The operations are as they stood when they met, so their positions are in the engine's
+/// history rather than the document.  Both carry the local timestamp of their transaction,
+/// which can be used to look up its metadata.  The range is in the document once the remote
+/// transaction has been applied: it covers the inserted text if it was kept, and is empty where
+/// the deleted text used to be otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict<T: Element = u8> {
+    kind: ConflictKind,
+    resolution: Resolution,
+    local: ConflictOperation<T>,
+    remote: ConflictOperation<T>,
+    position: Position,
+    length: Position,
+}
+
+impl<T: Element> Conflict<T> {
+    /// Creates a conflict over the given range of the document
+    pub fn new(kind: ConflictKind, resolution: Resolution, local: ConflictOperation<T>, remote: ConflictOperation<T>, range: (Position, Position)) -> Conflict<T> {
+        Conflict {
+            kind: kind,
+            resolution: resolution,
+            local: local,
+            remote: remote,
+            position: range.0,
+            length: range.1 - range.0,
+        }
+    }
+
+    /// Gets what kind of edits ran into each other
+    #[inline]
+    pub fn get_kind(&self) -> ConflictKind {
+        self.kind
+    }
+
+    /// Gets how the conflict was settled
+    #[inline]
+    pub fn get_resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Gets the operation that was already in the local history
+    #[inline]
+    pub fn get_local(&self) -> &ConflictOperation<T> {
+        &self.local
+    }
+
+    /// Gets the operation from the remote transaction
+    #[inline]
+    pub fn get_remote(&self) -> &ConflictOperation<T> {
+        &self.remote
+    }
+
+    /// Gets the position of the conflict in the document
+    #[inline]
+    pub fn get_position(&self) -> Position {
+        self.position
+    }
+
+    /// Gets the length of the inserted text that was kept, which is 0 if there is none
+    #[inline]
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+}
+
+/// Moves a range over the inserts that are applied after it, in effect order.  Text inserted
+/// where the range starts goes in front of it.
+pub fn through_inserts<'a, T: Element + 'a, I: Iterator<Item = &'a InsertOperation<T>>>(range: (Position, Position), inserts: I) -> (Position, Position) {
+    let (mut start, mut end) = range;
+    for insert in inserts {
+        let position = insert.get_position();
+        let length = insert.get_value().len() as Position;
+        if position < end || (position == start && start == end) {
+            end += length;
+        }
+        if position <= start {
+            start += length;
+        }
+    }
+    (start, end)
+}
+
+/// Moves a range over the deletes that are applied after it, in effect order.  Whatever part of
+/// the range is deleted shrinks to the position of its delete.
+pub fn through_deletes<'a, T: Element + 'a, I: Iterator<Item = &'a DeleteOperation<T>>>(range: (Position, Position), deletes: I) -> (Position, Position) {
+    let through = |point: Position, delete: &DeleteOperation<T>| {
+        let position = delete.get_position();
+        if point <= position {
+            point
+        } else if point >= position + delete.get_length() {
+            point - delete.get_length()
+        } else {
+            position
+        }
+    };
+    let (mut start, mut end) = range;
+    for delete in deletes {
+        start = through(start, delete);
+        end = through(end, delete);
+    }
+    (start, end)
+}
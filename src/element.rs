@@ -0,0 +1,56 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// An element of a synchronized sequence.
+///
+/// The engine usually keeps the bytes of a file synchronized, but it can just as well keep an
+/// ordered list of records synchronized, such as the rows of a table or the items of a list.
+/// Operations only ever compare and copy their elements, except when they are compressed to be
+/// sent to another site or saved, which is done with `write_to()` and `read_from()`.
+pub trait Element: Clone + PartialEq + fmt::Debug {
+    /// Writes `elements` to `writer`, so that they can be read back with `read_from()`
+    fn write_to<W: Write>(elements: &[Self], writer: &mut W) -> io::Result<()>;
+
+    /// Reads `count` elements, which were written with `write_to()`, from `reader`
+    fn read_from<R: Read>(reader: &mut R, count: usize) -> io::Result<Vec<Self>>;
+
+    /// Describes `elements` for logging and debugging
+    fn describe(elements: &[Self]) -> String {
+        format!("{:?}", elements)
+    }
+}
+
+impl Element for u8 {
+    #[inline]
+    fn write_to<W: Write>(elements: &[u8], writer: &mut W) -> io::Result<()> {
+        writer.write_all(elements)
+    }
+
+    fn read_from<R: Read>(reader: &mut R, count: usize) -> io::Result<Vec<u8>> {
+        // The count comes from whoever wrote the elements, so only what is really there is allocated
+        let mut elements = Vec::new();
+        try!(reader.by_ref().take(count as u64).read_to_end(&mut elements));
+        if elements.len() < count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Too few elements"));
+        }
+        Ok(elements)
+    }
+
+    fn describe(elements: &[u8]) -> String {
+        String::from_utf8_lossy(elements).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Element;
+    use std::io;
+
+    #[test]
+    fn reading_more_bytes_than_were_written_fails() {
+        let mut reader = &b"abc"[..];
+        assert_eq!(u8::read_from(&mut reader, usize::MAX).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let mut reader = &b"abc"[..];
+        assert_eq!(u8::read_from(&mut reader, 2).unwrap(), b"ab");
+    }
+}
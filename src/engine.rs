@@ -1,16 +1,74 @@
 use std::collections::linked_list::{LinkedList};
 use std::collections::hash_map::{HashMap, Entry};
+use std::collections::hash_set::HashSet;
 use std::collections::btree_map::{BTreeMap};
 use std::collections::vec_deque::VecDeque;
 use std::fs::{File};
 use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::mem;
 use std::fmt;
-use operations::{Operation, InsertOperation, DeleteOperation, Advance, OperationInternal};
+use std::sync::Arc;
+use operations::{Operation, InsertOperation, DeleteOperation, ReplaceOperation, MoveOperation, FormatOperation, AnchorOperation, Advance, OperationInternal, OverlapResult};
 use ::{OTError, ErrorKind as Kind, Offset, Position};
-use utils::{SequenceTransformer, SequenceSwapper, SequenceSplitter};
+use utils::{self, SequenceTransformer, SequenceSwapper, SequenceSplitter};
 use rdiff::Diff;
 use byteorder::{NetworkEndian, ByteOrder};
+use fingerprint::Fingerprint;
+use metadata::Metadata;
+use blame::{self, Authorship};
+use history::{self, Timeline};
+use markers::{self, Origin};
+use moves;
+use resurrect;
+use policy::ConflictPolicy;
+use conflict::{self, Conflict, ConflictKind, ConflictOperation, Resolution};
+use tiebreak::{self, TieBreak, SiteOrder, Ties, Clock};
+use format::{self, Formatting};
+use comments::Comments;
+use element::Element;
+use text;
+
+/// Written at the start of a compressed engine, where engines saved before the format had a
+/// version started with the number of inserts
+const ENGINE_HEADER: u32 = 0xFFFF_FFFF;
+/// The version of the compressed engine format.  Each version adds a section after the deletes,
+/// and engines saved with an earlier version are expanded with the defaults for what they lack.
+const ENGINE_VERSION: u8 = WITH_DISCARDED;
+/// The version that added checkpoints
+const WITH_CHECKPOINTS: u8 = 1;
+/// The version that added the metadata of the transactions
+const WITH_METADATA: u8 = 2;
+/// The version that added the conflict policy
+const WITH_POLICY: u8 = 3;
+/// The version that added the formatting
+const WITH_FORMATTING: u8 = 4;
+/// The version that added the comments
+const WITH_COMMENTS: u8 = 5;
+/// The version that added the tie-breaking strategy
+const WITH_TIE_BREAK: u8 = 6;
+/// The version that added text mode
+const WITH_TEXT_MODE: u8 = 7;
+/// The version that added the point the history was discarded up to
+const WITH_DISCARDED: u8 = 8;
+
+/// The most elements the inserts in the history of a snapshot may add up to.  Only their lengths
+/// are sent, so the placeholder text has to be allocated without anything to read.
+const MAX_SNAPSHOT_HISTORY: u64 = 1 << 32;
+
+/// Set in the first byte of a compressed transaction if it has a `last_timestamp`
+const HAS_STATE: u8 = 1;
+/// Set in the first byte of a compressed transaction if it carries a fingerprint
+const HAS_FINGERPRINT: u8 = 2;
+/// Set in the first byte of a compressed transaction if its deletes are followed by the content they remove
+const HAS_CONTENT: u8 = 4;
+/// Set in the first byte of a compressed transaction if it carries metadata
+const HAS_METADATA: u8 = 8;
+/// Set in the first byte of a compressed transaction if its deletes are followed by formats
+const HAS_FORMATS: u8 = 16;
+/// Set in the first byte of a compressed transaction if its formats are followed by anchors
+const HAS_ANCHORS: u8 = 32;
+/// Set in the first byte of a compressed transaction if its anchors are followed by moves
+const HAS_MOVES: u8 = 64;
 
 /// Process file change operations in such a way that they can be synchronized across sites
 ///
@@ -20,22 +78,56 @@ use byteorder::{NetworkEndian, ByteOrder};
 /// To use, simply pass through any local edits via `process_diffs()` (if they are recorded in a [`Diff`](https://dyule.github.io/rdiff/rdiff/struct.Diff.html))
 /// or `process_transaction()` (If the operations are already stored in a [`TransactionSequence`](struct.TransactionSequence.html)) and pass any remote edits received into `integrate_remote()`.
 ///
+/// The engine synchronizes bytes unless some other [`Element`](trait.Element.html) type is
+/// given, in which case it keeps an ordered list of elements synchronized instead.  The
+/// methods that only make sense for files, such as `process_diffs()`, are only available for
+/// bytes.
+///
 /// See the module level documentation for an example.
 #[derive(Clone)]
-pub struct Engine {
+pub struct Engine<T = u8> {
     /// The unique ID for this site
     site_id: u32,
 
     /// The inserts for this site, stored in effect order
-    inserts: LinkedList<InsertOperation>,
+    inserts: LinkedList<InsertOperation<T>>,
 
     /// The deletes for this site, stored in effect order
-    deletes: LinkedList<DeleteOperation>,
+    deletes: LinkedList<DeleteOperation<T>>,
+
+    /// The local timestamp of the snapshot this engine was started from, if any.  The
+    /// contents of the inserts made up to that point are not known.
+    snapshot_timestamp: Option<u32>,
+
+    /// The local timestamp of the last operation forgotten through `discard_history()`, if any.
+    /// The states before it are no longer part of the history.
+    discarded_timestamp: Option<u32>,
+
+    /// The names given to states of the history, in the form accepted by `get_operations_since()`
+    checkpoints: BTreeMap<String, Option<(u32, u32)>>,
+
+    /// The metadata of the transactions in the history, by local timestamp
+    metadata: BTreeMap<u32, Metadata>,
+
+    /// What happens to inserts made inside ranges that are deleted concurrently
+    policy: ConflictPolicy,
+
+    /// Which of two concurrent inserts at the same position goes first
+    tie_break: Arc<dyn TieBreak>,
+
+    /// Whether local edits are widened so that they never split a UTF-8 character
+    text_mode: bool,
+
+    /// The attributes set on the document by the formats of the transactions in the history
+    formatting: Formatting,
+
+    /// The threads of comments on the document, anchored by the transactions in the history
+    comments: Comments,
 
 }
 
 /// Tracks the relationship between local timestamps and the timestamp on remote machines.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TimeStamper {
     /// A mapping between the remote id of a transaction and its local timstamp
     time_mapping: HashMap<(u32, u32), u32>,
@@ -48,130 +140,403 @@ pub struct TimeStamper {
 
 }
 
+/// A copy of a document that a new site can start from, instead of replaying the whole history.
+///
+/// As well as the document, a snapshot holds the state it was taken at, every timestamp, and the
+/// shape of the history: where each operation happened and how long it was, but not the text that
+/// was inserted, which is either part of the document already or has since been deleted.  That is
+/// all an engine needs to integrate transactions made concurrently with the snapshot.
+///
+/// An engine started from a snapshot can't provide the operations from before the snapshot
+/// through `get_operations_since()`.  It doesn't remember this through `compress_to()`, so
+/// it should be saved as a new snapshot instead.
+///
+/// The snapshot keeps the engine's checkpoints, along with the deleted text needed to rebuild
+/// them through `Engine::get_document_at()`, and the formatting and threads of comments on the
+/// document.
+///
+/// Snapshots are only taken of engines that synchronize bytes.  The inserted text they leave out
+/// is read back as zero bytes, which have no counterpart for other kinds of `Element`.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    document: Vec<u8>,
+    state: Option<(u32, u32)>,
+    inserts: LinkedList<InsertOperation>,
+    deletes: LinkedList<DeleteOperation>,
+    stamper: TimeStamper,
+    checkpoints: BTreeMap<String, Option<(u32, u32)>>,
+    policy: ConflictPolicy,
+    tie_break: Vec<u8>,
+    text_mode: bool,
+    discarded_timestamp: Option<u32>,
+    formatting: Formatting,
+    comments: Comments,
+}
+
 /// Represents a sequence of transactions that can be performed on a file.
 ///
 /// The operations are stored in the order they occur in the file, and each operation
 /// assumes the previous operations have taken place.  Furthermore, the insertion operations are
 /// assumed to take place prior to the deletion operations.
-#[derive(Debug, Clone)]
-pub struct TransactionSequence {
+#[derive(Clone)]
+pub struct TransactionSequence<T = u8> {
     /// Last time stamp assigned before the operations in this sequence were performed
     last_timestamp: Option<(u32, u32)>,
 
     /// The inserts for this sequence, stored in effect order
-    pub inserts: LinkedList<InsertOperation>,
+    pub inserts: LinkedList<InsertOperation<T>>,
 
     /// The deletes for this sequence, stored in effect order
-    pub deletes: LinkedList<DeleteOperation>,
+    pub deletes: LinkedList<DeleteOperation<T>>,
+
+    /// The fingerprint of the document the sender had after performing this sequence, if it sent one
+    fingerprint: Option<Fingerprint>,
+
+    /// Who made this sequence, when and why, if the sender said
+    metadata: Option<Metadata>,
+
+    /// The attributes this sequence sets on the document
+    formats: Vec<FormatOperation>,
+
+    /// The threads of comments this sequence anchors to the document
+    anchors: Vec<AnchorOperation>,
+
+    /// The moves this sequence makes with its inserts and deletes
+    moves: Vec<MoveOperation>,
+}
+
+/// An incoming operation that overlapped an existing one while being transformed
+struct Collision<O1, O2> {
+    /// The incoming operation as it was before it was transformed by the existing one
+    incoming: O1,
+    existing: O2,
+    overlap: OverlapResult,
+    /// Where the incoming operation ended up in the transformed sequence, before any pieces were joined
+    piece: usize,
 }
 
 // Public methods
-impl Engine {
+impl<T: Element> Engine<T> {
     #[inline]
     /// Creates a new engine for the given site id.  The id should be
     /// unique across all clients, and probably generated by the server
-    pub fn new(site_id: u32) -> Engine {
+    pub fn new(site_id: u32) -> Engine<T> {
         Engine {
             site_id: site_id,
             inserts: LinkedList::new(),
             deletes: LinkedList::new(),
+            snapshot_timestamp: None,
+            discarded_timestamp: None,
+            checkpoints: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            policy: ConflictPolicy::default(),
+            tie_break: Arc::new(SiteOrder),
+            text_mode: false,
+            formatting: Formatting::new(),
+            comments: Comments::new(),
         }
     }
 
-    /// Convert the diffs we got from analyzing a file into a TransactionSequence
-    /// we can send to another site for synchronization.
-    pub fn process_diffs(&mut self, diff: Diff, stamper: &mut TimeStamper) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
-        let current_timestamp = stamper.get_last_timestamp();
-        let new_timestamp = stamper.stamp_local(self.site_id);
-        let inserts = diff.inserts().map(|insert| {
-            InsertOperation::new(
-                insert.get_position()as Position,
-                insert.get_data().clone(),
-                new_timestamp,
-                self.site_id,
-            )
-        }).collect();
+    /// Integrates the sequence of operations given by `remote_sequence` into the local history.  The ordering
+    /// properties of the local history will be maintained, and a sequence of operations that
+    /// can be applied to the local state will be returned.
+    pub fn integrate_remote(&mut self, remote_sequence: &mut TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &mut TimeStamper) -> Result<(), OTError> {
+        try!(self.integrate_remote_with_conflicts(remote_sequence, lookup, stamper));
+        Ok(())
+    }
 
+    /// Like `integrate_remote()`, but also reports where the remote operations ran into concurrent
+    /// local ones: inserts that landed inside a range the other site deleted, and deletes that
+    /// overlapped.  Each [`Conflict`](struct.Conflict.html) says how it was settled, and where
+    /// it is in the document once `remote_sequence` has been applied.
+    pub fn integrate_remote_with_conflicts(&mut self, remote_sequence: &mut TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &mut TimeStamper) -> Result<Vec<Conflict<T>>, OTError> {
+        self.integrate(remote_sequence, lookup, stamper, None)
+    }
 
-        let deletes = diff.deletes().map(|delete| {
-            DeleteOperation::new(
-                delete.get_position() as Position,
-                delete.get_length() as Position,
-                new_timestamp
-            )
-        }).collect();
-        let mut lookup = BTreeMap::new();
-        lookup.insert(new_timestamp, (self.site_id, new_timestamp));
-        let mut sequence = TransactionSequence::new(current_timestamp.map(|(_local, remote)| remote), inserts, deletes);
-        self.process_transaction(&mut sequence);
-        (sequence, lookup)
+    /// Like `integrate_remote()`, but also applies `remote_sequence` to `document`, which must be
+    /// the document this engine's history has produced, and then carries the edits this site made
+    /// inside the ranges the remote moves took away over to where they went.  Text inserted inside
+    /// a moved range at the same time as the move is taken out of where it was left and put in the
+    /// copy, and text deleted from the range at the same time is deleted from the copy as well.
+    ///
+    /// Each site carries its own edits, so that they are only carried once.  They are carried by
+    /// a local transaction, which has already been applied to `document` and processed, and must
+    /// be sent out like any other.  Moves are only sent along with the transaction that made them,
+    /// so the same transaction resent by `get_operations_since()` is just a copy and a delete.
+    #[allow(clippy::type_complexity)]
+    pub fn integrate_remote_with_moves(&mut self, remote_sequence: &mut TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, document: &mut Vec<T>, stamper: &mut TimeStamper) -> Result<Option<(TransactionSequence<T>, BTreeMap<u32, (u32, u32)>)>, OTError> {
+        let reference_time = try!(self.get_reference_time(remote_sequence.last_timestamp, stamper));
+        try!(self.integrate(remote_sequence, lookup, stamper, None));
+        *document = remote_sequence.apply_to_elements(document);
+        if remote_sequence.moves.is_empty() {
+            return Ok(None);
+        }
+
+        let (inserts, deletes) = {
+            let site_id = self.site_id;
+            let known = Self::known_to_sender(reference_time, lookup, stamper);
+            let own = |timestamp: u32| stamper.get_remote_timestamp_for(timestamp).is_some_and(|(made_by, _)| made_by == site_id);
+            let timeline = try!(Timeline::new(&self.inserts, &self.deletes, document));
+            try!(moves::carry(&timeline, &remote_sequence.moves, &known, &own, site_id))
+        };
+        if inserts.is_empty() && deletes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.process_local(inserts, deletes, document, stamper)))
     }
 
-    /// Integrates the sequence of operations given by `remote_sequence` into the local history.  The ordering
-    /// properties of the local history will be maintained, and a sequence of operations that
-    /// can be applied to the local state will be returned.
-    pub fn integrate_remote(&mut self, remote_sequence: &mut TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &mut TimeStamper) -> Result<(), OTError> {
+    /// Transforms `operation`, which was made on the document as it was before `remote_sequence`
+    /// was integrated and applied, so that it can be performed after it.  Anything the sequence
+    /// inserted inside the moved range is moved along with it, and an insert at the destination
+    /// goes before or after the moved range as this engine's tie-breaking strategy decides.
+    /// `lookup` is the one the sequence was integrated with.
+    pub fn transform_move(&self, remote_sequence: &TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, operation: &mut MoveOperation) {
+        let mut source = operation.get_source();
+        remote_sequence.transform_replace(&mut source);
+        let mut target = LinkedList::new();
+        target.push_back(operation.get_target::<T>());
+        {
+            let ties = Ties::new(&*self.tie_break, Clock::Own, Clock::Remote(lookup));
+            Self::transform_with(&mut target, &remote_sequence.inserts, ConflictPolicy::KeepInsert, &ties);
+        }
+        Self::transform(&mut target, &remote_sequence.deletes);
+        operation.set_from(&source, target.front().unwrap());
+    }
+
+    /// Integrates a remote sequence, returning the conflicts it ran into.  If `remote_deletes` is
+    /// given, it is filled with the remote deletes once they take every insert into account, but
+    /// before the local deletes have taken out what they had already deleted.
+    fn integrate(&mut self, remote_sequence: &mut TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &mut TimeStamper, remote_deletes: Option<&mut LinkedList<DeleteOperation<T>>>) -> Result<Vec<Conflict<T>>, OTError> {
+        // The sender's fingerprint only describes our document if we have done nothing it didn't know about
+        if remote_sequence.fingerprint.is_some() && !try!(self.sender_knows_history(remote_sequence, lookup, stamper)) {
+            remote_sequence.fingerprint = None;
+        }
+
+        // Find the copy each remote move made, before anything is changed
+        let copies: Vec<usize> = try!(Self::find_copies(&remote_sequence.inserts, &remote_sequence.moves).into_iter()
+            .map(|copy| copy.ok_or(OTError::new(Kind::OutOfRange))).collect());
 
         //Get all the local inserts that have happened since the last sync with the remote site
-        let local_concurrent_inserts = try!(self.get_concurrent_inserts(&remote_sequence, lookup, stamper));
+        let local_concurrent_inserts = try!(self.get_concurrent_inserts(remote_sequence, lookup, stamper));
         // Transform the remote inserts so that they account for the changes from the local inserts
-        Engine::transform(&mut remote_sequence.inserts, &local_concurrent_inserts);
+        {
+            let ties = Ties::new(&*self.tie_break, Clock::Remote(lookup), Clock::Local(stamper));
+            Self::transform_with(&mut remote_sequence.inserts, &local_concurrent_inserts, ConflictPolicy::KeepInsert, &ties);
+        }
 
         // Save the inserts as they are before integrating the local deletes, since that will be
         // used later for integrating the remote deletes
         let mut transformed_remote_inserts = remote_sequence.inserts.clone();
 
         // Transform the remote inserts so that they account for the changes from the local deletes
-        Engine::transform(&mut remote_sequence.inserts, &self.deletes);
+        let insert_collisions = Self::transform_with(&mut remote_sequence.inserts, &self.deletes, self.policy, &Ties::by_site());
+        // Find where the inserts that landed in local deletes are once the rest of the remote inserts are in
+        let inserts_in_deletes: Vec<_> = insert_collisions.into_iter().map(|collision| {
+            let range = {
+                let insert = remote_sequence.inserts.iter().nth(collision.piece).unwrap();
+                (insert.get_position(), insert.get_position() + insert.get_value().len() as Position)
+            };
+            let range = conflict::through_inserts(range, remote_sequence.inserts.iter().skip(collision.piece + 1));
+            (collision, range)
+        }).collect();
+        if self.policy == ConflictPolicy::DropInsert {
+            // The inserts that were deleted along with their surroundings don't change anything here
+            let inserts = mem::take(&mut remote_sequence.inserts);
+            remote_sequence.inserts = inserts.into_iter().filter(|insert| !insert.get_value().is_empty()).collect();
+        }
 
         self.assign_timestamps(&mut transformed_remote_inserts, lookup, stamper);
 
         // Merge the transformed remote inserts with the local.  Note that we use the inserts that have not been
         // transformed by deletes, as the local inserts always preceded the deletes.
-        Engine::merge_sequences(&mut self.inserts, &transformed_remote_inserts);
+        {
+            let ties = Ties::new(&*self.tie_break, Clock::Local(stamper), Clock::Local(stamper));
+            Self::merge_sequences(&mut self.inserts, &transformed_remote_inserts, &ties);
+        }
 
         // Adjust the local deletes with the remote inserts that have been merged into the local inserts
-        Engine::transform(&mut self.deletes, &transformed_remote_inserts);
+        Self::transform_with(&mut self.deletes, &transformed_remote_inserts, self.policy, &Ties::by_site());
         // Transform the remote deletes with all of the local inserts that happened since the last sync
-        let transformed_concurrent_inserts = try!(self.get_concurrent_inserts(&remote_sequence, lookup, stamper));
+        let transformed_concurrent_inserts = try!(self.get_concurrent_inserts(remote_sequence, lookup, stamper));
 
-        Engine::transform(&mut remote_sequence.deletes, &transformed_concurrent_inserts);
+        let delete_collisions = Self::transform_with(&mut remote_sequence.deletes, &transformed_concurrent_inserts, self.policy, &Ties::by_site());
+        // The remote formats are placed before every delete as well, so text inserted inside them
+        // since the last sync joins them
+        let format_ranges: Vec<_> = remote_sequence.formats.iter().map(|format| {
+            let position = format.get_position();
+            conflict::through_inserts((position, position + format.get_length()), transformed_concurrent_inserts.iter())
+        }).collect();
+        let anchor_ranges: Vec<_> = remote_sequence.anchors.iter().map(|anchor| {
+            let position = anchor.get_position();
+            conflict::through_inserts((position, position + anchor.get_length()), transformed_concurrent_inserts.iter())
+        }).collect();
+        // The remote moves stay there, along with where their copies ended up
+        let move_ranges: Vec<_> = remote_sequence.moves.iter().zip(copies).map(|(operation, copy)| {
+            let position = operation.get_position();
+            let range = conflict::through_inserts((position, position + operation.get_length()), transformed_concurrent_inserts.iter());
+            let destination = transformed_remote_inserts.iter().nth(copy).and_then(|insert| self.locate_in_inserts(insert)).map(|(start, _)| start);
+            (range, destination.unwrap_or(range.0))
+        }).collect();
+        let deletes_around_inserts: Vec<_> = delete_collisions.into_iter().filter(|collision| matches!(collision.overlap, OverlapResult::Encloses(_))).filter_map(|collision| self.locate_insert(&collision.existing).map(|range| (collision, range))).collect();
         trace!("Sequence: {:?}", remote_sequence);
+        if let Some(remote_deletes) = remote_deletes {
+            *remote_deletes = remote_sequence.deletes.clone();
+        }
 
         // Transform the remote deletes with ALL of the local deletes.
-        Engine::transform(&mut remote_sequence.deletes, &self.deletes);
+        let overlap_collisions = Self::transform_with(&mut remote_sequence.deletes, &self.deletes, ConflictPolicy::KeepInsert, &Ties::by_site());
         trace!("Sequence: {:?}", remote_sequence);
+        let overlapping_deletes: Vec<_> = overlap_collisions.into_iter().map(|collision| {
+            let position = remote_sequence.deletes.iter().nth(collision.piece).unwrap().get_position();
+            let range = conflict::through_deletes((position, position), remote_sequence.deletes.iter().skip(collision.piece + 1));
+            (collision, range)
+        }).collect();
 
-        self.assign_timestamps(&mut remote_sequence.deletes, &lookup, stamper);
+        self.assign_timestamps(&mut remote_sequence.deletes, lookup, stamper);
+
+        if let Some(ref metadata) = remote_sequence.metadata {
+            let timestamp = lookup.values().filter_map(|&(site_id, timestamp)| stamper.get_local_timestamp_for(site_id, timestamp)).max();
+            if let Some(timestamp) = timestamp {
+                self.metadata.insert(timestamp, metadata.clone());
+            }
+        }
 
         // Merge the remote deletes that have taken all the local operations into effect with the local deletes
-         Engine::merge_sequences(&mut self.deletes, &mut remote_sequence.deletes);
+         Self::merge_sequences(&mut self.deletes, &remote_sequence.deletes, &Ties::by_site());
 
-         Ok(())
+        // Move the formatting over the remote operations, then add the remote formats where every
+        // delete has left them
+        self.formatting.update(&remote_sequence.inserts, &remote_sequence.deletes);
+        for (format, range) in remote_sequence.formats.iter_mut().zip(format_ranges) {
+            let (start, end) = conflict::through_deletes(range, self.deletes.iter());
+            format.set_range(start, end - start);
+            let &(site_id, timestamp) = try!(lookup.get(&format.get_timestamp()).ok_or(OTError::new(Kind::NoSuchState)));
+            format.set_timestamp(stamper.stamp_remote(site_id, timestamp));
+            format.set_site_id(site_id);
+            self.formatting.set(format, (site_id, timestamp));
+        }
+        // Likewise for the comments and the remote anchors
+        self.comments.update(&remote_sequence.inserts, &remote_sequence.deletes);
+        for (anchor, range) in remote_sequence.anchors.iter_mut().zip(anchor_ranges) {
+            let (start, end) = conflict::through_deletes(range, self.deletes.iter());
+            anchor.set_range(start, end - start);
+            let &(site_id, timestamp) = try!(lookup.get(&anchor.get_timestamp()).ok_or(OTError::new(Kind::NoSuchState)));
+            anchor.set_timestamp(stamper.stamp_remote(site_id, timestamp));
+            anchor.set_site_id(site_id);
+            self.comments.anchor(anchor);
+        }
+        for (operation, ((start, end), destination)) in remote_sequence.moves.iter_mut().zip(move_ranges) {
+            operation.set_range(start, end - start, destination);
+            let &(site_id, timestamp) = try!(lookup.get(&operation.get_timestamp()).ok_or(OTError::new(Kind::NoSuchState)));
+            operation.set_timestamp(stamper.stamp_remote(site_id, timestamp));
+            operation.set_site_id(site_id);
+        }
 
+        let insert_resolution = match self.policy {
+            ConflictPolicy::KeepInsert | ConflictPolicy::Markers | ConflictPolicy::Resurrect => Resolution::InsertKept,
+            ConflictPolicy::DropInsert => Resolution::InsertDeleted,
+        };
+        let mut conflicts = Vec::new();
+        for (mut collision, range) in inserts_in_deletes {
+            Self::localize(&mut collision.incoming, lookup, stamper);
+            conflicts.push(Conflict::new(ConflictKind::InsertInsideDelete, insert_resolution,
+                                         ConflictOperation::Delete(collision.existing),
+                                         ConflictOperation::Insert(collision.incoming),
+                                         conflict::through_deletes(range, remote_sequence.deletes.iter())));
+        }
+        for (mut collision, range) in deletes_around_inserts {
+            Self::localize(&mut collision.incoming, lookup, stamper);
+            conflicts.push(Conflict::new(ConflictKind::InsertInsideDelete, insert_resolution,
+                                         ConflictOperation::Insert(collision.existing),
+                                         ConflictOperation::Delete(collision.incoming),
+                                         conflict::through_deletes(range, remote_sequence.deletes.iter())));
+        }
+        for (mut collision, range) in overlapping_deletes {
+            Self::localize(&mut collision.incoming, lookup, stamper);
+            conflicts.push(Conflict::new(ConflictKind::OverlappingDeletes, Resolution::DeletedOnce,
+                                         ConflictOperation::Delete(collision.existing),
+                                         ConflictOperation::Delete(collision.incoming),
+                                         range));
+        }
+        Ok(conflicts)
     }
 
     /// Processes a series of operations prior to being sent out to remote sites.  The operations must
     /// have been performed on the data after every operation in the local history, but no others.  The
     /// operations in the transaction must also be effect order, with the inserts preceding the deletes.
-    pub fn process_transaction(&mut self, outgoing_sequence: &mut TransactionSequence) {
+    /// In text mode, they must not split any characters (see `TransactionSequence::align_to_chars()`).
+    pub fn process_transaction(&mut self, outgoing_sequence: &mut TransactionSequence<T>) {
 
+        // Keep the formatting in place on the document the sequence was made on
+        self.formatting.update(&outgoing_sequence.inserts, &outgoing_sequence.deletes);
+        self.comments.update(&outgoing_sequence.inserts, &outgoing_sequence.deletes);
+
+        // Find the copy each outgoing move made.  A move whose copy has been changed since it was
+        // made, such as by `align_to_chars()`, is sent as an ordinary copy and delete.
+        let copies = Self::find_copies(&outgoing_sequence.inserts, &outgoing_sequence.moves);
+        let moves = mem::take(&mut outgoing_sequence.moves);
+        let mut moves: Vec<_> = moves.into_iter().zip(copies).filter_map(|(operation, copy)| copy.map(|copy| (operation, copy))).collect();
 
         // Swap the execution order of the outgoing insert operations so that they happen before the local deletes
-        Engine::swap(&mut outgoing_sequence.inserts, &mut self.deletes);
+        Self::swap(&mut outgoing_sequence.inserts, &mut self.deletes);
+
+        // Place the moved ranges before the local deletes as well, where the remote sites can
+        // find what they inserted inside them
+        for &mut (ref mut operation, _) in moves.iter_mut() {
+            let position = operation.get_position();
+            let (start, end) = format::before_deletes((position, position + operation.get_length()), self.deletes.iter());
+            let destination = operation.get_destination();
+            operation.set_range(start, end - start, destination);
+        }
 
         // Split the outgoing sequence by the existing deletes so that there is no overlap during the swap phase.
-        Engine::split_by(&mut outgoing_sequence.deletes, &self.deletes);
+        Self::split_by(&mut outgoing_sequence.deletes, &self.deletes);
         
         let original_deletes = outgoing_sequence.deletes.clone();
         // Swap the execution order of the outgoing delete operations so they happen before the local deletes
-        Engine::swap(&mut outgoing_sequence.deletes, &mut self.deletes.clone());
+        Self::swap(&mut outgoing_sequence.deletes, &mut self.deletes.clone());
 
         // Record that we've performed the outgoing insertion operations
-        Engine::merge_sequences(&mut self.inserts, &outgoing_sequence.inserts);
+        {
+            let ties = Ties::new(&*self.tie_break, Clock::Own, Clock::Own);
+            Self::merge_sequences(&mut self.inserts, &outgoing_sequence.inserts, &ties);
+        }
 
         // Record that we've performed the outgoing delete operations
-        Engine::merge_sequences(&mut self.deletes, &original_deletes);
+        Self::merge_sequences(&mut self.deletes, &original_deletes, &Ties::by_site());
+
+        for (mut operation, copy) in moves {
+            if let Some((destination, _)) = outgoing_sequence.inserts.iter().nth(copy).and_then(|insert| self.locate_in_inserts(insert)) {
+                let (position, length) = (operation.get_position(), operation.get_length());
+                operation.set_range(position, length, destination);
+                outgoing_sequence.moves.push(operation);
+            }
+        }
+
+        // Record the outgoing formats, and place them before every delete, where the remote
+        // sites can transform them like the outgoing deletes
+        for format in outgoing_sequence.formats.iter_mut() {
+            self.formatting.set(format, (format.get_site_id(), format.get_timestamp()));
+            let position = format.get_position();
+            let (start, end) = format::before_deletes((position, position + format.get_length()), self.deletes.iter());
+            format.set_range(start, end - start);
+        }
+        for anchor in outgoing_sequence.anchors.iter_mut() {
+            self.comments.anchor(anchor);
+            let position = anchor.get_position();
+            let (start, end) = format::before_deletes((position, position + anchor.get_length()), self.deletes.iter());
+            anchor.set_range(start, end - start);
+        }
+
+        if let Some(ref metadata) = outgoing_sequence.metadata {
+            let timestamp = outgoing_sequence.inserts.iter().map(|insert| insert.get_timestamp())
+                .chain(outgoing_sequence.deletes.iter().map(|delete| delete.get_timestamp()))
+                .chain(outgoing_sequence.formats.iter().map(|format| format.get_timestamp()))
+                .chain(outgoing_sequence.anchors.iter().map(|anchor| anchor.get_timestamp())).max();
+            if let Some(timestamp) = timestamp {
+                self.metadata.insert(timestamp, metadata.clone());
+            }
+        }
 
     }
 
@@ -181,82 +546,503 @@ impl Engine {
     // }
 
     /// Get all the operations since, but not including the given state
-    pub fn get_operations_since(&self, remote_state: Option<(u32, u32)>, stamper: &TimeStamper) -> Result<TransactionSequence, OTError> {
-        if let Some((remote_site_id, remote_timestamp)) = remote_state {
-            let reference_time = try!(stamper.get_local_timestamp_for(remote_site_id, remote_timestamp)
-                                               .ok_or(OTError::new(Kind::NoSuchState)));
+    pub fn get_operations_since(&self, remote_state: Option<(u32, u32)>, stamper: &TimeStamper) -> Result<TransactionSequence<T>, OTError> {
+        let reference_time = try!(self.get_reference_time(remote_state, stamper));
+        if let Some(snapshot_time) = self.snapshot_timestamp {
+            if reference_time.is_none_or(|reference_time| reference_time < snapshot_time) {
+                return Err(OTError::new(Kind::NoSuchState));
+            }
+        }
+        let since = |timestamp: u32| reference_time.is_none_or(|reference_time| timestamp > reference_time);
+        let inserts = self.inserts.iter().filter(|o| since(o.get_timestamp())).cloned().collect();
+        let deletes = self.deletes.iter().filter(|o| since(o.get_timestamp())).cloned().collect();
+        Ok(TransactionSequence::new(remote_state, inserts, deletes))
+    }
 
+    /// Reconstructs the document as it was at the given state, or before any operations if it is
+    /// `None`.  `document` must be the document this engine's history has produced, and any text
+    /// that has been deleted since the state must have been captured by its delete.
+    pub fn get_document_at(&self, document: &[T], state: Option<(u32, u32)>, stamper: &TimeStamper) -> Result<Vec<T>, OTError> {
+        let reference_time = try!(self.get_reference_time(state, stamper));
+        try!(Timeline::new(&self.inserts, &self.deletes, document)).document_at(reference_time)
+    }
 
-            let inserts = self.inserts.iter().filter(|o| o.get_timestamp() > reference_time).cloned().collect();
-            let deletes = self.deletes.iter().filter(|o| o.get_timestamp() > reference_time).cloned().collect();
-            Ok(TransactionSequence::new(Some((remote_site_id, remote_timestamp)), inserts, deletes))
-        } else {
-            Ok(TransactionSequence::new(None, self.inserts.iter().cloned().collect(), self.deletes.iter().cloned().collect()))
+    /// Turns `document` back into the document it was at the given state, as in
+    /// `get_document_at()`, and returns the local transaction that did it.  Like
+    /// `process_diffs()`, the transaction has already been processed and can be sent straight out.
+    #[allow(clippy::type_complexity)]
+    pub fn revert_to(&mut self, document: &mut Vec<T>, state: Option<(u32, u32)>, stamper: &mut TimeStamper) -> Result<(TransactionSequence<T>, BTreeMap<u32, (u32, u32)>), OTError> {
+        let reference_time = try!(self.get_reference_time(state, stamper));
+        let timeline = try!(Timeline::new(&self.inserts, &self.deletes, document));
+        let (inserts, deletes) = try!(timeline.revert_to(reference_time, self.site_id));
+        Ok(self.process_local(inserts, deletes, document, stamper))
+    }
+
+    /// Works out who wrote each part of `document`, which must be the document this engine's
+    /// history has produced.  The document is covered by ranges in order, and every byte in a
+    /// range was inserted by the same operation.
+    pub fn blame(&self, document: &[T], stamper: &TimeStamper) -> Result<Vec<Authorship>, OTError> {
+        blame::annotate(&self.inserts, &self.deletes, document.len() as Position, stamper)
+    }
+
+    /// Names `state` so that it can be found again with `get_checkpoint()`, replacing any
+    /// checkpoint that already has the name.  The state must be one the stamper knows about.
+    pub fn set_checkpoint(&mut self, name: &str, state: Option<(u32, u32)>, stamper: &TimeStamper) -> Result<(), OTError> {
+        try!(self.get_reference_time(state, stamper));
+        self.checkpoints.insert(name.to_string(), state);
+        Ok(())
+    }
+
+    /// Gets the state named `name`, which can be passed anywhere else a state is accepted, such
+    /// as `get_operations_since()` or `get_document_at()`
+    pub fn get_checkpoint(&self, name: &str) -> Result<Option<(u32, u32)>, OTError> {
+        self.checkpoints.get(name).cloned().ok_or(OTError::new(Kind::NoSuchState))
+    }
+
+    /// Forgets the checkpoint named `name`, returning whether there was one
+    pub fn remove_checkpoint(&mut self, name: &str) -> bool {
+        self.checkpoints.remove(name).is_some()
+    }
+
+    /// Gets every checkpoint, by name
+    #[inline]
+    pub fn get_checkpoints(&self) -> &BTreeMap<String, Option<(u32, u32)>> {
+        &self.checkpoints
+    }
+
+    /// Forgets the operations up to and including `state`, so that the history doesn't grow
+    /// without bound.  Every site must already have integrated them, and every transaction still
+    /// to be integrated must have been made on `state` or a later one, which is what a server's
+    /// acknowledgement promises.  The text the forgotten operations inserted is treated as if it
+    /// had been there from the start, so `blame()` no longer knows who wrote it.
+    ///
+    /// Checkpoints are never discarded: if one was set before `state`, only the operations up to
+    /// and including the earliest checkpoint are forgotten, and the rest go once it is removed.
+    ///
+    /// Afterwards, the state discarded up to is the earliest state the engine accepts, and the
+    /// metadata from before it is removed.  Discarding a state before one that was already
+    /// discarded does nothing.
+    pub fn discard_history(&mut self, state: (u32, u32), stamper: &TimeStamper) -> Result<(), OTError> {
+        let mut reference_time = try!(stamper.get_local_timestamp_for(state.0, state.1).ok_or(OTError::new(Kind::NoSuchState)));
+        let earliest_checkpoint = self.checkpoints.values().map(|&checkpoint| {
+            checkpoint.and_then(|(site_id, timestamp)| stamper.get_local_timestamp_for(site_id, timestamp))
+        }).min();
+        match earliest_checkpoint {
+            // A checkpoint on the empty history keeps all of it
+            Some(None) => return Ok(()),
+            Some(Some(checkpoint)) => reference_time = reference_time.min(checkpoint),
+            None => ()
+        }
+        if self.discarded_timestamp.is_some_and(|discarded| reference_time <= discarded) {
+            return Ok(());
         }
+        let (inserts, deletes) = history::discard_before(&self.inserts, &self.deletes, reference_time);
+        self.inserts = inserts;
+        self.deletes = deletes;
+        self.metadata = self.metadata.split_off(&(reference_time + 1));
+        self.discarded_timestamp = Some(reference_time);
+        Ok(())
+    }
+
+    /// Gets the metadata of the transaction with the given local timestamp, if it had any
+    pub fn get_metadata(&self, timestamp: u32) -> Option<&Metadata> {
+        self.metadata.get(&timestamp)
+    }
+
+    /// Records the metadata of the transaction with the given local timestamp.  Transactions
+    /// that carry metadata have it recorded when they go through `process_transaction()` or
+    /// `integrate_remote()`, so this is only needed for transactions that are given metadata
+    /// afterwards, such as those made by `process_diffs()`, or that have no operations.
+    pub fn set_metadata(&mut self, timestamp: u32, metadata: Option<Metadata>) {
+        match metadata {
+            Some(metadata) => self.metadata.insert(timestamp, metadata),
+            None => self.metadata.remove(&timestamp)
+        };
+    }
+
+    /// Gets the attributes that the formats of the transactions in the history have set on the
+    /// document
+    #[inline]
+    pub fn get_formatting(&self) -> &Formatting {
+        &self.formatting
+    }
+
+    /// Gets the threads of comments on the document, anchored where the transactions in the
+    /// history left them
+    #[inline]
+    pub fn get_comments(&self) -> &Comments {
+        &self.comments
+    }
+
+    /// Gets the threads of comments on the document, so that comments written here or received
+    /// from other sites can be added to them
+    #[inline]
+    pub fn get_comments_mut(&mut self) -> &mut Comments {
+        &mut self.comments
+    }
+
+    /// Gets the policy for inserts made inside ranges that are deleted concurrently
+    #[inline]
+    pub fn get_conflict_policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+
+    /// Sets the policy for inserts made inside ranges that are deleted concurrently.  Every
+    /// site must use the same policy, and it should be chosen before any remote transactions
+    /// are integrated.
+    #[inline]
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.policy = policy;
+    }
+
+    /// Gets the strategy that decides which of two concurrent inserts at the same position goes first
+    #[inline]
+    pub fn get_tie_break(&self) -> &Arc<dyn TieBreak> {
+        &self.tie_break
+    }
+
+    /// Sets the strategy that decides which of two concurrent inserts at the same position goes
+    /// first.  As with the conflict policy, every site must use the same strategy, and it should
+    /// be chosen before any remote transactions are integrated.  It is saved by `compress_to()`
+    /// and in a [`Snapshot`](struct.Snapshot.html) by its description, but a strategy from
+    /// outside this crate can't be found from its description, so an engine saved with one has
+    /// to be expanded with `expand_with_tie_break()`.
+    #[inline]
+    pub fn set_tie_break(&mut self, tie_break: Arc<dyn TieBreak>) {
+        self.tie_break = tie_break;
     }
 
     /// Compress this engine and write to `writer`.  The output can then be expanded
     /// back into an equivilent Engine using `expand_from()`
     pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, ENGINE_HEADER);
+        try!(writer.write_all(&int_buf));
+        try!(writer.write_all(&[ENGINE_VERSION]));
         NetworkEndian::write_u32(&mut int_buf, self.inserts.len() as u32);
-        try!(writer.write(&mut int_buf));
+        try!(writer.write_all(&int_buf));
         for insert in self.inserts.iter() {
             try!(insert.compress_to(writer, true));
         }
         NetworkEndian::write_u32(&mut int_buf, self.deletes.len() as u32);
-        try!(writer.write(&mut int_buf));
+        try!(writer.write_all(&int_buf));
         for delete in self.deletes.iter() {
-            try!(delete.compress_to(writer));
+            try!(delete.compress_to(writer, true, true));
         }
-        Ok(())
+        try!(compress_checkpoints_to(&self.checkpoints, writer));
+        NetworkEndian::write_u32(&mut int_buf, self.metadata.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (timestamp, metadata) in self.metadata.iter() {
+            NetworkEndian::write_u32(&mut int_buf, *timestamp);
+            try!(writer.write_all(&int_buf));
+            try!(metadata.compress_to(writer));
+        }
+        try!(self.policy.compress_to(writer));
+        try!(self.formatting.compress_to(writer));
+        try!(self.comments.compress_to(writer));
+        try!(tiebreak::compress_to(&self.tie_break.describe(), writer));
+        try!(writer.write_all(&[self.text_mode as u8]));
+        compress_discarded_to(self.discarded_timestamp, writer)
     }
 
     /// Expand this engine from previously compressed data in `reader`.  The data in reader
-    /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R, site_id: u32) -> io::Result<Engine> {
+    /// should have been written using `compress_to()` by an engine whose tie-breaking strategy
+    /// is one of this crate's.  Engines saved by earlier versions of this crate are given the
+    /// defaults for whatever they didn't save.
+    pub fn expand_from<R: Read>(reader: &mut R, site_id: u32) -> io::Result<Engine<T>> {
+        Self::expand(reader, site_id, None)
+    }
+
+    /// Like `expand_from()`, but for an engine saved with `tie_break`, which can be any strategy.
+    /// Fails if the engine was saved with a different strategy.
+    pub fn expand_with_tie_break<R: Read>(reader: &mut R, site_id: u32, tie_break: Arc<dyn TieBreak>) -> io::Result<Engine<T>> {
+        Self::expand(reader, site_id, Some(tie_break))
+    }
+
+    fn expand<R: Read>(reader: &mut R, site_id: u32, tie_break: Option<Arc<dyn TieBreak>>) -> io::Result<Engine<T>> {
         trace!("Expanding engine");
+        let mut bool_buf = [0;1];
         let mut int_buf = [0;4];
-        trace!("Reading insert length");
         try!(reader.read_exact(&mut int_buf));
+        let version = if NetworkEndian::read_u32(&int_buf) == ENGINE_HEADER {
+            try!(reader.read_exact(&mut bool_buf));
+            if bool_buf[0] > ENGINE_VERSION {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown engine format version {}", bool_buf[0])));
+            }
+            try!(reader.read_exact(&mut int_buf));
+            bool_buf[0]
+        } else {
+            0
+        };
+        trace!("Engine format version was: {}", version);
         let insert_len = NetworkEndian::read_u32(&int_buf);
         trace!("Insert length was: {}", insert_len);
-        let inserts = (0..insert_len).map(|_|InsertOperation::expand_from(reader, None).unwrap()).collect();
+        let inserts = try!((0..insert_len).map(|_| InsertOperation::expand_from(reader, None)).collect::<io::Result<_>>());
         trace!("Read inserts");
         trace!("Reading delete length");
         try!(reader.read_exact(&mut int_buf));
         let delete_len = NetworkEndian::read_u32(&int_buf);
         trace!("Delete length was: {}", delete_len);
-        let deletes = (0..delete_len).map(|_|DeleteOperation::expand_from(reader).unwrap()).collect();
+        let deletes = try!((0..delete_len).map(|_| DeleteOperation::expand_from(reader, None, true)).collect::<io::Result<_>>());
         trace!("Read deletes");
+        let checkpoints = if version >= WITH_CHECKPOINTS {
+            try!(expand_checkpoints_from(reader))
+        } else {
+            BTreeMap::new()
+        };
+        let mut metadata = BTreeMap::new();
+        if version >= WITH_METADATA {
+            try!(reader.read_exact(&mut int_buf));
+            let metadata_len = NetworkEndian::read_u32(&int_buf);
+            for _ in 0..metadata_len {
+                try!(reader.read_exact(&mut int_buf));
+                metadata.insert(NetworkEndian::read_u32(&int_buf), try!(Metadata::expand_from(reader)));
+            }
+        }
+        let policy = if version >= WITH_POLICY {
+            try!(ConflictPolicy::expand_from(reader))
+        } else {
+            ConflictPolicy::default()
+        };
+        let formatting = if version >= WITH_FORMATTING {
+            try!(Formatting::expand_from(reader))
+        } else {
+            Formatting::new()
+        };
+        let comments = if version >= WITH_COMMENTS {
+            try!(Comments::expand_from(reader))
+        } else {
+            Comments::new()
+        };
+        let description = if version >= WITH_TIE_BREAK {
+            try!(tiebreak::expand_from(reader))
+        } else {
+            SiteOrder.describe()
+        };
+        let tie_break = match tie_break {
+            Some(tie_break) if version < WITH_TIE_BREAK || tie_break.describe() == description => tie_break,
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "The engine was saved with a different tie-breaking strategy")),
+            None => try!(tiebreak::restore(&description).ok_or(io::Error::new(io::ErrorKind::InvalidData, "The engine was saved with an unknown tie-breaking strategy"))),
+        };
+        let text_mode = if version >= WITH_TEXT_MODE {
+            try!(reader.read_exact(&mut bool_buf));
+            bool_buf[0] == 1
+        } else {
+            false
+        };
+        let discarded_timestamp = if version >= WITH_DISCARDED {
+            try!(expand_discarded_from(reader))
+        } else {
+            None
+        };
 
         Ok(Engine {
             site_id: site_id,
             inserts: inserts,
             deletes: deletes,
+            snapshot_timestamp: None,
+            discarded_timestamp: discarded_timestamp,
+            checkpoints: checkpoints,
+            metadata: metadata,
+            policy: policy,
+            tie_break: tie_break,
+            text_mode: text_mode,
+            formatting: formatting,
+            comments: comments,
         })
     }
 }
 
-// Private methods
+// Methods for engines that synchronize bytes
 impl Engine {
+    /// Convert the diffs we got from analyzing a file into a TransactionSequence
+    /// we can send to another site for synchronization.  Fails in text mode, where the changes
+    /// can't be widened to whole characters without the file they were made on, so use
+    /// `process_diffs_with_base()` instead.
+    #[allow(clippy::type_complexity)]
+    pub fn process_diffs(&mut self, diff: Diff, stamper: &mut TimeStamper) -> Result<(TransactionSequence, BTreeMap<u32, (u32, u32)>), OTError> {
+        if self.text_mode {
+            return Err(OTError::new(Kind::UnknownBase));
+        }
+        Ok(self.process_diffs_from(diff, None, stamper))
+    }
 
+    /// Like `process_diffs()`, but `base` is the contents of the file before the changes were made,
+    /// which lets the deletes record the content they removed.  In text mode, the changes are
+    /// also widened so that they don't split any characters.
+    pub fn process_diffs_with_base(&mut self, diff: Diff, base: &[u8], stamper: &mut TimeStamper) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
+        self.process_diffs_from(diff, Some(base), stamper)
+    }
 
-    fn get_concurrent_inserts(&self, remote_sequence: &TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &TimeStamper) -> Result<LinkedList<InsertOperation>, OTError> {
-        let mut tail_timestamp = None;
-        for insert in remote_sequence.inserts.iter() {
-            let timestamp = insert.get_timestamp();
-            if let Some((local, _remote)) = tail_timestamp {
+    /// Like `integrate_remote()`, but also applies `remote_sequence` to `document`, which must be
+    /// the document this engine's history has produced.  Under
+    /// [`ConflictPolicy::Markers`](enum.ConflictPolicy.html), the lines where the remote operations
+    /// collided with this site's own are then replaced with conflict markers around both versions:
+    ///
+    /// ```text
+    /// <<<<<<< site 1
+    /// port = 8080
+    /// =======
+    /// port = 443
+    /// >>>>>>> site 2
+    /// ```
+    ///
+    /// Only the site with the lower id of the two marks a collision, so that the markers go in
+    /// once.  The markers are a local transaction, which has already been applied to `document`
+    /// and processed, and must be sent out like any other.  Both versions need the text that was
+    /// deleted from them, so the deletes involved must have captured their content.
+    ///
+    /// Under [`ConflictPolicy::Resurrect`](enum.ConflictPolicy.html), the text this site deleted
+    /// around remote inserts is put back instead, in the same kind of local transaction.  Only the
+    /// site that made the delete puts it back, so its deletes must have captured their content.
+    #[allow(clippy::type_complexity)]
+    pub fn integrate_remote_with_markers(&mut self, remote_sequence: &mut TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>, document: &mut Vec<u8>, stamper: &mut TimeStamper) -> Result<Option<(TransactionSequence, BTreeMap<u32, (u32, u32)>)>, OTError> {
+        let reference_time = try!(self.get_reference_time(remote_sequence.last_timestamp, stamper));
+        let mut remote_deletes = LinkedList::new();
+        let conflicts = try!(self.integrate(remote_sequence, lookup, stamper, Some(&mut remote_deletes)));
+        *document = remote_sequence.apply_to_bytes(document);
+        let site_id = self.site_id;
+        if self.policy == ConflictPolicy::Resurrect {
+            return self.resurrect(&conflicts, reference_time, lookup, document, stamper);
+        }
+        let conflicts: Vec<_> = conflicts.into_iter().filter(|conflict| {
+            conflict.get_local().get_site_id() == site_id && site_id < conflict.get_remote().get_site_id()
+        }).collect();
+        if self.policy != ConflictPolicy::Markers || conflicts.is_empty() {
+            return Ok(None);
+        }
 
-                if timestamp < local {
-                    tail_timestamp = Some((timestamp, try!(lookup.get(&timestamp).ok_or(OTError::new(Kind::NoSuchState)))))
+        // Sort every operation into the remote transaction, the local operations it didn't know
+        // about, and the ones both sides had
+        let (inserts, deletes) = {
+            let remote: HashSet<u32> = lookup.values().filter_map(|&(site_id, timestamp)| stamper.get_local_timestamp_for(site_id, timestamp)).collect();
+            let known = Self::known_to_sender(reference_time, lookup, stamper);
+            let origin = |timestamp: u32| {
+                if remote.contains(&timestamp) {
+                    Origin::Remote
+                } else if known(timestamp) {
+                    Origin::Both
+                } else {
+                    Origin::Local
                 }
-            } else {
-                tail_timestamp = Some((timestamp, try!(lookup.get(&timestamp).ok_or(OTError::new(Kind::NoSuchState)))))
+            };
+            let timeline = try!(Timeline::new(&self.inserts, &self.deletes, document));
+            try!(markers::mark(&timeline, &remote_deletes, &origin, &conflicts, document, site_id))
+        };
+        if inserts.is_empty() && deletes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.process_local(inserts, deletes, document, stamper)))
+    }
+
+    /// Puts back the text this site deleted around the inserts of the remote transaction that was
+    /// just integrated and applied to `document`, as a local transaction
+    #[allow(clippy::type_complexity)]
+    fn resurrect(&mut self, conflicts: &[Conflict], reference_time: Option<u32>, lookup: &BTreeMap<u32, (u32, u32)>, document: &mut Vec<u8>, stamper: &mut TimeStamper) -> Result<Option<(TransactionSequence, BTreeMap<u32, (u32, u32)>)>, OTError> {
+        let site_id = self.site_id;
+        let deleted_here = conflicts.iter().any(|conflict| {
+            conflict.get_kind() == ConflictKind::InsertInsideDelete && matches!(*conflict.get_local(), ConflictOperation::Delete(ref delete) if delete.get_site_id() == site_id)
+        });
+        if !deleted_here {
+            return Ok(None);
+        }
+
+        let (inserts, deletes) = {
+            let remote: HashSet<u32> = lookup.values().filter_map(|&(site_id, timestamp)| stamper.get_local_timestamp_for(site_id, timestamp)).collect();
+            let known = Self::known_to_sender(reference_time, lookup, stamper);
+            let own = |timestamp: u32| stamper.get_remote_timestamp_for(timestamp).is_some_and(|(made_by, _)| made_by == site_id);
+            let timeline = try!(Timeline::new(&self.inserts, &self.deletes, document));
+            try!(resurrect::restore(&timeline, &|timestamp| remote.contains(&timestamp), &|timestamp| own(timestamp) && !known(timestamp), site_id))
+        };
+        if inserts.is_empty() && deletes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.process_local(inserts, deletes, document, stamper)))
+    }
+
+    /// Checks whether the engine is in text mode
+    #[inline]
+    pub fn is_text_mode(&self) -> bool {
+        self.text_mode
+    }
+
+    /// Sets whether the engine is in text mode, for documents that hold UTF-8 text.  In text
+    /// mode, the changes found by `process_diffs_with_base()` are widened to whole characters
+    /// before they are processed, and `process_diffs()` refuses changes it can't widen.
+    /// Transactions given to `process_transaction()` have to be widened with
+    /// `TransactionSequence::align_to_chars()` first.  Every site should be in text mode, so that
+    /// the transformed operations never split a character either.  Like the tie-breaking
+    /// strategy, it is saved by `compress_to()` and in a [`Snapshot`](struct.Snapshot.html), and
+    /// sessions check that both sides agree on it.
+    #[inline]
+    pub fn set_text_mode(&mut self, text_mode: bool) {
+        self.text_mode = text_mode;
+    }
+
+    /// Processes the diffs of a local file, capturing the deleted content from `base` if it is known
+    fn process_diffs_from(&mut self, diff: Diff, base: Option<&[u8]>, stamper: &mut TimeStamper) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
+        let current_timestamp = stamper.get_last_timestamp();
+        let new_timestamp = stamper.stamp_local(self.site_id);
+        let inserts = diff.inserts().map(|insert| {
+            InsertOperation::new(
+                insert.get_position()as Position,
+                insert.get_data().clone(),
+                new_timestamp,
+                self.site_id,
+            )
+        }).collect();
+
+
+        let deletes = diff.deletes().map(|delete| {
+            DeleteOperation::new(
+                delete.get_position() as Position,
+                delete.get_length() as Position,
+                new_timestamp,
+                self.site_id,
+            )
+        }).collect();
+        let mut lookup = BTreeMap::new();
+        lookup.insert(new_timestamp, (self.site_id, new_timestamp));
+        let mut sequence = TransactionSequence::new(current_timestamp.map(|(_local, remote)| remote), inserts, deletes);
+        if let Some(base) = base {
+            if self.text_mode {
+                sequence.align_to_chars(base);
             }
+            sequence.capture_deleted(base);
         }
-        for delete in remote_sequence.deletes.iter() {
-            let timestamp = delete.get_timestamp();
+        self.process_transaction(&mut sequence);
+        (sequence, lookup)
+    }
+}
+
+// Private methods
+impl<T: Element> Engine<T> {
+
+    /// Finds the local time of a remote state, or `None` for the state before any operations.
+    /// States whose operations have been discarded are no longer known.
+    fn get_reference_time(&self, remote_state: Option<(u32, u32)>, stamper: &TimeStamper) -> Result<Option<u32>, OTError> {
+        let reference_time = if let Some((remote_site_id, remote_timestamp)) = remote_state {
+            Some(try!(stamper.get_local_timestamp_for(remote_site_id, remote_timestamp)
+                             .ok_or(OTError::new(Kind::NoSuchState))))
+        } else {
+            None
+        };
+        if let Some(discarded) = self.discarded_timestamp {
+            if reference_time.is_none_or(|reference_time| reference_time < discarded) {
+                return Err(OTError::new(Kind::NoSuchState));
+            }
+        }
+        Ok(reference_time)
+    }
+
+    fn get_concurrent_inserts(&self, remote_sequence: &TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &TimeStamper) -> Result<LinkedList<InsertOperation<T>>, OTError> {
+        let mut tail_timestamp = None;
+        let timestamps = remote_sequence.inserts.iter().map(|insert| insert.get_timestamp())
+            .chain(remote_sequence.deletes.iter().map(|delete| delete.get_timestamp()))
+            .chain(remote_sequence.formats.iter().map(|format| format.get_timestamp()))
+            .chain(remote_sequence.anchors.iter().map(|anchor| anchor.get_timestamp()))
+            .chain(remote_sequence.moves.iter().map(|operation| operation.get_timestamp()));
+        for timestamp in timestamps {
             if let Some((local, _remote)) = tail_timestamp {
 
                 if timestamp < local {
@@ -267,57 +1053,154 @@ impl Engine {
             }
         }
         let tail_timestamp = tail_timestamp.map(|(_, &(site_id, timestamp))| stamper.get_local_timestamp_for(site_id, timestamp).unwrap());
+
         trace!("Getting inserts after {:?} and before {:?}", remote_sequence.last_timestamp, tail_timestamp);
-        if let Some((remote_site_id, remote_timestamp)) = remote_sequence.last_timestamp {
-            let reference_time = try!(stamper.get_local_timestamp_for(remote_site_id, remote_timestamp)
-                                               .ok_or(OTError::new(Kind::NoSuchState)));
-            Ok(self.inserts.iter().filter(|o|
-                if let Some(tail) = tail_timestamp {
-                    o.get_timestamp() > reference_time && o.get_timestamp() < tail
-                } else {
-                    o.get_timestamp() > reference_time
-                }).cloned().collect())
-        } else {
-            Ok(self.inserts.iter().filter(|o|
-                if let Some(tail) = tail_timestamp {
-                    o.get_timestamp() < tail
-                } else {
-                    true
-                }).cloned().collect())
-        }
+        let reference_time = try!(self.get_reference_time(remote_sequence.last_timestamp, stamper));
+        let known = Self::known_to_sender(reference_time, lookup, stamper);
+        Ok(self.inserts.iter().filter(|o| !known(o.get_timestamp()) &&
+            tail_timestamp.is_none_or(|tail| o.get_timestamp() < tail)).cloned().collect())
+    }
+
+    /// Checks whether every operation we have stamped was known to the site that sent `remote_sequence`
+    fn sender_knows_history(&self, remote_sequence: &TransactionSequence<T>, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &TimeStamper) -> Result<bool, OTError> {
+        let reference_time = try!(self.get_reference_time(remote_sequence.last_timestamp, stamper));
+        let known = Self::known_to_sender(reference_time, lookup, stamper);
+        Ok(stamper.stamp_mapping.keys().all(|&local| known(local)))
+    }
 
+    /// Builds a check of whether the site that sent a transaction with the timestamps in `lookup`,
+    /// made on the state with the local time `reference_time`, knew about the operation with a
+    /// given local timestamp.  Operations arrive from each site in the order they were made, so
+    /// the sender also knew about every operation from a site whose later operations are in the
+    /// transaction, even if it came after the reference time.
+    fn known_to_sender<'a>(reference_time: Option<u32>, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &'a TimeStamper) -> impl Fn(u32) -> bool + 'a {
+        let mut latest_known = HashMap::new();
+        for &(site_id, timestamp) in lookup.values() {
+            let latest = latest_known.entry(site_id).or_insert(timestamp);
+            if timestamp > *latest {
+                *latest = timestamp;
+            }
+        }
+        move |local: u32| {
+            reference_time.is_some_and(|reference_time| local <= reference_time) ||
+                stamper.stamp_mapping.get(&local).is_some_and(|&(site_id, timestamp)| {
+                    latest_known.get(&site_id).is_some_and(|&latest| timestamp <= latest)
+                })
+        }
     }
 
-    fn assign_timestamps<O: Operation>(&mut self, sequence: &mut LinkedList<O>, timestamp_lookup: &BTreeMap<u32, (u32, u32)>, stamper: &mut TimeStamper) {
+    fn assign_timestamps<O: OperationInternal>(&mut self, sequence: &mut LinkedList<O>, timestamp_lookup: &BTreeMap<u32, (u32, u32)>, stamper: &mut TimeStamper) {
         trace!("Assigning time_stamps to {:?}", sequence);
         for o in sequence.iter_mut() {
             // TODO add better error handling here.
             let &(remote_site_id, remote_timestamp) = timestamp_lookup.get(&o.get_timestamp()).unwrap();
             let local_timestamp = stamper.stamp_remote(remote_site_id, remote_timestamp);
-            o.set_timestamp(local_timestamp)
+            o.set_timestamp(local_timestamp);
+            o.set_site_id(remote_site_id)
         }
         trace!("Timestamps assigned to {:?}", sequence);
     }
 
+    /// Gives an operation from a remote sequence the local timestamp of its transaction
+    fn localize<O: OperationInternal>(operation: &mut O, lookup: &BTreeMap<u32, (u32, u32)>, stamper: &TimeStamper) {
+        if let Some(&(site_id, timestamp)) = lookup.get(&operation.get_timestamp()) {
+            if let Some(local_timestamp) = stamper.get_local_timestamp_for(site_id, timestamp) {
+                operation.set_timestamp(local_timestamp);
+                operation.set_site_id(site_id);
+            }
+        }
+    }
+
+    /// Finds the range an insert from the history covers in the document the history has produced
+    fn locate_insert(&self, insert: &InsertOperation<T>) -> Option<(Position, Position)> {
+        self.locate_in_inserts(insert).map(|range| conflict::through_deletes(range, self.deletes.iter()))
+    }
+
+    /// Finds the range an insert from the history covers once every insert, but no delete, has
+    /// been applied
+    fn locate_in_inserts(&self, insert: &InsertOperation<T>) -> Option<(Position, Position)> {
+        self.inserts.iter().position(|other| other == insert).map(|index| {
+            let range = (insert.get_position(), insert.get_position() + insert.get_value().len() as Position);
+            conflict::through_inserts(range, self.inserts.iter().skip(index + 1))
+        })
+    }
+
+    /// Finds which of `inserts` is the copy made by each of `moves`: the one with the same
+    /// timestamp that starts at the destination once all of `inserts` have been applied
+    fn find_copies(inserts: &LinkedList<InsertOperation<T>>, moves: &[MoveOperation]) -> Vec<Option<usize>> {
+        let starts: Vec<_> = inserts.iter().enumerate().map(|(index, insert)| {
+            let range = (insert.get_position(), insert.get_position() + insert.get_value().len() as Position);
+            (conflict::through_inserts(range, inserts.iter().skip(index + 1)).0, insert.get_timestamp(), insert.get_value().is_empty())
+        }).collect();
+        moves.iter().map(|operation| {
+            starts.iter().position(|&(start, timestamp, empty)| {
+                start == operation.get_destination() && timestamp == operation.get_timestamp() && !empty
+            })
+        }).collect()
+    }
+
+    /// Stamps `inserts` and `deletes`, which were worked out on `document`, as a new local
+    /// transaction, then applies it to `document` and processes it
+    fn process_local(&mut self, mut inserts: LinkedList<InsertOperation<T>>, mut deletes: LinkedList<DeleteOperation<T>>, document: &mut Vec<T>, stamper: &mut TimeStamper) -> (TransactionSequence<T>, BTreeMap<u32, (u32, u32)>) {
+        let current_timestamp = stamper.get_last_timestamp();
+        let new_timestamp = stamper.stamp_local(self.site_id);
+        for insert in inserts.iter_mut() {
+            insert.set_timestamp(new_timestamp);
+        }
+        for delete in deletes.iter_mut() {
+            delete.set_timestamp(new_timestamp);
+        }
+        let mut lookup = BTreeMap::new();
+        lookup.insert(new_timestamp, (self.site_id, new_timestamp));
+        let mut sequence = TransactionSequence::new(current_timestamp.map(|(_local, remote)| remote), inserts, deletes);
+        *document = sequence.apply_to_elements(document);
+        self.process_transaction(&mut sequence);
+        (sequence, lookup)
+    }
+
+    #[inline]
     fn transform<O1: OperationInternal, O2: OperationInternal>(incoming_sequence: &mut LinkedList<O1>, existing_sequence: &LinkedList<O2>)  {
+        Self::transform_with(incoming_sequence, existing_sequence, ConflictPolicy::KeepInsert, &Ties::by_site());
+    }
+
+    /// Transforms the incoming sequence by the existing one, and returns every place where an
+    /// incoming operation overlapped an existing one
+    fn transform_with<O1: OperationInternal, O2: OperationInternal>(incoming_sequence: &mut LinkedList<O1>, existing_sequence: &LinkedList<O2>, policy: ConflictPolicy, ties: &Ties) -> Vec<Collision<O1, O2>> {
         trace!("Transforming {:?} by {:?}", incoming_sequence, existing_sequence);
-        let mut incoming_iter = incoming_sequence.iter_mut();
+        // The incoming operations are taken out of the sequence and put back once they have been
+        // transformed, followed by the pieces they were split into
+        let mut incoming_iter = mem::take(incoming_sequence).into_iter();
         let mut existing_iter = existing_sequence.iter();
         let mut saved_op = None;
         let mut incoming_op = incoming_iter.next();
         let mut existing_op = existing_iter.next();
-        let mut transformer = SequenceTransformer::new();
+        let mut transformer = SequenceTransformer::new(policy, ties);
+        // How many extra pieces each incoming operation was split into
+        let mut splits = Vec::new();
+        let mut current_splits = 0;
+        // How many operations the transformed sequence has before the current one
+        let mut pieces = 0;
+        let mut collisions = Vec::new();
         loop {
 
             let advance_action = if let Some(existing_op) = existing_op {
-                 if let Some(ref mut incoming_op) = incoming_op {
-                     transformer.transform_operations::<O1, O2>(incoming_op, existing_op)
-                } else {
-                    if let Some(ref mut incoming_op) = saved_op{
-                        transformer.transform_operations::<O1, O2>(incoming_op, existing_op)
-                    } else {
-                        Advance::Existing
-                    }
+                let incoming = match incoming_op {
+                    Some(ref mut incoming_op) => Some(transformer.transform_operations::<O1, O2>(incoming_op, existing_op)),
+                    None => saved_op.as_mut().map(|incoming_op| transformer.transform_operations::<O1, O2>(incoming_op, existing_op))
+                };
+                match incoming {
+                    Some((advance, collision)) => {
+                        if let Some((incoming, overlap)) = collision {
+                            collisions.push(Collision {
+                                incoming: incoming,
+                                existing: existing_op.clone(),
+                                overlap: overlap,
+                                piece: pieces + current_splits,
+                            });
+                        }
+                        advance
+                    },
+                    None => Advance::Existing
                 }
             } else{
                 if let Some(ref mut incoming_op) = incoming_op {
@@ -332,29 +1215,65 @@ impl Engine {
             };
             match advance_action {
                 Advance::Incoming => {
-                    if saved_op.is_some() {
-                        incoming_iter.insert_next(mem::replace(&mut saved_op, None).unwrap())
-                    }
+                    incoming_sequence.extend(incoming_op.take());
+                    incoming_sequence.extend(saved_op.take());
                     incoming_op = incoming_iter.next();
+                    splits.push(current_splits);
+                    pieces += current_splits + 1;
+                    current_splits = 0;
                 },
                 Advance::Existing => {
                     existing_op = existing_iter.next();
                 },
                 Advance::Neither(new_op) => {
-                    if saved_op.is_some() {
-                        incoming_iter.insert_next(mem::replace(&mut saved_op, Some(new_op)).unwrap())
-                    } else {
-                        mem::replace(&mut saved_op, Some(new_op));
-                    }
-                    incoming_op = None;
+                    incoming_sequence.extend(incoming_op.take());
+                    incoming_sequence.extend(saved_op.replace(new_op));
+                    current_splits += 1;
                 }
             }
 
 
         }
+        incoming_sequence.extend(incoming_op);
+        incoming_sequence.extend(incoming_iter);
+        if policy == ConflictPolicy::DropInsert {
+            Self::join_splits(incoming_sequence, &splits);
+        }
+        collisions
     }
 
-    fn swap<O: OperationInternal>(incoming_sequence: &mut LinkedList<O>, existing_sequence: &mut LinkedList<DeleteOperation>)  {
+    /// Puts back together the operations that were split around the inserts they enclosed, so
+    /// that they take out the inserts as well.  Everything between the pieces of an operation
+    /// was inserted concurrently, since the pieces started out next to each other.
+    fn join_splits<O: OperationInternal>(sequence: &mut LinkedList<O>, splits: &[usize]) {
+        let mut pieces = mem::take(sequence).into_iter();
+        // The inserts taken out so far, which the operations after them no longer have to step over
+        let mut absorbed = 0;
+        for &count in splits {
+            let mut operation = pieces.next().unwrap();
+            let mut previous_position = operation.get_position();
+            operation.update_position_by(-absorbed);
+            for _ in 0..count {
+                let piece = pieces.next().unwrap();
+                let gap = (piece.get_position() - previous_position) as Offset;
+                previous_position = piece.get_position();
+                operation.update_size_by(gap - piece.get_increment());
+                absorbed += gap;
+            }
+            sequence.push_back(operation);
+        }
+    }
+
+
+    /// Transforms a replace by a sequence, joining it back together if it had to be split
+    fn transform_replace<O: OperationInternal>(replace: ReplaceOperation<T>, existing_sequence: &LinkedList<O>) -> ReplaceOperation<T> {
+        let mut pieces = LinkedList::new();
+        pieces.push_back(replace);
+        Self::transform(&mut pieces, existing_sequence);
+        ReplaceOperation::join(pieces.iter()).unwrap()
+    }
+
+    fn swap<O: OperationInternal>(incoming_sequence: &mut LinkedList<O>, existing_sequence: &mut LinkedList<DeleteOperation<T>>)  {
         trace!("Swapping {:?} and {:?}", incoming_sequence, existing_sequence);
         {
             let mut incoming_iter = incoming_sequence.iter_mut();
@@ -366,7 +1285,7 @@ impl Engine {
 
                 let advance_incoming = if let Some(ref mut existing_op) = existing_op {
                      if let Some(ref mut incoming_op) = incoming_op {
-                         swapper.swap_operations::<O>(incoming_op, existing_op)
+                         swapper.swap_operations::<O, T>(incoming_op, existing_op)
                     } else {
                         swapper.swap_existing(existing_op);
                         false
@@ -392,9 +1311,9 @@ impl Engine {
 
 
 
-    fn split_by(incoming_sequence: &mut LinkedList<DeleteOperation>, existing_sequence: &LinkedList<DeleteOperation>)  {
+    fn split_by(incoming_sequence: &mut LinkedList<DeleteOperation<T>>, existing_sequence: &LinkedList<DeleteOperation<T>>)  {
         trace!("splitting {:?} by {:?}", incoming_sequence, existing_sequence);
-        let mut incoming_iter = incoming_sequence.iter_mut();
+        let mut incoming_iter = mem::take(incoming_sequence).into_iter();
         let mut existing_iter = existing_sequence.iter();
         let mut saved_op = None;
         let mut incoming_op = incoming_iter.next();
@@ -411,7 +1330,7 @@ impl Engine {
                     break
                 }
             } else{
-                if let Some(_) = saved_op {
+                if saved_op.is_some() {
                     Advance::Incoming
                } else {
                    break
@@ -419,65 +1338,124 @@ impl Engine {
             };
             match advance_action {
                 Advance::Incoming => {
-                    if saved_op.is_some() {
-                        incoming_iter.insert_next(mem::replace(&mut saved_op, None).unwrap())
-                    }
+                    incoming_sequence.extend(incoming_op.take());
+                    incoming_sequence.extend(saved_op.take());
                     incoming_op = incoming_iter.next();
                 },
                 Advance::Existing => {
                     existing_op = existing_iter.next();
                 },
                 Advance::Neither(new_op) => {
-                    if saved_op.is_some() {
-                        incoming_iter.insert_next(mem::replace(&mut saved_op, Some(new_op)).unwrap())
-                    } else {
-                        mem::replace(&mut saved_op, Some(new_op));
-                    }
-                    incoming_op = None;
+                    incoming_sequence.extend(incoming_op.take());
+                    incoming_sequence.extend(saved_op.replace(new_op));
                 }
             }
 
 
         }
+        incoming_sequence.extend(incoming_op);
+        incoming_sequence.extend(incoming_iter);
     }
 
 
 
-    fn merge_sequences<O: OperationInternal>(seq1: &mut LinkedList<O>, seq2: &LinkedList<O>) {
-        fn something_less<O: OperationInternal>(mut a: Option<&mut O>, b: &O, offset: Offset) -> bool {
+    fn merge_sequences<O: OperationInternal>(seq1: &mut LinkedList<O>, seq2: &LinkedList<O>, ties: &Ties) {
+        fn something_less<O: OperationInternal>(mut a: Option<&mut O>, b: &O, offset: Offset, ties: &Ties) -> bool {
             trace!("Comparing {:?} and {:?} with offset {}", a, b, offset);
             match a {
-                Some(ref mut a) => {
-                    if a.get_position() as Offset <= b.get_position() as Offset - offset {
-                        (**a).update_position_by(offset);
-                        true
-                    } else {
-                        false
-                    }
-
-                }, None => {
-                    false
-                }
+                Some(ref mut a) if a.merges_before(b, offset, ties) => {
+                    (**a).update_position_by(offset);
+                    true
+                },
+                _ => false
             }
         }
         trace!("Merging sequence {:?} into {:?}", seq2, seq1);
-        let mut seq1_iter = seq1.iter_mut();
-        let mut seq2_iter = seq2.iter();
+        let mut seq1_iter = mem::take(seq1).into_iter().peekable();
         let mut offset = 0;
-        while let Some(elem2) = seq2_iter.next() {
-            while something_less(seq1_iter.peek_next(), &elem2, offset) {
-                seq1_iter.next();
+        for elem2 in seq2.iter() {
+            while something_less(seq1_iter.peek_mut(), elem2, offset, ties) {
+                seq1.push_back(seq1_iter.next().unwrap());
             }
             offset += elem2.get_increment();
-            seq1_iter.insert_next(elem2.clone());
+            seq1.push_back(elem2.clone());
+        }
+        for mut elem1 in seq1_iter {
+            elem1.update_position_by(offset);
+            seq1.push_back(elem1);
         }
-        while seq1_iter.peek_next().is_some() {
-            seq1_iter.next().unwrap().update_position_by(offset);
+    }
+}
+
+fn compress_checkpoints_to<W: Write>(checkpoints: &BTreeMap<String, Option<(u32, u32)>>, writer: &mut W) -> io::Result<()> {
+    let mut int_buf = [0;4];
+    NetworkEndian::write_u32(&mut int_buf, checkpoints.len() as u32);
+    try!(writer.write_all(&int_buf));
+    for (name, state) in checkpoints.iter() {
+        NetworkEndian::write_u32(&mut int_buf, name.len() as u32);
+        try!(writer.write_all(&int_buf));
+        try!(writer.write_all(name.as_bytes()));
+        if let Some((site_id, timestamp)) = *state {
+            try!(writer.write_all(&[1]));
+            NetworkEndian::write_u32(&mut int_buf, site_id);
+            try!(writer.write_all(&int_buf));
+            NetworkEndian::write_u32(&mut int_buf, timestamp);
+            try!(writer.write_all(&int_buf));
+        } else {
+            try!(writer.write_all(&[0]));
         }
     }
+    Ok(())
+}
+
+fn compress_discarded_to<W: Write>(discarded_timestamp: Option<u32>, writer: &mut W) -> io::Result<()> {
+    if let Some(timestamp) = discarded_timestamp {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, timestamp);
+        try!(writer.write_all(&[1]));
+        writer.write_all(&int_buf)
+    } else {
+        writer.write_all(&[0])
+    }
+}
+
+fn expand_discarded_from<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut bool_buf = [0;1];
+    try!(reader.read_exact(&mut bool_buf));
+    if bool_buf[0] == 1 {
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut int_buf));
+        Ok(Some(NetworkEndian::read_u32(&int_buf)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn expand_checkpoints_from<R: Read>(reader: &mut R) -> io::Result<BTreeMap<String, Option<(u32, u32)>>> {
+    let mut bool_buf = [0;1];
+    let mut int_buf = [0;4];
+    try!(reader.read_exact(&mut int_buf));
+    let checkpoint_len = NetworkEndian::read_u32(&int_buf);
+    let mut checkpoints = BTreeMap::new();
+    for _ in 0..checkpoint_len {
+        try!(reader.read_exact(&mut int_buf));
+        let name = try!(u8::read_from(reader, NetworkEndian::read_u32(&int_buf) as usize));
+        let name = try!(String::from_utf8(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Checkpoint name is not UTF-8")));
+        try!(reader.read_exact(&mut bool_buf));
+        let state = if bool_buf[0] == 1 {
+            try!(reader.read_exact(&mut int_buf));
+            let site_id = NetworkEndian::read_u32(&int_buf);
+            try!(reader.read_exact(&mut int_buf));
+            Some((site_id, NetworkEndian::read_u32(&int_buf)))
+        } else {
+            None
+        };
+        checkpoints.insert(name, state);
+    }
+    Ok(checkpoints)
 }
 
-impl fmt::Debug for Engine {
+impl<T: Element> fmt::Debug for Engine<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         try!(writeln!(f, "Site: {}", self.site_id));
         try!(writeln!(f, "Inserts: {:?}", self.inserts));
@@ -485,6 +1463,27 @@ impl fmt::Debug for Engine {
     }
 }
 
+impl<T: Element> fmt::Debug for TransactionSequence<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("TransactionSequence")
+            .field("last_timestamp", &self.last_timestamp)
+            .field("inserts", &self.inserts)
+            .field("deletes", &self.deletes)
+            .field("fingerprint", &self.fingerprint)
+            .field("metadata", &self.metadata)
+            .field("formats", &self.formats)
+            .field("anchors", &self.anchors)
+            .field("moves", &self.moves)
+            .finish()
+    }
+}
+
+impl Default for TimeStamper {
+    fn default() -> TimeStamper {
+        TimeStamper::new()
+    }
+}
+
 impl TimeStamper {
     /// Create a new `TimeStamper`, with no stamps yet assigned
     pub fn new() -> TimeStamper {
@@ -516,7 +1515,11 @@ impl TimeStamper {
 
     /// Stamp a local operation.  This will always create a new timestamp
     pub fn stamp_local(&mut self, site_id: u32) -> u32 {
-        let time_stamp =  self.last_timestamp.map_or(0, |(local, _)| local + 1);
+        let mut time_stamp =  self.last_timestamp.map_or(0, |(local, _)| local + 1);
+        // A site that has been repaired may already have used this timestamp before the repair
+        while self.time_mapping.contains_key(&(site_id, time_stamp)) {
+            time_stamp += 1;
+        }
         self.time_mapping.insert((site_id, time_stamp), time_stamp);
         self.stamp_mapping.insert(time_stamp, (site_id, time_stamp));
         self.last_timestamp = Some((time_stamp, (site_id, time_stamp)));
@@ -525,7 +1528,12 @@ impl TimeStamper {
 
     /// Gets the local timestamp corresponding to a given remote site_id and remote timestamp
     pub fn get_local_timestamp_for(&self, remote_site_id: u32, remote_timestamp: u32) -> Option<u32> {
-        self.time_mapping.get(&(remote_site_id, remote_timestamp)).map(|t| {*t})
+        self.time_mapping.get(&(remote_site_id, remote_timestamp)).copied()
+    }
+
+    /// Gets the remote site_id and remote timestamp corresponding to a given local timestamp
+    pub fn get_remote_timestamp_for(&self, local_timestamp: u32) -> Option<(u32, u32)> {
+        self.stamp_mapping.get(&local_timestamp).copied()
     }
 
     /// Gets a mapping of timestamps since the given remote site_id and remote timesamp, ordered sequentially, or none if the remote timestamp isn't in the lookup
@@ -541,8 +1549,16 @@ impl TimeStamper {
         }
     }
 
+    /// Gets the remote id of the most recent operation that did not originate at `site_id`, or
+    /// None if every operation so far came from `site_id`
+    pub fn get_last_remote_timestamp(&self, site_id: u32) -> Option<(u32, u32)> {
+        self.stamp_mapping.iter().filter(|&(_, &(remote_site_id, _))| {
+            remote_site_id != site_id
+        }).max_by_key(|&(local_timestamp, _)| *local_timestamp).map(|(_, remote)| *remote)
+    }
+
     /// Gets all of the timestamps that will be needed to lookup the operations in the transaction
-    pub fn get_timestamps_for(&self, transaction: &TransactionSequence) -> BTreeMap<u32, (u32, u32)> {
+    pub fn get_timestamps_for<T: Element>(&self, transaction: &TransactionSequence<T>) -> BTreeMap<u32, (u32, u32)> {
         let mut map = BTreeMap::new();
         for insert in transaction.inserts.iter() {
             let timestamp = insert.get_timestamp();
@@ -552,9 +1568,23 @@ impl TimeStamper {
             let timestamp = delete.get_timestamp();
             map.insert(timestamp, *self.stamp_mapping.get(&timestamp).unwrap());
         }
+        for format in transaction.formats.iter() {
+            let timestamp = format.get_timestamp();
+            map.insert(timestamp, *self.stamp_mapping.get(&timestamp).unwrap());
+        }
+        for anchor in transaction.anchors.iter() {
+            let timestamp = anchor.get_timestamp();
+            map.insert(timestamp, *self.stamp_mapping.get(&timestamp).unwrap());
+        }
         map
     }
 
+    /// Gets the number of operations that have been stamped
+    #[inline]
+    pub fn get_stamp_count(&self) -> usize {
+        self.time_mapping.len()
+    }
+
     #[inline]
     /// Gets the most recent timestamp this stamper has assigned, or None if it has not yet assigned a timestamp.
     /// The timestamp contains both the local and remote timestamps
@@ -616,72 +1646,378 @@ impl TimeStamper {
     }
 }
 
-impl TransactionSequence {
+impl Snapshot {
+    /// Takes a snapshot of `document`, which must be the result of every operation in `engine`
+    pub fn new(document: Vec<u8>, engine: &Engine, stamper: &TimeStamper) -> Snapshot {
+        Snapshot {
+            document: document,
+            state: stamper.get_last_timestamp().map(|(_local, remote)| remote),
+            inserts: engine.inserts.clone(),
+            deletes: engine.deletes.clone(),
+            stamper: stamper.clone(),
+            checkpoints: engine.checkpoints.clone(),
+            policy: engine.policy,
+            tie_break: engine.tie_break.describe(),
+            text_mode: engine.text_mode,
+            discarded_timestamp: engine.discarded_timestamp,
+            formatting: engine.formatting.clone(),
+            comments: engine.comments.clone(),
+        }
+    }
+
+    /// Gets the document the snapshot was taken of
+    #[inline]
+    pub fn get_document(&self) -> &[u8] {
+        &self.document
+    }
+
+    /// Gets the `(site id, timestamp)` of the last operation included in the snapshot, or `None`
+    /// if it was taken of an empty history
+    #[inline]
+    pub fn get_state(&self) -> Option<(u32, u32)> {
+        self.state
+    }
+
+    /// Gets the description of the tie-breaking strategy of the engine the snapshot was taken from
+    #[inline]
+    pub fn get_tie_break(&self) -> &[u8] {
+        &self.tie_break
+    }
+
+    /// Starts a new site from this snapshot, returning the document along with the engine and
+    /// timestamps to synchronize it with.  If the snapshot was taken from an engine with a
+    /// tie-breaking strategy from outside this crate, the strategy has to be set again, and it
+    /// should be checked against `get_tie_break()`.
+    pub fn into_parts(self, site_id: u32) -> (Vec<u8>, Engine, TimeStamper) {
+        let snapshot_timestamp = self.state.and_then(|(site, timestamp)| self.stamper.get_local_timestamp_for(site, timestamp));
+        let engine = Engine {
+            site_id: site_id,
+            inserts: self.inserts,
+            deletes: self.deletes,
+            snapshot_timestamp: snapshot_timestamp,
+            discarded_timestamp: self.discarded_timestamp,
+            checkpoints: self.checkpoints,
+            metadata: BTreeMap::new(),
+            policy: self.policy,
+            tie_break: tiebreak::restore(&self.tie_break).unwrap_or_else(|| Arc::new(SiteOrder)),
+            text_mode: self.text_mode,
+            formatting: self.formatting,
+            comments: self.comments,
+        };
+        (self.document, engine, self.stamper)
+    }
+
+    /// Compress this snapshot and write to `writer`.  The output can then be expanded
+    /// back into an equivilent snapshot using `expand_from()`, except that the text of
+    /// the inserts is left out, as is the text of the deletes made before every checkpoint.
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u64(&mut long_buf, self.document.len() as u64);
+        try!(writer.write_all(&long_buf));
+        try!(writer.write_all(&self.document));
+        if let Some((site_id, timestamp)) = self.state {
+            try!(writer.write_all(&[1]));
+            NetworkEndian::write_u32(&mut int_buf, site_id);
+            try!(writer.write_all(&int_buf));
+            NetworkEndian::write_u32(&mut int_buf, timestamp);
+            try!(writer.write_all(&int_buf));
+        } else {
+            try!(writer.write_all(&[0]));
+        }
+        try!(self.stamper.compress_to(writer));
+
+        NetworkEndian::write_u32(&mut int_buf, self.inserts.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for insert in self.inserts.iter() {
+            NetworkEndian::write_u32(&mut int_buf, insert.get_timestamp());
+            try!(writer.write_all(&int_buf));
+            NetworkEndian::write_u64(&mut long_buf, insert.get_position());
+            try!(writer.write_all(&long_buf));
+            NetworkEndian::write_u64(&mut long_buf, insert.get_value().len() as u64);
+            try!(writer.write_all(&long_buf));
+            NetworkEndian::write_u32(&mut int_buf, insert.get_site_id());
+            try!(writer.write_all(&int_buf));
+        }
+        // Rebuilding a checkpoint only needs the text deleted since then
+        let earliest_checkpoint = self.checkpoints.values().map(|&state| {
+            state.and_then(|(site_id, timestamp)| self.stamper.get_local_timestamp_for(site_id, timestamp))
+        }).min();
+        NetworkEndian::write_u32(&mut int_buf, self.deletes.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for delete in self.deletes.iter() {
+            let needed = earliest_checkpoint.is_some_and(|checkpoint| checkpoint.is_none_or(|checkpoint| delete.get_timestamp() > checkpoint));
+            if needed {
+                try!(delete.compress_to(writer, true, true));
+            } else {
+                let mut delete = delete.clone();
+                delete.set_content(None);
+                try!(delete.compress_to(writer, true, true));
+            }
+        }
+        try!(compress_checkpoints_to(&self.checkpoints, writer));
+        try!(self.policy.compress_to(writer));
+        try!(tiebreak::compress_to(&self.tie_break, writer));
+        try!(writer.write_all(&[self.text_mode as u8]));
+        try!(compress_discarded_to(self.discarded_timestamp, writer));
+        try!(self.formatting.compress_to(writer));
+        self.comments.compress_to(writer)
+    }
+
+    /// Expand a snapshot from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Snapshot> {
+        let mut bool_buf = [0;1];
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut long_buf));
+        let document = try!(u8::read_from(reader, NetworkEndian::read_u64(&long_buf) as usize));
+        try!(reader.read_exact(&mut bool_buf));
+        let state = if bool_buf[0] == 1 {
+            try!(reader.read_exact(&mut int_buf));
+            let site_id = NetworkEndian::read_u32(&int_buf);
+            try!(reader.read_exact(&mut int_buf));
+            Some((site_id, NetworkEndian::read_u32(&int_buf)))
+        } else {
+            None
+        };
+        let stamper = try!(TimeStamper::expand_from(reader));
+
+        try!(reader.read_exact(&mut int_buf));
+        let insert_len = NetworkEndian::read_u32(&int_buf);
+        let mut inserts = LinkedList::new();
+        let mut history_len = 0u64;
+        for _ in 0..insert_len {
+            try!(reader.read_exact(&mut int_buf));
+            let timestamp = NetworkEndian::read_u32(&int_buf);
+            try!(reader.read_exact(&mut long_buf));
+            let position = NetworkEndian::read_u64(&long_buf);
+            try!(reader.read_exact(&mut long_buf));
+            let length = NetworkEndian::read_u64(&long_buf);
+            history_len = history_len.saturating_add(length);
+            if history_len > MAX_SNAPSHOT_HISTORY {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "The history of the snapshot is too long"));
+            }
+            let length = length as usize;
+            try!(reader.read_exact(&mut int_buf));
+            let site_id = NetworkEndian::read_u32(&int_buf);
+            // Only the length of the text matters for integrating new transactions
+            inserts.push_back(InsertOperation::new(position, vec![0; length], timestamp, site_id));
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let delete_len = NetworkEndian::read_u32(&int_buf);
+        let mut deletes = LinkedList::new();
+        for _ in 0..delete_len {
+            deletes.push_back(try!(DeleteOperation::expand_from(reader, None, true)));
+        }
+        let checkpoints = try!(expand_checkpoints_from(reader));
+        let policy = try!(ConflictPolicy::expand_from(reader));
+        let tie_break = try!(tiebreak::expand_from(reader));
+        try!(reader.read_exact(&mut bool_buf));
+        let text_mode = bool_buf[0] == 1;
+        let discarded_timestamp = try!(expand_discarded_from(reader));
+        let formatting = try!(Formatting::expand_from(reader));
+        let comments = try!(Comments::expand_from(reader));
+        Ok(Snapshot {
+            document: document,
+            state: state,
+            inserts: inserts,
+            deletes: deletes,
+            stamper: stamper,
+            checkpoints: checkpoints,
+            policy: policy,
+            tie_break: tie_break,
+            text_mode: text_mode,
+            discarded_timestamp: discarded_timestamp,
+            formatting: formatting,
+            comments: comments,
+        })
+    }
+}
+
+impl<T: Element> TransactionSequence<T> {
 
     /// Construct a new `TransactionSequence` from the given operations and metadata
     /// `last_timestamp` is the last stamp that was assigned before this operation was created
     /// `timestamp_lookup` is a mapping between local timestamps and their remote counterparts
     #[inline]
-    pub fn new(last_timestamp: Option<(u32, u32)>, inserts: LinkedList<InsertOperation>, deletes: LinkedList<DeleteOperation>) -> TransactionSequence {
+    pub fn new(last_timestamp: Option<(u32, u32)>, inserts: LinkedList<InsertOperation<T>>, deletes: LinkedList<DeleteOperation<T>>) -> TransactionSequence<T> {
         TransactionSequence {
             last_timestamp: last_timestamp,
             inserts: inserts,
-            deletes: deletes
+            deletes: deletes,
+            fingerprint: None,
+            metadata: None,
+            formats: Vec::new(),
+            anchors: Vec::new(),
+            moves: Vec::new(),
+        }
+    }
+
+    /// Gets the `(site id, timestamp)` of the state this sequence was created on, or `None`
+    /// if it was created on an empty history
+    #[inline]
+    pub fn get_last_timestamp(&self) -> Option<(u32, u32)> {
+        self.last_timestamp
+    }
+
+    /// Sets the state this sequence was created on
+    #[inline]
+    pub fn set_last_timestamp(&mut self, last_timestamp: Option<(u32, u32)>) {
+        self.last_timestamp = last_timestamp;
+    }
+
+    /// Creates a sequence that performs `replace`, which can then be run through
+    /// `Engine::process_transaction()` like any other local transaction
+    pub fn from_replace(last_timestamp: Option<(u32, u32)>, replace: &ReplaceOperation<T>) -> TransactionSequence<T> {
+        let mut inserts = LinkedList::new();
+        let mut deletes = LinkedList::new();
+        let value = replace.get_value();
+        if !value.is_empty() {
+            inserts.push_back(InsertOperation::new(replace.get_position(), value.to_vec(), replace.get_timestamp(), replace.get_site_id()));
+        }
+        // Deletes happen after inserts, so the replaced bytes follow the new value
+        if replace.get_length() > 0 {
+            deletes.push_back(DeleteOperation::new(replace.get_position() + value.len() as Position, replace.get_length(), replace.get_timestamp(), replace.get_site_id()));
+        }
+        TransactionSequence::new(last_timestamp, inserts, deletes)
+    }
+
+    /// Creates a sequence that performs `operation` on `document`, which can then be run through
+    /// `Engine::process_transaction()` like any other local transaction.  The moved elements are
+    /// copied to the destination and deleted from where they were, and the move is sent along
+    /// with them, so that sites that made edits inside the range at the same time can carry them
+    /// over with `Engine::integrate_remote_with_moves()`.  Fails if the range or the destination
+    /// is past the end of `document`, or if the destination is inside the range.
+    pub fn from_move(last_timestamp: Option<(u32, u32)>, operation: &MoveOperation, document: &[T]) -> Result<TransactionSequence<T>, OTError> {
+        let position = operation.get_position();
+        let length = operation.get_length();
+        let destination = operation.get_destination();
+        let end = try!(position.checked_add(length).ok_or(OTError::new(Kind::OutOfRange)));
+        if end > document.len() as Position || destination > document.len() as Position {
+            return Err(OTError::new(Kind::OutOfRange));
+        }
+        if position < destination && destination < end {
+            return Err(OTError::new(Kind::MoveIntoItself));
         }
+        let mut sequence = TransactionSequence::new(last_timestamp, LinkedList::new(), LinkedList::new());
+        if length > 0 {
+            let value = document[position as usize..end as usize].to_vec();
+            sequence.inserts.push_back(InsertOperation::new(destination, value, operation.get_timestamp(), operation.get_site_id()));
+            // Deletes happen after inserts, so the moved elements follow the copy if it went in front of them
+            let source = if destination <= position { end } else { position };
+            sequence.deletes.push_back(DeleteOperation::new(source, length, operation.get_timestamp(), operation.get_site_id()));
+            let mut moved = operation.clone();
+            moved.set_range(source, length, destination);
+            sequence.moves.push(moved);
+        }
+        Ok(sequence)
+    }
+
+    /// Transforms `replace`, which was made on the document as it was before this (integrated)
+    /// sequence was applied, so that it can be performed after it.  Anything this sequence
+    /// inserted inside the replaced range is replaced as well.
+    pub fn transform_replace(&self, replace: &mut ReplaceOperation<T>) {
+        *replace = Engine::<T>::transform_replace(replace.clone(), &self.inserts);
+        *replace = Engine::<T>::transform_replace(replace.clone(), &self.deletes);
+    }
+
+    /// Gets the fingerprint of the document this sequence should produce.  After the sequence
+    /// has been integrated, this is `None` unless the local document should match the sender's.
+    #[inline]
+    pub fn get_fingerprint(&self) -> Option<Fingerprint> {
+        self.fingerprint
+    }
+
+    /// Gets the metadata the sender attached to this sequence
+    #[inline]
+    pub fn get_metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Attaches metadata describing who made this sequence, when and why.  It should be attached
+    /// before the sequence is processed, so that the engine records it along with the history.
+    #[inline]
+    pub fn set_metadata(&mut self, metadata: Option<Metadata>) {
+        self.metadata = metadata;
+    }
+
+    /// Attaches the fingerprint of the document the sender had after performing this sequence,
+    /// so that the other sites can check that they converged
+    #[inline]
+    pub fn set_fingerprint(&mut self, fingerprint: Option<Fingerprint>) {
+        self.fingerprint = fingerprint;
+    }
+
+    /// Gets the formats of this sequence.  Before the sequence is processed they are placed in
+    /// the document it produces, and once it has been integrated they are placed in the local
+    /// document after the sequence has been applied to it.
+    #[inline]
+    pub fn get_formats(&self) -> &[FormatOperation] {
+        &self.formats
+    }
+
+    /// Adds a format to this sequence, placed in the document the sequence produces.  It should
+    /// have the same timestamp as the rest of the sequence.
+    pub fn add_format(&mut self, format: FormatOperation) {
+        self.formats.push(format);
+    }
+
+    /// Gets the anchors of this sequence, which are placed in the same way as its formats
+    #[inline]
+    pub fn get_anchors(&self) -> &[AnchorOperation] {
+        &self.anchors
+    }
+
+    /// Adds an anchor to this sequence, placed in the document the sequence produces.  It should
+    /// have the same timestamp as the rest of the sequence.
+    pub fn add_anchor(&mut self, anchor: AnchorOperation) {
+        self.anchors.push(anchor);
+    }
+
+    /// Gets the moves this sequence makes, which come from `from_move()`.  Once the sequence has
+    /// been processed or integrated, each one is placed in the history with every insert but no
+    /// deletes, and its destination is where the copy of the range starts.
+    #[inline]
+    pub fn get_moves(&self) -> &[MoveOperation] {
+        &self.moves
+    }
+
+    /// Adds a move that the inserts and deletes of this sequence make, placed like the ones made by
+    /// `from_move()`: its range is where it is once the inserts are in, and its destination is
+    /// where its copy starts.  A move whose copy isn't found is sent as an ordinary copy and delete.
+    pub fn add_move(&mut self, operation: MoveOperation) {
+        self.moves.push(operation);
     }
 
 //Words words words. More words! Hey Words!words!s words
 //Got the stuff! And this other thing! Now how about this?
 
-    /// Apply the operations in this sequence to a file.  This should not be called until after
-    /// the sequence has been integrated via [`Engine::integrate_remote`](struct.Engine.html#method.integrate_remote)
-    /// The file must have been opened on both read and write mode (see [OpenOptions](https://doc.rust-lang.org/nightly/std/fs/struct.OpenOptions.html)).
-    pub fn apply(&self, file: &mut File) -> io::Result<()> {
+    /// Apply the operations in this sequence to a copy of `elements`, returning the result.  As
+    /// with `apply()`, the sequence must already have been integrated.
+    pub fn apply_to_elements(&self, elements: &[T]) -> Vec<T> {
         use std::slice;
-        try!(file.seek(SeekFrom::Start(0)));
-        // TODO try to find the size of the file?
-        // XXX In future, we want this to read in chunks of the file at a time.
-        let mut file_bytes = Vec::new();
-
-        try!(file.read_to_end(&mut file_bytes));
-        try!(file.seek(SeekFrom::Start(0)));
-        try!(file.set_len(0));
-        let mut insert_stack:VecDeque<slice::Iter<u8>> = VecDeque::new();
+        let mut result = Vec::with_capacity(elements.len());
+        let mut insert_stack:VecDeque<slice::Iter<T>> = VecDeque::new();
         let mut current_delete = 0;
-        insert_stack.push_front(file_bytes.iter());
+        insert_stack.push_front(elements.iter());
         let mut inserts = self.inserts.iter().peekable();
         let mut deletes = self.deletes.iter().peekable();
         let mut insert_index = 0;
         let mut delete_index = 0;
         while !insert_stack.is_empty() {
-            let should_insert = if let Some(next_insert) = inserts.peek() {
-                if next_insert.get_position() == insert_index {
-                    true
-                } else {
-                    false
-                }
-            } else {
-                false
-            };
+            let should_insert = inserts.peek().is_some_and(|next_insert| next_insert.get_position() == insert_index);
             if should_insert {
                 insert_stack.push_front(inserts.next().unwrap().get_value().iter());
             } else {
-                let should_delete = if let Some(delete) = deletes.peek() {
-                    if delete.get_position() == delete_index {
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                };
+                let should_delete = deletes.peek().is_some_and(|delete| delete.get_position() == delete_index);
                 if should_delete {
                     current_delete += deletes.next().unwrap().get_length();
                 } else {
                     let next = insert_stack.front_mut().unwrap().next();
                     if let Some(next) = next {
                         if current_delete == 0 {
-                            try!(file.write(&[*next]));
+                            result.push(next.clone());
                             insert_index += 1;
                             delete_index += 1;
                         } else {
@@ -694,44 +2030,112 @@ impl TransactionSequence {
                 }
             }
         }
-        Ok(())
+        result
+    }
+
+    /// Fills in the content of any deletes that don't know what they remove, using `document`,
+    /// which is the data the sequence is about to be applied to.  For a local transaction, this
+    /// must be done before it is run through `Engine::process_transaction()`, so that the history
+    /// has the content too.
+    pub fn capture_deleted(&mut self, document: &[T]) {
+        // Deletes are performed on the data after all the inserts
+        let inserted = TransactionSequence::new(None, self.inserts.clone(), LinkedList::new()).apply_to_elements(document);
+        let mut removed = 0;
+        for delete in self.deletes.iter_mut() {
+            // and after every delete before them
+            let start = (delete.get_position() + removed) as usize;
+            let end = start + delete.get_length() as usize;
+            if delete.get_content().is_none() && end <= inserted.len() {
+                delete.set_content(Some(inserted[start..end].to_vec()));
+            }
+            removed += delete.get_length();
+        }
     }
 
     /// Compress this transaction and write to `writer`.  The output can then be expanded
     /// back into an equivilent Transaction using `expand_from()`
     pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         let mut int_buf = [0;4];
+        let mut flags = 0;
+        if self.last_timestamp.is_some() {
+            flags |= HAS_STATE;
+        }
+        if self.fingerprint.is_some() {
+            flags |= HAS_FINGERPRINT;
+        }
+        if self.metadata.is_some() {
+            flags |= HAS_METADATA;
+        }
+        let include_content = self.deletes.iter().any(|delete| delete.get_content().is_some());
+        if include_content {
+            flags |= HAS_CONTENT;
+        }
+        if !self.formats.is_empty() {
+            flags |= HAS_FORMATS;
+        }
+        if !self.anchors.is_empty() {
+            flags |= HAS_ANCHORS;
+        }
+        if !self.moves.is_empty() {
+            flags |= HAS_MOVES;
+        }
+        try!(writer.write(&[flags]));
         if let Some((site_id, timestamp)) = self.last_timestamp {
-            try!(writer.write(&[1]));
             NetworkEndian::write_u32(&mut int_buf, site_id);
-            try!(writer.write(&mut int_buf));
+            try!(writer.write_all(&int_buf));
             NetworkEndian::write_u32(&mut int_buf, timestamp);
-            try!(writer.write(&mut int_buf));
-        } else {
-            try!(writer.write(&[0]));
+            try!(writer.write_all(&int_buf));
+        }
+        if let Some(ref fingerprint) = self.fingerprint {
+            try!(fingerprint.compress_to(writer));
+        }
+        if let Some(ref metadata) = self.metadata {
+            try!(metadata.compress_to(writer));
         }
 
         NetworkEndian::write_u32(&mut int_buf, self.inserts.len() as u32);
-        try!(writer.write(&mut int_buf));
+        try!(writer.write_all(&int_buf));
         for insert in self.inserts.iter() {
             try!(insert.compress_to(writer, false));
         }
         NetworkEndian::write_u32(&mut int_buf, self.deletes.len() as u32);
-        try!(writer.write(&mut int_buf));
+        try!(writer.write_all(&int_buf));
         for delete in self.deletes.iter() {
-            try!(delete.compress_to(writer));
+            try!(delete.compress_to(writer, false, include_content));
+        }
+        if !self.formats.is_empty() {
+            NetworkEndian::write_u32(&mut int_buf, self.formats.len() as u32);
+            try!(writer.write_all(&int_buf));
+            for format in self.formats.iter() {
+                try!(format.compress_to(writer, false));
+            }
+        }
+        if !self.anchors.is_empty() {
+            NetworkEndian::write_u32(&mut int_buf, self.anchors.len() as u32);
+            try!(writer.write_all(&int_buf));
+            for anchor in self.anchors.iter() {
+                try!(anchor.compress_to(writer, false));
+            }
+        }
+        if !self.moves.is_empty() {
+            NetworkEndian::write_u32(&mut int_buf, self.moves.len() as u32);
+            try!(writer.write_all(&int_buf));
+            for operation in self.moves.iter() {
+                try!(operation.compress_to(writer, false));
+            }
         }
         Ok(())
     }
 
     /// Expand this transaction from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<TransactionSequence> {
+    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<TransactionSequence<T>> {
         trace!("Reading transaction");
         let mut bool_buffer = [0;1];
         let mut int_buf = [0;4];
         try!(reader.read_exact(&mut bool_buffer));
-        let last_timestamp = if bool_buffer[0] == 1 {
+        let flags = bool_buffer[0];
+        let last_timestamp = if flags & HAS_STATE != 0 {
             trace!("Reading State");
             try!(reader.read_exact(&mut int_buf));
             let site_id = NetworkEndian::read_u32(&int_buf);
@@ -742,41 +2146,190 @@ impl TransactionSequence {
             trace!("No state");
             None
         };
+        let fingerprint = if flags & HAS_FINGERPRINT != 0 {
+            Some(try!(Fingerprint::expand_from(reader)))
+        } else {
+            None
+        };
+        let metadata = if flags & HAS_METADATA != 0 {
+            Some(try!(Metadata::expand_from(reader)))
+        } else {
+            None
+        };
+
+        let mut int_buf = [0;4];
+        trace!("Reading insert length");
+        try!(reader.read_exact(&mut int_buf));
+        let insert_len = NetworkEndian::read_u32(&int_buf);
+        trace!("Insert length was: {}", insert_len);
+        let mut inserts = LinkedList::new();
+        for _ in 0..insert_len {
+            inserts.push_back(try!(InsertOperation::expand_from(reader, timestamp_lookup)))
+        }
+        trace!("Read inserts");
+        trace!("Reading delete length");
+        try!(reader.read_exact(&mut int_buf));
+        let delete_len = NetworkEndian::read_u32(&int_buf);
+        trace!("Delete length was: {}", delete_len);
+        let mut deletes = LinkedList::new();
+        for _ in 0..delete_len {
+            deletes.push_back(try!(DeleteOperation::expand_from(reader, timestamp_lookup, flags & HAS_CONTENT != 0)));
+        }
+        trace!("Read deletes");
+        let mut formats = Vec::new();
+        if flags & HAS_FORMATS != 0 {
+            try!(reader.read_exact(&mut int_buf));
+            let format_len = NetworkEndian::read_u32(&int_buf);
+            for _ in 0..format_len {
+                formats.push(try!(FormatOperation::expand_from(reader, timestamp_lookup)));
+            }
+        }
+        let mut anchors = Vec::new();
+        if flags & HAS_ANCHORS != 0 {
+            try!(reader.read_exact(&mut int_buf));
+            let anchor_len = NetworkEndian::read_u32(&int_buf);
+            for _ in 0..anchor_len {
+                anchors.push(try!(AnchorOperation::expand_from(reader, timestamp_lookup)));
+            }
+        }
+        let mut moves = Vec::new();
+        if flags & HAS_MOVES != 0 {
+            try!(reader.read_exact(&mut int_buf));
+            let move_len = NetworkEndian::read_u32(&int_buf);
+            for _ in 0..move_len {
+                moves.push(try!(MoveOperation::expand_from(reader, timestamp_lookup)));
+            }
+        }
+        Ok(TransactionSequence {
+            last_timestamp: last_timestamp,
+            inserts: inserts,
+            deletes: deletes,
+            fingerprint: fingerprint,
+            metadata: metadata,
+            formats: formats,
+            anchors: anchors,
+            moves: moves,
+        })
+    }
+}
+
+// Methods for sequences of bytes
+impl TransactionSequence {
+    /// Checks `document`, which this sequence has been integrated into and applied to, against
+    /// the fingerprint the sender attached.  `state` is the `(site id, timestamp)` the sequence
+    /// was sent as, and is reported if the documents diverged.
+    pub fn verify(&self, document: &[u8], state: Option<(u32, u32)>) -> Result<(), OTError> {
+        if let Some(expected) = self.fingerprint {
+            let found = Fingerprint::of(document);
+            if found != expected {
+                return Err(OTError::new(Kind::Diverged {
+                    state: state,
+                    expected: expected,
+                    found: found,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the operations in this sequence to a file.  This should not be called until after
+    /// the sequence has been integrated via [`Engine::integrate_remote`](struct.Engine.html#method.integrate_remote)
+    /// The file must have been opened on both read and write mode (see [OpenOptions](https://doc.rust-lang.org/nightly/std/fs/struct.OpenOptions.html)).
+    /// Any deletes that don't know what they remove have it filled in from the file.
+    pub fn apply(&mut self, file: &mut File) -> io::Result<()> {
+        try!(file.seek(SeekFrom::Start(0)));
+        // TODO try to find the size of the file?
+        // XXX In future, we want this to read in chunks of the file at a time.
+        let mut file_bytes = Vec::new();
+
+        try!(file.read_to_end(&mut file_bytes));
+        self.capture_deleted(&file_bytes);
+        try!(file.seek(SeekFrom::Start(0)));
+        try!(file.set_len(0));
+        file.write_all(&self.apply_to_elements(&file_bytes))
+    }
+
+    /// Apply the operations in this sequence to an in-memory copy of a file, returning the
+    /// resulting bytes.  As with `apply()`, the sequence must already have been integrated.
+    pub fn apply_to_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        self.apply_to_elements(bytes)
+    }
+
+    /// Widens the operations so that none of them starts or ends in the middle of a UTF-8
+    /// character, using `document`, which is the data the sequence is about to be applied to.
+    /// The sequence makes the same change afterwards, but the characters it only partly changed
+    /// are deleted and inserted whole.  For a local transaction, this must be done before it is
+    /// run through `Engine::process_transaction()`.  A sequence that doesn't fit `document` is
+    /// left as it is.
+    pub fn align_to_chars(&mut self, document: &[u8]) {
+        let stamp = self.inserts.front().map(|insert| (insert.get_timestamp(), insert.get_site_id()))
+            .or(self.deletes.front().map(|delete| (delete.get_timestamp(), delete.get_site_id())));
+        let (timestamp, site_id) = match stamp {
+            Some(stamp) => stamp,
+            None => return
+        };
+        let captured = self.deletes.iter().any(|delete| delete.get_content().is_some());
+        let result = match utils::trace_origins(document, &self.inserts, &self.deletes) {
+            Some(result) => result,
+            None => return
+        };
+        let result_bytes: Vec<u8> = result.iter().map(|&(byte, _)| byte).collect();
 
-        let mut int_buf = [0;4];
-        trace!("Reading insert length");
-        try!(reader.read_exact(&mut int_buf));
-        let insert_len = NetworkEndian::read_u32(&int_buf);
-        trace!("Insert length was: {}", insert_len);
-        let mut inserts = LinkedList::new();
-        for _ in 0..insert_len {
-            inserts.push_back(try!(InsertOperation::expand_from(reader, timestamp_lookup)))
+        // Widen each changed region until it covers whole characters on both sides, joining it
+        // with any region it runs into
+        let boundary = |bytes: &[u8], offset: usize| text::is_char_boundary(bytes, offset as Position);
+        let mut aligned: Vec<(usize, usize, usize, usize)> = Vec::new();
+        let mut regions = utils::changed_regions(document.len(), &result).into_iter().peekable();
+        while let Some(mut region) = regions.next() {
+            loop {
+                if !boundary(document, region.0) || !boundary(&result_bytes, region.2) {
+                    region.0 -= 1;
+                    region.2 -= 1;
+                    if aligned.last().is_some_and(|previous| region.0 <= previous.1) {
+                        let previous = aligned.pop().unwrap();
+                        region.0 = previous.0;
+                        region.2 = previous.2;
+                    }
+                } else if !boundary(document, region.1) || !boundary(&result_bytes, region.3) {
+                    region.1 += 1;
+                    region.3 += 1;
+                    if regions.peek().is_some_and(|next| next.0 <= region.1) {
+                        let next = regions.next().unwrap();
+                        region.1 = next.1;
+                        region.3 = next.3;
+                    }
+                } else {
+                    break;
+                }
+            }
+            aligned.push(region);
         }
-        trace!("Read inserts");
-        trace!("Reading delete length");
-        try!(reader.read_exact(&mut int_buf));
-        let delete_len = NetworkEndian::read_u32(&int_buf);
-        trace!("Delete length was: {}", delete_len);
-        let mut deletes = LinkedList::new();
-        for _ in 0..delete_len {
-            deletes.push_back(try!(DeleteOperation::expand_from(reader)));
+        let (inserts, deletes) = utils::operations_for_regions(&aligned, &result_bytes, timestamp, site_id);
+        self.inserts = inserts;
+        self.deletes = deletes;
+        if captured {
+            self.capture_deleted(document);
         }
-        trace!("Read deletes");
-        Ok(TransactionSequence {
-            last_timestamp: last_timestamp,
-            inserts: inserts,
-            deletes: deletes
-        })
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::{Engine, TransactionSequence, TimeStamper};
+    use super::{Engine, TransactionSequence, TimeStamper, Snapshot, ENGINE_VERSION, compress_checkpoints_to, expand_checkpoints_from};
+    use metadata::Metadata;
+    use policy::ConflictPolicy;
+    use conflict::{Conflict, ConflictKind, ConflictOperation, Resolution};
+    use tiebreak::{TieBreak, SiteOrder, TimestampOrder, PriorityOrder};
     use std::collections::{LinkedList, BTreeMap};
-    use operations::{InsertOperation, DeleteOperation, Operation};
-    use ::{Position};
+    use std::sync::Arc;
+    use operations::{InsertOperation, DeleteOperation, ReplaceOperation, MoveOperation, FormatOperation, AnchorOperation, Operation};
+    use comments::Comment;
+    use test_utils::local_edit;
+    use element::Element;
+    use byteorder::{NetworkEndian, ByteOrder};
+    use std::io;
+    use ::{ErrorKind as Kind, Position};
     extern crate env_logger;
 
     macro_rules! create_list {
@@ -793,20 +2346,20 @@ mod tests {
         }).collect()
     }
 
-    fn generate_delete_list(operation_details: Vec<(Position, Position)>, starting_time: u32) -> LinkedList<DeleteOperation> {
+    fn generate_delete_list(operation_details: Vec<(Position, Position)>, site_id: u32, starting_time: u32) -> LinkedList<DeleteOperation> {
         operation_details.iter().map(|&(position, length)| {
-            DeleteOperation::new(position, length, starting_time)
+            DeleteOperation::new(position, length, starting_time, site_id)
         }).collect()
     }
 
-    fn to_insert_tuple_vec<'a>(list: &'a LinkedList<InsertOperation>) -> Vec<(Position, &'a str)> {
+    fn to_insert_tuple_vec(list: &LinkedList<InsertOperation>) -> Vec<(Position, &str)> {
         use std::str;
         list.iter().map(|op| {
-            (op.get_position(), unsafe {str::from_utf8_unchecked(&op.get_value())})
+            (op.get_position(), unsafe {str::from_utf8_unchecked(op.get_value())})
         }).collect()
     }
 
-    fn to_delete_tuple_vec<'a>(list: &'a LinkedList<DeleteOperation>) -> Vec<(Position, Position)> {
+    fn to_delete_tuple_vec(list: &LinkedList<DeleteOperation>) -> Vec<(Position, Position)> {
         list.iter().map(|op| {
             (op.get_position(), op.get_length())
         }).collect()
@@ -835,7 +2388,7 @@ mod tests {
             // insert "u" after the 'o' in "brown"
             (20, "u"),
         ], 1, 4);
-        Engine::transform(&mut sequence1, &sequence2);
+        Engine::<u8>::transform(&mut sequence1, &sequence2);
         // After sequence2 is applied, we would have "The very quickly brouwn fox"
         assert_eq!(to_insert_tuple_vec(&sequence1), vec![
             // Add an "ee" after "the"
@@ -864,7 +2417,7 @@ mod tests {
             (15, 2),
             // delete the "o" from "fox"
             (19, 1),
-        ], 0);
+        ], 1, 0);
         // after sequence1 is applied, we would have "Th vry qckly brwn fx"
         let sequence2 = generate_insert_list(vec![
             // Add an "ee" after "the"
@@ -877,7 +2430,7 @@ mod tests {
             (36, "xx!"),
         ], 2, 5);
         // After sequence2 is applied, we will have "Theee very quicklyk brouwnwnwnwn foxxx!"
-        Engine::transform(&mut sequence1, &sequence2);
+        Engine::<u8>::transform(&mut sequence1, &sequence2);
         assert_eq!(to_delete_tuple_vec(&sequence1), vec![
             // delete the first "e" from "theee"
             (2, 1),
@@ -902,7 +2455,7 @@ mod tests {
           (15, 7),
           // Delete "laz"
           (20, 3),
-      ], 0);
+      ], 1, 0);
       // After sequence1 is applied, we will have "The wn fox jump the y dog"
       let mut sequence2 =  generate_delete_list(vec![
           // Delete "he qu"
@@ -913,10 +2466,10 @@ mod tests {
           (4, 4),
           // Delete "the lazy dog"
           (21, 12),
-      ], 3);
+      ], 1, 3);
       // After sequence2 is applied, we will have "Ti b fox jumped over "
       let mut seq1_prime = sequence1.clone();
-      Engine::transform(&mut seq1_prime, &sequence2);
+      Engine::<u8>::transform(&mut seq1_prime, &sequence2);
       assert_eq!(to_delete_tuple_vec(&seq1_prime), vec![
           // Delete "i"
           (1, 1),
@@ -928,7 +2481,7 @@ mod tests {
           (11, 0),
       ]);
       // After both are applied we will have "T fox jump "
-      Engine::transform(&mut sequence2, &sequence1);
+      Engine::<u8>::transform(&mut sequence2, &sequence1);
       assert_eq!(to_delete_tuple_vec(&sequence2), vec![
           // Delete "he "
           (1, 3),
@@ -955,7 +2508,7 @@ mod tests {
           (6, 3),
           // Delete "" after "n"
           (11, 0),
-      ], 0);
+      ], 1, 0);
       // After sequence1 is applied, we will have "Te quibrown fox jumped over the lazy dog"
       let mut sequence2 = generate_delete_list(vec![
           // Delete "e"
@@ -968,9 +2521,9 @@ mod tests {
           (18, 2),
           // Detete " " after "the
           (29, 1),
-      ], 4);
+      ], 1, 4);
       // After sequence2 is applied, we will have "Th quik brn fox jued over thelazy dog"
-      Engine::transform(&mut sequence2, &sequence1);
+      Engine::<u8>::transform(&mut sequence2, &sequence1);
       assert_eq!(to_delete_tuple_vec(&sequence2), vec![
           // Delete "e"
           (1, 1),
@@ -1000,7 +2553,7 @@ mod tests {
           (18, 3),
           // Delete "dog"
           (24, 3),
-      ], 0);
+      ], 1, 0);
       // After these operations run, we will have " quick  fox  over  lazy "
       let mut sequence2 =  generate_delete_list(vec![
           // Delete "quick"
@@ -1011,10 +2564,10 @@ mod tests {
           (19, 4),
           // Delete "lazy"
           (24, 4),
-      ], 5);
+      ], 1, 5);
       // After these operations, we will have "The  brown  jumped  the  dog"
       let mut seq1_prime = sequence1.clone();
-      Engine::transform(&mut seq1_prime, &sequence2);
+      Engine::<u8>::transform(&mut seq1_prime, &sequence2);
       assert_eq!(to_delete_tuple_vec(&seq1_prime), vec![
           // Delete "The"
           (0, 3),
@@ -1028,7 +2581,7 @@ mod tests {
           (8, 3),
       ]);
 
-      Engine::transform(&mut sequence2, &sequence1);
+      Engine::<u8>::transform(&mut sequence2, &sequence1);
       assert_eq!(to_delete_tuple_vec(&sequence2), vec![
           // Delete "quick"
           (1, 5),
@@ -1049,7 +2602,7 @@ mod tests {
           (0, 10),
           // Delete "jumped  the  dog"
           (2, 16),
-      ], 0);
+      ], 1, 0);
       // After these operations run, we will have " "
       let sequence2 = generate_delete_list(vec![
           // Delete "quick"
@@ -1060,7 +2613,7 @@ mod tests {
           (19, 4),
           // Delete "lazy"
           (24, 4),
-      ], 2);
+      ], 1, 2);
       // After these operations, we will have "The  brown  jumped  the  dog"
       Engine::split_by(&mut sequence1, &sequence2);
       assert_eq!(to_delete_tuple_vec(&sequence1), vec![
@@ -1091,7 +2644,7 @@ mod tests {
             (8, 3),
             // Delete "f" from "fox"
             (11, 1)
-        ], 3);
+        ], 1, 3);
       // After this runs, we will have  "T quick wn ox"
       Engine::swap(&mut sequence1, &mut sequence2);
       assert_eq!(to_insert_tuple_vec(&sequence1), vec![
@@ -1128,7 +2681,7 @@ mod tests {
               (6, 3),
               // Delete "dog"
               (8, 3),
-          ], 0);
+          ], 1, 0);
           // After these operations run, we will have " quick  fox  over  lazy "
           let mut sequence2 = generate_delete_list(vec![
               // Delete "quick"
@@ -1139,7 +2692,7 @@ mod tests {
               (19, 4),
               // Delete "lazy"
               (24, 4),
-          ], 5);
+          ], 1, 5);
           // After these operations, we will have "The  brown  jumped  the  dog"
 
           Engine::swap(&mut sequence1, &mut sequence2);
@@ -1175,7 +2728,7 @@ mod tests {
               (0, 10),
               // Delete "jumped  the  dog"
               (2, 16),
-          ], 0);
+          ], 1, 0);
           // After these operations run, we will have " "
           let mut sequence2 = generate_delete_list(vec![
               // Delete "quick"
@@ -1186,7 +2739,7 @@ mod tests {
               (19, 4),
               // Delete "lazy"
               (24, 4),
-          ], 2);
+          ], 1, 2);
           // After these operations, we will have "The  brown  jumped  the  dog"
           Engine::split_by(&mut sequence1, &sequence2);
           Engine::swap(&mut sequence1, &mut sequence2);
@@ -1241,7 +2794,7 @@ mod tests {
               (15, 2),
               // delete the "o" from "fox"
               (19, 1),
-          ], 1);
+          ], 1, 1);
           let mut stamper = TimeStamper::new();
           stamper.stamp_local(1);
           stamper.stamp_local(1);
@@ -1265,7 +2818,7 @@ mod tests {
               (11, 3),
               // Delete "f" from "foxxx"
               (20, 1)
-          ], 0));
+          ], 2, 0));
           // After the deletes, we would have "Tee quickk wnwnwnwn oxxx!"
 
           engine.integrate_remote(&mut sequence, &lookup, &mut stamper).unwrap();
@@ -1358,7 +2911,7 @@ mod tests {
             (15, 2),
             // delete the "o" from "fox"
             (19, 1),
-        ], 4);
+        ], 1, 4);
 
         // After the deletes are applied, we would have "Th vry qckly brwn fx"
 
@@ -1379,7 +2932,7 @@ mod tests {
             (15, 2),
             // Delete "f" from "foxxx!"
             (24, 1)
-        ], 4));
+        ], 2, 4));
         // After the deletes, we would have "Tee vry qcklyk wnwnwnwn oxxx!"
 
         engine.process_transaction(&mut sequence);
@@ -1445,9 +2998,1127 @@ mod tests {
         // After all the deletes are applied, we should have "Tee vry qcklyk wnwnwnwn xxx!"
     }
 
+    #[test]
+    fn test_transform_through_enclosing_delete() {
+        // Starting with "0123456789ABC", "23456" has been deleted
+        let existing = generate_delete_list(vec![(2, 5)], 1, 0);
+
+        // A delete inside it has nothing left to delete, and the ones after it move back by
+        // what was deleted apart from the part they had already removed
+        let mut deletes = generate_delete_list(vec![(3, 2), (10, 1)], 2, 0);
+        Engine::<u8>::transform(&mut deletes, &existing);
+        assert_eq!(to_delete_tuple_vec(&deletes), vec![
+            (2, 0),
+            (7, 1),
+        ]);
+
+        // An insert inside it is kept, so the ones after it only move back by what was deleted
+        let mut inserts = generate_insert_list(vec![(3, "ab"), (10, "c")], 2, 0);
+        Engine::<u8>::transform(&mut inserts, &existing);
+        assert_eq!(to_insert_tuple_vec(&inserts), vec![
+            (2, "ab"),
+            (5, "c"),
+        ]);
+    }
+
+    #[test]
+    fn test_concurrent_inserts_skip_what_the_sender_knew() {
+        let mut engine = Engine::new(2);
+        let mut stamper = TimeStamper::new();
+        // Site 1 inserted "a", then site 2 inserted "b" in front of it
+        let mut lookup = BTreeMap::new();
+        lookup.insert(0, (1, 0));
+        stamper.stamp_remote(1, 0);
+        let mut sequence = TransactionSequence::new(None, generate_insert_list(vec![(0, "a")], 1, 0), LinkedList::new());
+        engine.integrate_remote(&mut sequence, &lookup, &mut stamper).unwrap();
+        let timestamp = stamper.stamp_local(2);
+        let mut sequence = TransactionSequence::new(None, generate_insert_list(vec![(0, "b")], 2, timestamp), LinkedList::new());
+        engine.process_transaction(&mut sequence);
+
+        // Everything after the state a transaction was made on is concurrent with it
+        let mut lookup = BTreeMap::new();
+        lookup.insert(0, (3, 0));
+        stamper.stamp_remote(3, 0);
+        let sequence = TransactionSequence::new(None, generate_insert_list(vec![(0, "c")], 3, 0), LinkedList::new());
+        let concurrent = engine.get_concurrent_inserts(&sequence, &lookup, &stamper).unwrap();
+        assert_eq!(to_insert_tuple_vec(&concurrent), vec![(0, "a"), (0, "b")]);
+
+        // Except for the earlier operations of a site that has later ones in the transaction,
+        // since every site's operations arrive in the order they were made
+        let mut lookup = BTreeMap::new();
+        lookup.insert(1, (1, 1));
+        stamper.stamp_remote(1, 1);
+        let sequence = TransactionSequence::new(None, generate_insert_list(vec![(0, "c")], 1, 1), LinkedList::new());
+        let concurrent = engine.get_concurrent_inserts(&sequence, &lookup, &stamper).unwrap();
+        assert_eq!(to_insert_tuple_vec(&concurrent), vec![(0, "b")]);
+    }
+
+    #[test]
+    fn test_integrate_insert_inside_concurrent_delete() {
+        let mut engine = Engine::new(2);
+        let mut stamper = TimeStamper::new();
+        stamper.stamp_remote(1, 0);
+        stamper.stamp_local(2);
+        // Both sites start with "oou"
+        engine.inserts = generate_insert_list(vec![(0, "oou")], 1, 0);
+        // Locally, "oo" has been deleted, leaving "u"
+        engine.deletes = generate_delete_list(vec![(0, 2)], 1, 1);
+
+        // Remotely, "iii" was inserted between the "o"s and "w" after the "u", giving "oiiiouw"
+        let mut lookup = BTreeMap::new();
+        lookup.insert(1, (1, 1));
+        stamper.stamp_remote(1, 1);
+        let mut sequence = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![
+            (1, "iii"),
+            (6, "w"),
+        ], 1, 1), LinkedList::new());
+
+        engine.integrate_remote(&mut sequence, &lookup, &mut stamper).unwrap();
+        assert_eq!(to_insert_tuple_vec(&sequence.inserts), vec![
+            (0, "iii"),
+            (4, "w"),
+        ]);
+        assert_eq!(sequence.apply_to_bytes(b"u"), b"iiiuw");
+    }
+
+    #[test]
+    fn test_integrate_concurrent_inserts_at_same_position() {
+        let mut engine = Engine::new(2);
+        let mut stamper = TimeStamper::new();
+        stamper.stamp_local(2);
+        // Locally, "rr" was typed into an empty file
+        engine.inserts = generate_insert_list(vec![(0, "rr")], 2, 0);
+
+        // Remotely, "zzz" then "t" was typed, then the last "z" was removed, giving "zzt"
+        let mut lookup = BTreeMap::new();
+        lookup.insert(0, (1, 0));
+        stamper.stamp_remote(1, 0);
+        let mut sequence = TransactionSequence::new(None, generate_insert_list(vec![
+            (0, "zzz"),
+            (3, "t"),
+        ], 1, 0), generate_delete_list(vec![(2, 1)], 1, 0));
+
+        engine.integrate_remote(&mut sequence, &lookup, &mut stamper).unwrap();
+        // The lower site id wins the tie, so the remote text comes first
+        assert_eq!(sequence.apply_to_bytes(b"rr"), b"zztrr");
+        assert_eq!(to_insert_tuple_vec(&engine.inserts), vec![
+            (0, "zzz"),
+            (3, "t"),
+            (4, "rr"),
+        ]);
+    }
+
+    #[test]
+    fn test_transform_replace() {
+        let document = b"The quick fox";
+        let replace = ReplaceOperation::new(4, 5, "slow".bytes().collect(), 0, 2);
+
+        // Text inserted inside the replaced word is replaced with it
+        let remote = TransactionSequence::new(None, generate_insert_list(vec![(4, "very "), (11, "i")], 1, 0), LinkedList::new());
+        let mut transformed = replace.clone();
+        remote.transform_replace(&mut transformed);
+        assert_eq!((transformed.get_position(), transformed.get_length()), (9, 6));
+        let document2 = remote.apply_to_bytes(document);
+        assert_eq!(TransactionSequence::from_replace(None, &transformed).apply_to_bytes(&document2), b"The very slow fox");
+
+        // If the word was deleted, the new value is still put in its place
+        let remote = TransactionSequence::new(None, LinkedList::new(), generate_delete_list(vec![(4, 6)], 1, 0));
+        let mut transformed = replace.clone();
+        remote.transform_replace(&mut transformed);
+        assert_eq!((transformed.get_position(), transformed.get_length()), (4, 0));
+        let document2 = remote.apply_to_bytes(document);
+        assert_eq!(TransactionSequence::from_replace(None, &transformed).apply_to_bytes(&document2), b"The slowfox");
+    }
+
+    #[test]
+    fn test_transform_move() {
+        let document = b"one two three";
+        let operation = MoveOperation::new(4, 4, 0, 0, 2);
+        assert_eq!(TransactionSequence::from_move(None, &operation, document).unwrap().apply_to_bytes(document), b"two one three");
+        let operation = MoveOperation::new(0, 4, 8, 0, 2);
+        assert_eq!(TransactionSequence::from_move(None, &operation, document).unwrap().apply_to_bytes(document), b"two one three");
+
+        // An edit inside the moved range is carried along with it
+        let mut engine = Engine::new(2);
+        let mut lookup = BTreeMap::new();
+        lookup.insert(0, (1, 0));
+        let operation = MoveOperation::new(4, 4, 0, 0, 2);
+        let remote = TransactionSequence::new(None, generate_insert_list(vec![(5, "X")], 1, 0), LinkedList::new());
+        let mut transformed = operation.clone();
+        engine.transform_move(&remote, &lookup, &mut transformed);
+        assert_eq!((transformed.get_position(), transformed.get_length(), transformed.get_destination()), (4, 5, 0));
+        let document2 = remote.apply_to_bytes(document);
+        assert_eq!(TransactionSequence::from_move(None, &transformed, &document2).unwrap().apply_to_bytes(&document2), b"tXwo one three");
+
+        // The destination follows the edits around it, and ties with concurrent inserts are broken
+        // by the engine's strategy
+        let remote = TransactionSequence::new(None, generate_insert_list(vec![(0, "zero ")], 1, 0), generate_delete_list(vec![(11, 1)], 1, 0));
+        let mut transformed = operation.clone();
+        engine.transform_move(&remote, &lookup, &mut transformed);
+        let document2 = remote.apply_to_bytes(document);
+        assert_eq!(document2, b"zero one tw three");
+        assert_eq!(TransactionSequence::from_move(None, &transformed, &document2).unwrap().apply_to_bytes(&document2), b"zero tw one three");
+        let mut order = PriorityOrder::new();
+        order.set_priority(2, 1);
+        engine.set_tie_break(Arc::new(order));
+        let mut transformed = operation.clone();
+        engine.transform_move(&remote, &lookup, &mut transformed);
+        assert_eq!(TransactionSequence::from_move(None, &transformed, &document2).unwrap().apply_to_bytes(&document2), b"tw zero one three");
+    }
+
+    #[test]
+    fn test_moves_carry_concurrent_edits() {
+        let document = b"one two three";
+        let operation = MoveOperation::new(10, 4, 0, 0, 2);
+        assert!(matches!(TransactionSequence::from_move(None, &operation, document).unwrap_err().kind, Kind::OutOfRange));
+        let operation = MoveOperation::new(4, 4, 6, 0, 2);
+        assert!(matches!(TransactionSequence::from_move(None, &operation, document).unwrap_err().kind, Kind::MoveIntoItself));
+
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "one two three")], 1, timestamp), LinkedList::new());
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // One site moves "two " to the front, while the other puts an "X" in it and deletes its "o"
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut moved = TransactionSequence::from_move(Some((1, 0)), &MoveOperation::new(4, 4, 0, timestamp1, 1), document).unwrap();
+        let mut document1 = moved.apply_to_bytes(document);
+        assert_eq!(document1, b"two one three");
+        engine1.process_transaction(&mut moved);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut edit = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(5, "X")], 2, timestamp2), generate_delete_list(vec![(7, 1)], 2, timestamp2));
+        let mut document2 = edit.apply_to_bytes(document);
+        assert_eq!(document2, b"one tXw three");
+        engine2.process_transaction(&mut edit);
+
+        let lookup1 = stamper1.get_timestamps_for(&moved);
+        let lookup2 = stamper2.get_timestamps_for(&edit);
+        let mut buffer = Vec::new();
+        moved.compress_to(&mut buffer).unwrap();
+        let mut moved = TransactionSequence::expand_from(&mut &buffer[..], Some(&lookup1)).unwrap();
+        assert_eq!(moved.get_moves().len(), 1);
+        stamper1.stamp_remote(2, timestamp2);
+        assert!(engine1.integrate_remote_with_moves(&mut edit, &lookup2, &mut document1, &mut stamper1).unwrap().is_none());
+        assert_eq!(document1, b"two one Xthree");
+        stamper2.stamp_remote(1, timestamp1);
+        let (mut carried, lookup) = engine2.integrate_remote_with_moves(&mut moved, &lookup1, &mut document2, &mut stamper2).unwrap().unwrap();
+        assert_eq!(document2, b"tXw one three");
+
+        // The site that made the move takes the carried edits like any others
+        stamper1.stamp_remote(2, lookup.values().next().unwrap().1);
+        engine1.integrate_remote(&mut carried, &lookup, &mut stamper1).unwrap();
+        document1 = carried.apply_to_bytes(&document1);
+        assert_eq!(document1, document2);
+    }
+
+    #[test]
+    fn test_move_missing_from_lookup() {
+        let document = b"one two three";
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "one two three")], 1, timestamp), LinkedList::new());
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // The lookup only has the timestamp of the first transaction, not of the move
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut moved = TransactionSequence::from_move(Some((1, 0)), &MoveOperation::new(4, 4, 0, timestamp1, 1), document).unwrap();
+        engine1.process_transaction(&mut moved);
+        assert!(matches!(engine2.integrate_remote(&mut moved, &lookup, &mut stamper2).unwrap_err().kind, Kind::NoSuchState));
+        assert_eq!(engine2.inserts.len(), 1);
+    }
+
+    #[test]
+    fn test_deletes_capture_content() {
+        let document = b"The quick brown fox";
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut local = TransactionSequence::new(None, generate_insert_list(vec![(4, "very ")], 1, timestamp), generate_delete_list(vec![(15, 6)], 1, timestamp));
+        local.capture_deleted(document);
+        assert_eq!(local.deletes.front().unwrap().get_content(), Some(&b"brown "[..]));
+        engine1.process_transaction(&mut local);
+        let document1 = local.apply_to_bytes(document);
+        let mut lookup1 = BTreeMap::new();
+        lookup1.insert(timestamp, (1, timestamp));
+
+        let timestamp = stamper2.stamp_local(2);
+        let mut concurrent = TransactionSequence::new(None, LinkedList::new(), generate_delete_list(vec![(10, 9)], 2, timestamp));
+        concurrent.capture_deleted(document);
+        engine2.process_transaction(&mut concurrent);
+        let document2 = concurrent.apply_to_bytes(document);
+        let mut lookup2 = BTreeMap::new();
+        lookup2.insert(timestamp, (2, timestamp));
+
+        // The content survives being sent
+        let mut buffer = Vec::new();
+        concurrent.compress_to(&mut buffer).unwrap();
+        let mut remote = TransactionSequence::expand_from(&mut &buffer[..], Some(&lookup2)).unwrap();
+        assert_eq!(remote.deletes, concurrent.deletes);
+
+        // Only the part that hadn't already been deleted is left
+        stamper1.stamp_remote(2, 0);
+        engine1.integrate_remote(&mut remote, &lookup2, &mut stamper1).unwrap();
+        assert_eq!(remote.deletes.iter().map(|delete| delete.get_content().unwrap().to_vec()).collect::<Vec<_>>(), vec![b"fox".to_vec()]);
+        let history = engine1.get_operations_since(None, &stamper1).unwrap();
+        assert_eq!(history.deletes.iter().map(|delete| delete.get_content().unwrap().to_vec()).collect::<Vec<_>>(), vec![b"brown ".to_vec(), b"fox".to_vec()]);
+        assert_eq!(history.deletes.iter().map(|delete| delete.get_site_id()).collect::<Vec<_>>(), vec![1, 2]);
+
+        let mut remote = local.clone();
+        stamper2.stamp_remote(1, 0);
+        engine2.integrate_remote(&mut remote, &lookup1, &mut stamper2).unwrap();
+        assert!(remote.deletes.iter().all(|delete| delete.get_content() == Some(&b""[..])));
+        assert_eq!(remote.apply_to_bytes(&document2), b"The very quick ");
+        assert_eq!(TransactionSequence::new(None, LinkedList::new(), generate_delete_list(vec![(15, 3)], 1, 0)).apply_to_bytes(&document1), b"The very quick ");
+    }
+
+    #[test]
+    fn test_snapshot_integrates_concurrent_transactions() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut document1 = Vec::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        let mut document2 = Vec::new();
+
+        let (mut first, lookup) = local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(0, "Hello world")], vec![]);
+        stamper2.stamp_remote(1, 0);
+        engine2.integrate_remote(&mut first, &lookup, &mut stamper2).unwrap();
+        document2 = first.apply_to_bytes(&document2);
+        local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(6, "there")], vec![(11, 5)]);
+        // Site 2 hasn't seen the second edit when it makes its own
+        let (concurrent, lookup) = local_edit(&mut engine2, &mut stamper2, 2, &mut document2, vec![(11, "!")], vec![(0, 1)]);
+
+        let snapshot = Snapshot::new(document1.clone(), &engine1, &stamper1);
+        let mut buffer = Vec::new();
+        snapshot.compress_to(&mut buffer).unwrap();
+        // Text that has been deleted isn't part of the snapshot
+        assert!(!buffer.windows(5).any(|window| window == b"world"));
+        let snapshot = Snapshot::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(snapshot.get_document(), b"Hello there");
+        assert_eq!(snapshot.get_state(), Some((1, 1)));
+        let (document3, mut engine3, mut stamper3) = snapshot.into_parts(3);
+        assert!(engine3.get_operations_since(None, &stamper3).is_err());
+        assert!(engine3.get_operations_since(Some((1, 0)), &stamper3).is_err());
+        assert!(engine3.get_operations_since(Some((1, 1)), &stamper3).is_ok());
+
+        // The new site integrates the concurrent edit just like the site it came from
+        let mut sequence1 = concurrent.clone();
+        stamper1.stamp_remote(2, 1);
+        engine1.integrate_remote(&mut sequence1, &lookup, &mut stamper1).unwrap();
+        let mut sequence3 = concurrent.clone();
+        stamper3.stamp_remote(2, 1);
+        engine3.integrate_remote(&mut sequence3, &lookup, &mut stamper3).unwrap();
+        assert_eq!(sequence1.apply_to_bytes(&document1), b"ello there!");
+        assert_eq!(sequence3.apply_to_bytes(&document3), b"ello there!");
+    }
+
+    #[test]
+    fn test_snapshot_lengths_are_checked() {
+        let mut engine = Engine::new(1);
+        let mut buffer = Vec::new();
+        Snapshot::new(b"x".to_vec(), &engine, &TimeStamper::new()).compress_to(&mut buffer).unwrap();
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "x")], 1, 0), LinkedList::new());
+        engine.process_transaction(&mut transaction);
+        let mut with_insert = Vec::new();
+        Snapshot::new(b"x".to_vec(), &engine, &TimeStamper::new()).compress_to(&mut with_insert).unwrap();
+        assert!(Snapshot::expand_from(&mut &with_insert[..]).is_ok());
+
+        // A document longer than what was sent
+        let mut corrupted = with_insert.clone();
+        NetworkEndian::write_u64(&mut corrupted[..8], u64::MAX);
+        assert!(Snapshot::expand_from(&mut &corrupted[..]).is_err());
+
+        // An insert longer than a snapshot's history may be, after its count, timestamp and position
+        let inserts = buffer.iter().zip(with_insert.iter()).position(|(a, b)| a != b).unwrap() - 3;
+        let length = inserts + 4 + 4 + 8;
+        assert_eq!(NetworkEndian::read_u64(&with_insert[length..length + 8]), 1);
+        let mut corrupted = with_insert.clone();
+        NetworkEndian::write_u64(&mut corrupted[length..length + 8], u64::MAX);
+        assert_eq!(Snapshot::expand_from(&mut &corrupted[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut engine = Engine::new(1);
+        let mut stamper = TimeStamper::new();
+        let mut document = Vec::new();
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(0, "Hello world")], vec![]);
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![], vec![(0, 1)]);
+        engine.set_checkpoint("release-1.4", Some((1, 1)), &stamper).unwrap();
+        assert!(engine.set_checkpoint("release-1.5", Some((1, 2)), &stamper).is_err());
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(10, "!")], vec![(4, 6)]);
+        assert_eq!(document, b"ello!");
+
+        let release = engine.get_checkpoint("release-1.4").unwrap();
+        assert_eq!(release, Some((1, 1)));
+        assert!(engine.get_checkpoint("release-1.5").is_err());
+        let since = engine.get_operations_since(release, &stamper).unwrap();
+        assert_eq!(since.inserts.len(), 1);
+        assert_eq!(since.deletes.len(), 1);
+        assert_eq!(engine.get_document_at(&document, release, &stamper).unwrap(), b"ello world");
+
+        // Checkpoints are saved with the engine
+        let mut buffer = Vec::new();
+        engine.compress_to(&mut buffer).unwrap();
+        let engine2 = Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap();
+        assert_eq!(engine2.get_checkpoints(), engine.get_checkpoints());
+
+        // A snapshot keeps the text deleted since the checkpoint, but not before
+        let snapshot = Snapshot::new(document.clone(), &engine, &stamper);
+        let mut buffer = Vec::new();
+        snapshot.compress_to(&mut buffer).unwrap();
+        assert!(buffer.windows(6).any(|window| window == b" world"));
+        assert!(!buffer.windows(5).any(|window| window == b"Hello"));
+        let (document3, engine3, stamper3) = Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(3);
+        let release = engine3.get_checkpoint("release-1.4").unwrap();
+        assert!(engine3.get_operations_since(release, &stamper3).is_err());
+        assert_eq!(engine3.get_document_at(&document3, release, &stamper3).unwrap(), b"ello world");
+        assert!(engine3.get_document_at(&document3, Some((1, 0)), &stamper3).is_err());
+
+        // A name longer than what was saved is an error
+        let mut buffer = Vec::new();
+        compress_checkpoints_to(engine.get_checkpoints(), &mut buffer).unwrap();
+        NetworkEndian::write_u32(&mut buffer[4..8], u32::MAX);
+        assert!(expand_checkpoints_from(&mut &buffer[..]).is_err());
+
+        assert!(engine.remove_checkpoint("release-1.4"));
+        assert!(!engine.remove_checkpoint("release-1.4"));
+    }
+
+    #[test]
+    fn test_discard_history() {
+        let mut engine = Engine::new(1);
+        let mut stamper = TimeStamper::new();
+        let mut document = Vec::new();
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(0, "Hello world")], vec![]);
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![], vec![(5, 6)]);
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(5, " there")], vec![]);
+        assert_eq!(document, b"Hello there");
+        engine.set_checkpoint("hello", Some((1, 1)), &stamper).unwrap();
+
+        // An earlier checkpoint holds the discard back until it is removed
+        let mut held = engine.clone();
+        held.set_checkpoint("start", Some((1, 0)), &stamper).unwrap();
+        held.discard_history((1, 1), &stamper).unwrap();
+        assert_eq!(held.get_checkpoint("start").unwrap(), Some((1, 0)));
+        assert_eq!(held.get_document_at(&document, Some((1, 0)), &stamper).unwrap(), b"Hello world");
+        assert!(held.deletes.front().unwrap().get_content().is_some());
+        assert!(held.remove_checkpoint("start"));
+        held.discard_history((1, 1), &stamper).unwrap();
+        assert!(held.deletes.front().unwrap().get_content().is_none());
+
+        // Only the last insert is left once the first two transactions are forgotten, along
+        // with where the deleted text was
+        let mut discarded = engine.clone();
+        discarded.discard_history((1, 1), &stamper).unwrap();
+        assert_eq!(to_insert_tuple_vec(&discarded.inserts), vec![(11, " there")]);
+        assert_eq!(to_delete_tuple_vec(&discarded.deletes), vec![(5, 6)]);
+        assert!(discarded.deletes.front().unwrap().get_content().is_none());
+        assert_eq!(discarded.get_checkpoint("hello").unwrap(), Some((1, 1)));
+        assert!(discarded.get_operations_since(Some((1, 0)), &stamper).is_err());
+        assert_eq!(discarded.get_operations_since(Some((1, 1)), &stamper).unwrap().inserts.len(), 1);
+        assert_eq!(discarded.get_document_at(&document, Some((1, 1)), &stamper).unwrap(), b"Hello");
+        // Going back is a no-op
+        discarded.discard_history((1, 0), &stamper).unwrap();
+        assert_eq!(discarded.inserts.len(), 1);
+
+        // A transaction made on the state that was discarded is integrated just as before
+        let mut lookup = BTreeMap::new();
+        lookup.insert(0, (2, 0));
+        stamper.stamp_remote(2, 0);
+        let remote = TransactionSequence::new(Some((1, 1)), generate_insert_list(vec![(5, "!")], 2, 0), generate_delete_list(vec![(0, 1)], 2, 0));
+        let mut kept = remote.clone();
+        engine.integrate_remote(&mut kept, &lookup, &mut stamper).unwrap();
+        let mut remote = remote;
+        discarded.integrate_remote(&mut remote, &lookup, &mut stamper).unwrap();
+        assert_eq!(kept.apply_to_bytes(&document), b"ello! there");
+        assert_eq!(remote.apply_to_bytes(&document), b"ello! there");
+
+        // But one made on an earlier state isn't
+        let mut lookup = BTreeMap::new();
+        lookup.insert(1, (3, 0));
+        stamper.stamp_remote(3, 0);
+        let mut remote = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(0, "?")], 3, 1), LinkedList::new());
+        assert!(matches!(discarded.integrate_remote(&mut remote, &lookup, &mut stamper).unwrap_err().kind, Kind::NoSuchState));
+
+        // The engine remembers what it forgot
+        let mut buffer = Vec::new();
+        discarded.compress_to(&mut buffer).unwrap();
+        let expanded = Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap();
+        assert!(expanded.get_operations_since(Some((1, 0)), &stamper).is_err());
+        let mut buffer = Vec::new();
+        Snapshot::new(document.clone(), &discarded, &stamper).compress_to(&mut buffer).unwrap();
+        let (_, restored, stamper) = Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(4);
+        assert!(restored.get_document_at(&document, Some((1, 0)), &stamper).is_err());
+    }
+
+    #[test]
+    fn test_expand_earlier_versions() {
+        let mut engine = Engine::new(1);
+        let mut sequence = TransactionSequence::new(None, generate_insert_list(vec![(0, "Hello world")], 1, 1), generate_delete_list(vec![], 1, 1));
+        engine.process_transaction(&mut sequence);
+        let mut sequence = TransactionSequence::new(Some((1, 1)), generate_insert_list(vec![], 1, 2), generate_delete_list(vec![(0, 1)], 1, 2));
+        engine.process_transaction(&mut sequence);
+
+        // Engines saved before the format had a version only held their inserts and deletes
+        let mut buffer = Vec::new();
+        let mut int_buf = [0; 4];
+        NetworkEndian::write_u32(&mut int_buf, engine.inserts.len() as u32);
+        buffer.extend_from_slice(&int_buf);
+        for insert in engine.inserts.iter() {
+            insert.compress_to(&mut buffer, true).unwrap();
+        }
+        NetworkEndian::write_u32(&mut int_buf, engine.deletes.len() as u32);
+        buffer.extend_from_slice(&int_buf);
+        for delete in engine.deletes.iter() {
+            delete.compress_to(&mut buffer, true, true).unwrap();
+        }
+        let engine2 = Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap();
+        assert_eq!(engine2.inserts, engine.inserts);
+        assert_eq!(engine2.deletes, engine.deletes);
+        assert!(engine2.get_checkpoints().is_empty());
+        assert_eq!(engine2.get_tie_break().describe(), SiteOrder.describe());
+        assert!(!engine2.text_mode);
+
+        // A version from the future can't be read
+        let mut buffer = Vec::new();
+        engine.compress_to(&mut buffer).unwrap();
+        assert_eq!(buffer[4], ENGINE_VERSION);
+        buffer[4] = ENGINE_VERSION + 1;
+        assert!(Engine::<u8>::expand_from(&mut &buffer[..], 1).is_err());
+    }
+
+    /// A row of a table, identified by its key
+    #[derive(Debug, Clone, PartialEq)]
+    struct Row(u32);
+
+    impl Element for Row {
+        fn write_to<W: io::Write>(elements: &[Row], writer: &mut W) -> io::Result<()> {
+            let mut int_buf = [0; 4];
+            for row in elements {
+                NetworkEndian::write_u32(&mut int_buf, row.0);
+                try!(writer.write_all(&int_buf));
+            }
+            Ok(())
+        }
+
+        fn read_from<R: io::Read>(reader: &mut R, count: usize) -> io::Result<Vec<Row>> {
+            let mut int_buf = [0; 4];
+            let mut rows = Vec::with_capacity(count);
+            for _ in 0..count {
+                try!(reader.read_exact(&mut int_buf));
+                rows.push(Row(NetworkEndian::read_u32(&int_buf)));
+            }
+            Ok(rows)
+        }
+    }
+
+    #[test]
+    fn test_records_are_synchronized() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(0, vec![Row(1), Row(2), Row(3)], timestamp, 1));
+        let mut transaction = TransactionSequence::new(None, inserts, LinkedList::new());
+        let table = transaction.apply_to_elements(&[]);
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // One site removes the second row while the other adds a row after it
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(1, 1, timestamp1, 1));
+        let mut delete = TransactionSequence::new(Some((1, 0)), LinkedList::new(), deletes);
+        let mut table1 = delete.apply_to_elements(&table);
+        engine1.process_transaction(&mut delete);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(2, vec![Row(4)], timestamp2, 2));
+        let mut insert = TransactionSequence::new(Some((1, 0)), inserts, LinkedList::new());
+        let mut table2 = insert.apply_to_elements(&table);
+        engine2.process_transaction(&mut insert);
+
+        // Records go over the wire with the serialization of their element type
+        let lookup1 = stamper1.get_timestamps_for(&delete);
+        let lookup2 = stamper2.get_timestamps_for(&insert);
+        let mut buffer = Vec::new();
+        insert.compress_to(&mut buffer).unwrap();
+        let mut insert = TransactionSequence::<Row>::expand_from(&mut &buffer[..], Some(&lookup2)).unwrap();
+        stamper1.stamp_remote(2, timestamp2);
+        engine1.integrate_remote(&mut insert, &lookup2, &mut stamper1).unwrap();
+        table1 = insert.apply_to_elements(&table1);
+        stamper2.stamp_remote(1, timestamp1);
+        engine2.integrate_remote(&mut delete, &lookup1, &mut stamper2).unwrap();
+        table2 = delete.apply_to_elements(&table2);
+        assert_eq!(table1, vec![Row(1), Row(4), Row(3)]);
+        assert_eq!(table2, table1);
+
+        let mut buffer = Vec::new();
+        engine1.compress_to(&mut buffer).unwrap();
+        let engine1 = Engine::<Row>::expand_from(&mut &buffer[..], 1).unwrap();
+        let mut expanded_buffer = Vec::new();
+        engine1.compress_to(&mut expanded_buffer).unwrap();
+        assert_eq!(expanded_buffer, buffer);
+    }
+
+    #[test]
+    fn test_metadata_is_kept_with_the_history() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2: Engine = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        stamper2.stamp_local(2);
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "Hello")], 1, timestamp), LinkedList::new());
+        let mut metadata = Metadata::new();
+        metadata.set_author(Some("Ada".to_string()));
+        metadata.set_message(Some("Say hello".to_string()));
+        transaction.set_metadata(Some(metadata.clone()));
+        engine1.process_transaction(&mut transaction);
+        assert_eq!(engine1.get_metadata(timestamp), Some(&metadata));
+
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        let mut buffer = Vec::new();
+        transaction.compress_to(&mut buffer).unwrap();
+        let mut transaction = TransactionSequence::expand_from(&mut &buffer[..], Some(&lookup)).unwrap();
+        assert_eq!(transaction.get_metadata(), Some(&metadata));
+        let local_timestamp = stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+        assert_eq!(engine2.get_metadata(local_timestamp), Some(&metadata));
+        assert_eq!(engine2.get_metadata(0), None);
+
+        let mut buffer = Vec::new();
+        engine2.compress_to(&mut buffer).unwrap();
+        let mut engine2 = Engine::<u8>::expand_from(&mut &buffer[..], 2).unwrap();
+        assert_eq!(engine2.get_metadata(local_timestamp), Some(&metadata));
+        engine2.set_metadata(local_timestamp, None);
+        assert_eq!(engine2.get_metadata(local_timestamp), None);
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn concurrent_insert_and_delete(policy: ConflictPolicy) -> ((Vec<u8>, Vec<Conflict>), (Vec<u8>, Vec<Conflict>)) {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        engine1.set_conflict_policy(policy);
+        engine2.set_conflict_policy(policy);
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "The quick brown fox")], 1, timestamp), LinkedList::new());
+        let document = transaction.apply_to_bytes(b"");
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // One site deletes "quick brown " while the other puts a word in the middle of it
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut delete = TransactionSequence::new(Some((1, 0)), LinkedList::new(), generate_delete_list(vec![(4, 12)], 1, timestamp1));
+        let mut document1 = delete.apply_to_bytes(&document);
+        engine1.process_transaction(&mut delete);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut insert = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(10, "red ")], 2, timestamp2), LinkedList::new());
+        let mut document2 = insert.apply_to_bytes(&document);
+        engine2.process_transaction(&mut insert);
+
+        let lookup1 = stamper1.get_timestamps_for(&delete);
+        let lookup2 = stamper2.get_timestamps_for(&insert);
+        stamper1.stamp_remote(2, timestamp2);
+        let conflicts1 = engine1.integrate_remote_with_conflicts(&mut insert, &lookup2, &mut stamper1).unwrap();
+        document1 = insert.apply_to_bytes(&document1);
+        stamper2.stamp_remote(1, timestamp1);
+        let conflicts2 = engine2.integrate_remote_with_conflicts(&mut delete, &lookup1, &mut stamper2).unwrap();
+        document2 = delete.apply_to_bytes(&document2);
+        ((document1, conflicts1), (document2, conflicts2))
+    }
+
+    #[test]
+    fn test_formats_follow_concurrent_edits() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "The quick brown fox")], 1, timestamp), LinkedList::new());
+        let document = transaction.apply_to_bytes(b"");
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // One site makes "quick brown" bold, while the other puts a word in the middle of it,
+        // deletes the first word and makes "brown fox" not bold
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut bold = TransactionSequence::new(Some((1, 0)), LinkedList::new(), LinkedList::new());
+        bold.add_format(FormatOperation::new(4, 11, "bold", Some("true".to_string()), timestamp1, 1));
+        let mut document1 = bold.apply_to_bytes(&document);
+        engine1.process_transaction(&mut bold);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut edit = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(10, "red ")], 2, timestamp2), generate_delete_list(vec![(0, 4)], 2, timestamp2));
+        edit.add_format(FormatOperation::new(10, 9, "bold", Some("false".to_string()), timestamp2, 2));
+        let mut document2 = edit.apply_to_bytes(&document);
+        engine2.process_transaction(&mut edit);
+
+        let lookup1 = stamper1.get_timestamps_for(&bold);
+        let lookup2 = stamper2.get_timestamps_for(&edit);
+        let mut buffer = Vec::new();
+        bold.compress_to(&mut buffer).unwrap();
+        let mut bold = TransactionSequence::expand_from(&mut &buffer[..], Some(&lookup1)).unwrap();
+        stamper1.stamp_remote(2, timestamp2);
+        engine1.integrate_remote(&mut edit, &lookup2, &mut stamper1).unwrap();
+        document1 = edit.apply_to_bytes(&document1);
+        stamper2.stamp_remote(1, timestamp1);
+        engine2.integrate_remote(&mut bold, &lookup1, &mut stamper2).unwrap();
+        document2 = bold.apply_to_bytes(&document2);
+        assert_eq!(document1, b"quick red brown fox");
+        assert_eq!(document2, document1);
+        assert_eq!((bold.get_formats()[0].get_position(), bold.get_formats()[0].get_length()), (0, 15));
+
+        // Both formats were stamped at the same time, so the one from site 2 wins everywhere
+        let runs = |engine: &Engine| engine.get_formatting().get_runs(document1.len() as Position).into_iter().map(|(start, end, attributes)| {
+            (start, end, attributes.get("bold").cloned())
+        }).collect::<Vec<_>>();
+        assert_eq!(runs(&engine1), vec![(0, 10, Some("true".to_string())), (10, 19, Some("false".to_string()))]);
+        assert_eq!(runs(&engine2), runs(&engine1));
+
+        let mut buffer = Vec::new();
+        engine2.compress_to(&mut buffer).unwrap();
+        assert_eq!(Engine::<u8>::expand_from(&mut &buffer[..], 2).unwrap().get_formatting(), engine2.get_formatting());
+
+        // A site that joins from a snapshot sees the same spans
+        let mut buffer = Vec::new();
+        Snapshot::new(document2.clone(), &engine2, &stamper2).compress_to(&mut buffer).unwrap();
+        let (document3, engine3, _) = Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(3);
+        assert_eq!(document3, document2);
+        assert_eq!(runs(&engine3), runs(&engine1));
+    }
+
+    #[test]
+    fn test_format_missing_from_lookup() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "The quick brown fox")], 1, timestamp), LinkedList::new());
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // The lookup only has the timestamp of the first transaction, not of the format
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut bold = TransactionSequence::new(Some((1, 0)), LinkedList::new(), LinkedList::new());
+        bold.add_format(FormatOperation::new(4, 11, "bold", Some("true".to_string()), timestamp1, 1));
+        engine1.process_transaction(&mut bold);
+        assert!(matches!(engine2.integrate_remote(&mut bold, &lookup, &mut stamper2).unwrap_err().kind, Kind::NoSuchState));
+        assert!(engine2.get_formatting().get_spans().is_empty());
+    }
+
+    #[test]
+    fn test_comments_follow_concurrent_edits() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "The quick brown fox")], 1, timestamp), LinkedList::new());
+        let document = transaction.apply_to_bytes(b"");
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // One site starts a thread on "quick brown", while the other puts a word in the middle of it
+        let thread = engine1.get_comments_mut().next_id(1);
+        let comment = Comment::new(engine1.get_comments_mut().next_id(1), thread, "Which fox?");
+        engine1.get_comments_mut().add_comment(comment.clone());
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut anchor = TransactionSequence::new(Some((1, 0)), LinkedList::new(), LinkedList::new());
+        anchor.add_anchor(AnchorOperation::new(4, 11, thread, timestamp1, 1));
+        engine1.process_transaction(&mut anchor);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut insert = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(10, "red ")], 2, timestamp2), LinkedList::new());
+        let mut document2 = insert.apply_to_bytes(&document);
+        engine2.process_transaction(&mut insert);
+
+        // The comment gets to the other site before the anchor does
+        let mut buffer = Vec::new();
+        comment.compress_to(&mut buffer).unwrap();
+        assert!(engine2.get_comments_mut().add_comment(Comment::expand_from(&mut &buffer[..]).unwrap()));
+        assert_eq!(engine2.get_comments().get_thread(thread).unwrap().get_range(), None);
+
+        let lookup1 = stamper1.get_timestamps_for(&anchor);
+        let lookup2 = stamper2.get_timestamps_for(&insert);
+        let mut buffer = Vec::new();
+        anchor.compress_to(&mut buffer).unwrap();
+        let mut anchor = TransactionSequence::expand_from(&mut &buffer[..], Some(&lookup1)).unwrap();
+        stamper1.stamp_remote(2, timestamp2);
+        engine1.integrate_remote(&mut insert, &lookup2, &mut stamper1).unwrap();
+        stamper2.stamp_remote(1, timestamp1);
+        engine2.integrate_remote(&mut anchor, &lookup1, &mut stamper2).unwrap();
+        document2 = anchor.apply_to_bytes(&document2);
+        assert_eq!(document2, b"The quick red brown fox");
+        for engine in [&engine1, &engine2].iter() {
+            let thread = engine.get_comments().get_thread(thread).unwrap();
+            assert_eq!(thread.get_range(), Some((4, 19)));
+            assert_eq!(thread.get_comments(), ::std::slice::from_ref(&comment));
+        }
+
+        // Deleting all of the text the thread is anchored to orphans it
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut delete = TransactionSequence::new(Some((1, timestamp1)), LinkedList::new(), generate_delete_list(vec![(4, 16)], 2, timestamp2));
+        engine2.process_transaction(&mut delete);
+        let lookup2 = stamper2.get_timestamps_for(&delete);
+        stamper1.stamp_remote(2, timestamp2);
+        engine1.integrate_remote(&mut delete, &lookup2, &mut stamper1).unwrap();
+        for engine in [&engine1, &engine2].iter() {
+            assert_eq!(engine.get_comments().get_orphaned().iter().map(|thread| thread.get_id()).collect::<Vec<_>>(), vec![thread]);
+        }
+
+        let mut buffer = Vec::new();
+        engine1.compress_to(&mut buffer).unwrap();
+        assert_eq!(Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap().get_comments(), engine1.get_comments());
+
+        // A site started from a snapshot keeps the threads, and doesn't hand out their ids again
+        let mut buffer = Vec::new();
+        Snapshot::new(document2.clone(), &engine1, &stamper1).compress_to(&mut buffer).unwrap();
+        let (_, mut engine3, _) = Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(1);
+        assert_eq!(engine3.get_comments(), engine1.get_comments());
+        let id = engine3.get_comments_mut().next_id(1);
+        assert!(id != thread && id != comment.get_id());
+    }
+
+    #[test]
+    fn test_anchor_missing_from_lookup() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "The quick brown fox")], 1, timestamp), LinkedList::new());
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // The lookup only has the timestamp of the first transaction, not of the anchor
+        let thread = engine1.get_comments_mut().next_id(1);
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut anchor = TransactionSequence::new(Some((1, 0)), LinkedList::new(), LinkedList::new());
+        anchor.add_anchor(AnchorOperation::new(4, 11, thread, timestamp1, 1));
+        engine1.process_transaction(&mut anchor);
+        assert!(matches!(engine2.integrate_remote(&mut anchor, &lookup, &mut stamper2).unwrap_err().kind, Kind::NoSuchState));
+        assert!(engine2.get_comments().get_threads().is_empty());
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let ((document1, _), (document2, _)) = concurrent_insert_and_delete(ConflictPolicy::KeepInsert);
+        assert_eq!(document1, b"The red fox");
+        assert_eq!(document2, b"The red fox");
+        let ((document1, _), (document2, _)) = concurrent_insert_and_delete(ConflictPolicy::DropInsert);
+        assert_eq!(document1, b"The fox");
+        assert_eq!(document2, b"The fox");
+
+        let mut engine = Engine::new(1);
+        engine.set_conflict_policy(ConflictPolicy::DropInsert);
+        let mut buffer = Vec::new();
+        engine.compress_to(&mut buffer).unwrap();
+        assert_eq!(Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap().get_conflict_policy(), ConflictPolicy::DropInsert);
+        let snapshot = Snapshot::new(Vec::new(), &engine, &TimeStamper::new());
+        let mut buffer = Vec::new();
+        snapshot.compress_to(&mut buffer).unwrap();
+        let (_, engine, _) = Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(2);
+        assert_eq!(engine.get_conflict_policy(), ConflictPolicy::DropInsert);
+    }
+
+    fn concurrent_inserts_at_front(tie_break: Arc<dyn TieBreak>) -> (Vec<u8>, Vec<u8>) {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        engine1.set_tie_break(tie_break.clone());
+        engine2.set_tie_break(tie_break);
+
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "fox")], 1, timestamp), LinkedList::new());
+        let document = transaction.apply_to_bytes(b"");
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // The first site makes an edit of its own before both sites type at the front, so its
+        // insert there gets a later timestamp than the second site's
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut edit = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(3, "!")], 1, timestamp1), LinkedList::new());
+        let mut document1 = edit.apply_to_bytes(&document);
+        engine1.process_transaction(&mut edit);
+        let timestamp1_front = stamper1.stamp_local(1);
+        let mut insert1 = TransactionSequence::new(Some((1, timestamp1)), generate_insert_list(vec![(0, "red ")], 1, timestamp1_front), LinkedList::new());
+        document1 = insert1.apply_to_bytes(&document1);
+        engine1.process_transaction(&mut insert1);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut insert2 = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(0, "sly ")], 2, timestamp2), LinkedList::new());
+        let mut document2 = insert2.apply_to_bytes(&document);
+        engine2.process_transaction(&mut insert2);
+
+        // The first site sends both of its transactions together
+        let mut operations1 = engine1.get_operations_since(Some((1, 0)), &stamper1).unwrap();
+        let lookup1 = stamper1.get_timestamps_since(Some((1, 0))).unwrap();
+        let lookup2 = stamper2.get_timestamps_for(&insert2);
+        stamper1.stamp_remote(2, timestamp2);
+        engine1.integrate_remote(&mut insert2, &lookup2, &mut stamper1).unwrap();
+        document1 = insert2.apply_to_bytes(&document1);
+        stamper2.stamp_remote(1, timestamp1);
+        stamper2.stamp_remote(1, timestamp1_front);
+        engine2.integrate_remote(&mut operations1, &lookup1, &mut stamper2).unwrap();
+        document2 = operations1.apply_to_bytes(&document2);
+        (document1, document2)
+    }
+
+    #[test]
+    fn test_tie_breaks() {
+        let (document1, document2) = concurrent_inserts_at_front(Arc::new(SiteOrder));
+        assert_eq!(document1, b"red sly fox!");
+        assert_eq!(document2, b"red sly fox!");
+        let (document1, document2) = concurrent_inserts_at_front(Arc::new(TimestampOrder));
+        assert_eq!(document1, b"sly red fox!");
+        assert_eq!(document2, b"sly red fox!");
+        let mut priorities = PriorityOrder::new();
+        priorities.set_priority(2, 1);
+        let (document1, document2) = concurrent_inserts_at_front(Arc::new(priorities.clone()));
+        assert_eq!(document1, b"sly red fox!");
+        assert_eq!(document2, b"sly red fox!");
+
+        // The strategy is saved along with the engine, and in snapshots
+        let mut engine = Engine::new(1);
+        engine.set_tie_break(Arc::new(priorities.clone()));
+        let mut buffer = Vec::new();
+        engine.compress_to(&mut buffer).unwrap();
+        assert_eq!(Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap().get_tie_break().describe(), priorities.describe());
+        assert!(Engine::<u8>::expand_with_tie_break(&mut &buffer[..], 1, Arc::new(SiteOrder)).is_err());
+        let mut buffer = Vec::new();
+        Snapshot::new(Vec::new(), &engine, &TimeStamper::new()).compress_to(&mut buffer).unwrap();
+        let (_, engine, _) = Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(2);
+        assert_eq!(engine.get_tie_break().describe(), priorities.describe());
+    }
+
+    #[test]
+    fn test_align_to_chars() {
+        use rdiff::BlockHashes;
+        // A byte diff of "café au lait" and "cafè à lait" only changes the last byte of each accent
+        let document = "café au lait".as_bytes();
+        let inserts = create_list!(InsertOperation::new(4, vec![0xA8], 1, 1), InsertOperation::new(7, vec![0xC3, 0xA0], 1, 1));
+        let deletes = create_list!(DeleteOperation::new(5, 1, 1, 1), DeleteOperation::new(8, 2, 1, 1));
+        let mut sequence = TransactionSequence::new(None, inserts, deletes);
+        let expected = sequence.apply_to_bytes(document);
+        assert_eq!(expected, "cafè à lait".as_bytes());
+        sequence.capture_deleted(document);
+        sequence.align_to_chars(document);
+        assert_eq!(sequence.apply_to_bytes(document), expected);
+        assert_eq!(to_insert_tuple_vec(&sequence.inserts), vec![(3, "è"), (8, "à")]);
+        assert_eq!(to_delete_tuple_vec(&sequence.deletes), vec![(5, 2), (8, 2)]);
+        assert_eq!(sequence.deletes.front().unwrap().get_content(), Some("é".as_bytes()));
+
+        // An engine in text mode widens the diffs it is given
+        let mut engine = Engine::new(1);
+        engine.set_text_mode(true);
+        let mut hashes = BlockHashes::new(document, 4).unwrap();
+        let diff = hashes.diff_and_update("cafè à lait".as_bytes()).unwrap();
+        let (transaction, _) = engine.process_diffs_with_base(diff, document, &mut TimeStamper::new());
+        assert_eq!(transaction.apply_to_bytes(document), expected);
+        assert!(transaction.inserts.iter().all(|insert| String::from_utf8(insert.get_value().to_vec()).is_ok()));
+        assert!(transaction.deletes.iter().all(|delete| String::from_utf8(delete.get_content().unwrap().to_vec()).is_ok()));
+
+        // Diffs without the document they were made on can't be widened, and text mode is saved
+        let diff = BlockHashes::new(document, 4).unwrap().diff_and_update("cafè à lait".as_bytes()).unwrap();
+        assert!(matches!(engine.process_diffs(diff, &mut TimeStamper::new()).unwrap_err().kind, Kind::UnknownBase));
+        let mut buffer = Vec::new();
+        engine.compress_to(&mut buffer).unwrap();
+        assert!(Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap().is_text_mode());
+        let mut buffer = Vec::new();
+        Snapshot::new(expected, &engine, &TimeStamper::new()).compress_to(&mut buffer).unwrap();
+        assert!(Snapshot::expand_from(&mut &buffer[..]).unwrap().into_parts(2).1.is_text_mode());
+    }
+
+    #[test]
+    fn test_conflict_reports() {
+        // Each site gives the other's operation the next of its own timestamps
+        let insert = InsertOperation::new(10, b"red ".to_vec(), 2, 2);
+        let delete = DeleteOperation::new(4, 12, 1, 1);
+        let ((_, conflicts1), (_, conflicts2)) = concurrent_insert_and_delete(ConflictPolicy::KeepInsert);
+        assert_eq!(conflicts1, vec![Conflict::new(ConflictKind::InsertInsideDelete, Resolution::InsertKept,
+                                                  ConflictOperation::Delete(delete),
+                                                  ConflictOperation::Insert(insert), (4, 8))]);
+        assert_eq!(conflicts2, vec![Conflict::new(ConflictKind::InsertInsideDelete, Resolution::InsertKept,
+                                                  ConflictOperation::Insert(InsertOperation::new(10, b"red ".to_vec(), 1, 2)),
+                                                  ConflictOperation::Delete(DeleteOperation::new(4, 12, 2, 1)), (4, 8))]);
+        let ((_, conflicts1), (_, conflicts2)) = concurrent_insert_and_delete(ConflictPolicy::DropInsert);
+        assert_eq!(conflicts1[0].get_resolution(), Resolution::InsertDeleted);
+        assert_eq!((conflicts1[0].get_position(), conflicts1[0].get_length()), (4, 0));
+        assert_eq!(conflicts2[0].get_resolution(), Resolution::InsertDeleted);
+        assert_eq!((conflicts2[0].get_position(), conflicts2[0].get_length()), (4, 0));
+
+        // Both sites delete "brown", along with different parts of what is around it
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "The quick brown fox")], 1, timestamp), LinkedList::new());
+        let document = transaction.apply_to_bytes(b"");
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut delete1 = TransactionSequence::new(Some((1, 0)), LinkedList::new(), generate_delete_list(vec![(4, 12)], 1, timestamp1));
+        let mut document1 = delete1.apply_to_bytes(&document);
+        engine1.process_transaction(&mut delete1);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut delete2 = TransactionSequence::new(Some((1, 0)), LinkedList::new(), generate_delete_list(vec![(10, 9)], 2, timestamp2));
+        let mut document2 = delete2.apply_to_bytes(&document);
+        engine2.process_transaction(&mut delete2);
+
+        let lookup1 = stamper1.get_timestamps_for(&delete1);
+        let lookup2 = stamper2.get_timestamps_for(&delete2);
+        stamper1.stamp_remote(2, timestamp2);
+        let conflicts1 = engine1.integrate_remote_with_conflicts(&mut delete2, &lookup2, &mut stamper1).unwrap();
+        document1 = delete2.apply_to_bytes(&document1);
+        stamper2.stamp_remote(1, timestamp1);
+        let conflicts2 = engine2.integrate_remote_with_conflicts(&mut delete1, &lookup1, &mut stamper2).unwrap();
+        document2 = delete1.apply_to_bytes(&document2);
+        assert_eq!(document1, b"The ");
+        assert_eq!(document2, b"The ");
+        for conflicts in [conflicts1, conflicts2].iter() {
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].get_kind(), ConflictKind::OverlappingDeletes);
+            assert_eq!(conflicts[0].get_resolution(), Resolution::DeletedOnce);
+            assert_eq!((conflicts[0].get_position(), conflicts[0].get_length()), (4, 0));
+        }
+    }
+
+    #[test]
+    fn test_conflict_markers() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        engine1.set_conflict_policy(ConflictPolicy::Markers);
+        engine2.set_conflict_policy(ConflictPolicy::Markers);
+        let timestamp = stamper1.stamp_local(1);
+        let mut transaction = TransactionSequence::new(None, generate_insert_list(vec![(0, "host = example.com\nport = 80\n")], 1, timestamp), LinkedList::new());
+        let document = transaction.apply_to_bytes(b"");
+        engine1.process_transaction(&mut transaction);
+        let lookup = stamper1.get_timestamps_for(&transaction);
+        stamper2.stamp_remote(1, timestamp);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // Both sites change the port at the same time
+        let timestamp1 = stamper1.stamp_local(1);
+        let mut edit1 = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(26, "8080")], 1, timestamp1), generate_delete_list(vec![(30, 2)], 1, timestamp1));
+        edit1.capture_deleted(&document);
+        let mut document1 = edit1.apply_to_bytes(&document);
+        engine1.process_transaction(&mut edit1);
+        let timestamp2 = stamper2.stamp_local(2);
+        let mut edit2 = TransactionSequence::new(Some((1, 0)), generate_insert_list(vec![(26, "443")], 2, timestamp2), generate_delete_list(vec![(29, 2)], 2, timestamp2));
+        edit2.capture_deleted(&document);
+        let mut document2 = edit2.apply_to_bytes(&document);
+        engine2.process_transaction(&mut edit2);
+
+        let lookup1 = stamper1.get_timestamps_for(&edit1);
+        let lookup2 = stamper2.get_timestamps_for(&edit2);
+        stamper1.stamp_remote(2, timestamp2);
+        let (mut markers, markers_lookup) = engine1.integrate_remote_with_markers(&mut edit2, &lookup2, &mut document1, &mut stamper1).unwrap().unwrap();
+        stamper2.stamp_remote(1, timestamp1);
+        // The other site leaves the markers to the site with the lower id
+        assert!(engine2.integrate_remote_with_markers(&mut edit1, &lookup1, &mut document2, &mut stamper2).unwrap().is_none());
+        for (_, &(site_id, timestamp)) in markers_lookup.iter() {
+            stamper2.stamp_remote(site_id, timestamp);
+        }
+        assert!(engine2.integrate_remote_with_markers(&mut markers, &markers_lookup, &mut document2, &mut stamper2).unwrap().is_none());
+
+        let expected = b"host = example.com\n<<<<<<< site 1\nport = 8080\n=======\nport = 443\n>>>>>>> site 2\n";
+        assert_eq!(String::from_utf8_lossy(&document1), String::from_utf8_lossy(expected));
+        assert_eq!(document2, document1);
+    }
+
+    #[test]
+    fn test_resurrect_deleted_text() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        engine1.set_conflict_policy(ConflictPolicy::Resurrect);
+        engine2.set_conflict_policy(ConflictPolicy::Resurrect);
+        let mut document1 = Vec::new();
+        let (mut transaction, lookup) = local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(0, "The quick brown fox")], vec![]);
+        let mut document2 = document1.clone();
+        stamper2.stamp_remote(1, 0);
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+
+        // One site deletes "quick brown " while the other writes inside it
+        let (mut delete, lookup1) = local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![], vec![(4, 12)]);
+        let (mut insert, lookup2) = local_edit(&mut engine2, &mut stamper2, 2, &mut document2, vec![(10, "very ")], vec![]);
+
+        // The site that wrote the text keeps it, and leaves the rest to the site that deleted around it
+        for (_, &(site_id, timestamp)) in lookup1.iter() {
+            stamper2.stamp_remote(site_id, timestamp);
+        }
+        assert!(engine2.integrate_remote_with_markers(&mut delete, &lookup1, &mut document2, &mut stamper2).unwrap().is_none());
+        assert_eq!(document2, b"The very fox");
+        for (_, &(site_id, timestamp)) in lookup2.iter() {
+            stamper1.stamp_remote(site_id, timestamp);
+        }
+        let (mut restored, restored_lookup) = engine1.integrate_remote_with_markers(&mut insert, &lookup2, &mut document1, &mut stamper1).unwrap().unwrap();
+        assert_eq!(document1, b"The quick very brown fox");
+
+        for (_, &(site_id, timestamp)) in restored_lookup.iter() {
+            stamper2.stamp_remote(site_id, timestamp);
+        }
+        assert!(engine2.integrate_remote_with_markers(&mut restored, &restored_lookup, &mut document2, &mut stamper2).unwrap().is_none());
+        assert_eq!(document2, document1);
+
+        let mut buffer = Vec::new();
+        engine1.compress_to(&mut buffer).unwrap();
+        assert_eq!(Engine::<u8>::expand_from(&mut &buffer[..], 1).unwrap().get_conflict_policy(), ConflictPolicy::Resurrect);
+    }
+
     #[test]
     fn full_process() {
-        let _ = env_logger::init().unwrap();
+        env_logger::init().unwrap();
         let mut engine = Engine::new(1);
         engine.inserts = generate_insert_list(vec![
             (0, "Some words"),
@@ -1457,7 +4128,7 @@ mod tests {
         engine.deletes = generate_delete_list(vec![
             (44, 8),
             (55, 2)
-        ], 0);
+        ], 1, 0);
         let mut stamper = TimeStamper::new();
         stamper.stamp_local(1);
         let mut engine2 = engine.clone();
@@ -1467,7 +4138,7 @@ mod tests {
         lookup.insert(0, (1, 0));
         let mut transaction = TransactionSequence::new(Some((1, 0)), LinkedList::new(), generate_delete_list(vec![
             (0, 55)
-        ], 0));
+        ], 1, 0));
 
         engine.process_transaction(&mut transaction);
 
@@ -0,0 +1,96 @@
+use std::io::{self, Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+use ::Position;
+
+const MODULUS: u32 = 65521;
+
+/// A summary of a document's contents, used to check that two sites have converged.
+///
+/// The fingerprint is made of the document's length and an Adler-32 style rolling hash of its
+/// bytes.  Different documents can share a fingerprint, but the edits that a faulty
+/// transformation makes are very unlikely to go unnoticed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    length: Position,
+    hash: u32,
+}
+
+impl Fingerprint {
+    /// Creates the fingerprint of a document
+    pub fn of(document: &[u8]) -> Fingerprint {
+        let mut a = 1;
+        let mut b = 0;
+        // As in zlib, this is the most bytes that can be summed before `b` could overflow
+        for chunk in document.chunks(5552) {
+            for &byte in chunk {
+                a += byte as u32;
+                b += a;
+            }
+            a %= MODULUS;
+            b %= MODULUS;
+        }
+        Fingerprint {
+            length: document.len() as Position,
+            hash: (b << 16) | a,
+        }
+    }
+
+    /// Creates a fingerprint from a previously computed length and hash
+    pub fn new(length: Position, hash: u32) -> Fingerprint {
+        Fingerprint {
+            length: length,
+            hash: hash,
+        }
+    }
+
+    /// Gets the length of the document
+    #[inline]
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+
+    /// Gets the hash of the document's contents
+    #[inline]
+    pub fn get_hash(&self) -> u32 {
+        self.hash
+    }
+
+    /// Compress this fingerprint and write to `writer`.  The output can then be expanded
+    /// back into an equivilent fingerprint using `expand_from()`
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u64(&mut long_buf, self.length);
+        try!(writer.write_all(&long_buf));
+        NetworkEndian::write_u32(&mut int_buf, self.hash);
+        writer.write_all(&int_buf)
+    }
+
+    /// Expand a fingerprint from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Fingerprint> {
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut long_buf));
+        try!(reader.read_exact(&mut int_buf));
+        Ok(Fingerprint {
+            length: NetworkEndian::read_u64(&long_buf),
+            hash: NetworkEndian::read_u32(&int_buf),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fingerprint;
+
+    #[test]
+    fn fingerprints_match_adler32() {
+        // The Adler-32 checksum of "Wikipedia"
+        assert_eq!(Fingerprint::of(b"Wikipedia").get_hash(), 0x11E60398);
+        assert_eq!(Fingerprint::of(b""), Fingerprint::new(0, 1));
+        assert!(Fingerprint::of(b"abc") != Fingerprint::of(b"acb"));
+        let long = vec![255u8; 100000];
+        assert_eq!(Fingerprint::of(&long).get_length(), 100000);
+    }
+}
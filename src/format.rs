@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, LinkedList};
+use std::io::{self, Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+use operations::{InsertOperation, DeleteOperation, FormatOperation, Operation};
+use element::Element;
+use conflict;
+use metadata;
+use Position;
+
+/// An attribute set on a range of the document by a [`FormatOperation`](struct.FormatOperation.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    start: Position,
+    end: Position,
+    key: String,
+    value: Option<String>,
+    origin: (u32, u32),
+}
+
+/// The formatting of a document: the spans set on it by format operations, kept in the same
+/// place in the document as it changes.
+///
+/// Text inserted inside a span becomes part of it, while text inserted at either end of it
+/// doesn't, and a span that has been deleted entirely is dropped.  Where spans with the same key
+/// overlap, the one that was set last wins: the one whose transaction was stamped later at the
+/// site that made it, or the one from the site with the higher id if they were stamped at the
+/// same time.  A site's timestamps count every transaction it has seen, so a format always
+/// wins over the ones its site had seen when it was made, and every site settles the rest in
+/// the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Formatting {
+    /// The spans, ordered from the first set to the last
+    spans: Vec<Span>,
+}
+
+impl Span {
+    /// Gets where the span starts in the document
+    #[inline]
+    pub fn get_start(&self) -> Position {
+        self.start
+    }
+
+    /// Gets where the span ends in the document
+    #[inline]
+    pub fn get_end(&self) -> Position {
+        self.end
+    }
+
+    /// Gets the attribute the span sets
+    #[inline]
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    /// Gets the value the span sets the attribute to, or `None` if it clears it
+    #[inline]
+    pub fn get_value(&self) -> Option<&str> {
+        self.value.as_ref().map(|value| &value[..])
+    }
+
+    /// Gets the `(site id, timestamp)` of the transaction that set the span, as it was stamped
+    /// at the site that made it
+    #[inline]
+    pub fn get_origin(&self) -> (u32, u32) {
+        self.origin
+    }
+
+    /// Whether this span was set after `other`
+    fn follows(&self, other: &Span) -> bool {
+        self.origin.1 > other.origin.1 || self.origin.1 == other.origin.1 && self.origin.0 > other.origin.0
+    }
+}
+
+impl Formatting {
+    /// Creates formatting with no spans
+    pub fn new() -> Formatting {
+        Formatting::default()
+    }
+
+    /// Gets every span, ordered from the first set to the last
+    #[inline]
+    pub fn get_spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// Gets the attributes set on the byte at `position`
+    pub fn get_attributes(&self, position: Position) -> BTreeMap<String, String> {
+        let mut attributes = BTreeMap::new();
+        for span in self.spans.iter().filter(|span| span.start <= position && position < span.end) {
+            match span.value {
+                Some(ref value) => attributes.insert(span.key.clone(), value.clone()),
+                None => attributes.remove(&span.key),
+            };
+        }
+        attributes
+    }
+
+    /// Splits a document of `length` bytes into ranges that have the same attributes all the way
+    /// through, giving the start and end of each range along with its attributes
+    pub fn get_runs(&self, length: Position) -> Vec<(Position, Position, BTreeMap<String, String>)> {
+        let mut boundaries: Vec<Position> = self.spans.iter()
+            .flat_map(|span| vec![span.start, span.end])
+            .filter(|&boundary| boundary < length)
+            .chain(Some(0))
+            .collect();
+        boundaries.sort();
+        boundaries.dedup();
+        let mut runs: Vec<(Position, Position, BTreeMap<String, String>)> = Vec::new();
+        for (index, &start) in boundaries.iter().enumerate() {
+            let end = boundaries.get(index + 1).cloned().unwrap_or(length);
+            let attributes = self.get_attributes(start);
+            match runs.last_mut() {
+                Some(ref mut last) if last.2 == attributes => {
+                    last.1 = end;
+                    continue;
+                },
+                _ => {}
+            }
+            runs.push((start, end, attributes));
+        }
+        runs.retain(|run| run.0 < run.1);
+        runs
+    }
+
+    /// Adds the span set by `format`, which has been placed in the document as it is now and
+    /// was made by the transaction stamped `origin` at the site that made it
+    pub fn set(&mut self, format: &FormatOperation, origin: (u32, u32)) {
+        if format.get_length() == 0 {
+            return;
+        }
+        let span = Span {
+            start: format.get_position(),
+            end: format.get_position() + format.get_length(),
+            key: format.get_key().to_string(),
+            value: format.get_value().map(|value| value.to_string()),
+            origin: origin,
+        };
+        let index = self.spans.iter().rposition(|other| span.follows(other)).map_or(0, |index| index + 1);
+        self.spans.insert(index, span);
+    }
+
+    /// Moves every span over the given inserts and deletes, which are applied to the document in
+    /// effect order, and drops the spans that are deleted entirely
+    pub fn update<T: Element>(&mut self, inserts: &LinkedList<InsertOperation<T>>, deletes: &LinkedList<DeleteOperation<T>>) {
+        for span in self.spans.iter_mut() {
+            let range = conflict::through_inserts((span.start, span.end), inserts.iter());
+            let (start, end) = conflict::through_deletes(range, deletes.iter());
+            span.start = start;
+            span.end = end;
+        }
+        self.spans.retain(|span| span.start < span.end);
+    }
+
+    /// Compress this formatting and write to `writer`.  The output can then be expanded
+    /// back into equivilent formatting using `expand_from()`.
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.spans.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for span in self.spans.iter() {
+            NetworkEndian::write_u64(&mut long_buf, span.start);
+            try!(writer.write_all(&long_buf));
+            NetworkEndian::write_u64(&mut long_buf, span.end);
+            try!(writer.write_all(&long_buf));
+            try!(metadata::write_string(writer, &span.key));
+            match span.value {
+                Some(ref value) => {
+                    try!(writer.write_all(&[1]));
+                    try!(metadata::write_string(writer, value));
+                },
+                None => {
+                    try!(writer.write_all(&[0]));
+                }
+            }
+            NetworkEndian::write_u32(&mut int_buf, span.origin.0);
+            try!(writer.write_all(&int_buf));
+            NetworkEndian::write_u32(&mut int_buf, span.origin.1);
+            try!(writer.write_all(&int_buf));
+        }
+        Ok(())
+    }
+
+    /// Expand formatting from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Formatting> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        let mut bool_buf = [0;1];
+        try!(reader.read_exact(&mut int_buf));
+        let span_len = NetworkEndian::read_u32(&int_buf);
+        let mut spans = Vec::new();
+        for _ in 0..span_len {
+            try!(reader.read_exact(&mut long_buf));
+            let start = NetworkEndian::read_u64(&long_buf);
+            try!(reader.read_exact(&mut long_buf));
+            let end = NetworkEndian::read_u64(&long_buf);
+            let key = try!(metadata::read_string(reader));
+            try!(reader.read_exact(&mut bool_buf));
+            let value = if bool_buf[0] != 0 {
+                Some(try!(metadata::read_string(reader)))
+            } else {
+                None
+            };
+            try!(reader.read_exact(&mut int_buf));
+            let site_id = NetworkEndian::read_u32(&int_buf);
+            try!(reader.read_exact(&mut int_buf));
+            let timestamp = NetworkEndian::read_u32(&int_buf);
+            spans.push(Span {
+                start: start,
+                end: end,
+                key: key,
+                value: value,
+                origin: (site_id, timestamp),
+            });
+        }
+        Ok(Formatting {
+            spans: spans,
+        })
+    }
+}
+
+/// Moves a range of the document that deletes are applied to back to where it was before they
+/// were applied, in effect order.  Deleted text at the start of the range is put in front of
+/// it, and deleted text at the end of it after it.
+pub fn before_deletes<'a, T: Element + 'a, I: DoubleEndedIterator<Item = &'a DeleteOperation<T>>>(range: (Position, Position), deletes: I) -> (Position, Position) {
+    let (mut start, mut end) = range;
+    for delete in deletes.rev() {
+        let position = delete.get_position();
+        if position <= start {
+            start += delete.get_length();
+        }
+        if position < end {
+            end += delete.get_length();
+        }
+    }
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::LinkedList;
+    use operations::{InsertOperation, DeleteOperation, FormatOperation};
+    use super::{Formatting, before_deletes};
+
+    #[test]
+    fn later_spans_win() {
+        let mut formatting = Formatting::new();
+        formatting.set(&FormatOperation::new(0, 10, "bold", Some("true".to_string()), 0, 1), (1, 4));
+        formatting.set(&FormatOperation::new(5, 10, "bold", None, 0, 2), (2, 3));
+        formatting.set(&FormatOperation::new(2, 4, "bold", Some("false".to_string()), 0, 3), (3, 4));
+        assert_eq!(formatting.get_spans().iter().map(|span| span.get_origin()).collect::<Vec<_>>(), vec![(2, 3), (1, 4), (3, 4)]);
+        assert_eq!(formatting.get_attributes(3).get("bold").map(|value| &value[..]), Some("false"));
+        assert_eq!(formatting.get_attributes(7).get("bold").map(|value| &value[..]), Some("true"));
+        assert_eq!(formatting.get_attributes(12).get("bold"), None);
+
+        let runs: Vec<_> = formatting.get_runs(20).into_iter().map(|(start, end, attributes)| (start, end, attributes.len())).collect();
+        assert_eq!(runs, vec![(0, 2, 1), (2, 6, 1), (6, 10, 1), (10, 20, 0)]);
+    }
+
+    #[test]
+    fn spans_follow_edits() {
+        let mut formatting = Formatting::new();
+        formatting.set(&FormatOperation::new(4, 5, "italic", Some("true".to_string()), 0, 1), (1, 0));
+        formatting.set(&FormatOperation::new(10, 3, "link", Some("https://example.com".to_string()), 0, 1), (1, 1));
+
+        // Text inserted inside the first span joins it, and text in front of it moves it along
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(0, b"A".to_vec(), 1, 2));
+        inserts.push_back(InsertOperation::new(7, b"xx".to_vec(), 1, 2));
+        // The second span is deleted entirely
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(12, 4, 1, 2));
+        formatting.update(&inserts, &deletes);
+        assert_eq!(formatting.get_spans().len(), 1);
+        assert_eq!((formatting.get_spans()[0].get_start(), formatting.get_spans()[0].get_end()), (5, 12));
+
+        // Deleted text where the range starts goes in front of it, and where it ends after it
+        let deletes = [DeleteOperation::<u8>::new(2, 3, 1, 2), DeleteOperation::new(4, 2, 1, 2)];
+        assert_eq!(before_deletes((2, 4), deletes.iter()), (5, 7));
+        assert_eq!(before_deletes((1, 3), deletes.iter()), (1, 6));
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::linked_list::LinkedList;
+use std::collections::btree_map::BTreeMap;
+use std::collections::btree_set::BTreeSet;
+use operations::{InsertOperation, DeleteOperation, Operation};
+use element::Element;
+use {OTError, ErrorKind as Kind, Position};
+
+/// Every element a history has ever had in its document, in the order they appear once all of
+/// the inserts have been applied, with the local timestamps of the operations that inserted and
+/// deleted each of them.  Elements that were in the document before the history began have no
+/// insert, and elements that are still in the document have no delete.  The content of a
+/// deleted element is only known if its delete captured it.
+pub struct Timeline<T = u8> {
+    elements: Vec<Option<T>>,
+    inserted: Vec<Option<u32>>,
+    deleted: Vec<Option<u32>>,
+}
+
+impl<T: Element> Timeline<T> {
+    /// Builds the timeline of a history in effect order, which has produced `document`
+    pub fn new(inserts: &LinkedList<InsertOperation<T>>, deletes: &LinkedList<DeleteOperation<T>>, document: &[T]) -> Result<Timeline<T>, OTError> {
+        // Put back what the deletes removed, last one first, to get the document as it was after the inserts
+        let mut elements: Vec<Option<T>> = document.iter().cloned().map(Some).collect();
+        let mut deleted = vec![None; document.len()];
+        for delete in deletes.iter().rev() {
+            let position = delete.get_position() as usize;
+            let length = delete.get_length() as usize;
+            if position > elements.len() {
+                return Err(OTError::new(Kind::OutOfRange));
+            }
+            let content = match delete.get_content() {
+                Some(content) => content.iter().cloned().map(Some).collect(),
+                None => vec![None; length]
+            };
+            splice_in(&mut elements, position, content);
+            splice_in(&mut deleted, position, vec![Some(delete.get_timestamp()); length]);
+        }
+
+        // Then replay the inserts over whatever was there to begin with
+        let inserted_length = inserts.iter().fold(0, |total, insert| total + insert.get_value().len());
+        if inserted_length > elements.len() {
+            return Err(OTError::new(Kind::OutOfRange));
+        }
+        let mut inserted = vec![None; elements.len() - inserted_length];
+        for insert in inserts.iter() {
+            let position = insert.get_position() as usize;
+            if position > inserted.len() {
+                return Err(OTError::new(Kind::OutOfRange));
+            }
+            splice_in(&mut inserted, position, vec![Some(insert.get_timestamp()); insert.get_value().len()]);
+        }
+        Ok(Timeline {
+            elements: elements,
+            inserted: inserted,
+            deleted: deleted,
+        })
+    }
+
+    /// Gets the number of elements the document has ever had
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Gets the element at `index`, if its content is known
+    #[inline]
+    pub fn get_element(&self, index: usize) -> Option<&T> {
+        self.elements[index].as_ref()
+    }
+
+    /// Gets the local timestamp of the operation that inserted the element at `index`
+    #[inline]
+    pub fn get_inserted(&self, index: usize) -> Option<u32> {
+        self.inserted[index]
+    }
+
+    /// Gets the local timestamp of the operation that deleted the element at `index`
+    #[inline]
+    pub fn get_deleted(&self, index: usize) -> Option<u32> {
+        self.deleted[index]
+    }
+
+    /// Gets the document as it was once every operation up to and including `reference_time`
+    /// had been performed, or before any of them if it is `None`
+    pub fn document_at(&self, reference_time: Option<u32>) -> Result<Vec<T>, OTError> {
+        let mut document = Vec::new();
+        for index in 0..self.elements.len() {
+            if self.existed_at(index, reference_time) {
+                document.push(try!(self.elements[index].clone().ok_or(OTError::new(Kind::UnknownContent))));
+            }
+        }
+        Ok(document)
+    }
+
+    /// Gets the operations, in effect order, that turn the current document back into the
+    /// document at `reference_time`.  The operations are made by `site_id` and have a timestamp of 0.
+    #[allow(clippy::type_complexity)]
+    pub fn revert_to(&self, reference_time: Option<u32>, site_id: u32) -> Result<(LinkedList<InsertOperation<T>>, LinkedList<DeleteOperation<T>>), OTError> {
+        let mut inserts = LinkedList::new();
+        let mut position = 0;
+        let mut value = Vec::new();
+        for index in 0..self.elements.len() {
+            if self.deleted[index].is_none() {
+                if !value.is_empty() {
+                    inserts.push_back(InsertOperation::new(position, value, 0, site_id));
+                    position += inserts.back().unwrap().get_value().len() as Position;
+                    value = Vec::new();
+                }
+                position += 1;
+            } else if self.existed_at(index, reference_time) {
+                value.push(try!(self.elements[index].clone().ok_or(OTError::new(Kind::UnknownContent))));
+            }
+        }
+        if !value.is_empty() {
+            inserts.push_back(InsertOperation::new(position, value, 0, site_id));
+        }
+
+        // Deletes happen after the inserts, and each one after the deletes before it
+        let mut deletes = LinkedList::new();
+        let mut position = 0;
+        let mut content = Vec::new();
+        for index in 0..self.elements.len() {
+            let exists_now = self.deleted[index].is_none();
+            let existed = self.existed_at(index, reference_time);
+            if exists_now && !existed {
+                content.push(self.elements[index].clone().unwrap());
+            } else if exists_now || existed {
+                if !content.is_empty() {
+                    let mut delete = DeleteOperation::new(position, content.len() as Position, 0, site_id);
+                    delete.set_content(Some(content));
+                    deletes.push_back(delete);
+                    content = Vec::new();
+                }
+                position += 1;
+            }
+        }
+        if !content.is_empty() {
+            let mut delete = DeleteOperation::new(position, content.len() as Position, 0, site_id);
+            delete.set_content(Some(content));
+            deletes.push_back(delete);
+        }
+        Ok((inserts, deletes))
+    }
+
+    /// Turns the elements to put in before elements of the timeline, and the elements of the
+    /// timeline to take out, into operations on the document the timeline has produced
+    #[allow(clippy::type_complexity)]
+    pub fn edits(&self, inserted: &BTreeMap<usize, Vec<T>>, removed: &BTreeSet<usize>, site_id: u32) -> Result<(LinkedList<InsertOperation<T>>, LinkedList<DeleteOperation<T>>), OTError> {
+        let mut inserts = LinkedList::new();
+        let mut position = 0;
+        for index in 0..self.len() + 1 {
+            if let Some(value) = inserted.get(&index) {
+                inserts.push_back(InsertOperation::new(position, value.clone(), 0, site_id));
+                position += value.len() as Position;
+            }
+            if index < self.len() && self.get_deleted(index).is_none() {
+                position += 1;
+            }
+        }
+
+        // Deletes happen after the inserts, and each one after the deletes before it
+        let mut deletes = LinkedList::new();
+        let mut position = 0;
+        let mut content = Vec::new();
+        for index in 0..self.len() + 1 {
+            let value = inserted.get(&index);
+            let kept = index < self.len() && self.get_deleted(index).is_none() && !removed.contains(&index);
+            if (value.is_some() || kept || index == self.len()) && !content.is_empty() {
+                let mut delete = DeleteOperation::new(position, content.len() as Position, 0, site_id);
+                delete.set_content(Some(content));
+                deletes.push_back(delete);
+                content = Vec::new();
+            }
+            if let Some(value) = value {
+                position += value.len() as Position;
+            }
+            if kept {
+                position += 1;
+            } else if removed.contains(&index) {
+                content.push(try!(self.get_element(index).cloned().ok_or(OTError::new(Kind::UnknownContent))));
+            }
+        }
+        Ok((inserts, deletes))
+    }
+
+    fn existed_at(&self, index: usize, reference_time: Option<u32>) -> bool {
+        let happened = |timestamp: u32| reference_time.is_some_and(|reference_time| timestamp <= reference_time);
+        self.inserted[index].is_none_or(&happened) && !self.deleted[index].is_some_and(&happened)
+    }
+}
+
+/// An element a history has ever had in its document, with the `(local timestamp, site id)` of
+/// the operations that inserted and deleted it, along with what they recorded of it
+#[derive(Clone)]
+struct Slot<T> {
+    inserted: Option<(u32, u32, T)>,
+    deleted: Option<(u32, u32, Option<T>)>,
+}
+
+/// Rewrites a history in effect order so that it no longer holds the operations up to and
+/// including `reference_time`.  The text those operations inserted is left as if it had been
+/// there before the history began.  The text they deleted is still counted, since other sites
+/// place their operations around it, but it is merged into as few deletes as possible, each
+/// with the timestamp `reference_time` and without its content.  Every later operation is
+/// kept where it was.
+pub fn discard_before<T: Element>(inserts: &LinkedList<InsertOperation<T>>, deletes: &LinkedList<DeleteOperation<T>>, reference_time: u32) -> (LinkedList<InsertOperation<T>>, LinkedList<DeleteOperation<T>>) {
+    let untouched = Slot { inserted: None, deleted: None };
+    // Lay out every element the inserts placed, along with as much of what was there to begin
+    // with as the operations reach
+    let mut slots = Vec::new();
+    for insert in inserts.iter() {
+        let position = insert.get_position() as usize;
+        if position > slots.len() {
+            slots.resize(position, untouched.clone());
+        }
+        let inserted = insert.get_value().iter().map(|element| Slot {
+            inserted: Some((insert.get_timestamp(), insert.get_site_id(), element.clone())),
+            deleted: None,
+        }).collect();
+        splice_in(&mut slots, position, inserted);
+    }
+    // Each delete takes out elements that are still there after the deletes before it
+    let mut remaining: Vec<usize> = (0..slots.len()).collect();
+    for delete in deletes.iter() {
+        let position = delete.get_position() as usize;
+        let end = position + delete.get_length() as usize;
+        while remaining.len() < end {
+            remaining.push(slots.len());
+            slots.push(untouched.clone());
+        }
+        let content = delete.get_content();
+        for (offset, index) in remaining.drain(position..end).enumerate() {
+            slots[index].deleted = Some((delete.get_timestamp(), delete.get_site_id(), content.map(|content| content[offset].clone())));
+        }
+    }
+
+    let forgotten = |timestamp: u32| timestamp <= reference_time;
+    for slot in slots.iter_mut() {
+        if slot.inserted.as_ref().is_some_and(|&(timestamp, _, _)| forgotten(timestamp)) {
+            slot.inserted = None;
+        }
+        if let Some((timestamp, site_id, _)) = slot.deleted {
+            if forgotten(timestamp) {
+                slot.deleted = Some((reference_time, site_id, None));
+            }
+        }
+    }
+
+    // Every element before an operation now belongs to an earlier one, so the operations can
+    // be laid down from the front of the document, one for each run of elements they share
+    let mut inserted: Vec<(Position, u32, u32, Vec<T>)> = Vec::new();
+    #[allow(clippy::type_complexity)]
+    let mut deleted: Vec<(Position, u32, u32, Position, Option<Vec<T>>)> = Vec::new();
+    let mut position = 0;
+    let mut last_deleted = false;
+    for (index, slot) in slots.into_iter().enumerate() {
+        if let Some((timestamp, site_id, element)) = slot.inserted {
+            match inserted.last_mut() {
+                Some(&mut (start, last_timestamp, last_site_id, ref mut value)) if (last_timestamp, last_site_id) == (timestamp, site_id) && start as usize + value.len() == index => {
+                    value.push(element);
+                },
+                _ => inserted.push((index as Position, timestamp, site_id, vec![element]))
+            }
+        }
+        match slot.deleted {
+            Some((timestamp, site_id, element)) => {
+                match deleted.last_mut() {
+                    Some(&mut (_, last_timestamp, last_site_id, ref mut length, ref mut content)) if last_deleted && (last_timestamp, last_site_id) == (timestamp, site_id) || last_deleted && forgotten(last_timestamp) && forgotten(timestamp) => {
+                        *length += 1;
+                        *content = match (content.take(), element) {
+                            (Some(mut content), Some(element)) => {
+                                content.push(element);
+                                Some(content)
+                            },
+                            _ => None
+                        };
+                    },
+                    _ => deleted.push((position, timestamp, site_id, 1, element.map(|element| vec![element])))
+                }
+                last_deleted = true;
+            },
+            None => {
+                position += 1;
+                last_deleted = false;
+            }
+        }
+    }
+    let inserts = inserted.into_iter().map(|(position, timestamp, site_id, value)| InsertOperation::new(position, value, timestamp, site_id)).collect();
+    let deletes = deleted.into_iter().map(|(position, timestamp, site_id, length, content)| {
+        let mut delete = DeleteOperation::new(position, length, timestamp, site_id);
+        delete.set_content(content);
+        delete
+    }).collect();
+    (inserts, deletes)
+}
+
+fn splice_in<T>(items: &mut Vec<T>, position: usize, new_items: Vec<T>) {
+    let tail = items.split_off(position);
+    items.extend(new_items);
+    items.extend(tail);
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::{Engine, TimeStamper};
+    use test_utils::{local_edit, local_edit_without_content};
+    use ErrorKind;
+
+    #[test]
+    fn earlier_documents_are_reconstructed() {
+        let mut engine = Engine::new(1);
+        let mut stamper = TimeStamper::new();
+        let mut document = Vec::new();
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(0, "Hello world")], vec![]);
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![], vec![(6, 5)]);
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(6, "there, big")], vec![(11, 5)]);
+        assert_eq!(document, b"Hello there");
+
+        assert_eq!(engine.get_document_at(&document, None, &stamper).unwrap(), b"");
+        assert_eq!(engine.get_document_at(&document, Some((1, 0)), &stamper).unwrap(), b"Hello world");
+        assert_eq!(engine.get_document_at(&document, Some((1, 1)), &stamper).unwrap(), b"Hello ");
+        assert_eq!(engine.get_document_at(&document, Some((1, 2)), &stamper).unwrap(), b"Hello there");
+        assert!(engine.get_document_at(&document, Some((2, 0)), &stamper).is_err());
+
+        // The history is saved with the deleted text
+        let mut buffer = Vec::new();
+        engine.compress_to(&mut buffer).unwrap();
+        let mut engine = Engine::expand_from(&mut &buffer[..], 1).unwrap();
+
+        let (_, lookup) = engine.revert_to(&mut document, Some((1, 0)), &mut stamper).unwrap();
+        assert_eq!(lookup.values().cloned().collect::<Vec<_>>(), vec![(1, 3)]);
+        assert_eq!(document, b"Hello world");
+        assert_eq!(engine.get_document_at(&document, Some((1, 2)), &stamper).unwrap(), b"Hello there");
+
+        // Another site ends up in the same place
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        let mut transaction = engine.get_operations_since(None, &stamper).unwrap();
+        let lookup = stamper.get_timestamps_since(None).unwrap();
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            stamper2.stamp_remote(site_id, timestamp);
+        }
+        engine2.integrate_remote(&mut transaction, &lookup, &mut stamper2).unwrap();
+        assert_eq!(transaction.apply_to_bytes(b""), b"Hello world");
+    }
+
+    #[test]
+    fn deleted_text_must_be_known() {
+        let mut engine = Engine::new(1);
+        let mut stamper = TimeStamper::new();
+        let mut document = Vec::new();
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(0, "Hello world")], vec![]);
+        local_edit_without_content(&mut engine, &mut stamper, 1, &mut document, vec![], vec![(5, 6)]);
+        local_edit(&mut engine, &mut stamper, 1, &mut document, vec![(5, "!")], vec![]);
+
+        match engine.get_document_at(&document, Some((1, 0)), &stamper) {
+            Err(error) => match error.kind {
+                ErrorKind::UnknownContent => {},
+                kind => panic!("Unexpected error {:?}", kind)
+            },
+            Ok(_) => panic!("The deleted text isn't known")
+        }
+        assert!(engine.revert_to(&mut document, Some((1, 0)), &mut stamper).is_err());
+        assert_eq!(document, b"Hello!");
+        // Nothing was stamped for the failed revert
+        assert_eq!(stamper.get_stamp_count(), 3);
+        // The text that is still there can be taken out again
+        assert_eq!(engine.get_document_at(&document, Some((1, 1)), &stamper).unwrap(), b"Hello");
+        engine.revert_to(&mut document, Some((1, 1)), &mut stamper).unwrap();
+        assert_eq!(document, b"Hello");
+    }
+}
@@ -5,6 +5,25 @@
 //! Any changes that are made to teh file should be run through the engine using either `process_diffs()` or `process_transaction()` prior to being broadcast.
 //! Any changes that are made at a remote site should be run through the engine using `integrate_remote()` prior to being applied to the file.
 //!
+//! Deployments that have a central server can use a [`Server`](struct.Server.html) instead, which sequences
+//! the transactions of every client so that each client only ever has to synchronize with the server.
+//!
+//! Documents that hold UTF-8 text can be kept from ever having a character split in half by
+//! putting the engine in text mode.  See the [`text`](text/index.html) module.
+//!
+//! Editors can drive the engine through the JSON-RPC interface in the [`rpc`](rpc/index.html) module,
+//! which the `optra-rpc` binary serves over stdin and stdout.  Language servers can convert the
+//! edits of the Language Server Protocol to and from transactions with the [`lsp`](lsp/index.html) module,
+//! and servers for JavaScript clients can convert the retain/insert/delete operations of ot.js with the
+//! [`otjs`](otjs/index.html) module.
+//!
+//! The engine synchronizes bytes by default, but operations and transactions can hold any type that
+//! implements [`Element`](trait.Element.html), such as the rows of a table or the items of a list.
+//! Transactions can also set attributes such as bold or links on ranges of the document with a
+//! [`FormatOperation`](struct.FormatOperation.html), which every engine keeps track of in its
+//! [`Formatting`](struct.Formatting.html), and anchor threads of comments to ranges with an
+//! [`AnchorOperation`](struct.AnchorOperation.html), kept in the engine's [`Comments`](struct.Comments.html).
+//!
 //! This crate generally works well with [`rdiff`](https://crates.io/crates/rdiff), but can work with
 //! any system that generates difference operations that are limited to insert and delete.
 //!
@@ -56,24 +75,68 @@
 //!# let mut file_hashes = BlockHashes::new(File::open("local_file").unwrap(), 8).unwrap();
 //!# let mut time_stamper = TimeStamper::new();
 //! let diffs = file_hashes.diff_and_update(File::open("local_file").unwrap()).unwrap();
-//! let (transaction, lookup) = engine.process_diffs(diffs, &mut time_stamper);
+//! let (transaction, lookup) = engine.process_diffs(diffs, &mut time_stamper).unwrap();
 //! send_transaction(transaction, lookup);
 //!# }
 //! ```
-#![feature(linked_list_extras)]
 #![deny(missing_docs)]
 #[macro_use]
 extern crate log;
 extern crate rdiff;
 extern crate byteorder;
+extern crate rustc_serialize;
 
+mod element;
 mod operations;
 mod utils;
 mod engine;
+mod server;
+mod fingerprint;
+mod metadata;
+mod repair;
+mod blame;
+mod history;
+mod policy;
+mod conflict;
+mod markers;
+mod moves;
+mod resurrect;
+mod tiebreak;
+mod format;
+mod comments;
+#[cfg(test)]
+mod test_utils;
+pub mod protocol;
+pub mod rpc;
+pub mod text;
+pub mod lsp;
+pub mod otjs;
+
+pub use operations::{InsertOperation, DeleteOperation, ReplaceOperation, MoveOperation, FormatOperation, AnchorOperation, Operation};
+
+pub use element::Element;
+
+pub use engine::{Engine, TransactionSequence, TimeStamper, Snapshot};
+
+pub use server::{Server, Outbound, Acknowledgement};
+
+pub use fingerprint::Fingerprint;
+
+pub use metadata::Metadata;
+
+pub use policy::ConflictPolicy;
+
+pub use tiebreak::{TieBreak, SiteOrder, TimestampOrder, PriorityOrder};
+
+pub use conflict::{Conflict, ConflictKind, ConflictOperation, Resolution};
+
+pub use format::{Formatting, Span};
+
+pub use comments::{Comments, Thread, Comment};
 
-pub use operations::{InsertOperation, DeleteOperation, Operation};
+pub use repair::{SiteState, Repair};
 
-pub use engine::{Engine, TransactionSequence, TimeStamper};
+pub use blame::Authorship;
 
 type Offset = i64;
 type Position = u64;
@@ -89,7 +152,30 @@ pub struct OTError {
 #[derive(Debug)]
 pub enum ErrorKind {
     /// The remote operations refer to a state that we have not yet recieved
-    NoSuchState
+    NoSuchState,
+    /// The operations came from a site that we are not synchronizing with
+    NoSuchSite,
+    /// A site with the same id is already being synchronized
+    DuplicateSite,
+    /// An edit refers to a position past the end of the document
+    OutOfRange,
+    /// A move would put the range it moves somewhere inside that range
+    MoveIntoItself,
+    /// Going back to an earlier state needs text whose delete did not record what it removed
+    UnknownContent,
+    /// In text mode, local changes can only be widened to whole characters if the document
+    /// they were made on is known
+    UnknownBase,
+    /// After applying a remote transaction, the document did not match the fingerprint the
+    /// sender attached to it
+    Diverged {
+        /// The `(site id, timestamp)` of the transaction that exposed the divergence
+        state: Option<(u32, u32)>,
+        /// The fingerprint of the sender's document
+        expected: Fingerprint,
+        /// The fingerprint of the local document
+        found: Fingerprint,
+    },
 }
 
 
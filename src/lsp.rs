@@ -0,0 +1,266 @@
+//! Conversions between transactions and the edits of the Language Server Protocol.
+//!
+//! A client reports its edits in a `textDocument/didChange` notification as a list of
+//! `TextDocumentContentChangeEvent`s, each of which replaces a range of the document with
+//! some text, and each of which is made to the document as the changes before it left it.
+//! [`changes_to_transaction()`](fn.changes_to_transaction.html) turns such a list into one
+//! transaction for `Engine::process_transaction()`.  Going the other way,
+//! [`changes_from_transaction()`](fn.changes_from_transaction.html) turns an integrated
+//! transaction into changes that can be sent to the client with `workspace/applyEdit`, using
+//! [`workspace_edit()`](fn.workspace_edit.html) to build the parameters.
+//!
+//! Positions are given by line and by column in UTF-16 code units, as the protocol requires.
+use std::str;
+use rustc_serialize::json::{Json, Object};
+use engine::TransactionSequence;
+use text::{self, LineColumn, ColumnUnit};
+use utils;
+use {OTError, ErrorKind, Position};
+
+/// A change to a text document, which replaces a range of the document with `text`.  A change
+/// without a range replaces the whole document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentChange {
+    /// The start and end of the replaced range, with columns in UTF-16 code units
+    pub range: Option<(LineColumn, LineColumn)>,
+    /// The text to put in place of the range
+    pub text: String,
+}
+
+impl ContentChange {
+    /// Creates a change that replaces the given range, or the whole document if there isn't one
+    #[inline]
+    pub fn new(range: Option<(LineColumn, LineColumn)>, text: String) -> ContentChange {
+        ContentChange {
+            range: range,
+            text: text,
+        }
+    }
+
+    /// Reads a change from a `TextDocumentContentChangeEvent`.  The deprecated `rangeLength`
+    /// is ignored.
+    pub fn from_json(json: &Json) -> Result<ContentChange, String> {
+        let range = match json.find("range") {
+            None | Some(&Json::Null) => None,
+            Some(range) => Some((try!(get_position(range, "start")), try!(get_position(range, "end"))))
+        };
+        let text = try!(json.find("text").and_then(Json::as_string).ok_or("Expected a string for text".to_string()));
+        Ok(ContentChange::new(range, text.to_string()))
+    }
+
+    /// Writes this change as a `TextDocumentContentChangeEvent`
+    pub fn to_json(&self) -> Json {
+        let mut object = Object::new();
+        if let Some(range) = self.range {
+            object.insert("range".to_string(), range_to_json(range));
+        }
+        object.insert("text".to_string(), Json::String(self.text.clone()));
+        Json::Object(object)
+    }
+
+    /// Writes this change as a `TextEdit`, for use in a `WorkspaceEdit`.  Returns `None` if the
+    /// change has no range, since a `TextEdit` always needs one.
+    pub fn to_text_edit_json(&self) -> Option<Json> {
+        self.range.map(|range| {
+            let mut object = Object::new();
+            object.insert("range".to_string(), range_to_json(range));
+            object.insert("newText".to_string(), Json::String(self.text.clone()));
+            Json::Object(object)
+        })
+    }
+}
+
+/// Turns the changes from a `didChange` notification, made one after another to `document`,
+/// into a transaction stamped with `timestamp` and `site_id`.  Fails with `OutOfRange` if a
+/// range isn't in the document as the changes before it left it, if it ends before it
+/// starts, or if either end falls inside a character.
+///
+/// Changes that overlap are combined, so the transaction holds at most one insert and one
+/// delete for each region of the document that was changed.
+pub fn changes_to_transaction(document: &[u8], changes: &[ContentChange], last_timestamp: Option<(u32, u32)>, timestamp: u32, site_id: u32) -> Result<TransactionSequence, OTError> {
+    // Pair each byte with the byte of the original document it came from, if any, so the
+    // changes can be found again once they have all been made
+    let mut bytes = document.to_vec();
+    let mut origins: Vec<Option<usize>> = (0..document.len()).map(Some).collect();
+    for change in changes {
+        let (start, end) = match change.range {
+            Some((start, end)) => {
+                let start = try!(text::to_offset(&bytes, start, ColumnUnit::Utf16).ok_or(OTError::new(ErrorKind::OutOfRange)));
+                let end = try!(text::to_offset(&bytes, end, ColumnUnit::Utf16).ok_or(OTError::new(ErrorKind::OutOfRange)));
+                (start as usize, end as usize)
+            },
+            None => (0, bytes.len())
+        };
+        if end < start {
+            return Err(OTError::new(ErrorKind::OutOfRange));
+        }
+        bytes.splice(start..end, change.text.bytes());
+        origins.splice(start..end, change.text.bytes().map(|_| None));
+    }
+    let result: Vec<(u8, Option<usize>)> = bytes.iter().cloned().zip(origins).collect();
+    let regions = utils::changed_regions(document.len(), &result);
+    let (inserts, deletes) = utils::operations_for_regions(&regions, &bytes, timestamp, site_id);
+    Ok(TransactionSequence::new(last_timestamp, inserts, deletes))
+}
+
+/// Gets the changes that will make the same change to `document` as an integrated
+/// transaction, where `document` is the buffer before the transaction is applied.
+///
+/// The changes don't overlap, their ranges are all in terms of `document`, and they are given
+/// from the end of the document to the start, so they can be made one after another as in a
+/// `didChange` notification, or all at once as in a `WorkspaceEdit`.  Returns `None` if the
+/// transaction doesn't fit `document`, if a change would start or end inside a character, or
+/// if it inserts text that isn't UTF-8.
+pub fn changes_from_transaction(document: &[u8], transaction: &TransactionSequence) -> Option<Vec<ContentChange>> {
+    let result = utils::trace_origins(document, &transaction.inserts, &transaction.deletes)?;
+    let bytes: Vec<u8> = result.iter().map(|&(byte, _)| byte).collect();
+    let mut changes = Vec::new();
+    for (document_front, document_back, result_front, result_back) in utils::changed_regions(document.len(), &result).into_iter().rev() {
+        let start = text::to_line_column(document, document_front as Position, ColumnUnit::Utf16);
+        let end = text::to_line_column(document, document_back as Position, ColumnUnit::Utf16);
+        let range = match (start, end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return None
+        };
+        match str::from_utf8(&bytes[result_front..result_back]) {
+            Ok(text) => changes.push(ContentChange::new(Some(range), text.to_string())),
+            Err(_) => return None
+        }
+    }
+    Some(changes)
+}
+
+/// Builds the parameters of a `workspace/applyEdit` request that makes `changes` to the
+/// document at `uri`.  Returns `None` if one of the changes has no range.
+pub fn workspace_edit(uri: &str, changes: &[ContentChange]) -> Option<Json> {
+    let mut edits = Vec::with_capacity(changes.len());
+    for change in changes {
+        match change.to_text_edit_json() {
+            Some(edit) => edits.push(edit),
+            None => return None
+        }
+    }
+    let mut documents = Object::new();
+    documents.insert(uri.to_string(), Json::Array(edits));
+    let mut edit = Object::new();
+    edit.insert("changes".to_string(), Json::Object(documents));
+    let mut params = Object::new();
+    params.insert("edit".to_string(), Json::Object(edit));
+    Some(Json::Object(params))
+}
+
+fn get_position(json: &Json, key: &str) -> Result<LineColumn, String> {
+    let position = try!(json.find(key).ok_or(format!("Expected a position for {}", key)));
+    let line = try!(position.find("line").and_then(Json::as_u64).ok_or(format!("Expected a number for {}.line", key)));
+    let character = try!(position.find("character").and_then(Json::as_u64).ok_or(format!("Expected a number for {}.character", key)));
+    Ok(LineColumn::new(line, character))
+}
+
+fn position_to_json(position: LineColumn) -> Json {
+    let mut object = Object::new();
+    object.insert("line".to_string(), Json::U64(position.line));
+    object.insert("character".to_string(), Json::U64(position.column));
+    Json::Object(object)
+}
+
+fn range_to_json(range: (LineColumn, LineColumn)) -> Json {
+    let mut object = Object::new();
+    object.insert("start".to_string(), position_to_json(range.0));
+    object.insert("end".to_string(), position_to_json(range.1));
+    Json::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::LinkedList;
+    use rustc_serialize::json::Json;
+    use engine::TransactionSequence;
+    use operations::{InsertOperation, DeleteOperation};
+    use text::LineColumn;
+    use super::{ContentChange, changes_to_transaction, changes_from_transaction, workspace_edit};
+
+    fn apply(document: &[u8], transaction: &TransactionSequence) -> Vec<u8> {
+        transaction.apply_to_bytes(document)
+    }
+
+    fn change(start: (u64, u64), end: (u64, u64), text: &str) -> ContentChange {
+        ContentChange::new(Some((LineColumn::new(start.0, start.1), LineColumn::new(end.0, end.1))), text.to_string())
+    }
+
+    #[test]
+    fn to_transaction() {
+        let document = "héllo\nwörld\n".as_bytes();
+        // The second change is made to the document as the first left it
+        let changes = vec![change((0, 0), (0, 1), "J"), change((1, 1), (1, 2), "o"), change((0, 5), (0, 5), "!")];
+        let transaction = changes_to_transaction(document, &changes, None, 3, 1).unwrap();
+        assert_eq!(apply(document, &transaction), "Jéllo!\nworld\n".as_bytes());
+        assert_eq!(transaction.inserts.len(), 3);
+        assert_eq!(transaction.deletes.len(), 2);
+
+        // Overlapping changes are combined
+        let changes = vec![change((0, 1), (0, 3), "ab"), change((0, 2), (0, 4), "cd")];
+        let transaction = changes_to_transaction(document, &changes, None, 3, 1).unwrap();
+        assert_eq!(apply(document, &transaction), "hacdo\nwörld\n".as_bytes());
+        assert_eq!(transaction.inserts.len(), 1);
+        assert_eq!(transaction.deletes.len(), 1);
+
+        let changes = vec![ContentChange::new(None, "new".to_string())];
+        let transaction = changes_to_transaction(document, &changes, None, 3, 1).unwrap();
+        assert_eq!(apply(document, &transaction), b"new");
+
+        assert!(changes_to_transaction(document, &[change((0, 3), (0, 1), "")], None, 3, 1).is_err());
+        assert!(changes_to_transaction(document, &[change((5, 0), (5, 0), "")], None, 3, 1).is_err());
+    }
+
+    #[test]
+    fn from_transaction() {
+        let document = "héllo\nwörld\n".as_bytes();
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(0, b"J".to_vec(), 3, 1));
+        inserts.push_back(InsertOperation::new(9, "ø".as_bytes().to_vec(), 3, 1));
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(1, 1, 3, 1));
+        deletes.push_back(DeleteOperation::new(10, 2, 3, 1));
+        let transaction = TransactionSequence::new(None, inserts, deletes);
+        let result = apply(document, &transaction);
+        assert_eq!(result, "Jéllo\nwørld\n".as_bytes());
+
+        let changes = changes_from_transaction(document, &transaction).unwrap();
+        assert_eq!(changes, vec![change((1, 1), (1, 2), "ø"), change((0, 0), (0, 1), "J")]);
+        // Made one after another, the changes give the same document
+        let transaction = changes_to_transaction(document, &changes, None, 4, 2).unwrap();
+        assert_eq!(apply(document, &transaction), result);
+
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(1, 1, 3, 1));
+        let transaction = TransactionSequence::new(None, LinkedList::new(), deletes);
+        assert_eq!(changes_from_transaction(document, &transaction), None);
+
+        // A transaction that doesn't fit the document gives nothing
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(20, b"x".to_vec(), 3, 1));
+        let transaction = TransactionSequence::new(None, inserts, LinkedList::new());
+        assert_eq!(changes_from_transaction(document, &transaction), None);
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(10, 5, 3, 1));
+        let transaction = TransactionSequence::new(None, LinkedList::new(), deletes);
+        assert_eq!(changes_from_transaction(document, &transaction), None);
+    }
+
+    #[test]
+    fn json() {
+        let json = Json::from_str(r#"{"range": {"start": {"line": 1, "character": 2}, "end": {"line": 1, "character": 4}}, "rangeLength": 2, "text": "ab"}"#).unwrap();
+        let parsed = ContentChange::from_json(&json).unwrap();
+        assert_eq!(parsed, change((1, 2), (1, 4), "ab"));
+        assert_eq!(ContentChange::from_json(&parsed.to_json()), Ok(parsed.clone()));
+        let full = ContentChange::from_json(&Json::from_str(r#"{"text": "all"}"#).unwrap()).unwrap();
+        assert_eq!(full, ContentChange::new(None, "all".to_string()));
+        assert!(ContentChange::from_json(&Json::from_str(r#"{"range": {"start": {"line": 1}}, "text": ""}"#).unwrap()).is_err());
+
+        let params = workspace_edit("file:///a.txt", &[parsed]).unwrap();
+        let edits = params.find_path(&["edit", "changes", "file:///a.txt"]).and_then(Json::as_array).unwrap();
+        assert_eq!(edits[0].find("newText").and_then(Json::as_string), Some("ab"));
+        assert_eq!(edits[0].find_path(&["range", "end", "character"]).and_then(Json::as_u64), Some(4));
+        assert_eq!(workspace_edit("file:///a.txt", &[full]), None);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::linked_list::LinkedList;
+use conflict::Conflict;
+use history::Timeline;
+use operations::{InsertOperation, DeleteOperation, Operation};
+use {OTError, ErrorKind as Kind, Position};
+
+/// Which side of an integration knew about an operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The operation came in with the remote transaction
+    Remote,
+    /// The operation was local, and the remote site didn't know about it
+    Local,
+    /// Both sites had the operation
+    Both,
+}
+
+/// Lines of the document that are replaced by conflict markers
+struct Region {
+    start: usize,
+    end: usize,
+    remote_site_id: u32,
+}
+
+/// Works out the operations, in effect order, that replace the lines of `document` involved in
+/// `conflicts` with conflict markers around the local and remote versions of them.  `document`
+/// is the one `timeline` was built from, and `remote_deletes` are the deletes of the remote
+/// transaction, taking every insert in the timeline into account.  The operations are made by
+/// `site_id` and have a timestamp of 0.
+pub fn mark<F: Fn(u32) -> Origin>(timeline: &Timeline, remote_deletes: &LinkedList<DeleteOperation>, origin: &F, conflicts: &[Conflict], document: &[u8], site_id: u32) -> Result<(LinkedList<InsertOperation>, LinkedList<DeleteOperation>), OTError> {
+    // Find which bytes the remote site deleted, since where both sites deleted the same byte
+    // the timeline only remembers the local delete
+    let removed = remote_deletes.iter().fold(0, |total, delete| total + delete.get_length() as usize);
+    if removed > timeline.len() {
+        return Err(OTError::new(Kind::OutOfRange));
+    }
+    let mut remote_deleted = vec![false; timeline.len() - removed];
+    for delete in remote_deletes.iter().rev() {
+        let position = delete.get_position() as usize;
+        if position > remote_deleted.len() {
+            return Err(OTError::new(Kind::OutOfRange));
+        }
+        let tail = remote_deleted.split_off(position);
+        remote_deleted.extend(vec![true; delete.get_length() as usize]);
+        remote_deleted.extend(tail);
+    }
+    let remaining: Vec<usize> = (0..timeline.len()).filter(|&index| timeline.get_deleted(index).is_none()).collect();
+
+    let mut inserts = LinkedList::new();
+    let mut deletes = LinkedList::new();
+    let mut inserted = 0;
+    let mut deleted = 0;
+    for region in regions(conflicts, document) {
+        // Everything the document ever had between the lines before and after the region
+        let first = if region.start == 0 { 0 } else { remaining[region.start - 1] + 1 };
+        let last = if region.end == document.len() { timeline.len() } else { remaining[region.end] };
+        let mut local = Vec::new();
+        let mut remote = Vec::new();
+        for (index, &deleted_remotely) in remote_deleted.iter().enumerate().take(last).skip(first) {
+            let inserted_by = timeline.get_inserted(index).map_or(Origin::Both, origin);
+            let deleted_by = timeline.get_deleted(index).map(origin);
+            if inserted_by != Origin::Remote && deleted_by.is_none_or(|deleted_by| deleted_by == Origin::Remote) {
+                local.push(try!(timeline.get_element(index).cloned().ok_or(OTError::new(Kind::UnknownContent))));
+            }
+            if inserted_by != Origin::Local && !deleted_remotely && deleted_by.is_none_or(|deleted_by| deleted_by == Origin::Local) {
+                remote.push(try!(timeline.get_element(index).cloned().ok_or(OTError::new(Kind::UnknownContent))));
+            }
+        }
+        if local == remote {
+            continue;
+        }
+
+        let mut value = format!("<<<<<<< site {}\n", site_id).into_bytes();
+        append_lines(&mut value, &local);
+        value.extend(b"=======\n".iter());
+        append_lines(&mut value, &remote);
+        value.extend(format!(">>>>>>> site {}\n", region.remote_site_id).into_bytes());
+
+        // The inserts all happen before the deletes
+        let length = value.len();
+        inserts.push_back(InsertOperation::new((region.start + inserted) as Position, value, 0, site_id));
+        inserted += length;
+        if region.end > region.start {
+            let mut delete = DeleteOperation::new((region.start + inserted - deleted) as Position, (region.end - region.start) as Position, 0, site_id);
+            delete.set_content(Some(document[region.start..region.end].to_vec()));
+            deletes.push_back(delete);
+            deleted += region.end - region.start;
+        }
+    }
+    Ok((inserts, deletes))
+}
+
+/// Widens each conflict to the whole lines it touches, and joins the ones that meet
+fn regions(conflicts: &[Conflict], document: &[u8]) -> Vec<Region> {
+    let mut regions: Vec<Region> = conflicts.iter().map(|conflict| {
+        let position = conflict.get_position() as usize;
+        let end = position + conflict.get_length() as usize;
+        let start = document[..position].iter().rposition(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+        let end = if start == end || document[end - 1] == b'\n' {
+            // Nothing is left of the conflict, or it ends with its line
+            end
+        } else {
+            document[end..].iter().position(|&byte| byte == b'\n').map_or(document.len(), |index| end + index + 1)
+        };
+        Region {
+            start: start,
+            end: end,
+            remote_site_id: conflict.get_remote().get_site_id(),
+        }
+    }).collect();
+    regions.sort_by_key(|region| (region.start, region.end));
+
+    let mut joined: Vec<Region> = Vec::new();
+    for region in regions {
+        match joined.last_mut() {
+            Some(ref mut last) if region.start <= last.end => {
+                if region.end > last.end {
+                    last.end = region.end;
+                }
+                continue;
+            },
+            _ => {}
+        }
+        joined.push(region);
+    }
+    joined
+}
+
+/// Adds one version of the lines inside the markers, ending it with a newline if it has none
+fn append_lines(value: &mut Vec<u8>, lines: &[u8]) {
+    value.extend(lines.iter());
+    if lines.last().is_some_and(|&byte| byte != b'\n') {
+        value.push(b'\n');
+    }
+}
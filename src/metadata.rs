@@ -0,0 +1,203 @@
+use std::collections::btree_map::BTreeMap;
+use std::io::{self, Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+use element::Element;
+
+const HAS_AUTHOR: u8 = 1;
+const HAS_TIME: u8 = 2;
+const HAS_MESSAGE: u8 = 4;
+
+/// Information about who made a transaction, when and why.
+///
+/// None of it takes part in synchronization: it is carried along with the transaction, and
+/// each engine keeps what it has seen so that its history can be audited.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Metadata {
+    author: Option<String>,
+    time: Option<u64>,
+    message: Option<String>,
+    properties: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    /// Creates empty metadata
+    pub fn new() -> Metadata {
+        Metadata::default()
+    }
+
+    /// Gets the name of the person who made the transaction
+    #[inline]
+    pub fn get_author(&self) -> Option<&str> {
+        self.author.as_ref().map(|author| &author[..])
+    }
+
+    /// Sets the name of the person who made the transaction
+    #[inline]
+    pub fn set_author(&mut self, author: Option<String>) {
+        self.author = author;
+    }
+
+    /// Gets the wall-clock time the transaction was made, in milliseconds since the Unix epoch
+    #[inline]
+    pub fn get_time(&self) -> Option<u64> {
+        self.time
+    }
+
+    /// Sets the wall-clock time the transaction was made, in milliseconds since the Unix epoch
+    #[inline]
+    pub fn set_time(&mut self, time: Option<u64>) {
+        self.time = time;
+    }
+
+    /// Gets the message describing the transaction
+    #[inline]
+    pub fn get_message(&self) -> Option<&str> {
+        self.message.as_ref().map(|message| &message[..])
+    }
+
+    /// Sets the message describing the transaction
+    #[inline]
+    pub fn set_message(&mut self, message: Option<String>) {
+        self.message = message;
+    }
+
+    /// Gets the value of a custom property
+    pub fn get_property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(|value| &value[..])
+    }
+
+    /// Sets the value of a custom property, or removes it if `value` is `None`
+    pub fn set_property(&mut self, key: &str, value: Option<String>) {
+        match value {
+            Some(value) => self.properties.insert(key.to_string(), value),
+            None => self.properties.remove(key)
+        };
+    }
+
+    /// Gets every custom property, by key
+    #[inline]
+    pub fn get_properties(&self) -> &BTreeMap<String, String> {
+        &self.properties
+    }
+
+    /// Compress this metadata and write to `writer`.  The output can then be expanded
+    /// back into equivilent metadata using `expand_from()`
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        let mut flags = 0;
+        if self.author.is_some() {
+            flags |= HAS_AUTHOR;
+        }
+        if self.time.is_some() {
+            flags |= HAS_TIME;
+        }
+        if self.message.is_some() {
+            flags |= HAS_MESSAGE;
+        }
+        try!(writer.write_all(&[flags]));
+        if let Some(ref author) = self.author {
+            try!(write_string(writer, author));
+        }
+        if let Some(time) = self.time {
+            NetworkEndian::write_u64(&mut long_buf, time);
+            try!(writer.write_all(&long_buf));
+        }
+        if let Some(ref message) = self.message {
+            try!(write_string(writer, message));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.properties.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (key, value) in self.properties.iter() {
+            try!(write_string(writer, key));
+            try!(write_string(writer, value));
+        }
+        Ok(())
+    }
+
+    /// Expand metadata from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Metadata> {
+        let mut bool_buf = [0;1];
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut bool_buf));
+        let flags = bool_buf[0];
+        let author = if flags & HAS_AUTHOR != 0 {
+            Some(try!(read_string(reader)))
+        } else {
+            None
+        };
+        let time = if flags & HAS_TIME != 0 {
+            try!(reader.read_exact(&mut long_buf));
+            Some(NetworkEndian::read_u64(&long_buf))
+        } else {
+            None
+        };
+        let message = if flags & HAS_MESSAGE != 0 {
+            Some(try!(read_string(reader)))
+        } else {
+            None
+        };
+        try!(reader.read_exact(&mut int_buf));
+        let property_len = NetworkEndian::read_u32(&int_buf);
+        let mut properties = BTreeMap::new();
+        for _ in 0..property_len {
+            let key = try!(read_string(reader));
+            properties.insert(key, try!(read_string(reader)));
+        }
+        Ok(Metadata {
+            author: author,
+            time: time,
+            message: message,
+            properties: properties,
+        })
+    }
+}
+
+/// Writes a string with its length in front
+pub fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    let mut int_buf = [0;4];
+    NetworkEndian::write_u32(&mut int_buf, value.len() as u32);
+    try!(writer.write_all(&int_buf));
+    writer.write_all(value.as_bytes())
+}
+
+/// Reads a string written by `write_string()`
+pub fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut int_buf = [0;4];
+    try!(reader.read_exact(&mut int_buf));
+    let value = try!(u8::read_from(reader, NetworkEndian::read_u32(&int_buf) as usize));
+    String::from_utf8(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "String is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metadata, write_string, read_string};
+    use byteorder::{NetworkEndian, ByteOrder};
+
+    #[test]
+    fn metadata_round_trips() {
+        let mut metadata = Metadata::new();
+        metadata.set_author(Some("Ada".to_string()));
+        metadata.set_time(Some(1500000000000));
+        metadata.set_property("ticket", Some("OPT-12".to_string()));
+        metadata.set_property("reviewed", Some("yes".to_string()));
+        metadata.set_property("reviewed", None);
+        let mut buffer = Vec::new();
+        metadata.compress_to(&mut buffer).unwrap();
+        let expanded = Metadata::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(expanded, metadata);
+        assert_eq!(expanded.get_message(), None);
+        assert_eq!(expanded.get_property("ticket"), Some("OPT-12"));
+        assert_eq!(expanded.get_properties().len(), 1);
+    }
+
+    #[test]
+    fn strings_longer_than_what_was_written_fail() {
+        let mut buffer = Vec::new();
+        write_string(&mut buffer, "Ada").unwrap();
+        NetworkEndian::write_u32(&mut buffer[..4], u32::MAX);
+        assert!(read_string(&mut &buffer[..]).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::linked_list::LinkedList;
+use std::collections::btree_map::BTreeMap;
+use std::collections::btree_set::BTreeSet;
+use history::Timeline;
+use operations::{InsertOperation, DeleteOperation, MoveOperation, Operation};
+use element::Element;
+use {OTError, ErrorKind as Kind};
+
+/// Works out the operations, in effect order, that carry this site's edits inside the ranges of
+/// `moves` over to the copies the moves made.  The moves have been integrated, so they are placed
+/// in the timeline with every insert but no deletes.  `known` tells whether the site that made the
+/// moves knew about the operation with a local timestamp, and `own` whether it was made at this
+/// site.  The operations are made by `site_id` and have a timestamp of 0.
+#[allow(clippy::type_complexity)]
+pub fn carry<T: Element, F: Fn(u32) -> bool, G: Fn(u32) -> bool>(timeline: &Timeline<T>, moves: &[MoveOperation], known: &F, own: &G, site_id: u32) -> Result<(LinkedList<InsertOperation<T>>, LinkedList<DeleteOperation<T>>), OTError> {
+    // What goes in before each element of the timeline, and which elements are taken out
+    let mut inserted: BTreeMap<usize, Vec<T>> = BTreeMap::new();
+    let mut removed = BTreeSet::new();
+    for operation in moves.iter() {
+        let start = operation.get_position() as usize;
+        let end = start + operation.get_length() as usize;
+        let copy = operation.get_destination() as usize;
+        if end > timeline.len() || copy > timeline.len() {
+            return Err(OTError::new(Kind::OutOfRange));
+        }
+        let moved = operation.get_timestamp();
+        // How many of the elements the move copied have been passed
+        let mut copied = 0;
+        for index in start..end {
+            let deleted = timeline.get_deleted(index);
+            match timeline.get_inserted(index) {
+                Some(inserted_by) if inserted_by != moved && !known(inserted_by) => {
+                    // Inserted at the same time as the move, so it was left behind unless the move deleted it
+                    if own(inserted_by) && deleted.is_none_or(|deleted_by| deleted_by == moved) {
+                        if let Some(element) = timeline.get_element(index) {
+                            inserted.entry(copy + copied).or_default().push(element.clone());
+                        }
+                        if deleted.is_none() {
+                            removed.insert(index);
+                        }
+                    }
+                },
+                _ => match deleted {
+                    Some(deleted_by) if deleted_by == moved => copied += 1,
+                    Some(deleted_by) if !known(deleted_by) => {
+                        // Deleted at the same time as the move, so it is still in the copy
+                        if own(deleted_by) && copy + copied < timeline.len() && timeline.get_deleted(copy + copied).is_none() {
+                            removed.insert(copy + copied);
+                        }
+                        copied += 1;
+                    },
+                    // Deleted before the move was made, so it wasn't copied
+                    _ => {}
+                }
+            }
+        }
+    }
+    timeline.edits(&inserted, &removed, site_id)
+}
@@ -3,6 +3,9 @@ use ::{Offset, Position};
 use std::io::{self, Write, Read};
 use byteorder::{NetworkEndian, ByteOrder};
 use std::collections::BTreeMap;
+use tiebreak::Ties;
+use element::Element;
+use metadata;
 
 /// An operation that will make a change to a file.
 pub trait Operation: fmt::Debug + Clone {
@@ -25,29 +28,100 @@ pub trait Operation: fmt::Debug + Clone {
     /// Sets the local timestamp of this operation
     fn set_timestamp(&mut self, new_timestamp: u32);
 
+    /// Gets the id of the site this operation was first performed at
+    fn get_site_id(&self) -> u32;
+
+}
+
+/// Represents an operation which inserts data into a file.  The data is bytes unless some other
+/// [`Element`](trait.Element.html) type is given.
+#[derive(PartialEq, Eq, Clone)]
+pub struct InsertOperation<T = u8> {
+    timestamp: u32,
+    position: Position,
+    value:Vec<T>,
+    site_id: u32
+}
+
+/// Represents an operation which removes data from a file.
+///
+/// A delete can also carry the content it removes, if it was known when the delete was made.
+/// The content follows the delete as it is split and trimmed by transformations, so it always
+/// holds exactly the bytes the delete still removes.
+#[derive(PartialEq, Eq, Clone)]
+pub struct DeleteOperation<T = u8> {
+    timestamp: u32,
+    position: Position,
+    length: Position,
+    content: Option<Vec<T>>,
+    site_id: u32
+}
+
+/// Represents an operation which replaces a range of a file with new data.
+///
+/// A replace removes `length` bytes at `position` and puts its value in their place.  When it
+/// is transformed by concurrent edits, an insert made inside the replaced range is replaced
+/// along with the rest of it, and the value stays in one piece at the front of the range.
+#[derive(PartialEq, Eq, Clone)]
+pub struct ReplaceOperation<T = u8> {
+    timestamp: u32,
+    position: Position,
+    length: Position,
+    value: Vec<T>,
+    site_id: u32
 }
 
-/// Represents an operation which inserts data into a file
+/// Represents an operation which moves a range of a file somewhere else in the file.
+///
+/// A move takes the `length` bytes at `position` and puts them at `destination`, which is a
+/// position in the file before the move and must not be inside the moved range.  When it is
+/// transformed by concurrent edits, an insert made inside the moved range is moved along with it.
+///
+/// A move is performed by a copy and a delete, but it is also sent along with them, so that a site
+/// which made edits inside the moved range at the same time can carry them over to the copy (see
+/// `Engine::integrate_remote_with_moves()`).
 #[derive(PartialEq, Eq, Clone)]
-pub struct InsertOperation {
+pub struct MoveOperation {
     timestamp: u32,
     position: Position,
-    value:Vec<u8>,
+    length: Position,
+    destination: Position,
     site_id: u32
 }
 
-/// Represents an operation which removes data from a file
+/// Represents an operation which sets an attribute, such as bold or a link, on a range of a file.
+///
+/// A format sets `key` to `value` on the `length` bytes at `position`, or clears it if `value`
+/// is `None`.  It doesn't change the contents of the file, so it is sent along with a
+/// transaction and kept by the engine as a [`Span`](struct.Span.html) of its formatting.
 #[derive(PartialEq, Eq, Clone)]
-pub struct DeleteOperation{
+pub struct FormatOperation {
     timestamp: u32,
     position: Position,
-    length: Position
+    length: Position,
+    key: String,
+    value: Option<String>,
+    site_id: u32
 }
 
-/// Represents the state of a document.  Essentially a timestamp and a site id.
+/// Represents an operation which anchors a thread of comments to a range of a file.
 ///
-/// The state has two timestamps, the remtoe timestamp (the stamp given to it by the site that originated it)
-/// and its local timestamp (which is the timestamp this site gave to it)
+/// An anchor attaches the thread identified by `thread` to the `length` bytes at `position`.  Like
+/// a format, it doesn't change the contents of the file, so it is sent along with a transaction
+/// and kept by the engine in its [`Comments`](struct.Comments.html).
+#[derive(PartialEq, Eq, Clone)]
+pub struct AnchorOperation {
+    timestamp: u32,
+    position: Position,
+    length: Position,
+    thread: (u32, u32),
+    site_id: u32
+}
+
+// Represents the state of a document.  Essentially a timestamp and a site id.
+//
+// The state has two timestamps, the remtoe timestamp (the stamp given to it by the site that originated it)
+// and its local timestamp (which is the timestamp this site gave to it)
 // #[derive(Debug, PartialEq, Eq, Clone)]
 // pub struct State {
 //     site_id: u32,
@@ -58,17 +132,20 @@ pub struct DeleteOperation{
 pub trait OperationInternal: Operation {
 
     fn update_position_by(&mut self, delta: Offset);
+    fn set_site_id(&mut self, site_id: u32);
     fn update_size_by(&mut self, delta: Offset);
+    fn trim_front(&mut self, amount: Position);
     fn set_length_to_zero(&mut self);
+    fn discard(&mut self);
     fn split(&mut self, split_pos: Position) -> Self;
-    fn check_overlap<O: OperationInternal>(&self, other: &O, my_offset: Offset, other_offset: Offset) -> OverlapResult;
-    fn check_overlap_with_insert(&self, other: &InsertOperation, my_offset: Offset, other_offset: Offset) -> OverlapResult;
-    fn check_overlap_with_delete(&self, other: &DeleteOperation, my_offset: Offset, other_offset: Offset) -> OverlapResult;
-    fn crossed_by<O: OperationInternal>(&self, other: &O, my_offset: Offset, other_offset: Offset) -> CrossResult;
-    fn crosses(&self, other: &DeleteOperation, my_offset: Offset, other_offset: Offset) -> CrossResult;
+    fn merges_before(&self, other: &Self, other_offset: Offset, ties: &Ties) -> bool;
+    fn check_overlap<O: OperationInternal>(&self, other: &O, my_offset: Offset, other_offset: Offset, ties: &Ties) -> OverlapResult;
+    fn check_overlap_with_insert<E: Element>(&self, other: &InsertOperation<E>, my_offset: Offset, other_offset: Offset, ties: &Ties) -> OverlapResult;
+    fn check_overlap_with_delete<E: Element>(&self, other: &DeleteOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult;
+    fn check_overlap_with_replace<E: Element>(&self, other: &ReplaceOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult;
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum OverlapResult {
     Precedes,
     Follows,
@@ -78,36 +155,774 @@ pub enum OverlapResult {
     EnclosedBy(Position)
 }
 
-pub enum Advance<O: OperationInternal> {
-    Incoming,
-    Existing,
-    Neither(O)
+pub enum Advance<O: OperationInternal> {
+    Incoming,
+    Existing,
+    Neither(O)
+}
+
+
+#[derive(PartialEq, Debug)]
+pub enum CrossResult {
+    Precedes,
+    Follows,
+    Crosses(Position)
+}
+
+impl<T: Element> InsertOperation<T> {
+
+    /// Creates a new `InsertOperation` that will insert the bytes represented by `value` in a file at location `position`
+    #[inline]
+    pub fn new(position: Position, value: Vec<T>, timestamp: u32, site_id: u32) -> InsertOperation<T> {
+        InsertOperation {
+            position: position,
+            value: value,
+            timestamp: timestamp,
+            site_id: site_id
+        }
+    }
+
+    /// Gets the bytes that will be inserted when this operation is applied
+    pub fn get_value(&self) -> &[T] {
+        &self.value
+    }
+
+    /// Compress this operation and write to `writer`.  The output can then be expanded
+    /// back into an equivilent operation using `expand_from()`.  If `include_site_id` is set to true
+    /// Then the site id is saved alongside everyhting else.  If this is the case, then when expanding
+    /// a timestamp lookup should not be passed in.
+    pub fn compress_to<W: Write>(&self, writer: &mut W, include_site_id: bool) -> io::Result<()> {
+
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.timestamp);
+        try!(writer.write(&int_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.position);
+        try!(writer.write(&long_buf));
+        NetworkEndian::write_u32(&mut int_buf, self.value.len() as u32);
+        try!(writer.write(&int_buf));
+        try!(T::write_to(&self.value, writer));
+        if include_site_id {
+            NetworkEndian::write_u32(&mut int_buf, self.site_id);
+            try!(writer.write(&int_buf));
+        }
+        Ok(())
+    }
+
+    /// Expand this operation from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<InsertOperation<T>> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let timestamp = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut long_buf));
+        let position = NetworkEndian::read_u64(&long_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let value_len = NetworkEndian::read_u32(&int_buf) as usize;
+        let value = try!(T::read_from(reader, value_len));
+        let site_id = if let Some(timestamp_lookup) = timestamp_lookup {
+            match timestamp_lookup.get(&timestamp) {
+                Some(&(site_id, _)) => site_id,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Timestamp {} not found in timestamp lookup", timestamp)));
+                }
+            }
+        } else {
+            try!(reader.read_exact(&mut int_buf));
+            NetworkEndian::read_u32(&int_buf)
+        };
+
+        Ok(InsertOperation{
+            position: position,
+            value: value,
+            timestamp: timestamp,
+            site_id: site_id
+        })
+    }
+
+    /// Whether this incoming insert goes before the existing insert `other`
+    fn compare_with_offsets<E: Element>(&self, other: &InsertOperation<E>, my_offset: Offset, other_offset: Offset, ties: &Ties) -> bool {
+        let my_pos = self.get_position() as Offset - my_offset;
+        let other_pos = other.get_position() as Offset - other_offset;
+        my_pos < other_pos || my_pos == other_pos && ties.incoming_first(self, other)
+    }
+}
+
+
+impl<T: Element> DeleteOperation<T> {
+
+    /// Creates a new `DeleteOperation` that woll delete `length` bytes at `position` in a file
+    #[inline]
+    pub fn new(position: Position, length: Position, timestamp: u32, site_id: u32) -> DeleteOperation<T> {
+        DeleteOperation {
+            position: position,
+            length: length,
+            timestamp: timestamp,
+            content: None,
+            site_id: site_id
+        }
+    }
+
+    /// Gets the number of bytes that will be removed when the delete operation is applied
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+
+    /// Gets the bytes this operation removes, if they are known
+    #[inline]
+    pub fn get_content(&self) -> Option<&[T]> {
+        self.content.as_ref().map(|content| &content[..])
+    }
+
+    /// Sets the bytes this operation removes.  The content must be `get_length()` bytes long.
+    pub fn set_content(&mut self, content: Option<Vec<T>>) {
+        debug_assert!(content.as_ref().is_none_or(|content| content.len() as Position == self.length));
+        self.content = content;
+    }
+
+    /// Compress this operation and write to `writer`.  The output can then be expanded
+    /// back into an equivilent operation using `expand_from()`.  As with inserts, if `include_site_id`
+    /// is set to true then the site id is saved alongside everything else, and a timestamp lookup
+    /// should not be passed in when expanding.  If `include_content` is set to true then the removed
+    /// content is saved as well, if it is known, and the same flag must be passed when expanding.
+    pub fn compress_to<W: Write>(&self, writer: &mut W, include_site_id: bool, include_content: bool) -> io::Result<()> {
+
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, self.timestamp);
+        try!(writer.write(&int_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.position);
+        try!(writer.write(&long_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.length);
+        try!(writer.write(&long_buf));
+        if include_site_id {
+            NetworkEndian::write_u32(&mut int_buf, self.site_id);
+            try!(writer.write(&int_buf));
+        }
+        if include_content {
+            // The content is always as long as the delete, so only its presence has to be recorded
+            if let Some(ref content) = self.content {
+                try!(writer.write(&[1]));
+                try!(T::write_to(content, writer));
+            } else {
+                try!(writer.write(&[0]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand this operation from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>, include_content: bool) -> io::Result<DeleteOperation<T>> {
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut int_buf));
+        let timestamp = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut long_buf));
+        let position = NetworkEndian::read_u64(&long_buf);
+        try!(reader.read_exact(&mut long_buf));
+        let len = NetworkEndian::read_u64(&long_buf);
+        let site_id = if let Some(timestamp_lookup) = timestamp_lookup {
+            match timestamp_lookup.get(&timestamp) {
+                Some(&(site_id, _)) => site_id,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Timestamp {} not found in timestamp lookup", timestamp)));
+                }
+            }
+        } else {
+            try!(reader.read_exact(&mut int_buf));
+            NetworkEndian::read_u32(&int_buf)
+        };
+        let content = if include_content {
+            let mut bool_buf = [0;1];
+            try!(reader.read_exact(&mut bool_buf));
+            if bool_buf[0] == 1 {
+                Some(try!(T::read_from(reader, len as usize)))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        Ok(DeleteOperation{
+            position: position,
+            length: len,
+            timestamp: timestamp,
+            content: content,
+            site_id: site_id
+        })
+    }
+}
+
+impl<T: Element> Operation for InsertOperation<T> {
+    // #[inline]
+    // fn get_state(&self) -> & State {
+    //     &self.state
+    // }
+    //
+    // #[inline]
+    // fn get_state_mut(&mut self) -> &mut State {
+    //     &mut self.state
+    // }
+
+    #[inline]
+    fn get_position(&self) -> Position {
+        self.position
+    }
+
+    #[inline]
+    fn get_increment(&self) -> Offset {
+        self.value.len() as Offset
+    }
+
+    #[inline]
+    fn get_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    #[inline]
+    fn set_timestamp(&mut self, new_timestamp: u32) {
+        self.timestamp = new_timestamp;
+    }
+
+    #[inline]
+    fn get_site_id(&self) -> u32 {
+        self.site_id
+    }
+}
+
+impl<T: Element> OperationInternal for InsertOperation<T> {
+
+
+    fn update_position_by(&mut self, delta: Offset) {
+        self.position = (self.position as Offset +  delta) as Position
+    }
+
+    #[inline]
+    fn set_site_id(&mut self, site_id: u32) {
+        self.site_id = site_id;
+    }
+
+    fn update_size_by(&mut self, _delta: Offset) {
+        unimplemented!();
+    }
+
+    fn trim_front(&mut self, amount: Position) {
+        self.value.drain(..amount as usize);
+    }
+
+    fn set_length_to_zero(&mut self) {
+        //Don't do anything, since we keep insert operations, even if they are in the middle of existing delete operations
+    }
+
+    fn discard(&mut self) {
+        self.value.clear();
+    }
+
+    fn split(&mut self, _split_pos: Position) -> InsertOperation<T> {
+        unimplemented!();
+    }
+
+    fn merges_before(&self, other: &InsertOperation<T>, other_offset: Offset, ties: &Ties) -> bool {
+        // Ties have to be broken the same way as in `check_overlap_with_insert`, or the merged
+        // history will disagree with the transformed operations about which insert came first
+        let my_pos = self.position as Offset;
+        let other_pos = other.position as Offset - other_offset;
+        my_pos < other_pos || my_pos == other_pos && !ties.incoming_first(other, self)
+    }
+
+    #[inline]
+    fn check_overlap<O: OperationInternal>(&self,  other: &O, my_offset: Offset, other_offset: Offset, ties: &Ties) -> OverlapResult {
+        other.check_overlap_with_insert(self, other_offset, my_offset, ties)
+    }
+    #[inline]
+    fn check_overlap_with_insert<E: Element>(&self, other: &InsertOperation<E>, my_offset: Offset, other_offset: Offset, ties: &Ties) -> OverlapResult {
+        if other.compare_with_offsets(self, other_offset, my_offset, ties) {
+            OverlapResult::Precedes
+        } else {
+            OverlapResult::Follows
+        }
+    }
+
+    fn check_overlap_with_delete<E: Element>(&self, other: &DeleteOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult {
+        let other_front = other.position as Offset - other_offset;
+        range_overlap_with_point(other_front, other_front + other.length as Offset, self.position as Offset - my_offset)
+    }
+
+    fn check_overlap_with_replace<E: Element>(&self, other: &ReplaceOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult {
+        let other_front = other.position as Offset - other_offset;
+        range_overlap_with_point(other_front, other_front + other.length as Offset, self.position as Offset - my_offset)
+    }
+
+}
+
+/// Checks where the range `front..back` of an operation that is being transformed lies
+/// relative to an existing insert at `point`
+fn range_overlap_with_point(front: Offset, back: Offset, point: Offset) -> OverlapResult {
+    //     |--range
+    //   |
+    if point <= front {
+        OverlapResult::Follows
+    }
+    //  |--range
+    //   |
+    else {
+        //  |--range--|
+        //   |
+        if point < back {
+            OverlapResult::Encloses((point - front) as Position)
+        }
+        //  |--range--|
+        //             |
+        else {
+            OverlapResult::Precedes
+        }
+    }
+}
+
+impl<T: Element> Operation for DeleteOperation<T> {
+    // #[inline]
+    // fn get_state(&self) -> & State {
+    //     &self.state
+    // }
+    //
+    // #[inline]
+    // fn get_state_mut(&mut self) -> &mut State {
+    //     &mut self.state
+    // }
+
+    #[inline]
+    fn get_position(&self) -> Position {
+        self.position
+    }
+
+    #[inline]
+    fn get_increment(&self) -> Offset {
+        -(self.length as Offset)
+    }
+
+    #[inline]
+    fn get_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    #[inline]
+    fn set_timestamp(&mut self, new_timestamp: u32) {
+        self.timestamp = new_timestamp;
+    }
+
+    #[inline]
+    fn get_site_id(&self) -> u32 {
+        self.site_id
+    }
+}
+
+impl<T: Element> OperationInternal for DeleteOperation<T> {
+    fn update_position_by(&mut self, delta: Offset) {
+        self.position = (self.position as Offset +  delta) as Position
+    }
+
+    #[inline]
+    fn set_site_id(&mut self, site_id: u32) {
+        self.site_id = site_id;
+    }
+
+    fn update_size_by(&mut self, delta: Offset) {
+        self.length = (self.length as Offset + delta) as Position;
+        // Only the end of the delete changes, and there is no way of knowing what it grew over
+        if delta > 0 {
+            self.content = None;
+        } else if let Some(ref mut content) = self.content {
+            content.truncate(self.length as usize);
+        }
+    }
+
+    fn trim_front(&mut self, amount: Position) {
+        self.length -= amount;
+        if let Some(ref mut content) = self.content {
+            content.drain(..amount as usize);
+        }
+    }
+
+    fn set_length_to_zero(&mut self) {
+        self.length = 0;
+        if let Some(ref mut content) = self.content {
+            content.clear();
+        }
+    }
+
+    #[inline]
+    fn discard(&mut self) {
+        self.set_length_to_zero();
+    }
+
+    fn split(&mut self, split_pos: Position) -> DeleteOperation<T> {
+        let mut new_op = DeleteOperation::new(self.position , self.length - split_pos, self.timestamp, self.site_id);
+        new_op.content = self.content.as_mut().map(|content| content.split_off(split_pos as usize));
+        self.length = split_pos;
+        new_op
+    }
+
+    fn merges_before(&self, other: &DeleteOperation<T>, other_offset: Offset, _ties: &Ties) -> bool {
+        self.position as Offset <= other.position as Offset - other_offset
+    }
+
+    #[inline]
+    fn check_overlap<O: OperationInternal>(&self,  other: &O, my_offset: Offset, other_offset: Offset, _ties: &Ties) -> OverlapResult {
+        other.check_overlap_with_delete(self, other_offset, my_offset)
+    }
+
+
+    fn check_overlap_with_insert<E: Element>(&self, other: &InsertOperation<E>, my_offset: Offset, other_offset: Offset, _ties: &Ties) -> OverlapResult {
+        let my_front = self.position as Offset - my_offset;
+        point_overlap_with_range(my_front, my_front + self.length as Offset, other.position as Offset - other_offset)
+    }
+
+    fn check_overlap_with_delete<E: Element>(&self, other: &DeleteOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult {
+        let my_front = self.position as Offset - my_offset;
+        let other_front = other.position as Offset - other_offset;
+        range_overlap_with_range(my_front, my_front + self.length as Offset, other_front, other_front + other.length as Offset)
+    }
+
+    fn check_overlap_with_replace<E: Element>(&self, other: &ReplaceOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult {
+        let my_front = self.position as Offset - my_offset;
+        let other_front = other.position as Offset - other_offset;
+        range_overlap_with_range(my_front, my_front + self.length as Offset, other_front, other_front + other.length as Offset)
+    }
+
+}
+
+/// Checks where the delete `incoming`, which is being split so that it doesn't overlap any
+/// existing delete, lies relative to the start of the existing delete `existing`
+pub fn delete_crosses<T: Element, E: Element>(incoming: &DeleteOperation<T>, existing: &DeleteOperation<E>, incoming_offset: Offset, existing_offset: Offset) -> CrossResult {
+    let my_front = incoming.position as Offset - incoming_offset;
+    let my_back = my_front + incoming.length as Offset;
+    let other_front = existing.position as Offset - existing_offset;
+    if other_front <= my_front {
+        CrossResult::Follows
+    } else {
+        if other_front < my_back {
+            CrossResult::Crosses((other_front - my_front) as Position)
+        } else {
+            CrossResult::Precedes
+        }
+    }
+}
+
+impl<T: Element> ReplaceOperation<T> {
+
+    /// Creates a new `ReplaceOperation` that will replace `length` bytes at `position` in a file with the bytes in `value`
+    #[inline]
+    pub fn new(position: Position, length: Position, value: Vec<T>, timestamp: u32, site_id: u32) -> ReplaceOperation<T> {
+        ReplaceOperation {
+            position: position,
+            length: length,
+            value: value,
+            timestamp: timestamp,
+            site_id: site_id
+        }
+    }
+
+    /// Gets the number of bytes that will be replaced when this operation is applied
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+
+    /// Gets the bytes that will be put in place of the replaced ones
+    pub fn get_value(&self) -> &[T] {
+        &self.value
+    }
+
+    /// Joins the pieces that a replace was split into while being transformed back into one
+    /// replace, which also covers anything that was inserted between the pieces
+    pub fn join<'a, I: IntoIterator<Item=&'a ReplaceOperation<T>>>(pieces: I) -> Option<ReplaceOperation<T>> where T: 'a {
+        let mut pieces = pieces.into_iter();
+        let mut joined = match pieces.next() {
+            Some(first) => first.clone(),
+            None => return None
+        };
+        // Each piece is positioned as if the ones before it had already been applied
+        let mut offset = joined.get_increment();
+        let mut end = joined.position + joined.length;
+        for piece in pieces {
+            end = (piece.position as Offset - offset) as Position + piece.length;
+            offset += piece.get_increment();
+        }
+        joined.length = end - joined.position;
+        Some(joined)
+    }
+}
+
+impl MoveOperation {
+
+    /// Creates a new `MoveOperation` that will move `length` bytes at `position` in a file to `destination`
+    #[inline]
+    pub fn new(position: Position, length: Position, destination: Position, timestamp: u32, site_id: u32) -> MoveOperation {
+        MoveOperation {
+            position: position,
+            length: length,
+            destination: destination,
+            timestamp: timestamp,
+            site_id: site_id
+        }
+    }
+
+    /// Gets the number of bytes that will be moved when this operation is applied
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+
+    /// Gets the position the bytes will be moved to, before they have been removed from their
+    /// old position
+    pub fn get_destination(&self) -> Position {
+        self.destination
+    }
+
+    /// Gets the range being moved, as a replace which puts nothing in its place.  Transforming
+    /// it carries any inserts made inside the range along with it.
+    pub fn get_source<T: Element>(&self) -> ReplaceOperation<T> {
+        ReplaceOperation::new(self.position, self.length, Vec::new(), self.timestamp, self.site_id)
+    }
+
+    /// Gets the point the range is being moved to, as an insert of nothing
+    pub fn get_target<T: Element>(&self) -> InsertOperation<T> {
+        InsertOperation::new(self.destination, Vec::new(), self.timestamp, self.site_id)
+    }
+
+    /// Updates the move from a transformed source and target
+    pub fn set_from<T: Element>(&mut self, source: &ReplaceOperation<T>, target: &InsertOperation<T>) {
+        self.position = source.position;
+        self.length = source.length;
+        self.destination = target.position;
+    }
+
+    /// Moves the operation to a new range and destination
+    pub fn set_range(&mut self, position: Position, length: Position, destination: Position) {
+        self.position = position;
+        self.length = length;
+        self.destination = destination;
+    }
+
+    /// Sets the id of the site this operation was first performed at
+    pub fn set_site_id(&mut self, site_id: u32) {
+        self.site_id = site_id;
+    }
+
+    /// Compress this operation and write to `writer`.  The output can then be expanded
+    /// back into an equivilent operation using `expand_from()`.  If `include_site_id` is set to true
+    /// Then the site id is saved alongside everyhting else.  If this is the case, then when expanding
+    /// a timestamp lookup should not be passed in.
+    pub fn compress_to<W: Write>(&self, writer: &mut W, include_site_id: bool) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.timestamp);
+        try!(writer.write(&int_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.position);
+        try!(writer.write(&long_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.length);
+        try!(writer.write(&long_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.destination);
+        try!(writer.write(&long_buf));
+        if include_site_id {
+            NetworkEndian::write_u32(&mut int_buf, self.site_id);
+            try!(writer.write(&int_buf));
+        }
+        Ok(())
+    }
+
+    /// Expand this operation from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<MoveOperation> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let timestamp = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut long_buf));
+        let position = NetworkEndian::read_u64(&long_buf);
+        try!(reader.read_exact(&mut long_buf));
+        let length = NetworkEndian::read_u64(&long_buf);
+        try!(reader.read_exact(&mut long_buf));
+        let destination = NetworkEndian::read_u64(&long_buf);
+        let site_id = if let Some(timestamp_lookup) = timestamp_lookup {
+            match timestamp_lookup.get(&timestamp) {
+                Some(&(site_id, _)) => site_id,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Timestamp {} not found in timestamp lookup", timestamp)));
+                }
+            }
+        } else {
+            try!(reader.read_exact(&mut int_buf));
+            NetworkEndian::read_u32(&int_buf)
+        };
+
+        Ok(MoveOperation {
+            position: position,
+            length: length,
+            destination: destination,
+            timestamp: timestamp,
+            site_id: site_id
+        })
+    }
+}
+
+impl<T: Element> Operation for ReplaceOperation<T> {
+    #[inline]
+    fn get_position(&self) -> Position {
+        self.position
+    }
+
+    #[inline]
+    fn get_increment(&self) -> Offset {
+        self.value.len() as Offset - self.length as Offset
+    }
+
+    #[inline]
+    fn get_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    #[inline]
+    fn set_timestamp(&mut self, new_timestamp: u32) {
+        self.timestamp = new_timestamp;
+    }
+
+    #[inline]
+    fn get_site_id(&self) -> u32 {
+        self.site_id
+    }
+}
+
+impl<T: Element> OperationInternal for ReplaceOperation<T> {
+    fn update_position_by(&mut self, delta: Offset) {
+        self.position = (self.position as Offset +  delta) as Position
+    }
+
+    #[inline]
+    fn set_site_id(&mut self, site_id: u32) {
+        self.site_id = site_id;
+    }
+
+    fn update_size_by(&mut self, delta: Offset) {
+        self.length = (self.length as Offset + delta) as Position
+    }
+
+    fn trim_front(&mut self, amount: Position) {
+        // The value stays at the front of whatever is left
+        self.length -= amount
+    }
+
+    fn set_length_to_zero(&mut self) {
+        // The value is still put in, even if everything it replaced is gone
+        self.length = 0
+    }
+
+    fn discard(&mut self) {
+        // Neither the value nor anything it would have replaced is left
+        self.value.clear();
+        self.length = 0
+    }
+
+    fn split(&mut self, split_pos: Position) -> ReplaceOperation<T> {
+        // The value stays with the front piece, so the back piece starts after it
+        let new_op = ReplaceOperation::new(self.position + self.value.len() as Position, self.length - split_pos, Vec::new(), self.timestamp, self.site_id);
+        self.length = split_pos;
+        new_op
+    }
+
+    fn merges_before(&self, other: &ReplaceOperation<T>, other_offset: Offset, _ties: &Ties) -> bool {
+        self.position as Offset <= other.position as Offset - other_offset
+    }
+
+    #[inline]
+    fn check_overlap<O: OperationInternal>(&self,  other: &O, my_offset: Offset, other_offset: Offset, _ties: &Ties) -> OverlapResult {
+        other.check_overlap_with_replace(self, other_offset, my_offset)
+    }
+
+    fn check_overlap_with_insert<E: Element>(&self, other: &InsertOperation<E>, my_offset: Offset, other_offset: Offset, _ties: &Ties) -> OverlapResult {
+        let my_front = self.position as Offset - my_offset;
+        point_overlap_with_range(my_front, my_front + self.length as Offset, other.position as Offset - other_offset)
+    }
+
+    fn check_overlap_with_delete<E: Element>(&self, other: &DeleteOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult {
+        let my_front = self.position as Offset - my_offset;
+        let other_front = other.position as Offset - other_offset;
+        range_overlap_with_range(my_front, my_front + self.length as Offset, other_front, other_front + other.length as Offset)
+    }
+
+    fn check_overlap_with_replace<E: Element>(&self, other: &ReplaceOperation<E>, my_offset: Offset, other_offset: Offset) -> OverlapResult {
+        let my_front = self.position as Offset - my_offset;
+        let other_front = other.position as Offset - other_offset;
+        range_overlap_with_range(my_front, my_front + self.length as Offset, other_front, other_front + other.length as Offset)
+    }
 }
 
+impl Operation for MoveOperation {
+    #[inline]
+    fn get_position(&self) -> Position {
+        self.position
+    }
 
-#[derive(PartialEq, Debug)]
-pub enum CrossResult {
-    Precedes,
-    Follows,
-    Crosses(Position)
+    #[inline]
+    fn get_increment(&self) -> Offset {
+        0
+    }
+
+    #[inline]
+    fn get_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    #[inline]
+    fn set_timestamp(&mut self, new_timestamp: u32) {
+        self.timestamp = new_timestamp;
+    }
+
+    #[inline]
+    fn get_site_id(&self) -> u32 {
+        self.site_id
+    }
 }
 
-impl InsertOperation {
+impl FormatOperation {
 
-    /// Creates a new `InsertOperation` that will insert the bytes represented by `value` in a file at location `position`
+    /// Creates a new `FormatOperation` that will set `key` to `value` on `length` bytes at `position` in a file
     #[inline]
-    pub fn new(position: Position, value: Vec<u8>, timestamp: u32, site_id: u32) -> InsertOperation {
-        InsertOperation {
+    pub fn new(position: Position, length: Position, key: &str, value: Option<String>, timestamp: u32, site_id: u32) -> FormatOperation {
+        FormatOperation {
             position: position,
+            length: length,
+            key: key.to_string(),
             value: value,
             timestamp: timestamp,
             site_id: site_id
         }
     }
 
-    /// Gets the bytes that will be inserted when this operation is applied
-    pub fn get_value(&self) -> &[u8] {
-        &self.value
+    /// Gets the number of bytes that will be formatted when this operation is applied
+    pub fn get_length(&self) -> Position {
+        self.length
+    }
+
+    /// Gets the attribute this operation sets
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    /// Gets the value the attribute is set to, or `None` if it is cleared
+    pub fn get_value(&self) -> Option<&str> {
+        self.value.as_ref().map(|value| &value[..])
+    }
+
+    /// Moves the operation to a new range
+    pub fn set_range(&mut self, position: Position, length: Position) {
+        self.position = position;
+        self.length = length;
+    }
+
+    /// Sets the id of the site this operation was first performed at
+    pub fn set_site_id(&mut self, site_id: u32) {
+        self.site_id = site_id;
     }
 
     /// Compress this operation and write to `writer`.  The output can then be expanded
@@ -115,16 +930,24 @@ impl InsertOperation {
     /// Then the site id is saved alongside everyhting else.  If this is the case, then when expanding
     /// a timestamp lookup should not be passed in.
     pub fn compress_to<W: Write>(&self, writer: &mut W, include_site_id: bool) -> io::Result<()> {
-
         let mut int_buf = [0;4];
         let mut long_buf = [0;8];
         NetworkEndian::write_u32(&mut int_buf, self.timestamp);
         try!(writer.write(&int_buf));
         NetworkEndian::write_u64(&mut long_buf, self.position);
         try!(writer.write(&long_buf));
-        NetworkEndian::write_u32(&mut int_buf, self.value.len() as u32);
-        try!(writer.write(&int_buf));
-        try!(writer.write(&self.value));
+        NetworkEndian::write_u64(&mut long_buf, self.length);
+        try!(writer.write(&long_buf));
+        try!(metadata::write_string(writer, &self.key));
+        match self.value {
+            Some(ref value) => {
+                try!(writer.write(&[1]));
+                try!(metadata::write_string(writer, value));
+            },
+            None => {
+                try!(writer.write(&[0]));
+            }
+        }
         if include_site_id {
             NetworkEndian::write_u32(&mut int_buf, self.site_id);
             try!(writer.write(&int_buf));
@@ -134,18 +957,23 @@ impl InsertOperation {
 
     /// Expand this operation from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<InsertOperation> {
+    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<FormatOperation> {
         let mut int_buf = [0;4];
         let mut long_buf = [0;8];
+        let mut bool_buf = [0;1];
         try!(reader.read_exact(&mut int_buf));
         let timestamp = NetworkEndian::read_u32(&int_buf);
         try!(reader.read_exact(&mut long_buf));
         let position = NetworkEndian::read_u64(&long_buf);
-        try!(reader.read_exact(&mut int_buf));
-        let value_len = NetworkEndian::read_u32(&int_buf) as usize;
-        let mut value = Vec::with_capacity(value_len);
-        value.resize(value_len, 0);
-        try!(reader.read_exact(&mut value));
+        try!(reader.read_exact(&mut long_buf));
+        let length = NetworkEndian::read_u64(&long_buf);
+        let key = try!(metadata::read_string(reader));
+        try!(reader.read_exact(&mut bool_buf));
+        let value = if bool_buf[0] != 0 {
+            Some(try!(metadata::read_string(reader)))
+        } else {
+            None
+        };
         let site_id = if let Some(timestamp_lookup) = timestamp_lookup {
             match timestamp_lookup.get(&timestamp) {
                 Some(&(site_id, _)) => site_id,
@@ -158,84 +986,114 @@ impl InsertOperation {
             NetworkEndian::read_u32(&int_buf)
         };
 
-        Ok(InsertOperation{
+        Ok(FormatOperation {
             position: position,
+            length: length,
+            key: key,
             value: value,
             timestamp: timestamp,
             site_id: site_id
         })
     }
-
-    fn compare_with_offsets(&self, other: &InsertOperation, my_offset: Offset, other_offset: Offset, ) -> bool {
-        let my_pos = self.get_position() as Offset - my_offset;
-        let other_pos = other.get_position() as Offset - other_offset;
-        my_pos < other_pos || my_pos == other_pos && self.site_id < other.site_id
-    }
 }
 
+impl AnchorOperation {
 
-impl DeleteOperation {
-
-    /// Creates a new `DeleteOperation` that woll delete `length` bytes at `position` in a file
+    /// Creates a new `AnchorOperation` that will anchor `thread` to `length` bytes at `position` in a file
     #[inline]
-    pub fn new(position: Position, length: Position, timestamp: u32) -> DeleteOperation {
-        DeleteOperation {
+    pub fn new(position: Position, length: Position, thread: (u32, u32), timestamp: u32, site_id: u32) -> AnchorOperation {
+        AnchorOperation {
             position: position,
             length: length,
-            timestamp: timestamp
+            thread: thread,
+            timestamp: timestamp,
+            site_id: site_id
         }
     }
 
-    /// Gets the number of bytes that will be removed when the delete operation is applied
+    /// Gets the number of bytes the thread will be anchored to
     pub fn get_length(&self) -> Position {
         self.length
     }
 
-    /// Compress this operation and write to `writer`.  The output can then be expanded
-    /// back into an equivilent operation using `expand_from()`
-    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    /// Gets the id of the thread being anchored
+    pub fn get_thread(&self) -> (u32, u32) {
+        self.thread
+    }
 
-        let mut long_buf = [0;8];
+    /// Moves the operation to a new range
+    pub fn set_range(&mut self, position: Position, length: Position) {
+        self.position = position;
+        self.length = length;
+    }
+
+    /// Sets the id of the site this operation was first performed at
+    pub fn set_site_id(&mut self, site_id: u32) {
+        self.site_id = site_id;
+    }
+
+    /// Compress this operation and write to `writer`.  The output can then be expanded
+    /// back into an equivilent operation using `expand_from()`.  If `include_site_id` is set to true
+    /// Then the site id is saved alongside everyhting else.  If this is the case, then when expanding
+    /// a timestamp lookup should not be passed in.
+    pub fn compress_to<W: Write>(&self, writer: &mut W, include_site_id: bool) -> io::Result<()> {
         let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
         NetworkEndian::write_u32(&mut int_buf, self.timestamp);
         try!(writer.write(&int_buf));
         NetworkEndian::write_u64(&mut long_buf, self.position);
         try!(writer.write(&long_buf));
         NetworkEndian::write_u64(&mut long_buf, self.length);
         try!(writer.write(&long_buf));
+        NetworkEndian::write_u32(&mut int_buf, self.thread.0);
+        try!(writer.write(&int_buf));
+        NetworkEndian::write_u32(&mut int_buf, self.thread.1);
+        try!(writer.write(&int_buf));
+        if include_site_id {
+            NetworkEndian::write_u32(&mut int_buf, self.site_id);
+            try!(writer.write(&int_buf));
+        }
         Ok(())
     }
 
     /// Expand this operation from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<DeleteOperation> {
-        let mut long_buf = [0;8];
+    pub fn expand_from<R: Read>(reader: &mut R, timestamp_lookup: Option<&BTreeMap<u32, (u32, u32)>>) -> io::Result<AnchorOperation> {
         let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
         try!(reader.read_exact(&mut int_buf));
         let timestamp = NetworkEndian::read_u32(&int_buf);
         try!(reader.read_exact(&mut long_buf));
         let position = NetworkEndian::read_u64(&long_buf);
         try!(reader.read_exact(&mut long_buf));
-        let len = NetworkEndian::read_u64(&long_buf);
-        Ok(DeleteOperation{
+        let length = NetworkEndian::read_u64(&long_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let thread_site_id = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let thread_number = NetworkEndian::read_u32(&int_buf);
+        let site_id = if let Some(timestamp_lookup) = timestamp_lookup {
+            match timestamp_lookup.get(&timestamp) {
+                Some(&(site_id, _)) => site_id,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Timestamp {} not found in timestamp lookup", timestamp)));
+                }
+            }
+        } else {
+            try!(reader.read_exact(&mut int_buf));
+            NetworkEndian::read_u32(&int_buf)
+        };
+
+        Ok(AnchorOperation {
             position: position,
-            length: len,
-            timestamp: timestamp
+            length: length,
+            thread: (thread_site_id, thread_number),
+            timestamp: timestamp,
+            site_id: site_id
         })
     }
 }
 
-impl Operation for InsertOperation {
-    // #[inline]
-    // fn get_state(&self) -> & State {
-    //     &self.state
-    // }
-    //
-    // #[inline]
-    // fn get_state_mut(&mut self) -> &mut State {
-    //     &mut self.state
-    // }
-
+impl Operation for AnchorOperation {
     #[inline]
     fn get_position(&self) -> Position {
         self.position
@@ -243,7 +1101,7 @@ impl Operation for InsertOperation {
 
     #[inline]
     fn get_increment(&self) -> Offset {
-        self.value.len() as Offset
+        0
     }
 
     #[inline]
@@ -255,90 +1113,14 @@ impl Operation for InsertOperation {
     fn set_timestamp(&mut self, new_timestamp: u32) {
         self.timestamp = new_timestamp;
     }
-}
-
-impl OperationInternal for InsertOperation {
-
-
-    fn update_position_by(&mut self, delta: Offset) {
-        self.position = (self.position as Offset +  delta) as Position
-    }
-
-    fn update_size_by(&mut self, _delta: Offset) {
-        unimplemented!();
-    }
-
-    fn set_length_to_zero(&mut self) {
-        //Don't do anything, since we keep insert operations, even if they are in the middle of existing delete operations
-    }
-
-    fn split(&mut self, _split_pos: Position) -> InsertOperation {
-        unimplemented!();
-    }
 
     #[inline]
-    fn check_overlap<O: OperationInternal>(&self,  other: &O, my_offset: Offset, other_offset: Offset) -> OverlapResult {
-        other.check_overlap_with_insert(self, other_offset, my_offset)
-    }
-    #[inline]
-    fn check_overlap_with_insert(&self, other: &InsertOperation, my_offset: Offset, other_offset: Offset) -> OverlapResult {
-        if other.compare_with_offsets(self, other_offset, my_offset) {
-            OverlapResult::Precedes
-        } else {
-            OverlapResult::Follows
-        }
-    }
-
-    fn check_overlap_with_delete(&self, other: &DeleteOperation, my_offset: Offset, other_offset: Offset) -> OverlapResult {
-        let my_pos = self.position as Offset - my_offset;
-        let other_front = other.position as Offset - other_offset;
-        let other_back = other_front + other.length as Offset;
-        //     |--other
-        //   |
-        if my_pos <= other_front {
-            OverlapResult::Follows
-        }
-        //  |--other
-        //   |
-        else {
-            //  |--other--|
-            //   |
-            if my_pos < other_back {
-                OverlapResult::Encloses((my_pos - other_front) as Position)
-            }
-            //  |--other--|
-            //             |
-            else {
-                OverlapResult::Precedes
-            }
-        }
-    }
-
-    fn crossed_by<O: OperationInternal>(&self, _other: &O, _my_offset: Offset, _other_offset: Offset) -> CrossResult {
-        unimplemented!();
-    }
-
-    fn crosses(&self, other: &DeleteOperation, my_offset: Offset, other_offset: Offset) -> CrossResult {
-        if other.position as Offset - other_offset <= self.position as Offset - my_offset {
-            CrossResult::Follows
-        } else {
-            CrossResult::Precedes
-        }
+    fn get_site_id(&self) -> u32 {
+        self.site_id
     }
-
 }
 
-impl Operation for DeleteOperation {
-    // #[inline]
-    // fn get_state(&self) -> & State {
-    //     &self.state
-    // }
-    //
-    // #[inline]
-    // fn get_state_mut(&mut self) -> &mut State {
-    //     &mut self.state
-    // }
-
+impl Operation for FormatOperation {
     #[inline]
     fn get_position(&self) -> Position {
         self.position
@@ -346,7 +1128,7 @@ impl Operation for DeleteOperation {
 
     #[inline]
     fn get_increment(&self) -> Offset {
-        -(self.length as Offset)
+        0
     }
 
     #[inline]
@@ -358,133 +1140,91 @@ impl Operation for DeleteOperation {
     fn set_timestamp(&mut self, new_timestamp: u32) {
         self.timestamp = new_timestamp;
     }
-}
-
-impl OperationInternal for DeleteOperation {
-    fn update_position_by(&mut self, delta: Offset) {
-        self.position = (self.position as Offset +  delta) as Position
-    }
-
-    fn update_size_by(&mut self, delta: Offset) {
-        self.length = (self.length as Offset + delta) as Position
-    }
-
-    fn set_length_to_zero(&mut self) {
-        self.length = 0
-    }
-
-    fn split(&mut self, split_pos: Position) -> DeleteOperation {
-        let new_op = DeleteOperation::new(self.position , self.length - split_pos, self.timestamp);
-        self.length = split_pos;
-        new_op
-    }
 
     #[inline]
-    fn check_overlap<O: OperationInternal>(&self,  other: &O, my_offset: Offset, other_offset: Offset) -> OverlapResult {
-        other.check_overlap_with_delete(self, other_offset, my_offset)
+    fn get_site_id(&self) -> u32 {
+        self.site_id
     }
+}
 
-
-    fn check_overlap_with_insert(&self, other: &InsertOperation, my_offset: Offset, other_offset: Offset) -> OverlapResult {
-        let my_front = self.position as Offset - my_offset;
-        let my_back = my_front + self.length as Offset;
-        let other_pos = other.position as Offset - other_offset;
-        //    |
-        //     |--self
-        if other_pos <= my_front {
-            OverlapResult::Precedes
-        }
-        //    |
-        // |--self
-        else {
-            //     |
-            //  |--self --|
-            if other_pos < my_back {
-                OverlapResult::EnclosedBy((other_pos - my_front) as Position)
-            } else
-            //              |
-            //  |--self --|
-            {
-                OverlapResult::Follows
-            }
+/// Checks where an insert at `other_pos`, which is being transformed, lies relative to the
+/// existing range `my_front..my_back`
+fn point_overlap_with_range(my_front: Offset, my_back: Offset, other_pos: Offset) -> OverlapResult {
+    //    |
+    //     |--self
+    if other_pos <= my_front {
+        OverlapResult::Precedes
+    }
+    //    |
+    // |--self
+    else {
+        //     |
+        //  |--self --|
+        if other_pos < my_back {
+            OverlapResult::EnclosedBy((other_pos - my_front) as Position)
+        } else
+        //              |
+        //  |--self --|
+        {
+            OverlapResult::Follows
         }
     }
-    fn check_overlap_with_delete(&self, other: &DeleteOperation, my_offset: Offset, other_offset: Offset) -> OverlapResult {
-        let my_front = self.position as Offset - my_offset;
-        let my_back = my_front + self.length as Offset;
-        let other_front = other.position as Offset - other_offset;
-        let other_back = other_front + other.length as Offset;
-        // |--other--
-        //     |--self--
-        if other_front < my_front {
-            // |--other--|
-            //    |--self--
-            if my_front < other_back {
-                // |--other-----|
-                //    |--self--|
-                if my_back < other_back {
-                    OverlapResult::Encloses((my_front - other_front) as Position)
-                }
-                // |--other--|
-                //    |--self--|
-                else {
-                    OverlapResult::OverlapFront((other_back - my_front) as Position)
-                }
+}
 
+/// Checks where the range `other_front..other_back` of an operation that is being transformed
+/// lies relative to the existing range `my_front..my_back`
+fn range_overlap_with_range(my_front: Offset, my_back: Offset, other_front: Offset, other_back: Offset) -> OverlapResult {
+    // |--other--
+    //     |--self--
+    if other_front < my_front {
+        // |--other--|
+        //    |--self--
+        if my_front < other_back {
+            // |--other-----|
+            //    |--self--|
+            if my_back < other_back {
+                OverlapResult::Encloses((my_front - other_front) as Position)
             }
             // |--other--|
-            //             |--self--
+            //    |--self--|
             else {
-                OverlapResult::Precedes
+                OverlapResult::OverlapFront((other_back - my_front) as Position)
             }
 
         }
-        //     |--other--
-        // |--self--
+        // |--other--|
+        //             |--self--
         else {
+            OverlapResult::Precedes
+        }
 
-            //     |--other
-            // |--self--|
-            if other_front < my_back {
-                //    |--other--|
-                // |--self-------|
-                if other_back < my_back {
-                    if other_front == my_front {
-                        OverlapResult::OverlapFront((other_back - my_front) as Position)
-                    } else {
-                        OverlapResult::EnclosedBy((other_front - my_front) as Position )
-                    }
-                }
-                //    |--other--|
-                // |--self--|
-                else {
-                    OverlapResult::OverlapBack((my_back - other_front) as Position)
+    }
+    //     |--other--
+    // |--self--
+    else {
+
+        //     |--other
+        // |--self--|
+        if other_front < my_back {
+            //    |--other--|
+            // |--self-------|
+            if other_back < my_back {
+                if other_front == my_front {
+                    OverlapResult::OverlapFront((other_back - my_front) as Position)
+                } else {
+                    OverlapResult::EnclosedBy((other_front - my_front) as Position )
                 }
             }
-            //            |--other--
+            //    |--other--|
             // |--self--|
             else {
-                OverlapResult::Follows
+                OverlapResult::OverlapBack((my_back - other_front) as Position)
             }
         }
-    }
-
-    fn crossed_by<O: OperationInternal>(&self, other: &O, my_offset: Offset, other_offset: Offset) -> CrossResult {
-        other.crosses(self, other_offset, my_offset)
-    }
-
-    fn crosses(&self, other: &DeleteOperation, my_offset: Offset, other_offset: Offset) -> CrossResult {
-        let my_front = self.position as Offset - my_offset;
-        let my_back = my_front + self.length as Offset;
-        let other_front = other.position as Offset - other_offset;
-        if other_front <= my_front {
-            CrossResult::Follows
-        } else {
-            if other_front < my_back {
-                CrossResult::Crosses((other_front - my_front) as Position)
-            } else {
-                CrossResult::Precedes
-            }
+        //            |--other--
+        // |--self--|
+        else {
+            OverlapResult::Follows
         }
     }
 }
@@ -500,9 +1240,9 @@ impl OperationInternal for DeleteOperation {
 //     }
 // }
 
-impl fmt::Debug for InsertOperation {
+impl<T: Element> fmt::Debug for InsertOperation<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "({}, {})[{}]", self.position, String::from_utf8_lossy(&self.value), self.timestamp)
+        write!(f, "({}, {})[{}]", self.position, T::describe(&self.value), self.timestamp)
     }
 }
 
@@ -517,12 +1257,36 @@ impl fmt::Debug for InsertOperation {
 //     }
 // }
 
-impl fmt::Debug for DeleteOperation {
+impl<T: Element> fmt::Debug for DeleteOperation<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f, "({}, {})[{}]", self.position, self.length, self.timestamp)
     }
 }
 
+impl<T: Element> fmt::Debug for ReplaceOperation<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "({}, {}, {})[{}]", self.position, self.length, T::describe(&self.value), self.timestamp)
+    }
+}
+
+impl fmt::Debug for MoveOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "({}, {} -> {})[{}]", self.position, self.length, self.destination, self.timestamp)
+    }
+}
+
+impl fmt::Debug for FormatOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "({}, {} {}={:?})[{}]", self.position, self.length, self.key, self.value, self.timestamp)
+    }
+}
+
+impl fmt::Debug for AnchorOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "({}, {} #{:?})[{}]", self.position, self.length, self.thread, self.timestamp)
+    }
+}
+
 // impl PartialOrd for DeleteOperation {
 //     fn partial_cmp(&self, other: &DeleteOperation) -> Option<Ordering> {
 //         match self.position.cmp(&other.position) {
@@ -632,7 +1396,9 @@ impl fmt::Debug for DeleteOperation {
 
 #[cfg(test)]
 mod test {
-    use super::{InsertOperation, DeleteOperation, OverlapResult, OperationInternal};
+    use super::{InsertOperation, DeleteOperation, OverlapResult, Operation, OperationInternal};
+    use tiebreak::Ties;
+    use std::collections::BTreeMap;
 
     #[test]
     fn overlapping() {
@@ -642,86 +1408,112 @@ mod test {
         // Insert / Insert
         let op1 = InsertOperation::new(2, "Some text".bytes().collect(), 0, 1);
         let op2 = InsertOperation::new(3, "Other words".bytes().collect(), 1, 2);
-        assert_eq!(op1.check_overlap(&op2, 0, 1), OverlapResult::Precedes);
+        assert_eq!(op1.check_overlap(&op2, 0, 1, &Ties::by_site()), OverlapResult::Precedes);
 
         let op1 = InsertOperation::new(2, "Some text".bytes().collect(), 0, 1);
         let op2 = InsertOperation::new(2, "Other words".bytes().collect(), 1, 2);
-        assert_eq!(op1.check_overlap(&op2, 0, 1), OverlapResult::Follows);
+        assert_eq!(op1.check_overlap(&op2, 0, 1, &Ties::by_site()), OverlapResult::Follows);
 
         // Insert / Delete
         let op1 = InsertOperation::new(2, "Some text".bytes().collect(), 0, 1);
-        let op2 = DeleteOperation::new(1, 5, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::EnclosedBy(1));
+        let op2 = DeleteOperation::<u8>::new(1, 5, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::EnclosedBy(1));
 
         let op1 = InsertOperation::new(2, "Some text".bytes().collect(), 0, 1);
-        let op2 = DeleteOperation::new(1, 5, 1);
-        assert_eq!(op1.check_overlap(&op2, -3, 0), OverlapResult::EnclosedBy(4));
+        let op2 = DeleteOperation::<u8>::new(1, 5, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, -3, 0, &Ties::by_site()), OverlapResult::EnclosedBy(4));
 
         let op1 = InsertOperation::new(2, "Some text".bytes().collect(), 0, 1);
-        let op2 = DeleteOperation::new(1, 5, 1);
-        assert_eq!(op1.check_overlap(&op2, -4, 0), OverlapResult::Follows);
+        let op2 = DeleteOperation::<u8>::new(1, 5, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, -4, 0, &Ties::by_site()), OverlapResult::Follows);
 
         let op1 = InsertOperation::new(2, "Some text".bytes().collect(), 0, 1);
-        let op2 = DeleteOperation::new(1, 5, 1);
-        assert_eq!(op1.check_overlap(&op2, 1, 0), OverlapResult::Precedes);
+        let op2 = DeleteOperation::<u8>::new(1, 5, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 1, 0, &Ties::by_site()), OverlapResult::Precedes);
 
         // Delete / Insert
-        let op1 = DeleteOperation::new(1, 5, 0);
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
         let op2 = InsertOperation::new(2, "Some text".bytes().collect(), 1, 2);
-        assert_eq!(op1.check_overlap(&op2, 0, 1), OverlapResult::Follows);
+        assert_eq!(op1.check_overlap(&op2, 0, 1, &Ties::by_site()), OverlapResult::Follows);
 
-        let op1 = DeleteOperation::new(1, 5, 0);
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
         let op2 = InsertOperation::new(2, "Some text".bytes().collect(), 1, 2);
-        assert_eq!(op1.check_overlap(&op2, 0, -3), OverlapResult::Encloses(4));
+        assert_eq!(op1.check_overlap(&op2, 0, -3, &Ties::by_site()), OverlapResult::Encloses(4));
 
-        let op1 = DeleteOperation::new(1, 5, 0);
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
         let op2 = InsertOperation::new(2, "Some text".bytes().collect(), 1, 2);
-        assert_eq!(op1.check_overlap(&op2, 0, -4), OverlapResult::Precedes);
+        assert_eq!(op1.check_overlap(&op2, 0, -4, &Ties::by_site()), OverlapResult::Precedes);
 
-        let op1 = DeleteOperation::new(11, 5, 0);
+        let op1 = DeleteOperation::<u8>::new(11, 5, 0, 1);
         let op2 = InsertOperation::new(2, "Some text".bytes().collect(), 1, 2);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::Follows);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::Follows);
 
         // Delete / Delete
-        let op1 = DeleteOperation::new(1, 5, 0);
-        let op2 = DeleteOperation::new(6, 3, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::Precedes);
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(6, 3, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::Precedes);
+
+        let op1 = DeleteOperation::<u8>::new(7, 1, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(4, 4, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 1, &Ties::by_site()), OverlapResult::Follows);
 
-        let op1 = DeleteOperation::new(7, 1, 0);
-        let op2 = DeleteOperation::new(4, 4, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 1), OverlapResult::Follows);
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(2, 4, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::OverlapFront(4));
 
-        let op1 = DeleteOperation::new(1, 5, 0);
-        let op2 = DeleteOperation::new(2, 4, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::OverlapFront(4));
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(2, 3, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::Encloses(1));
 
-        let op1 = DeleteOperation::new(1, 5, 0);
-        let op2 = DeleteOperation::new(2, 3, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::Encloses(1));
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(1, 5, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::OverlapBack(5));
 
-        let op1 = DeleteOperation::new(1, 5, 0);
-        let op2 = DeleteOperation::new(1, 5, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::OverlapBack(5));
+        let op1 = DeleteOperation::<u8>::new(1, 5, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(0, 4, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, -1, &Ties::by_site()), OverlapResult::OverlapBack(4));
 
-        let op1 = DeleteOperation::new(1, 5, 0);
-        let op2 = DeleteOperation::new(0, 4, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, -1), OverlapResult::OverlapBack(4));
+        let op1 = DeleteOperation::<u8>::new(4, 2, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(3, 2, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::OverlapBack(1));
 
-        let op1 = DeleteOperation::new(4, 2, 0);
-        let op2 = DeleteOperation::new(3, 2, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::OverlapBack(1));
+        let op1 = DeleteOperation::<u8>::new(4, 2, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(3, 3, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::OverlapBack(2));
 
-        let op1 = DeleteOperation::new(4, 2, 0);
-        let op2 = DeleteOperation::new(3, 3, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::OverlapBack(2));
+        let op1 = DeleteOperation::<u8>::new(4, 2, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(3, 4, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, 0, &Ties::by_site()), OverlapResult::EnclosedBy(1));
 
-        let op1 = DeleteOperation::new(4, 2, 0);
-        let op2 = DeleteOperation::new(3, 4, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, 0), OverlapResult::EnclosedBy(1));
+        let op1 = DeleteOperation::<u8>::new(9, 4, 0, 1);
+        let op2 = DeleteOperation::<u8>::new(2, 2, 1, 1);
+        assert_eq!(op1.check_overlap(&op2, 0, -5, &Ties::by_site()), OverlapResult::Follows);
 
-        let op1 = DeleteOperation::new(9, 4, 0);
-        let op2 = DeleteOperation::new(2, 2, 1);
-        assert_eq!(op1.check_overlap(&op2, 0, -5), OverlapResult::Follows);
+    }
 
+    #[test]
+    fn deletes_keep_their_content() {
+        let mut op = DeleteOperation::new(4, 9, 0, 1);
+        op.set_content(Some(b"brown fox".to_vec()));
+        let back = op.split(6);
+        assert_eq!(op.get_content(), Some(&b"brown "[..]));
+        assert_eq!(back.get_content(), Some(&b"fox"[..]));
+
+        op.trim_front(2);
+        assert_eq!(op.get_content(), Some(&b"own "[..]));
+        op.update_size_by(-1);
+        assert_eq!(op.get_content(), Some(&b"own"[..]));
+        op.update_size_by(1);
+        assert_eq!(op.get_content(), None);
+
+        let mut buffer = Vec::new();
+        back.compress_to(&mut buffer, true, true).unwrap();
+        assert_eq!(DeleteOperation::expand_from(&mut &buffer[..], None, true).unwrap(), back);
+        buffer.clear();
+        back.compress_to(&mut buffer, false, false).unwrap();
+        let mut lookup = BTreeMap::new();
+        lookup.insert(0, (3, 7));
+        let expanded = DeleteOperation::<u8>::expand_from(&mut &buffer[..], Some(&lookup), false).unwrap();
+        assert_eq!((expanded.get_content(), expanded.get_site_id()), (None, 3));
     }
 }
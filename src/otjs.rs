@@ -0,0 +1,294 @@
+//! Conversions between transactions and the retain/insert/delete operations of
+//! [ot.js](https://github.com/Operational-Transformation/ot.js) and the clients built on it.
+//!
+//! A [`TextOperation`](struct.TextOperation.html) walks over the whole document from start to
+//! end.  Each of its components either retains some of the document, inserts some text, or
+//! deletes some of the document.  In JSON, an operation is an array in which a positive number
+//! retains that many code units, a negative number deletes that many, and a string is
+//! inserted, so `[3, "abc", -2, 4]` replaces the two code units after the first three with
+//! `abc` in a document nine code units long.  Lengths are counted in UTF-16 code units, like
+//! the lengths of JavaScript strings.
+//!
+//! Where an insert and a delete meet, the insert always comes first, as it does in ot.js.
+//! This matches the effect order of a transaction, in which every insert is made before any
+//! delete.
+use std::str;
+use rustc_serialize::json::Json;
+use engine::TransactionSequence;
+use utils;
+use {OTError, ErrorKind};
+
+/// One step of a [`TextOperation`](struct.TextOperation.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Component {
+    /// Keeps this many code units of the document
+    Retain(u64),
+    /// Inserts this text
+    Insert(String),
+    /// Deletes this many code units of the document
+    Delete(u64),
+}
+
+/// An operation in the format used by ot.js, which retains, inserts and deletes text from the
+/// start of the document to its end
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TextOperation {
+    components: Vec<Component>,
+}
+
+impl TextOperation {
+    /// Creates an operation with no components, which only applies to an empty document
+    pub fn new() -> TextOperation {
+        TextOperation::default()
+    }
+
+    /// Gets the components of this operation.  Two components of the same kind are never next
+    /// to each other, and an insert never comes directly after a delete.
+    pub fn get_components(&self) -> &[Component] {
+        &self.components
+    }
+
+    /// Keeps the next `length` code units of the document
+    pub fn retain(&mut self, length: u64) -> &mut TextOperation {
+        if length > 0 {
+            if let Some(&mut Component::Retain(ref mut last)) = self.components.last_mut() {
+                *last += length;
+                return self;
+            }
+            self.components.push(Component::Retain(length));
+        }
+        self
+    }
+
+    /// Inserts `text` at the current position
+    pub fn insert(&mut self, text: &str) -> &mut TextOperation {
+        if !text.is_empty() {
+            // The insert goes in front of a delete that it would otherwise follow
+            let index = match self.components.last() {
+                Some(&Component::Delete(_)) => self.components.len() - 1,
+                _ => self.components.len()
+            };
+            if index > 0 {
+                if let Component::Insert(ref mut previous) = self.components[index - 1] {
+                    previous.push_str(text);
+                    return self;
+                }
+            }
+            self.components.insert(index, Component::Insert(text.to_string()));
+        }
+        self
+    }
+
+    /// Deletes the next `length` code units of the document
+    pub fn delete(&mut self, length: u64) -> &mut TextOperation {
+        if length > 0 {
+            if let Some(&mut Component::Delete(ref mut last)) = self.components.last_mut() {
+                *last += length;
+                return self;
+            }
+            self.components.push(Component::Delete(length));
+        }
+        self
+    }
+
+    /// The length, in code units, of the document this operation applies to
+    pub fn base_length(&self) -> u64 {
+        self.components.iter().map(|component| match *component {
+            Component::Retain(length) | Component::Delete(length) => length,
+            Component::Insert(_) => 0
+        }).sum()
+    }
+
+    /// The length, in code units, of the document this operation produces
+    pub fn target_length(&self) -> u64 {
+        self.components.iter().map(|component| match *component {
+            Component::Retain(length) => length,
+            Component::Insert(ref text) => text.encode_utf16().count() as u64,
+            Component::Delete(_) => 0
+        }).sum()
+    }
+
+    /// Reads an operation from its JSON array
+    pub fn from_json(json: &Json) -> Result<TextOperation, String> {
+        let components = try!(json.as_array().ok_or("Expected an array for an operation".to_string()));
+        let mut operation = TextOperation::new();
+        for component in components {
+            match *component {
+                Json::String(ref text) => operation.insert(text),
+                Json::U64(length) if length > 0 => operation.retain(length),
+                Json::I64(length) if length > 0 => operation.retain(length as u64),
+                Json::I64(length) if length < 0 => operation.delete(-length as u64),
+                _ => return Err(format!("Invalid component {}", component))
+            };
+        }
+        Ok(operation)
+    }
+
+    /// Writes this operation as a JSON array
+    pub fn to_json(&self) -> Json {
+        Json::Array(self.components.iter().map(|component| match *component {
+            Component::Retain(length) => Json::U64(length),
+            Component::Insert(ref text) => Json::String(text.clone()),
+            Component::Delete(length) => Json::I64(-(length as i64))
+        }).collect())
+    }
+
+    /// Turns this operation into a transaction that makes the same change to `document`,
+    /// stamped with `timestamp` and `site_id`.  Fails with `OutOfRange` if `document` isn't
+    /// UTF-8, if its length isn't the base length of this operation, or if a component would
+    /// end in the middle of a character.
+    pub fn to_transaction(&self, document: &[u8], last_timestamp: Option<(u32, u32)>, timestamp: u32, site_id: u32) -> Result<TransactionSequence, OTError> {
+        let text = try!(str::from_utf8(document).map_err(|_| OTError::new(ErrorKind::OutOfRange)));
+        // Pair each byte of the result with the byte of the document it came from, if any
+        let mut result = Vec::with_capacity(document.len());
+        let mut offset = 0;
+        for component in self.components.iter() {
+            match *component {
+                Component::Retain(length) => {
+                    let end = try!(advance(text, offset, length).ok_or(OTError::new(ErrorKind::OutOfRange)));
+                    result.extend((offset..end).map(|index| (document[index], Some(index))));
+                    offset = end;
+                },
+                Component::Insert(ref inserted) => result.extend(inserted.bytes().map(|byte| (byte, None))),
+                Component::Delete(length) => {
+                    offset = try!(advance(text, offset, length).ok_or(OTError::new(ErrorKind::OutOfRange)));
+                }
+            }
+        }
+        if offset != document.len() {
+            return Err(OTError::new(ErrorKind::OutOfRange));
+        }
+        let regions = utils::changed_regions(document.len(), &result);
+        let bytes: Vec<u8> = result.into_iter().map(|(byte, _)| byte).collect();
+        let (inserts, deletes) = utils::operations_for_regions(&regions, &bytes, timestamp, site_id);
+        Ok(TransactionSequence::new(last_timestamp, inserts, deletes))
+    }
+
+    /// Gets the operation that makes the same change to `document` as an integrated
+    /// transaction, where `document` is the buffer before the transaction is applied.  Returns
+    /// `None` if the transaction doesn't fit `document`, if it would split a character, or if it
+    /// inserts text that isn't UTF-8.
+    pub fn from_transaction(document: &[u8], transaction: &TransactionSequence) -> Option<TextOperation> {
+        let result = utils::trace_origins(document, &transaction.inserts, &transaction.deletes)?;
+        let bytes: Vec<u8> = result.iter().map(|&(byte, _)| byte).collect();
+        let mut operation = TextOperation::new();
+        let mut offset = 0;
+        for (document_front, document_back, result_front, result_back) in utils::changed_regions(document.len(), &result) {
+            let retained = code_units(&document[offset..document_front]);
+            let deleted = code_units(&document[document_front..document_back]);
+            let inserted = str::from_utf8(&bytes[result_front..result_back]);
+            match (retained, deleted, inserted) {
+                (Some(retained), Some(deleted), Ok(inserted)) => {
+                    operation.retain(retained).insert(inserted).delete(deleted);
+                },
+                _ => return None
+            }
+            offset = document_back;
+        }
+        match code_units(&document[offset..]) {
+            Some(retained) => operation.retain(retained),
+            None => return None
+        };
+        Some(operation)
+    }
+}
+
+/// Finds the byte offset `length` code units after `offset` in `text`, if it is between two
+/// characters
+fn advance(text: &str, offset: usize, length: u64) -> Option<usize> {
+    let mut units = 0;
+    for (index, character) in text[offset..].char_indices() {
+        if units == length {
+            return Some(offset + index);
+        } else if units > length {
+            return None;
+        }
+        units += character.len_utf16() as u64;
+    }
+    if units == length {
+        Some(text.len())
+    } else {
+        None
+    }
+}
+
+/// Counts the code units in `bytes`, if they hold whole characters
+fn code_units(bytes: &[u8]) -> Option<u64> {
+    str::from_utf8(bytes).ok().map(|text| text.encode_utf16().count() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::LinkedList;
+    use rustc_serialize::json::Json;
+    use engine::TransactionSequence;
+    use operations::{InsertOperation, DeleteOperation};
+    use super::{TextOperation, Component};
+
+    #[test]
+    fn builder() {
+        let mut operation = TextOperation::new();
+        operation.retain(2).retain(1).delete(2).insert("ab").insert("c").retain(0).retain(4);
+        assert_eq!(operation.get_components(), &[Component::Retain(3), Component::Insert("abc".to_string()), Component::Delete(2), Component::Retain(4)]);
+        assert_eq!(operation.base_length(), 9);
+        assert_eq!(operation.target_length(), 10);
+    }
+
+    #[test]
+    fn json() {
+        let json = Json::from_str(r#"[3, "abc", -2, 4]"#).unwrap();
+        let operation = TextOperation::from_json(&json).unwrap();
+        assert_eq!(operation.get_components(), &[Component::Retain(3), Component::Insert("abc".to_string()), Component::Delete(2), Component::Retain(4)]);
+        assert_eq!(operation.to_json(), json);
+        assert!(TextOperation::from_json(&Json::from_str("[3, 0]").unwrap()).is_err());
+        assert!(TextOperation::from_json(&Json::from_str(r#"{"ops": []}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn to_transaction() {
+        let document = "a😀bcé".as_bytes();
+        // The emoji is two code units long
+        let operation = TextOperation::from_json(&Json::from_str(r#"[1, -2, "x", 1, "yz", -1, 1]"#).unwrap()).unwrap();
+        let transaction = operation.to_transaction(document, None, 3, 1).unwrap();
+        assert_eq!(transaction.apply_to_bytes(document), "axbyzé".as_bytes());
+
+        // Splitting the emoji, or not covering the whole document, fails
+        let operation = TextOperation::from_json(&Json::from_str("[2, -1, 2]").unwrap()).unwrap();
+        assert!(operation.to_transaction(document, None, 3, 1).is_err());
+        let operation = TextOperation::from_json(&Json::from_str("[3]").unwrap()).unwrap();
+        assert!(operation.to_transaction(document, None, 3, 1).is_err());
+    }
+
+    #[test]
+    fn from_transaction() {
+        let document = "a😀bcé".as_bytes();
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(1, b"x".to_vec(), 3, 1));
+        inserts.push_back(InsertOperation::new(7, b"yz".to_vec(), 3, 1));
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(2, 4, 3, 1));
+        deletes.push_back(DeleteOperation::new(5, 1, 3, 1));
+        let transaction = TransactionSequence::new(None, inserts, deletes);
+        assert_eq!(transaction.apply_to_bytes(document), "axbyzé".as_bytes());
+
+        let operation = TextOperation::from_transaction(document, &transaction).unwrap();
+        assert_eq!(operation.to_json(), Json::from_str(r#"[1, "x", -2, 1, "yz", -1, 1]"#).unwrap());
+        let transaction = operation.to_transaction(document, None, 4, 2).unwrap();
+        assert_eq!(transaction.apply_to_bytes(document), "axbyzé".as_bytes());
+
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(2, 1, 3, 1));
+        let transaction = TransactionSequence::new(None, LinkedList::new(), deletes);
+        assert_eq!(TextOperation::from_transaction(document, &transaction), None);
+
+        // A transaction that doesn't fit the document gives nothing
+        let mut inserts = LinkedList::new();
+        inserts.push_back(InsertOperation::new(20, b"x".to_vec(), 3, 1));
+        let transaction = TransactionSequence::new(None, inserts, LinkedList::new());
+        assert_eq!(TextOperation::from_transaction(document, &transaction), None);
+        let mut deletes = LinkedList::new();
+        deletes.push_back(DeleteOperation::new(6, 5, 3, 1));
+        let transaction = TransactionSequence::new(None, LinkedList::new(), deletes);
+        assert_eq!(TextOperation::from_transaction(document, &transaction), None);
+    }
+}
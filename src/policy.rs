@@ -0,0 +1,56 @@
+use std::io::{self, Read, Write};
+
+/// Decides what happens to text that one site inserts inside a range that another site
+/// deletes at the same time.
+///
+/// Every site must use the same policy, or their documents will diverge.  Sessions check
+/// that both sides agree when they say hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The inserted text is kept, and the delete only removes the text around it
+    KeepInsert,
+    /// The delete removes the inserted text along with the rest of the range
+    DropInsert,
+    /// Edits are merged as with `KeepInsert`, and then wherever concurrent edits collided the
+    /// lines involved are replaced with git-style conflict markers around both versions.  See
+    /// `Engine::integrate_remote_with_markers()`.
+    Markers,
+    /// Edits are merged as with `KeepInsert`, and then the site that deleted the range puts back
+    /// what it deleted around the inserted text.  See `Engine::integrate_remote_with_markers()`.
+    Resurrect,
+}
+
+impl Default for ConflictPolicy {
+    #[inline]
+    fn default() -> ConflictPolicy {
+        ConflictPolicy::KeepInsert
+    }
+}
+
+impl ConflictPolicy {
+    /// Compress this policy and write to `writer`.  The output can then be expanded
+    /// back into an equivilent policy using `expand_from()`
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let tag = match *self {
+            ConflictPolicy::KeepInsert => 0,
+            ConflictPolicy::DropInsert => 1,
+            ConflictPolicy::Markers => 2,
+            ConflictPolicy::Resurrect => 3,
+        };
+        writer.write_all(&[tag])
+    }
+
+    /// Expand a policy from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<ConflictPolicy> {
+        let mut tag_buf = [0;1];
+        try!(reader.read_exact(&mut tag_buf));
+        match tag_buf[0] {
+            0 => Ok(ConflictPolicy::KeepInsert),
+            1 => Ok(ConflictPolicy::DropInsert),
+            2 => Ok(ConflictPolicy::Markers),
+            3 => Ok(ConflictPolicy::Resurrect),
+            tag => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown conflict policy {}", tag)))
+        }
+    }
+}
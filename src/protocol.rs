@@ -0,0 +1,1354 @@
+//! Messages and session handling for synchronizing two sites over a byte stream.
+//!
+//! A [`Session`](struct.Session.html) wraps an [`Engine`](../struct.Engine.html) and any
+//! transport that implements `Read + Write` (a `TcpStream`, a pipe, ...).  Each side starts
+//! by sending a `Hello`, which also checks that both sides settle conflicts with the same
+//! [`ConflictPolicy`](../enum.ConflictPolicy.html) and [`TieBreak`](../trait.TieBreak.html), and
+//! are both in text mode or not.  After that, local edits are sent as
+//! transactions and acknowledged by the other side once they have been integrated.  When a
+//! session starts again, each side sends the other what it has done since the state the other
+//! said hello with.  A site with no history can ask the other side for a snapshot instead of replaying every
+//! transaction.  If the two sides find that their documents have diverged, they elect one
+//! copy to keep and the other side is rebuilt from a snapshot of it.
+use std::collections::btree_map::BTreeMap;
+use std::collections::vec_deque::VecDeque;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+use rdiff::Diff;
+use engine::{Engine, TransactionSequence, TimeStamper, Snapshot};
+use element::Element;
+use fingerprint::Fingerprint;
+use repair::{SiteState, Repair};
+use policy::ConflictPolicy;
+use tiebreak;
+use OTError;
+
+const HELLO: u8 = 0;
+const TRANSACTION: u8 = 1;
+const ACKNOWLEDGEMENT: u8 = 2;
+const SNAPSHOT_REQUEST: u8 = 3;
+const SNAPSHOT: u8 = 4;
+const ERROR: u8 = 5;
+const DIVERGED: u8 = 6;
+const REPAIR: u8 = 7;
+
+/// A single message exchanged between two sites.  The transactions it carries hold bytes, like
+/// the engine of a [`Session`](struct.Session.html).
+#[derive(Debug)]
+pub enum Message {
+    /// Sent by each side when a session starts
+    Hello {
+        /// The site id of the sender
+        site_id: u32,
+        /// The most recent state the sender has seen, or `None` if it has no history.  The
+        /// other side catches the sender up from it.
+        state: Option<(u32, u32)>,
+        /// The conflict policy of the sender's engine, which must match our own
+        policy: ConflictPolicy,
+        /// The description of the tie-breaking strategy of the sender's engine, which must match our own
+        tie_break: Vec<u8>,
+        /// Whether the sender's engine is in text mode, which must match our own
+        text_mode: bool,
+    },
+    /// A transaction that has been run through the sender's engine
+    Transaction {
+        /// The transaction itself
+        transaction: TransactionSequence,
+        /// The lookup between the sender's timestamps and the `(site id, timestamp)` of each operation
+        lookup: BTreeMap<u32, (u32, u32)>,
+    },
+    /// Every transaction up to and including `state` has been integrated by the sender
+    Acknowledgement {
+        /// The `(site id, timestamp)` of the last transaction integrated
+        state: (u32, u32),
+    },
+    /// Asks the other side to send a `Snapshot`
+    SnapshotRequest,
+    /// The full document, along with the history needed to keep synchronizing it
+    Snapshot {
+        /// The snapshot of the sender's document
+        snapshot: Snapshot,
+    },
+    /// The sender's document no longer matches the other side's, and needs repairing
+    Diverged {
+        /// The state of the sender's copy, used to elect the copy to keep
+        state: SiteState,
+    },
+    /// The sender's copy was elected, and the other side should replace its own with it
+    Repair {
+        /// The snapshot of the sender's document
+        snapshot: Snapshot,
+    },
+    /// The sender could not continue the session
+    Error {
+        /// A description of what went wrong
+        message: String,
+    },
+}
+
+/// Something that happened as the result of processing a message in a [`Session`](struct.Session.html)
+// Events are handled as soon as they are returned, so the transaction isn't worth boxing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Event {
+    /// The other side introduced itself
+    Connected {
+        /// The site id of the other side
+        site_id: u32,
+    },
+    /// A remote transaction was integrated.  It should now be applied to the local document
+    /// using `TransactionSequence::apply()`, and checked with `Session::verify()`
+    Remote(TransactionSequence),
+    /// A transaction was ignored, either because it had already been integrated or because
+    /// it will be part of a snapshot that is on its way
+    Duplicate,
+    /// The other side integrated our transactions up to and including this state
+    Acknowledged((u32, u32)),
+    /// The other side asked for a snapshot.  Reply with `Session::send_snapshot()`
+    SnapshotRequested,
+    /// A snapshot was received and the session's history was replaced by it.  The local
+    /// document should be replaced by the given contents
+    Snapshot(Vec<u8>),
+    /// The other side found that the documents have diverged.  Reply with `Session::repair()`
+    RepairRequested(SiteState),
+    /// Our document was repaired from the other side's copy, and any transactions it had not
+    /// seen were rebased and sent again.  The local document should be replaced by the given
+    /// contents
+    Repaired(Vec<u8>),
+    /// The other side reported an error and the session is closed
+    Closed(String),
+}
+
+/// The stage a [`Session`](struct.Session.html) has reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Waiting for the other side to say hello
+    Connecting,
+    /// Waiting for a snapshot we asked for
+    AwaitingSnapshot,
+    /// Transactions can be exchanged freely
+    Synchronized,
+    /// Waiting for the other side's copy of a document that has diverged.  Local transactions
+    /// are held back until the repair arrives.
+    Repairing,
+    /// The session has ended
+    Closed,
+}
+
+/// Represents an error that occured during a session
+#[derive(Debug)]
+pub enum SessionError {
+    /// The transport failed
+    Io(io::Error),
+    /// A transaction could not be processed or integrated
+    Sync(OTError),
+    /// The other side sent a message that was not expected at this point in the session
+    Protocol(String),
+}
+
+/// Drives an [`Engine`](../struct.Engine.html) over a transport.
+///
+/// Messages are only read when `process_next()` is called, so a session can be used with
+/// blocking or non-blocking transports alike.  The transport is flushed after each message
+/// written to it.
+///
+/// Sessions only synchronize bytes.  They check each transaction against the fingerprint of the
+/// sender's document, find local changes by diffing a file, and bring new sites up to date with
+/// a [`Snapshot`](../struct.Snapshot.html), all of which work on bytes.
+pub struct Session<T: Read + Write> {
+    transport: T,
+    engine: Engine,
+    stamper: TimeStamper,
+    site_id: u32,
+    state: SessionState,
+    remote_site_id: Option<u32>,
+
+    /// The most recent operation received from the other side, which is the state any
+    /// transaction we send has been built on
+    last_remote: Option<(u32, u32)>,
+
+    /// Transactions that have been sent but not yet acknowledged
+    #[allow(clippy::type_complexity)]
+    pending: VecDeque<((u32, u32), TransactionSequence, BTreeMap<u32, (u32, u32)>)>,
+
+    /// Transactions that have been sent and acknowledged, kept so that a side which loses its
+    /// history can be caught up with them as they were sent
+    #[allow(clippy::type_complexity)]
+    sent: VecDeque<((u32, u32), TransactionSequence, BTreeMap<u32, (u32, u32)>)>,
+
+    /// The state we sent when asking for a repair.  Both sides must elect a copy using the
+    /// states they exchanged, even if they have made more edits since.
+    repair_state: Option<SiteState>,
+}
+
+impl Message {
+    /// Compress this message and write to `writer`.  The output can then be expanded
+    /// back into an equivilent message using `expand_from()`
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        match *self {
+            Message::Hello { site_id, state, policy, ref tie_break, text_mode } => {
+                try!(writer.write_all(&[HELLO]));
+                NetworkEndian::write_u32(&mut int_buf, site_id);
+                try!(writer.write_all(&int_buf));
+                try!(compress_state(writer, state));
+                try!(policy.compress_to(writer));
+                try!(tiebreak::compress_to(tie_break, writer));
+                try!(writer.write_all(&[text_mode as u8]));
+            },
+            Message::Transaction { ref transaction, ref lookup } => {
+                try!(writer.write_all(&[TRANSACTION]));
+                try!(compress_lookup(writer, lookup));
+                try!(transaction.compress_to(writer));
+            },
+            Message::Acknowledgement { state } => {
+                try!(writer.write_all(&[ACKNOWLEDGEMENT]));
+                try!(compress_state(writer, Some(state)));
+            },
+            Message::SnapshotRequest => {
+                try!(writer.write_all(&[SNAPSHOT_REQUEST]));
+            },
+            Message::Snapshot { ref snapshot } => {
+                try!(writer.write_all(&[SNAPSHOT]));
+                try!(snapshot.compress_to(writer));
+            },
+            Message::Diverged { ref state } => {
+                try!(writer.write_all(&[DIVERGED]));
+                try!(state.compress_to(writer));
+            },
+            Message::Repair { ref snapshot } => {
+                try!(writer.write_all(&[REPAIR]));
+                try!(snapshot.compress_to(writer));
+            },
+            Message::Error { ref message } => {
+                try!(writer.write_all(&[ERROR]));
+                try!(compress_bytes(writer, message.as_bytes()));
+            },
+        }
+        writer.flush()
+    }
+
+    /// Expand a message from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Message> {
+        let mut tag_buf = [0;1];
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut tag_buf));
+        trace!("Reading message {}", tag_buf[0]);
+        match tag_buf[0] {
+            HELLO => {
+                try!(reader.read_exact(&mut int_buf));
+                let site_id = NetworkEndian::read_u32(&int_buf);
+                let state = try!(expand_state(reader));
+                let policy = try!(ConflictPolicy::expand_from(reader));
+                let tie_break = try!(tiebreak::expand_from(reader));
+                try!(reader.read_exact(&mut tag_buf));
+                Ok(Message::Hello {
+                    site_id: site_id,
+                    state: state,
+                    policy: policy,
+                    tie_break: tie_break,
+                    text_mode: tag_buf[0] == 1,
+                })
+            },
+            TRANSACTION => {
+                let lookup = try!(expand_lookup(reader));
+                let transaction = try!(TransactionSequence::expand_from(reader, Some(&lookup)));
+                Ok(Message::Transaction {
+                    transaction: transaction,
+                    lookup: lookup,
+                })
+            },
+            ACKNOWLEDGEMENT => {
+                match try!(expand_state(reader)) {
+                    Some(state) => Ok(Message::Acknowledgement { state: state }),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidData, "Acknowledgement without a state"))
+                }
+            },
+            SNAPSHOT_REQUEST => Ok(Message::SnapshotRequest),
+            SNAPSHOT => {
+                let snapshot = try!(Snapshot::expand_from(reader));
+                Ok(Message::Snapshot {
+                    snapshot: snapshot,
+                })
+            },
+            DIVERGED => {
+                let state = try!(SiteState::expand_from(reader));
+                Ok(Message::Diverged {
+                    state: state,
+                })
+            },
+            REPAIR => {
+                let snapshot = try!(Snapshot::expand_from(reader));
+                Ok(Message::Repair {
+                    snapshot: snapshot,
+                })
+            },
+            ERROR => {
+                let message = try!(expand_bytes(reader));
+                Ok(Message::Error {
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                })
+            },
+            tag => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown message type {}", tag)))
+        }
+    }
+}
+
+impl<T: Read + Write> Session<T> {
+    /// Creates a new session that synchronizes `engine` over `transport`.  `site_id` must
+    /// be the id the engine was created with.
+    pub fn new(transport: T, site_id: u32, engine: Engine, stamper: TimeStamper) -> Session<T> {
+        let last_remote = stamper.get_last_remote_timestamp(site_id);
+        Session {
+            transport: transport,
+            engine: engine,
+            stamper: stamper,
+            site_id: site_id,
+            state: SessionState::Connecting,
+            remote_site_id: None,
+            last_remote: last_remote,
+            pending: VecDeque::new(),
+            sent: VecDeque::new(),
+            repair_state: None,
+        }
+    }
+
+    /// Starts the session by introducing ourselves to the other side
+    pub fn start(&mut self) -> Result<(), SessionError> {
+        let state = self.stamper.get_last_timestamp().map(|(_local, remote)| remote);
+        let hello = Message::Hello {
+            site_id: self.site_id,
+            state: state,
+            policy: self.engine.get_conflict_policy(),
+            tie_break: self.engine.get_tie_break().describe(),
+            text_mode: self.engine.is_text_mode(),
+        };
+        self.send(&hello)
+    }
+
+    /// Asks the other side for a snapshot of the document.  This should be done by sites
+    /// that are joining with no history of their own.
+    pub fn request_snapshot(&mut self) -> Result<(), SessionError> {
+        try!(self.send(&Message::SnapshotRequest));
+        self.state = SessionState::AwaitingSnapshot;
+        Ok(())
+    }
+
+    /// Sends a snapshot of the document, which must match the history in the session's engine
+    pub fn send_snapshot(&mut self, document: &[u8]) -> Result<(), SessionError> {
+        let snapshot = Message::Snapshot {
+            snapshot: Snapshot::new(document.to_vec(), &self.engine, &self.stamper),
+        };
+        self.send(&snapshot)
+    }
+
+    /// Processes the changes found in a local file, and sends them to the other side.
+    /// `document` is the file's new contents, which the other side will check its own against.
+    /// Fails in text mode, as `Engine::process_diffs()` does.
+    pub fn send_diffs(&mut self, diff: Diff, document: &[u8]) -> Result<(), SessionError> {
+        let (mut transaction, lookup) = try!(self.engine.process_diffs(diff, &mut self.stamper));
+        transaction.set_fingerprint(Some(Fingerprint::of(document)));
+        self.send_transaction(transaction, lookup)
+    }
+
+    /// Like `send_diffs()`, but `base` is the file's contents before the changes were made, as
+    /// in `Engine::process_diffs_with_base()`, so the deletes record what they removed
+    pub fn send_diffs_with_base(&mut self, diff: Diff, base: &[u8], document: &[u8]) -> Result<(), SessionError> {
+        let (mut transaction, lookup) = self.engine.process_diffs_with_base(diff, base, &mut self.stamper);
+        transaction.set_fingerprint(Some(Fingerprint::of(document)));
+        self.send_transaction(transaction, lookup)
+    }
+
+    /// Sends a local transaction that has already been run through `Engine::process_transaction()`
+    pub fn send_transaction(&mut self, mut transaction: TransactionSequence, lookup: BTreeMap<u32, (u32, u32)>) -> Result<(), SessionError> {
+        if self.state == SessionState::Closed {
+            return Err(SessionError::Protocol("The session is closed".to_string()));
+        }
+        let state = match lookup.values().max_by_key(|&&(_, timestamp)| timestamp) {
+            Some(&state) => state,
+            None => return Ok(())
+        };
+        // The other side knows about our own history, so the transaction only has to say
+        // which of the other side's operations it was built on
+        transaction.set_last_timestamp(self.last_remote);
+        if self.state == SessionState::Repairing {
+            // It will be rebased onto the repaired document and sent then
+            self.pending.push_back((state, transaction, lookup));
+            return Ok(());
+        }
+        let message = Message::Transaction {
+            transaction: transaction,
+            lookup: lookup,
+        };
+        try!(self.send(&message));
+        if let Message::Transaction { transaction, lookup } = message {
+            self.pending.push_back((state, transaction, lookup));
+        }
+        Ok(())
+    }
+
+    /// Sends every transaction that has not been acknowledged yet.  This is done when the other
+    /// side says hello if it can't be caught up from the state it gives.  Transactions the other
+    /// side has already integrated will be ignored by it.
+    pub fn resend_pending(&mut self) -> Result<(), SessionError> {
+        let mut messages = Vec::with_capacity(self.pending.len());
+        for (_, transaction, lookup) in self.pending.iter() {
+            messages.push(Message::Transaction {
+                transaction: transaction.clone(),
+                lookup: lookup.clone(),
+            });
+        }
+        for message in messages.iter() {
+            try!(self.send(message));
+        }
+        Ok(())
+    }
+
+    /// Returns the session to the `Connecting` state, such as after the transport has been
+    /// reconnected.  Unacknowledged transactions are kept, and the other side is caught up once
+    /// the session has been started again and it has said hello.
+    pub fn reset(&mut self) {
+        self.state = SessionState::Connecting;
+        self.remote_site_id = None;
+        self.repair_state = None;
+    }
+
+    /// Adds a transaction to the unacknowledged transactions without sending it, such as one
+    /// restored from a previous run.  It will be sent when the other side says hello, if it
+    /// isn't caught up some other way, or by the next call to `resend_pending()`.
+    pub fn queue_pending(&mut self, transaction: TransactionSequence, lookup: BTreeMap<u32, (u32, u32)>) {
+        if let Some(&state) = lookup.values().max_by_key(|&&(_, timestamp)| timestamp) {
+            self.pending.push_back((state, transaction, lookup));
+        }
+    }
+
+    /// Reads and handles the next message from the other side
+    pub fn process_next(&mut self) -> Result<Event, SessionError> {
+        self.process_message(None)
+    }
+
+    /// Like `process_next()`, but a remote transaction is also applied to `document`, which must
+    /// be the document this session's history has produced, and checked against it, asking for
+    /// a repair if it doesn't match.  Under [`ConflictPolicy::Markers`](../enum.ConflictPolicy.html),
+    /// where it collided with our own edits is then marked as in
+    /// `Engine::integrate_remote_with_markers()`, and the markers are sent to the other side.
+    /// Under `ConflictPolicy::Resurrect`, the text we deleted around its inserts is put back and
+    /// sent in the same way.
+    /// The transaction in the `Event::Remote` has already been applied, and must not be again.
+    pub fn process_next_with_document(&mut self, document: &mut Vec<u8>) -> Result<Event, SessionError> {
+        self.process_message(Some(document))
+    }
+
+    fn process_message(&mut self, document: Option<&mut Vec<u8>>) -> Result<Event, SessionError> {
+        if self.state == SessionState::Closed {
+            return Err(SessionError::Protocol("The session is closed".to_string()));
+        }
+        let message = try!(Message::expand_from(&mut self.transport));
+        trace!("Session {} received {:?}", self.site_id, message);
+        match (self.state, message) {
+            (_, Message::Error { message }) => {
+                self.state = SessionState::Closed;
+                Ok(Event::Closed(message))
+            },
+            (SessionState::Connecting, Message::Hello { site_id, state, policy, tie_break, text_mode }) => {
+                try!(self.check_policy(policy, &tie_break, text_mode));
+                self.remote_site_id = Some(site_id);
+                self.state = SessionState::Synchronized;
+                try!(self.catch_up(state));
+                Ok(Event::Connected { site_id: site_id })
+            },
+            (SessionState::AwaitingSnapshot, Message::Hello { site_id, policy, tie_break, text_mode, .. }) => {
+                try!(self.check_policy(policy, &tie_break, text_mode));
+                self.remote_site_id = Some(site_id);
+                Ok(Event::Connected { site_id: site_id })
+            },
+            (SessionState::AwaitingSnapshot, Message::Transaction { .. }) |
+            (SessionState::AwaitingSnapshot, Message::Acknowledgement { .. }) => {
+                // Anything sent before our request will be part of the snapshot
+                Ok(Event::Duplicate)
+            },
+            (SessionState::AwaitingSnapshot, Message::Snapshot { snapshot }) => {
+                let (document, mut engine, stamper) = snapshot.into_parts(self.site_id);
+                // Both sides said hello with the same strategy, which may be one the snapshot can't restore
+                engine.set_tie_break(self.engine.get_tie_break().clone());
+                self.engine = engine;
+                self.stamper = stamper;
+                self.last_remote = self.stamper.get_last_remote_timestamp(self.site_id);
+                self.pending.clear();
+                self.sent.clear();
+                self.state = SessionState::Synchronized;
+                Ok(Event::Snapshot(document))
+            },
+            (SessionState::Synchronized, Message::Transaction { mut transaction, lookup }) => {
+                self.integrate(&mut transaction, &lookup, document).map(|duplicate| {
+                    if duplicate {
+                        Event::Duplicate
+                    } else {
+                        Event::Remote(transaction)
+                    }
+                })
+            },
+            (SessionState::Synchronized, Message::Acknowledgement { state }) => {
+                self.acknowledge(state);
+                Ok(Event::Acknowledged(state))
+            },
+            (SessionState::Synchronized, Message::SnapshotRequest) => Ok(Event::SnapshotRequested),
+            (SessionState::Synchronized, Message::Diverged { state }) |
+            (SessionState::Repairing, Message::Diverged { state }) => Ok(Event::RepairRequested(state)),
+            (SessionState::Repairing, Message::Transaction { .. }) => {
+                // Anything sent before the repair will be part of it
+                Ok(Event::Duplicate)
+            },
+            (SessionState::Repairing, Message::Acknowledgement { state }) => {
+                self.acknowledge(state);
+                Ok(Event::Acknowledged(state))
+            },
+            (SessionState::Repairing, Message::Repair { snapshot }) => {
+                let mut repair = Repair::new(snapshot, self.site_id);
+                repair.set_tie_break(self.engine.get_tie_break().clone());
+                let mut rebased = Vec::new();
+                for (_, transaction, lookup) in self.pending.iter() {
+                    if let Some(transaction) = try!(repair.rebase(transaction, lookup)) {
+                        rebased.push(transaction);
+                    }
+                }
+                let (document, engine, stamper) = repair.into_parts();
+                self.engine = engine;
+                self.stamper = stamper;
+                self.last_remote = self.stamper.get_last_remote_timestamp(self.site_id);
+                self.pending.clear();
+                self.sent.clear();
+                self.repair_state = None;
+                self.state = SessionState::Synchronized;
+                for (transaction, lookup) in rebased {
+                    try!(self.send_transaction(transaction, lookup));
+                }
+                Ok(Event::Repaired(document))
+            },
+            (state, message) => {
+                let description = format!("Unexpected message {:?} while {:?}", message, state);
+                let _ = self.send(&Message::Error { message: description.clone() });
+                self.state = SessionState::Closed;
+                Err(SessionError::Protocol(description))
+            }
+        }
+    }
+
+    /// Tells the other side that `document` has diverged from its copy, such as after
+    /// `verify()` failed.  The two sides elect a copy to keep, and if it isn't ours the
+    /// session will produce an `Event::Repaired` with the other side's copy.
+    pub fn request_repair(&mut self, document: &[u8]) -> Result<(), SessionError> {
+        if self.state == SessionState::Closed {
+            return Err(SessionError::Protocol("The session is closed".to_string()));
+        }
+        let state = SiteState::new(self.site_id, document, &self.stamper);
+        try!(self.send(&Message::Diverged { state: state.clone() }));
+        self.repair_state = Some(state);
+        self.state = SessionState::Repairing;
+        Ok(())
+    }
+
+    /// Answers an `Event::RepairRequested`.  If our copy is elected, a snapshot of `document`
+    /// is sent to the other side, otherwise we wait for the other side's copy.
+    pub fn repair(&mut self, remote: &SiteState, document: &[u8]) -> Result<(), SessionError> {
+        let state = match self.repair_state {
+            Some(ref state) if self.state == SessionState::Repairing => state.clone(),
+            _ => SiteState::new(self.site_id, document, &self.stamper)
+        };
+        if state.elect(remote) == self.site_id {
+            let snapshot = Message::Repair {
+                snapshot: Snapshot::new(document.to_vec(), &self.engine, &self.stamper),
+            };
+            try!(self.send(&snapshot));
+            // The other side is rebuilt from the snapshot, which includes everything we sent
+            self.pending.clear();
+            self.sent.clear();
+            self.repair_state = None;
+            self.state = SessionState::Synchronized;
+            Ok(())
+        } else if self.state != SessionState::Repairing {
+            self.request_repair(document)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks the local document against the fingerprint attached to a transaction from an
+    /// `Event::Remote`, once the transaction has been applied to it
+    pub fn verify(&self, transaction: &TransactionSequence, document: &[u8]) -> Result<(), SessionError> {
+        transaction.verify(document, self.last_remote).map_err(SessionError::Sync)
+    }
+
+    /// Gets the stage the session has reached
+    #[inline]
+    pub fn get_state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Gets the site id of the other side, once it has said hello
+    #[inline]
+    pub fn get_remote_site_id(&self) -> Option<u32> {
+        self.remote_site_id
+    }
+
+    /// Gets the number of transactions sent that have not been acknowledged
+    #[inline]
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Gets the transactions sent that have not been acknowledged, oldest first
+    #[allow(clippy::type_complexity)]
+    pub fn get_pending(&self) -> Vec<(&TransactionSequence, &BTreeMap<u32, (u32, u32)>)> {
+        self.pending.iter().map(|(_, transaction, lookup)| (transaction, lookup)).collect()
+    }
+
+    /// Gets the transport the session communicates over
+    #[inline]
+    pub fn get_transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Gets the engine driven by this session
+    #[inline]
+    pub fn get_engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Gets the timestamps used by this session
+    #[inline]
+    pub fn get_stamper(&self) -> &TimeStamper {
+        &self.stamper
+    }
+
+    /// Ends the session, returning the transport, engine and timestamps so that they can be
+    /// persisted or reused
+    pub fn into_parts(self) -> (T, Engine, TimeStamper) {
+        (self.transport, self.engine, self.stamper)
+    }
+
+    /// Integrates a remote transaction and acknowledges it, applying it to `document` as
+    /// `process_next_with_document()` does if one is given.  Returns true if the transaction
+    /// had already been integrated.
+    fn integrate(&mut self, transaction: &mut TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>, mut document: Option<&mut Vec<u8>>) -> Result<bool, SessionError> {
+        let state = match lookup.values().max_by_key(|&&(_, timestamp)| timestamp) {
+            Some(&state) => state,
+            None => return Ok(true)
+        };
+        let duplicate = lookup.values().all(|&(site_id, timestamp)| self.stamper.get_local_timestamp_for(site_id, timestamp).is_some());
+        if duplicate {
+            try!(self.send(&Message::Acknowledgement { state: state }));
+            return Ok(true);
+        }
+        // Make sure every remote timestamp has a local counterpart before integrating.  They are
+        // only kept if it works, so that the transaction isn't taken for a duplicate when resent.
+        let mut stamper = self.stamper.clone();
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            stamper.stamp_remote(site_id, timestamp);
+        }
+        let (markers, received) = match document {
+            Some(ref mut document) => {
+                let base = document.clone();
+                let markers = try!(self.engine.integrate_remote_with_markers(transaction, lookup, document, &mut stamper));
+                // The sender's fingerprint describes the document before our markers went in
+                (markers, Some(transaction.apply_to_bytes(&base)))
+            },
+            None => {
+                try!(self.engine.integrate_remote(transaction, lookup, &mut stamper));
+                (None, None)
+            }
+        };
+        self.stamper = stamper;
+        self.last_remote = Some(state);
+        try!(self.send(&Message::Acknowledgement { state: state }));
+        if let (Some(document), Some(received)) = (document, received) {
+            if self.verify(transaction, &received).is_err() {
+                try!(self.request_repair(document));
+            }
+        }
+        if let Some((markers, lookup)) = markers {
+            try!(self.send_transaction(markers, lookup));
+        }
+        Ok(false)
+    }
+
+    /// Sends the other side everything we have done since `state`, the most recent state it said
+    /// hello with.  This can only be done if all of it was done here, since the other side may
+    /// have heard of anything else some other way.  Otherwise, or if we don't know `state`, the
+    /// unacknowledged transactions are sent again instead.  The transactions are sent as they
+    /// were first sent if we still have all of them, so that their metadata, formats, anchors,
+    /// moves and fingerprints go too, and otherwise rebuilt from the history as one transaction.
+    fn catch_up(&mut self, state: Option<(u32, u32)>) -> Result<(), SessionError> {
+        let transaction = match self.engine.get_operations_since(state, &self.stamper) {
+            Ok(transaction) => transaction,
+            Err(_) => return self.resend_pending()
+        };
+        let lookup = self.stamper.get_timestamps_for(&transaction);
+        if lookup.is_empty() {
+            return Ok(());
+        }
+        if lookup.values().any(|&(site_id, _)| site_id != self.site_id) {
+            return self.resend_pending();
+        }
+        // Our own timestamps are the same here and at the other side
+        let since = match state {
+            Some((site_id, timestamp)) => self.stamper.get_local_timestamp_for(site_id, timestamp).unwrap_or(0),
+            None => 0
+        };
+        let stored: Vec<_> = self.sent.iter().chain(self.pending.iter())
+            .filter(|&&((_, timestamp), _, _)| timestamp > since)
+            .collect();
+        let covered = lookup.values().all(|state| stored.iter().any(|&(_, _, stored_lookup)| stored_lookup.values().any(|stored_state| stored_state == state)));
+        if covered {
+            let messages: Vec<_> = stored.into_iter().map(|(_, transaction, lookup)| Message::Transaction {
+                transaction: transaction.clone(),
+                lookup: lookup.clone(),
+            }).collect();
+            for message in messages.iter() {
+                try!(self.send(message));
+            }
+            return Ok(());
+        }
+        self.send(&Message::Transaction {
+            transaction: transaction,
+            lookup: lookup,
+        })
+    }
+
+    /// Forgets the pending transactions up to and including `state`.  Only our own transactions
+    /// are pending, so an acknowledgement of some other site's transaction forgets nothing.
+    fn acknowledge(&mut self, state: (u32, u32)) {
+        while self.pending.front().is_some_and(|&(pending_state, _, _)| pending_state.0 == state.0 && pending_state.1 <= state.1) {
+            if let Some(sent) = self.pending.pop_front() {
+                self.sent.push_back(sent);
+            }
+        }
+    }
+
+    /// Closes the session if the other side settles conflicts, breaks ties or splits text
+    /// differently, since the documents would drift apart as soon as the two sides disagreed
+    fn check_policy(&mut self, policy: ConflictPolicy, tie_break: &[u8], text_mode: bool) -> Result<(), SessionError> {
+        let description = if policy != self.engine.get_conflict_policy() {
+            format!("Conflict policy {:?} does not match {:?}", policy, self.engine.get_conflict_policy())
+        } else if tie_break != &self.engine.get_tie_break().describe()[..] {
+            format!("Tie-breaking strategy {:?} does not match {:?}", tie_break, self.engine.get_tie_break())
+        } else if text_mode != self.engine.is_text_mode() {
+            format!("Text mode {} does not match {}", text_mode, self.engine.is_text_mode())
+        } else {
+            return Ok(());
+        };
+        let _ = self.send(&Message::Error { message: description.clone() });
+        self.state = SessionState::Closed;
+        Err(SessionError::Protocol(description))
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), SessionError> {
+        trace!("Session {} sending {:?}", self.site_id, message);
+        try!(message.compress_to(&mut self.transport));
+        // Lets transports that buffer or frame their output know where each message ends
+        self.transport.flush().map_err(SessionError::Io)
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(error: io::Error) -> SessionError {
+        SessionError::Io(error)
+    }
+}
+
+impl From<OTError> for SessionError {
+    fn from(error: OTError) -> SessionError {
+        SessionError::Sync(error)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SessionError::Io(ref error) => write!(f, "Transport error: {}", error),
+            SessionError::Sync(ref error) => write!(f, "Synchronization error: {:?}", error.kind),
+            SessionError::Protocol(ref message) => write!(f, "Protocol error: {}", message),
+        }
+    }
+}
+
+impl error::Error for SessionError {
+    fn description(&self) -> &str {
+        match *self {
+            SessionError::Io(_) => "transport error",
+            SessionError::Sync(_) => "synchronization error",
+            SessionError::Protocol(_) => "protocol error",
+        }
+    }
+}
+
+impl<T: Read + Write> fmt::Debug for Session<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(writeln!(f, "Session: {} ({:?})", self.site_id, self.state));
+        try!(writeln!(f, "Remote: {:?}", self.remote_site_id));
+        writeln!(f, "Pending: {}", self.pending.len())
+    }
+}
+
+fn compress_state<W: Write>(writer: &mut W, state: Option<(u32, u32)>) -> io::Result<()> {
+    let mut int_buf = [0;4];
+    if let Some((site_id, timestamp)) = state {
+        try!(writer.write_all(&[1]));
+        NetworkEndian::write_u32(&mut int_buf, site_id);
+        try!(writer.write_all(&int_buf));
+        NetworkEndian::write_u32(&mut int_buf, timestamp);
+        try!(writer.write_all(&int_buf));
+    } else {
+        try!(writer.write_all(&[0]));
+    }
+    Ok(())
+}
+
+fn expand_state<R: Read>(reader: &mut R) -> io::Result<Option<(u32, u32)>> {
+    let mut bool_buf = [0;1];
+    let mut int_buf = [0;4];
+    try!(reader.read_exact(&mut bool_buf));
+    if bool_buf[0] == 1 {
+        try!(reader.read_exact(&mut int_buf));
+        let site_id = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let timestamp = NetworkEndian::read_u32(&int_buf);
+        Ok(Some((site_id, timestamp)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn compress_lookup<W: Write>(writer: &mut W, lookup: &BTreeMap<u32, (u32, u32)>) -> io::Result<()> {
+    let mut int_buf = [0;4];
+    NetworkEndian::write_u32(&mut int_buf, lookup.len() as u32);
+    try!(writer.write_all(&int_buf));
+    for (&local, &(site_id, remote)) in lookup.iter() {
+        NetworkEndian::write_u32(&mut int_buf, local);
+        try!(writer.write_all(&int_buf));
+        NetworkEndian::write_u32(&mut int_buf, site_id);
+        try!(writer.write_all(&int_buf));
+        NetworkEndian::write_u32(&mut int_buf, remote);
+        try!(writer.write_all(&int_buf));
+    }
+    Ok(())
+}
+
+fn expand_lookup<R: Read>(reader: &mut R) -> io::Result<BTreeMap<u32, (u32, u32)>> {
+    let mut int_buf = [0;4];
+    try!(reader.read_exact(&mut int_buf));
+    let lookup_len = NetworkEndian::read_u32(&int_buf);
+    let mut lookup = BTreeMap::new();
+    for _ in 0..lookup_len {
+        try!(reader.read_exact(&mut int_buf));
+        let local = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let site_id = NetworkEndian::read_u32(&int_buf);
+        try!(reader.read_exact(&mut int_buf));
+        let remote = NetworkEndian::read_u32(&int_buf);
+        lookup.insert(local, (site_id, remote));
+    }
+    Ok(lookup)
+}
+
+fn compress_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let mut long_buf = [0;8];
+    NetworkEndian::write_u64(&mut long_buf, bytes.len() as u64);
+    try!(writer.write_all(&long_buf));
+    writer.write_all(bytes)
+}
+
+fn expand_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut long_buf = [0;8];
+    try!(reader.read_exact(&mut long_buf));
+    u8::read_from(reader, NetworkEndian::read_u64(&long_buf) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, Session, SessionState, SessionError, Event};
+    use engine::{Engine, TransactionSequence, TimeStamper, Snapshot};
+    use fingerprint::Fingerprint;
+    use metadata::Metadata;
+    use repair::SiteState;
+    use policy::ConflictPolicy;
+    use tiebreak::TimestampOrder;
+    use ErrorKind;
+    use operations::{InsertOperation, DeleteOperation};
+    use test_utils;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    /// One end of an in-memory, bidirectional byte stream
+    struct Pipe {
+        incoming: Rc<RefCell<VecDeque<u8>>>,
+        outgoing: Rc<RefCell<VecDeque<u8>>>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (Pipe { incoming: a.clone(), outgoing: b.clone() }, Pipe { incoming: b, outgoing: a })
+    }
+
+    impl Pipe {
+        fn has_data(&self) -> bool {
+            !self.incoming.borrow().is_empty()
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut incoming = self.incoming.borrow_mut();
+            let mut read = 0;
+            while read < buf.len() {
+                match incoming.pop_front() {
+                    Some(byte) => buf[read] = byte,
+                    None => break
+                }
+                read += 1;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.borrow_mut().extend(buf.iter().cloned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn local_edit(session: &mut Session<Pipe>, site_id: u32, document: &mut Vec<u8>, inserts: Vec<(u64, &'static str)>, deletes: Vec<(u64, u64)>) {
+        let (mut transaction, lookup) = test_utils::local_edit(&mut session.engine, &mut session.stamper, site_id, document, inserts, deletes);
+        transaction.set_fingerprint(Some(Fingerprint::of(document)));
+        session.send_transaction(transaction, lookup).unwrap();
+    }
+
+    fn drain(session: &mut Session<Pipe>, document: &mut Vec<u8>) -> Vec<Event> {
+        let mut events = vec![];
+        while session.transport.has_data() {
+            let event = session.process_next().unwrap();
+            match event {
+                Event::Remote(ref transaction) => {
+                    *document = transaction.apply_to_bytes(document);
+                    if session.verify(transaction, document).is_err() {
+                        session.request_repair(document).unwrap();
+                    }
+                },
+                Event::Snapshot(ref snapshot) => *document = snapshot.clone(),
+                Event::RepairRequested(ref state) => session.repair(state, document).unwrap(),
+                Event::Repaired(ref repaired) => *document = repaired.clone(),
+                _ => {}
+            }
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut lookup = BTreeMap::new();
+        lookup.insert(3, (1, 3));
+        let mut transaction = TransactionSequence::new(Some((2, 1)),
+            vec![InsertOperation::new(2, "Some text".bytes().collect(), 3, 1)].into_iter().collect(),
+            vec![DeleteOperation::new(4, 2, 3, 1)].into_iter().collect());
+        transaction.set_fingerprint(Some(Fingerprint::new(9, 1234)));
+        let messages = vec![
+            Message::Hello { site_id: 4, state: Some((2, 7)), policy: ConflictPolicy::KeepInsert, tie_break: vec![0], text_mode: false },
+            Message::Hello { site_id: 4, state: None, policy: ConflictPolicy::DropInsert, tie_break: vec![2, 0, 0, 0, 1, 0, 0, 0, 5], text_mode: true },
+            Message::Transaction { transaction: transaction, lookup: lookup },
+            Message::Acknowledgement { state: (1, 3) },
+            Message::SnapshotRequest,
+            Message::Snapshot { snapshot: Snapshot::new("Hi".bytes().collect(), &Engine::new(2), &TimeStamper::new()) },
+            Message::Diverged { state: SiteState::new(3, b"Hello", &TimeStamper::new()) },
+            Message::Repair { snapshot: Snapshot::new("Hi".bytes().collect(), &Engine::new(2), &TimeStamper::new()) },
+            Message::Error { message: "Something went wrong".to_string() },
+        ];
+        for message in messages {
+            let mut buffer = Vec::new();
+            message.compress_to(&mut buffer).unwrap();
+            let expanded = Message::expand_from(&mut &buffer[..]).unwrap();
+            assert_eq!(format!("{:?}", expanded), format!("{:?}", message));
+        }
+        assert!(Message::expand_from(&mut &[9u8][..]).is_err());
+
+        // A length longer than the message is an error, not an allocation of that length
+        let mut buffer = Vec::new();
+        Message::Error { message: "Something went wrong".to_string() }.compress_to(&mut buffer).unwrap();
+        for byte in buffer[1..9].iter_mut() {
+            *byte = 0xFF;
+        }
+        assert!(Message::expand_from(&mut &buffer[..]).is_err());
+    }
+
+    #[test]
+    fn session_exchanges_transactions() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document1 = Vec::new();
+        let mut document2 = Vec::new();
+
+        session1.start().unwrap();
+        session2.start().unwrap();
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        assert_eq!(session1.get_state(), SessionState::Synchronized);
+        assert_eq!(session2.get_remote_site_id(), Some(1));
+
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "The quick brown fox")], vec![]);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+        assert_eq!(session1.pending_len(), 0);
+
+        // Concurrent edits on both sides
+        local_edit(&mut session1, 1, &mut document1, vec![(4, "very ")], vec![(0, 4)]);
+        local_edit(&mut session2, 2, &mut document2, vec![(19, " jumps")], vec![(10, 6)]);
+        assert_eq!(session1.pending_len(), 1);
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+        assert_eq!(document1, document2);
+        assert_eq!(document1, b"very quick fox jumps");
+        assert_eq!(session1.pending_len(), 0);
+        assert_eq!(session2.pending_len(), 0);
+
+        // Sending a transaction again doesn't apply it twice
+        local_edit(&mut session2, 2, &mut document2, vec![(0, "A ")], vec![]);
+        session2.resend_pending().unwrap();
+        let events = drain(&mut session1, &mut document1);
+        assert!(matches!(events[1], Event::Duplicate));
+        drain(&mut session2, &mut document2);
+        assert_eq!(document1, document2);
+    }
+
+    #[test]
+    fn session_bootstraps_from_snapshot() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut document1 = Vec::new();
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "Hello world")], vec![(0, 1)]);
+        session1.pending.clear();
+        // Throw away the transaction, the new site will get it from the snapshot
+        session1.transport.incoming.borrow_mut().clear();
+        session1.transport.outgoing.borrow_mut().clear();
+
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document2 = Vec::new();
+        session1.start().unwrap();
+        session2.start().unwrap();
+        session2.request_snapshot().unwrap();
+        assert_eq!(session2.get_state(), SessionState::AwaitingSnapshot);
+        let events = drain(&mut session1, &mut document1);
+        assert!(matches!(events[1], Event::SnapshotRequested));
+        session1.send_snapshot(&document1.clone()).unwrap();
+        drain(&mut session2, &mut document2);
+        assert_eq!(session2.get_state(), SessionState::Synchronized);
+        assert_eq!(document2, b"ello world");
+
+        local_edit(&mut session2, 2, &mut document2, vec![(10, "!")], vec![]);
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "H")], vec![]);
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+        assert_eq!(document1, b"Hello world!");
+        assert_eq!(document2, b"Hello world!");
+    }
+
+    #[test]
+    fn session_resumes_after_reconnecting() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document1 = Vec::new();
+        let mut document2 = Vec::new();
+        session1.start().unwrap();
+        session2.start().unwrap();
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "The quick brown fox")], vec![]);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+
+        // Both sides edit while the connection is down, and site 2 is restarted from its parts
+        local_edit(&mut session2, 2, &mut document2, vec![(19, " jumps")], vec![]);
+        local_edit(&mut session1, 1, &mut document1, vec![(4, "very ")], vec![(0, 4)]);
+        let (pipe1, pipe2) = pipe();
+        *session1.get_transport_mut() = pipe1;
+        session1.reset();
+        let pending: Vec<_> = session2.get_pending().into_iter().map(|(transaction, lookup)| {
+            (transaction.clone(), lookup.clone())
+        }).collect();
+        let (_, engine, stamper) = session2.into_parts();
+        let mut session2 = Session::new(pipe2, 2, engine, stamper);
+        for (transaction, lookup) in pending {
+            session2.queue_pending(transaction, lookup);
+        }
+
+        // Site 1 doesn't know the state site 2 says hello with, so it sends what is pending,
+        // while site 2 catches site 1 up from the state it says hello with
+        session1.start().unwrap();
+        session2.start().unwrap();
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        assert_eq!(document1, b"very quick brown fox jumps");
+        assert_eq!(document1, document2);
+        assert_eq!(session1.pending_len(), 0);
+        assert_eq!(session2.pending_len(), 0);
+    }
+
+    #[test]
+    fn session_catches_up_a_site_that_lost_history() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document1 = Vec::new();
+        let mut document2 = Vec::new();
+        session1.start().unwrap();
+        session2.start().unwrap();
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "The quick brown fox")], vec![]);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+
+        // Site 2 saves its state, then integrates and acknowledges an edit before it is lost
+        let mut saved = Vec::new();
+        session2.engine.compress_to(&mut saved).unwrap();
+        session2.stamper.compress_to(&mut saved).unwrap();
+        let saved_document = document2.clone();
+        let (mut transaction, lookup) = test_utils::local_edit(&mut session1.engine, &mut session1.stamper, 1, &mut document1, vec![(4, "very ")], vec![(0, 4)]);
+        let mut metadata = Metadata::new();
+        metadata.set_author(Some("Ada".to_string()));
+        transaction.set_metadata(Some(metadata));
+        transaction.set_fingerprint(Some(Fingerprint::of(&document1)));
+        session1.send_transaction(transaction, lookup).unwrap();
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+        assert_eq!(session1.pending_len(), 0);
+
+        let (pipe1, pipe2) = pipe();
+        *session1.get_transport_mut() = pipe1;
+        session1.reset();
+        let mut reader = &saved[..];
+        let engine = Engine::expand_from(&mut reader, 2).unwrap();
+        let stamper = TimeStamper::expand_from(&mut reader).unwrap();
+        let mut session2 = Session::new(pipe2, 2, engine, stamper);
+        let mut document2 = saved_document;
+
+        session1.start().unwrap();
+        session2.start().unwrap();
+        drain(&mut session1, &mut document1);
+        let events = drain(&mut session2, &mut document2);
+        match events[1] {
+            Event::Remote(ref transaction) => {
+                assert_eq!(transaction.get_metadata().and_then(|metadata| metadata.get_author()), Some("Ada"));
+                assert!(transaction.get_fingerprint().is_some());
+            },
+            _ => panic!("Expected the lost edit")
+        }
+        drain(&mut session1, &mut document1);
+        assert_eq!(document2, b"very quick brown fox");
+        assert_eq!(document1, document2);
+    }
+
+    #[test]
+    fn failed_transactions_can_be_sent_again() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document1 = Vec::new();
+        let mut document2 = Vec::new();
+        session1.start().unwrap();
+        session2.start().unwrap();
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+
+        let (first, first_lookup) = test_utils::local_edit(&mut session2.engine, &mut session2.stamper, 2, &mut document2, vec![(0, "Hello")], vec![]);
+        let (second, second_lookup) = test_utils::local_edit(&mut session2.engine, &mut session2.stamper, 2, &mut document2, vec![(5, " world")], vec![]);
+        let second = Message::Transaction { transaction: second, lookup: second_lookup };
+
+        // The second edit arrives before the state it was made on, so it can't be integrated yet
+        session2.send(&second).unwrap();
+        assert!(matches!(session1.process_next(), Err(SessionError::Sync(_))));
+        session2.send(&Message::Transaction { transaction: first, lookup: first_lookup }).unwrap();
+        session2.send(&second).unwrap();
+        let events = drain(&mut session1, &mut document1);
+        assert!(events.iter().all(|event| matches!(*event, Event::Remote(_))));
+        assert_eq!(document1, b"Hello world");
+    }
+
+    #[test]
+    fn acknowledgements_are_matched_by_site() {
+        let (pipe1, _pipe2) = pipe();
+        let mut session = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut document = Vec::new();
+        session.state = SessionState::Synchronized;
+        local_edit(&mut session, 1, &mut document, vec![(0, "Hello")], vec![]);
+        local_edit(&mut session, 1, &mut document, vec![(5, " world")], vec![]);
+        assert_eq!(session.pending_len(), 2);
+
+        // Another site's timestamp says nothing about ours, however late it is
+        session.acknowledge((2, 9));
+        assert_eq!(session.pending_len(), 2);
+        session.acknowledge((1, 0));
+        assert_eq!(session.pending_len(), 1);
+        session.acknowledge((1, 1));
+        assert_eq!(session.pending_len(), 0);
+    }
+
+    #[test]
+    fn session_detects_divergence() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document1 = Vec::new();
+        let mut document2 = Vec::new();
+        session1.start().unwrap();
+        session2.start().unwrap();
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "The quick brown fox")], vec![]);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+
+        // Something other than the engine changes the second site's copy
+        document2[4] = b'Q';
+        local_edit(&mut session1, 1, &mut document1, vec![(19, " jumps")], vec![]);
+        match session2.process_next().unwrap() {
+            Event::Remote(transaction) => {
+                document2 = transaction.apply_to_bytes(&document2);
+                match session2.verify(&transaction, &document2) {
+                    Err(SessionError::Sync(error)) => match error.kind {
+                        ErrorKind::Diverged { state, expected, found } => {
+                            assert_eq!(state, Some((1, 1)));
+                            assert_eq!(expected, Fingerprint::of(&document1));
+                            assert_eq!(found, Fingerprint::of(&document2));
+                        },
+                        kind => panic!("Unexpected error {:?}", kind)
+                    },
+                    result => panic!("Divergence was not detected: {:?}", result)
+                }
+            },
+            e => panic!("Unexpected event {:?}", e)
+        }
+    }
+
+    #[test]
+    fn diverged_sessions_are_repaired() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        let mut document1 = Vec::new();
+        let mut document2 = Vec::new();
+        session1.start().unwrap();
+        session2.start().unwrap();
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "The quick brown fox")], vec![]);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+
+        document2[4] = b'Q';
+        local_edit(&mut session1, 1, &mut document1, vec![(19, " jumps")], vec![]);
+        drain(&mut session2, &mut document2);
+        assert_eq!(session2.get_state(), SessionState::Repairing);
+
+        // Edits made while waiting for the repair are held back, then rebased onto it
+        local_edit(&mut session2, 2, &mut document2, vec![(25, "!")], vec![]);
+        assert_eq!(document2, b"The Quick brown fox jumps!");
+        drain(&mut session1, &mut document1);
+        let events = drain(&mut session2, &mut document2);
+        assert!(events.iter().any(|event| matches!(*event, Event::Repaired(_))));
+        assert_eq!(session2.get_state(), SessionState::Synchronized);
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        assert_eq!(document1, b"The quick brown fox jumps!");
+        assert_eq!(document1, document2);
+        assert_eq!(session1.pending_len(), 0);
+        assert_eq!(session2.pending_len(), 0);
+
+        // The sites carry on as usual afterwards
+        local_edit(&mut session2, 2, &mut document2, vec![(4, "very ")], vec![]);
+        local_edit(&mut session1, 1, &mut document1, vec![(0, "See ")], vec![]);
+        drain(&mut session1, &mut document1);
+        drain(&mut session2, &mut document2);
+        drain(&mut session1, &mut document1);
+        assert_eq!(document1, b"See The very quick brown fox jumps!");
+        assert_eq!(document1, document2);
+    }
+
+    #[test]
+    fn unexpected_messages_close_the_session() {
+        let (pipe1, pipe2) = pipe();
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, Engine::new(2), TimeStamper::new());
+        session2.request_snapshot().unwrap();
+        assert!(session1.process_next().is_err());
+        assert_eq!(session1.get_state(), SessionState::Closed);
+        match session2.process_next().unwrap() {
+            Event::Closed(_) => {},
+            e => panic!("Unexpected event {:?}", e)
+        }
+    }
+
+    #[test]
+    fn sessions_must_agree_on_the_conflict_policy() {
+        let (pipe1, pipe2) = pipe();
+        let mut engine2 = Engine::new(2);
+        engine2.set_conflict_policy(ConflictPolicy::DropInsert);
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, engine2, TimeStamper::new());
+        session1.start().unwrap();
+        session2.start().unwrap();
+        match session1.process_next() {
+            Err(SessionError::Protocol(_)) => {},
+            result => panic!("Unexpected result {:?}", result)
+        }
+        assert_eq!(session1.get_state(), SessionState::Closed);
+        // The other side turns down our hello as well
+        assert!(session2.process_next().is_err());
+        assert_eq!(session2.get_state(), SessionState::Closed);
+    }
+
+    #[test]
+    fn sessions_must_agree_on_text_mode() {
+        let (pipe1, pipe2) = pipe();
+        let mut engine2 = Engine::new(2);
+        engine2.set_text_mode(true);
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, engine2, TimeStamper::new());
+        session1.start().unwrap();
+        session2.start().unwrap();
+        match session1.process_next() {
+            Err(SessionError::Protocol(_)) => {},
+            result => panic!("Unexpected result {:?}", result)
+        }
+        assert_eq!(session1.get_state(), SessionState::Closed);
+    }
+
+    #[test]
+    fn sessions_must_agree_on_the_tie_break() {
+        let (pipe1, pipe2) = pipe();
+        let mut engine2 = Engine::new(2);
+        engine2.set_tie_break(Arc::new(TimestampOrder));
+        let mut session1 = Session::new(pipe1, 1, Engine::new(1), TimeStamper::new());
+        let mut session2 = Session::new(pipe2, 2, engine2, TimeStamper::new());
+        session1.start().unwrap();
+        session2.start().unwrap();
+        match session1.process_next() {
+            Err(SessionError::Protocol(_)) => {},
+            result => panic!("Unexpected result {:?}", result)
+        }
+        assert_eq!(session1.get_state(), SessionState::Closed);
+    }
+}
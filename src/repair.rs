@@ -0,0 +1,258 @@
+use std::collections::btree_map::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use byteorder::{NetworkEndian, ByteOrder};
+use engine::{Engine, TransactionSequence, TimeStamper, Snapshot};
+use fingerprint::Fingerprint;
+use operations::Operation;
+use tiebreak::TieBreak;
+use {OTError, ErrorKind as Kind};
+
+/// What a site knows about its copy of the document, exchanged when two sites find that
+/// their copies have diverged so that they can agree on which copy to keep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteState {
+    site_id: u32,
+    fingerprint: Fingerprint,
+    operations: u32,
+}
+
+/// Rebuilds a site that has diverged from a snapshot of the authoritative copy.
+///
+/// The snapshot replaces the site's document and history.  Any local transactions that the
+/// authoritative site had not integrated yet are then passed to `rebase()`, which integrates
+/// them into the new history as if they had just arrived from another site, and gives back
+/// the transactions to send in their place.
+pub struct Repair {
+    site_id: u32,
+    document: Vec<u8>,
+    engine: Engine,
+    stamper: TimeStamper,
+}
+
+impl SiteState {
+    /// Describes the state of `document` at the given site, which has the history in `stamper`
+    pub fn new(site_id: u32, document: &[u8], stamper: &TimeStamper) -> SiteState {
+        SiteState {
+            site_id: site_id,
+            fingerprint: Fingerprint::of(document),
+            operations: stamper.get_stamp_count() as u32,
+        }
+    }
+
+    /// Gets the id of the site
+    #[inline]
+    pub fn get_site_id(&self) -> u32 {
+        self.site_id
+    }
+
+    /// Gets the fingerprint of the site's document
+    #[inline]
+    pub fn get_fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// Gets the number of operations the site has integrated
+    #[inline]
+    pub fn get_operations(&self) -> u32 {
+        self.operations
+    }
+
+    /// Elects the site whose copy should be kept.  The site that has integrated the most
+    /// operations wins, since it has the least to lose, and ties go to the lowest site id.
+    /// Both sites come to the same answer no matter which of them asks.
+    pub fn elect(&self, other: &SiteState) -> u32 {
+        if self.operations > other.operations || (self.operations == other.operations && self.site_id < other.site_id) {
+            self.site_id
+        } else {
+            other.site_id
+        }
+    }
+
+    /// Compress this state and write to `writer`.  The output can then be expanded
+    /// back into an equivilent state using `expand_from()`
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, self.site_id);
+        try!(writer.write_all(&int_buf));
+        try!(self.fingerprint.compress_to(writer));
+        NetworkEndian::write_u32(&mut int_buf, self.operations);
+        writer.write_all(&int_buf)
+    }
+
+    /// Expand a state from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<SiteState> {
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut int_buf));
+        let site_id = NetworkEndian::read_u32(&int_buf);
+        let fingerprint = try!(Fingerprint::expand_from(reader));
+        try!(reader.read_exact(&mut int_buf));
+        Ok(SiteState {
+            site_id: site_id,
+            fingerprint: fingerprint,
+            operations: NetworkEndian::read_u32(&int_buf),
+        })
+    }
+}
+
+impl Repair {
+    /// Starts repairing `site_id` from a snapshot of the authoritative site
+    pub fn new(snapshot: Snapshot, site_id: u32) -> Repair {
+        let (document, engine, stamper) = snapshot.into_parts(site_id);
+        Repair {
+            site_id: site_id,
+            document: document,
+            engine: engine,
+            stamper: stamper,
+        }
+    }
+
+    /// Gives the repaired engine `tie_break`, which `Snapshot::into_parts()` can't restore if it
+    /// is a strategy from outside this crate.  It must be the strategy the snapshot was taken with.
+    #[inline]
+    pub fn set_tie_break(&mut self, tie_break: Arc<dyn TieBreak>) {
+        self.engine.set_tie_break(tie_break);
+    }
+
+    /// Rebases a transaction that this site had sent, or was about to send, before the repair.
+    /// Transactions must be rebased in the order they were made.  Returns the transaction to
+    /// send instead, or `None` if the authoritative site had already integrated it.
+    #[allow(clippy::type_complexity)]
+    pub fn rebase(&mut self, transaction: &TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>) -> Result<Option<(TransactionSequence, BTreeMap<u32, (u32, u32)>)>, OTError> {
+        if lookup.values().all(|&(site_id, timestamp)| self.stamper.get_local_timestamp_for(site_id, timestamp).is_some()) {
+            return Ok(None);
+        }
+        // The transaction keeps its original timestamps, which the authoritative site has never
+        // seen, and is transformed by everything in the snapshot that it didn't know about
+        let mut rebased = transaction.clone();
+        rebased.set_fingerprint(None);
+        // The stamps are only kept if integrating works, so that the transaction can be rebased again
+        let mut stamper = self.stamper.clone();
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            stamper.stamp_remote(site_id, timestamp);
+        }
+        try!(self.engine.integrate_remote(&mut rebased, lookup, &mut stamper));
+        self.stamper = stamper;
+        self.document = rebased.apply_to_bytes(&self.document);
+        // The operations still carry the timestamps they had before the repair
+        for insert in rebased.inserts.iter_mut() {
+            let timestamp = try!(self.restamp(insert.get_timestamp(), lookup));
+            insert.set_timestamp(timestamp);
+        }
+        for delete in rebased.deletes.iter_mut() {
+            let timestamp = try!(self.restamp(delete.get_timestamp(), lookup));
+            delete.set_timestamp(timestamp);
+        }
+
+        // Everything else in the new history came from the snapshot, so it is known to the
+        // authoritative site
+        rebased.set_last_timestamp(self.stamper.get_last_remote_timestamp(self.site_id));
+        rebased.set_fingerprint(Some(Fingerprint::of(&self.document)));
+        let lookup = self.stamper.get_timestamps_for(&rebased);
+        Ok(Some((rebased, lookup)))
+    }
+
+    /// Gets the repaired document, including every transaction rebased so far
+    #[inline]
+    pub fn get_document(&self) -> &[u8] {
+        &self.document
+    }
+
+    /// Finishes the repair, returning the document and the history to continue from
+    pub fn into_parts(self) -> (Vec<u8>, Engine, TimeStamper) {
+        (self.document, self.engine, self.stamper)
+    }
+
+    /// Gets the timestamp in the repaired history of an operation from before the repair
+    fn restamp(&self, timestamp: u32, lookup: &BTreeMap<u32, (u32, u32)>) -> Result<u32, OTError> {
+        lookup.get(&timestamp).and_then(|&(site_id, timestamp)| self.stamper.get_local_timestamp_for(site_id, timestamp))
+              .ok_or(OTError::new(Kind::NoSuchState))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SiteState, Repair};
+    use engine::{Engine, TimeStamper, Snapshot};
+    use fingerprint::Fingerprint;
+    use test_utils::local_edit;
+
+    #[test]
+    fn elections_agree() {
+        let mut stamper = TimeStamper::new();
+        stamper.stamp_local(1);
+        let busy = SiteState::new(2, b"abc", &stamper);
+        let idle = SiteState::new(1, b"abd", &TimeStamper::new());
+        assert_eq!(busy.elect(&idle), 2);
+        assert_eq!(idle.elect(&busy), 2);
+        let other = SiteState::new(3, b"abc", &stamper);
+        assert_eq!(busy.elect(&other), 2);
+        assert_eq!(other.elect(&busy), 2);
+
+        let mut buffer = Vec::new();
+        busy.compress_to(&mut buffer).unwrap();
+        assert_eq!(SiteState::expand_from(&mut &buffer[..]).unwrap(), busy);
+    }
+
+    #[test]
+    fn unsent_transactions_are_rebased() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut document1 = Vec::new();
+        let (mut first, lookup) = local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(0, "The quick fox")], vec![]);
+
+        // The second site integrates the first edit, but its copy of the document is damaged
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            stamper2.stamp_remote(site_id, timestamp);
+        }
+        engine2.integrate_remote(&mut first, &lookup, &mut stamper2).unwrap();
+        let mut document2 = b"The quack fox".to_vec();
+        let unsent = local_edit(&mut engine2, &mut stamper2, 2, &mut document2, vec![(13, " jumps")], vec![]);
+
+        // Meanwhile the first site carries on editing
+        local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(4, "very ")], vec![]);
+
+        let mut repair = Repair::new(Snapshot::new(document1.clone(), &engine1, &stamper1), 2);
+        let (mut rebased, rebased_lookup) = repair.rebase(&unsent.0, &unsent.1).unwrap().unwrap();
+        assert_eq!(repair.get_document(), b"The very quick fox jumps");
+        assert_eq!(rebased.get_fingerprint(), Some(Fingerprint::of(b"The very quick fox jumps")));
+        assert_eq!(rebased_lookup.values().cloned().collect::<Vec<_>>(), vec![(2, 1)]);
+
+        // The first site can integrate the rebased transaction as usual
+        for (_, &(site_id, timestamp)) in rebased_lookup.iter() {
+            stamper1.stamp_remote(site_id, timestamp);
+        }
+        engine1.integrate_remote(&mut rebased, &rebased_lookup, &mut stamper1).unwrap();
+        document1 = rebased.apply_to_bytes(&document1);
+        rebased.verify(&document1, None).unwrap();
+
+        // New edits on the repaired site don't reuse the timestamps from before the repair
+        let (_, mut engine2, mut stamper2) = repair.into_parts();
+        let mut document2 = document1.clone();
+        let (_, lookup) = local_edit(&mut engine2, &mut stamper2, 2, &mut document2, vec![(0, "!")], vec![]);
+        assert!(lookup.values().all(|&(site_id, timestamp)| site_id == 2 && timestamp > 1));
+        assert!(Repair::new(Snapshot::new(document1, &engine1, &stamper1), 2).rebase(&unsent.0, &unsent.1).unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_rebases_are_not_taken_as_integrated() {
+        let mut engine1 = Engine::new(1);
+        let mut stamper1 = TimeStamper::new();
+        let mut document1 = Vec::new();
+        local_edit(&mut engine1, &mut stamper1, 1, &mut document1, vec![(0, "The quick fox")], vec![]);
+
+        // The unsent transaction was made on an edit from a site the snapshot never heard of
+        let mut engine2 = Engine::new(2);
+        let mut stamper2 = TimeStamper::new();
+        let mut document2 = document1.clone();
+        let (mut unsent, lookup) = local_edit(&mut engine2, &mut stamper2, 2, &mut document2, vec![(13, " jumps")], vec![]);
+        unsent.set_last_timestamp(Some((3, 0)));
+
+        let mut repair = Repair::new(Snapshot::new(document1, &engine1, &stamper1), 2);
+        assert!(repair.rebase(&unsent, &lookup).is_err());
+        assert!(repair.rebase(&unsent, &lookup).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::linked_list::LinkedList;
+use std::collections::btree_map::BTreeMap;
+use std::collections::btree_set::BTreeSet;
+use history::Timeline;
+use operations::{InsertOperation, DeleteOperation};
+use element::Element;
+use {OTError, ErrorKind as Kind};
+
+/// Works out the operations, in effect order, that put back the text this site deleted around
+/// the inserts of a remote transaction.  `remote` tells whether the operation with a local
+/// timestamp is the remote transaction, and `restorable` whether it was made at this site without
+/// the remote site knowing about it.  Wherever text the remote transaction inserted is still in
+/// the document and has a restorable delete on both sides of it, everything that delete took out
+/// next to it is put back.  The operations are made by `site_id` and have a timestamp of 0.
+#[allow(clippy::type_complexity)]
+pub fn restore<T: Element, F: Fn(u32) -> bool, G: Fn(u32) -> bool>(timeline: &Timeline<T>, remote: &F, restorable: &G, site_id: u32) -> Result<(LinkedList<InsertOperation<T>>, LinkedList<DeleteOperation<T>>), OTError> {
+    let inserted_remotely = |index: usize| timeline.get_inserted(index).is_some_and(remote) && timeline.get_deleted(index).is_none();
+    let mut restored = BTreeSet::new();
+    let mut index = 0;
+    while index < timeline.len() {
+        if !inserted_remotely(index) {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < timeline.len() && inserted_remotely(index) {
+            index += 1;
+        }
+        if start == 0 || index == timeline.len() {
+            continue;
+        }
+        let delete = match (timeline.get_deleted(start - 1), timeline.get_deleted(index)) {
+            (Some(before), Some(after)) if before == after && restorable(before) => before,
+            _ => continue
+        };
+        // Take in the whole of the delete on either side, along with any other remote text inside it
+        let inside = |index: usize| timeline.get_deleted(index) == Some(delete) || inserted_remotely(index);
+        let mut first = start;
+        while first > 0 && inside(first - 1) {
+            first -= 1;
+        }
+        let mut last = index;
+        while last < timeline.len() && inside(last) {
+            last += 1;
+        }
+        restored.extend((first..last).filter(|&index| timeline.get_deleted(index) == Some(delete)));
+        index = last;
+    }
+
+    // Each run of restored elements goes back in before the first of them
+    let mut inserted: BTreeMap<usize, Vec<T>> = BTreeMap::new();
+    let mut run = None;
+    for &index in restored.iter() {
+        let element = match timeline.get_element(index) {
+            Some(element) => element.clone(),
+            None => return Err(OTError::new(Kind::UnknownContent))
+        };
+        let first = match run {
+            Some((first, last)) if last + 1 == index => first,
+            _ => index
+        };
+        inserted.entry(first).or_default().push(element);
+        run = Some((first, index));
+    }
+    timeline.edits(&inserted, &BTreeSet::new(), site_id)
+}
@@ -0,0 +1,745 @@
+//! A JSON-RPC interface to the engine, for editors that would rather talk to a subprocess
+//! over stdin and stdout than link against the engine.
+//!
+//! An [`RpcServer`](struct.RpcServer.html) keeps a [`Document`](struct.Document.html) for every
+//! buffer the editor has opened.  The editor reports its own edits with `local_edit`, which
+//! returns a transaction to send to the other sites however it likes, and hands transactions
+//! from other sites to `remote`, which returns the edits to make to its buffer.  Transactions
+//! are encoded as JSON rather than with `compress_to()`, so the editor never has to look inside
+//! them.  `remote` reports `diverged` if the document no longer matches the sender's copy
+//! once the transaction has been applied.  Positions are byte offsets into the UTF-8 contents
+//! of the document.  Text is sent as a string, unless it isn't valid UTF-8, such as when an edit
+//! cuts a character in half, in which case it is sent as an array of byte values.  Text can be
+//! given either way as well.
+//!
+//! | Method       | Params                                           | Result                                                   |
+//! |--------------|--------------------------------------------------|----------------------------------------------------------|
+//! | `open`       | `document`, `site_id`, `content`, `policy`       | `null`                                                   |
+//! | `close`      | `document`                                       | `null`                                                   |
+//! | `local_edit` | `document`, `position`, `delete`, `text`         | `{"transaction": ...}`                                   |
+//! | `remote`     | `document`, `transaction`                        | `{"duplicate": bool, "diverged": bool, "edits": [...]}`  |
+//! | `content`    | `document`                                       | `{"content": "..."}`                                     |
+//!
+//! `content` is optional, and so is `policy`, which is either `"keep"` (the default) or `"drop"`
+//! and must be the same at every site.  It decides whether text inserted inside a range that
+//! is deleted concurrently is kept or deleted along with the range.  A document that is
+//! already open has to be closed before it can be opened again.
+//!
+//! Messages can either be written one per line, or framed with a `Content-Length` header as in
+//! the Language Server Protocol.  Each response is framed the same way as its request.
+use std::collections::btree_map::BTreeMap;
+use std::collections::HashMap;
+use std::collections::LinkedList;
+use std::io::{self, BufRead, Write};
+use rustc_serialize::json::{Json, Object};
+use engine::{Engine, TransactionSequence, TimeStamper};
+use fingerprint::Fingerprint;
+use metadata::Metadata;
+use operations::{InsertOperation, DeleteOperation, Operation};
+use policy::ConflictPolicy;
+use {OTError, Position};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SYNC_ERROR: i64 = -32000;
+
+/// A change to make to an editor's buffer.  Edits must be made in the order they are given,
+/// since each position takes the edits before it into account.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Edit {
+    /// The byte offset to make the change at
+    pub position: Position,
+    /// The number of bytes to remove at `position`
+    pub delete: Position,
+    /// The text to insert at `position`
+    pub text: Vec<u8>,
+}
+
+/// The synchronized state of one of an editor's buffers, whose contents are held as bytes
+#[derive(Debug)]
+pub struct Document {
+    site_id: u32,
+    engine: Engine,
+    stamper: TimeStamper,
+    content: Vec<u8>,
+    /// The most recent state received from another site
+    last_remote: Option<(u32, u32)>,
+}
+
+/// Handles JSON-RPC requests on behalf of an editor.  Its documents hold bytes, since edits
+/// arrive as text with byte offsets, and there is no JSON form for other kinds of element.
+#[derive(Debug)]
+pub struct RpcServer {
+    documents: HashMap<String, Document>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl Edit {
+    /// Gets the edits that will make the same change as an integrated transaction
+    pub fn from_transaction(transaction: &TransactionSequence) -> Vec<Edit> {
+        // The inserts are in effect order, and the deletes are positioned as though every
+        // insert and every earlier delete has already been made
+        let inserts = transaction.inserts.iter().map(|insert| Edit {
+            position: insert.get_position(),
+            delete: 0,
+            text: insert.get_value().to_vec(),
+        });
+        let deletes = transaction.deletes.iter().filter(|delete| delete.get_length() > 0).map(|delete| Edit {
+            position: delete.get_position(),
+            delete: delete.get_length(),
+            text: Vec::new(),
+        });
+        inserts.chain(deletes).collect()
+    }
+
+    /// Makes this edit to a copy of a buffer, or fails with `OutOfRange` if the buffer is too
+    /// short for it
+    pub fn apply_to_bytes(&self, bytes: &[u8]) -> Result<Vec<u8>, OTError> {
+        if self.position.checked_add(self.delete).is_none_or(|end| end > bytes.len() as Position) {
+            return Err(OTError::new(::ErrorKind::OutOfRange));
+        }
+        let position = self.position as usize;
+        let mut result = Vec::with_capacity(bytes.len() + self.text.len());
+        result.extend_from_slice(&bytes[..position]);
+        result.extend_from_slice(&self.text);
+        result.extend_from_slice(&bytes[position + self.delete as usize..]);
+        Ok(result)
+    }
+}
+
+impl Document {
+    /// Creates a document for the given site, starting with `content`.  Every site must start
+    /// with the same content.
+    pub fn new(site_id: u32, content: Vec<u8>) -> Document {
+        Document::from_parts(site_id, content, Engine::new(site_id), TimeStamper::new())
+    }
+
+    /// Creates a document from a previously saved engine and timestamps.  `content` must
+    /// include every operation in the engine's history.
+    pub fn from_parts(site_id: u32, content: Vec<u8>, engine: Engine, stamper: TimeStamper) -> Document {
+        let last_remote = stamper.get_last_remote_timestamp(site_id);
+        Document {
+            site_id: site_id,
+            engine: engine,
+            stamper: stamper,
+            content: content,
+            last_remote: last_remote,
+        }
+    }
+
+    /// Records an edit made by the local user, who deleted `delete` bytes at `position` and
+    /// then inserted `text` there.  Returns the transaction to send to the other sites, or
+    /// `None` if the edit didn't change anything.
+    #[allow(clippy::type_complexity)]
+    pub fn local_edit(&mut self, position: Position, delete: Position, text: Vec<u8>) -> Result<Option<(TransactionSequence, BTreeMap<u32, (u32, u32)>)>, OTError> {
+        if position.checked_add(delete).is_none_or(|end| end > self.content.len() as Position) {
+            return Err(OTError::new(::ErrorKind::OutOfRange));
+        }
+        if delete == 0 && text.is_empty() {
+            return Ok(None);
+        }
+        let timestamp = self.stamper.stamp_local(self.site_id);
+        let mut inserts = LinkedList::new();
+        let mut deletes = LinkedList::new();
+        // Deletes happen after inserts, so the deleted text follows the inserted text
+        if delete > 0 {
+            deletes.push_back(DeleteOperation::new(position + text.len() as Position, delete, timestamp, self.site_id));
+        }
+        if !text.is_empty() {
+            inserts.push_back(InsertOperation::new(position, text, timestamp, self.site_id));
+        }
+        let mut transaction = TransactionSequence::new(self.last_remote, inserts, deletes);
+        if self.engine.is_text_mode() {
+            transaction.align_to_chars(&self.content);
+        }
+        transaction.capture_deleted(&self.content);
+        self.content = transaction.apply_to_bytes(&self.content);
+        transaction.set_fingerprint(Some(Fingerprint::of(&self.content)));
+        self.engine.process_transaction(&mut transaction);
+        let mut lookup = BTreeMap::new();
+        lookup.insert(timestamp, (self.site_id, timestamp));
+        Ok(Some((transaction, lookup)))
+    }
+
+    /// Integrates a transaction from another site, returning the edits to make to the buffer,
+    /// or `None` if the transaction had already been integrated.  Afterwards the transaction can
+    /// be checked against the document with `TransactionSequence::verify()`.
+    pub fn integrate(&mut self, transaction: &mut TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>) -> Result<Option<Vec<Edit>>, OTError> {
+        let state = match lookup.values().max_by_key(|&&(_, timestamp)| timestamp) {
+            Some(&state) => state,
+            None => return Ok(None)
+        };
+        if lookup.values().all(|&(site_id, timestamp)| self.stamper.get_local_timestamp_for(site_id, timestamp).is_some()) {
+            return Ok(None);
+        }
+        // The stamps are only kept if integrating works, so that a retry isn't taken for a duplicate
+        let mut stamper = self.stamper.clone();
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            stamper.stamp_remote(site_id, timestamp);
+        }
+        try!(self.engine.integrate_remote(transaction, lookup, &mut stamper));
+        self.stamper = stamper;
+        self.last_remote = Some(state);
+        self.content = transaction.apply_to_bytes(&self.content);
+        Ok(Some(Edit::from_transaction(transaction)))
+    }
+
+    /// Gets the current contents of the document
+    #[inline]
+    pub fn get_content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Gets the engine holding the document's history
+    #[inline]
+    pub fn get_engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Gets the timestamps used by the document
+    #[inline]
+    pub fn get_stamper(&self) -> &TimeStamper {
+        &self.stamper
+    }
+}
+
+/// Encodes a transaction and its timestamp lookup as JSON
+pub fn transaction_to_json(transaction: &TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>) -> Json {
+    let inserts = transaction.inserts.iter().map(|insert| {
+        let mut object = Object::new();
+        object.insert("timestamp".to_string(), Json::U64(insert.get_timestamp() as u64));
+        object.insert("position".to_string(), Json::U64(insert.get_position()));
+        object.insert("value".to_string(), bytes_to_json(insert.get_value()));
+        Json::Object(object)
+    }).collect();
+    let deletes = transaction.deletes.iter().map(|delete| {
+        let mut object = Object::new();
+        object.insert("timestamp".to_string(), Json::U64(delete.get_timestamp() as u64));
+        object.insert("position".to_string(), Json::U64(delete.get_position()));
+        object.insert("length".to_string(), Json::U64(delete.get_length()));
+        if let Some(content) = delete.get_content() {
+            object.insert("content".to_string(), bytes_to_json(content));
+        }
+        Json::Object(object)
+    }).collect();
+    let lookup = lookup.iter().map(|(&timestamp, &(site_id, remote_timestamp))| {
+        let mut object = Object::new();
+        object.insert("timestamp".to_string(), Json::U64(timestamp as u64));
+        object.insert("site_id".to_string(), Json::U64(site_id as u64));
+        object.insert("remote_timestamp".to_string(), Json::U64(remote_timestamp as u64));
+        Json::Object(object)
+    }).collect();
+    let mut object = Object::new();
+    object.insert("last_timestamp".to_string(), match transaction.get_last_timestamp() {
+        Some((site_id, timestamp)) => Json::Array(vec![Json::U64(site_id as u64), Json::U64(timestamp as u64)]),
+        None => Json::Null
+    });
+    object.insert("inserts".to_string(), Json::Array(inserts));
+    object.insert("deletes".to_string(), Json::Array(deletes));
+    object.insert("lookup".to_string(), Json::Array(lookup));
+    if let Some(fingerprint) = transaction.get_fingerprint() {
+        let mut value = Object::new();
+        value.insert("length".to_string(), Json::U64(fingerprint.get_length()));
+        value.insert("hash".to_string(), Json::U64(fingerprint.get_hash() as u64));
+        object.insert("fingerprint".to_string(), Json::Object(value));
+    }
+    if let Some(metadata) = transaction.get_metadata() {
+        let mut value = Object::new();
+        if let Some(author) = metadata.get_author() {
+            value.insert("author".to_string(), Json::String(author.to_string()));
+        }
+        if let Some(time) = metadata.get_time() {
+            value.insert("time".to_string(), Json::U64(time));
+        }
+        if let Some(message) = metadata.get_message() {
+            value.insert("message".to_string(), Json::String(message.to_string()));
+        }
+        let properties = metadata.get_properties().iter().map(|(key, value)| (key.clone(), Json::String(value.clone()))).collect();
+        value.insert("properties".to_string(), Json::Object(properties));
+        object.insert("metadata".to_string(), Json::Object(value));
+    }
+    Json::Object(object)
+}
+
+/// Decodes a transaction and its timestamp lookup from JSON written by `transaction_to_json()`
+#[allow(clippy::type_complexity)]
+pub fn transaction_from_json(json: &Json) -> Result<(TransactionSequence, BTreeMap<u32, (u32, u32)>), String> {
+    let mut lookup = BTreeMap::new();
+    for entry in try!(get_array(json, "lookup")) {
+        lookup.insert(try!(get_u32(entry, "timestamp")), (try!(get_u32(entry, "site_id")), try!(get_u32(entry, "remote_timestamp"))));
+    }
+    let last_timestamp = match json.find("last_timestamp") {
+        None | Some(&Json::Null) => None,
+        Some(state) => match state.as_array().map(|state| (state.len(), state.first().and_then(as_u32), state.get(1).and_then(as_u32))) {
+            Some((2, Some(site_id), Some(timestamp))) => Some((site_id, timestamp)),
+            _ => return Err("last_timestamp must be null or [site_id, timestamp]".to_string())
+        }
+    };
+    let mut inserts = LinkedList::new();
+    for insert in try!(get_array(json, "inserts")) {
+        let timestamp = try!(get_u32(insert, "timestamp"));
+        let site_id = match lookup.get(&timestamp) {
+            Some(&(site_id, _)) => site_id,
+            None => return Err(format!("Timestamp {} not found in lookup", timestamp))
+        };
+        let value = try!(get_bytes(insert, "value"));
+        inserts.push_back(InsertOperation::new(try!(get_u64(insert, "position")), value, timestamp, site_id));
+    }
+    let mut deletes = LinkedList::new();
+    for delete in try!(get_array(json, "deletes")) {
+        let length = try!(get_u64(delete, "length"));
+        let timestamp = try!(get_u32(delete, "timestamp"));
+        let site_id = match lookup.get(&timestamp) {
+            Some(&(site_id, _)) => site_id,
+            None => return Err(format!("Timestamp {} not found in lookup", timestamp))
+        };
+        let mut operation = DeleteOperation::new(try!(get_u64(delete, "position")), length, timestamp, site_id);
+        if delete.find("content").is_some() {
+            let content = try!(get_bytes(delete, "content"));
+            if content.len() as Position != length {
+                return Err("The content of a delete must be as long as the delete".to_string());
+            }
+            operation.set_content(Some(content));
+        }
+        deletes.push_back(operation);
+    }
+    let mut transaction = TransactionSequence::new(last_timestamp, inserts, deletes);
+    if let Some(fingerprint) = json.find("fingerprint") {
+        transaction.set_fingerprint(Some(Fingerprint::new(try!(get_u64(fingerprint, "length")), try!(get_u32(fingerprint, "hash")))));
+    }
+    if let Some(value) = json.find("metadata") {
+        let mut metadata = Metadata::new();
+        if value.find("author").is_some() {
+            metadata.set_author(Some(try!(get_string(value, "author")).to_string()));
+        }
+        if value.find("time").is_some() {
+            metadata.set_time(Some(try!(get_u64(value, "time"))));
+        }
+        if value.find("message").is_some() {
+            metadata.set_message(Some(try!(get_string(value, "message")).to_string()));
+        }
+        if let Some(properties) = value.find("properties") {
+            let properties = try!(properties.as_object().ok_or("Expected an object for properties".to_string()));
+            for (key, property) in properties.iter() {
+                let property = try!(property.as_string().ok_or(format!("Expected a string for {}", key)));
+                metadata.set_property(key, Some(property.to_string()));
+            }
+        }
+        transaction.set_metadata(Some(metadata));
+    }
+    Ok((transaction, lookup))
+}
+
+fn get_array<'a>(json: &'a Json, key: &str) -> Result<&'a Vec<Json>, String> {
+    json.find(key).and_then(Json::as_array).ok_or(format!("Expected an array for {}", key))
+}
+
+fn get_u64(json: &Json, key: &str) -> Result<u64, String> {
+    json.find(key).and_then(Json::as_u64).ok_or(format!("Expected a number for {}", key))
+}
+
+fn get_u32(json: &Json, key: &str) -> Result<u32, String> {
+    json.find(key).and_then(as_u32).ok_or(format!("Expected a number no larger than {} for {}", u32::MAX, key))
+}
+
+fn as_u32(json: &Json) -> Option<u32> {
+    json.as_u64().and_then(|value| if value <= u32::MAX as u64 { Some(value as u32) } else { None })
+}
+
+fn get_string<'a>(json: &'a Json, key: &str) -> Result<&'a str, String> {
+    json.find(key).and_then(Json::as_string).ok_or(format!("Expected a string for {}", key))
+}
+
+/// Reads text written by `bytes_to_json()`
+fn get_bytes(json: &Json, key: &str) -> Result<Vec<u8>, String> {
+    match json.find(key) {
+        Some(Json::String(text)) => Ok(text.as_bytes().to_vec()),
+        Some(Json::Array(bytes)) => bytes.iter().map(|byte| match byte.as_u64() {
+            Some(byte) if byte <= u8::MAX as u64 => Ok(byte as u8),
+            _ => Err(format!("Expected bytes from 0 to 255 for {}", key))
+        }).collect(),
+        _ => Err(format!("Expected a string or an array of bytes for {}", key))
+    }
+}
+
+/// Encodes text as a string, or as an array of its bytes if it isn't valid UTF-8
+fn bytes_to_json(bytes: &[u8]) -> Json {
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(text) => Json::String(text),
+        Err(_) => Json::Array(bytes.iter().map(|&byte| Json::U64(byte as u64)).collect())
+    }
+}
+
+fn edits_to_json(edits: &[Edit]) -> Json {
+    Json::Array(edits.iter().map(|edit| {
+        let mut object = Object::new();
+        object.insert("position".to_string(), Json::U64(edit.position));
+        object.insert("delete".to_string(), Json::U64(edit.delete));
+        object.insert("text".to_string(), bytes_to_json(&edit.text));
+        Json::Object(object)
+    }).collect())
+}
+
+impl RpcError {
+    fn new(code: i64, message: String) -> RpcError {
+        RpcError {
+            code: code,
+            message: message,
+        }
+    }
+
+    fn params(message: String) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+}
+
+impl Default for RpcServer {
+    fn default() -> RpcServer {
+        RpcServer::new()
+    }
+}
+
+impl RpcServer {
+    /// Creates a server with no open documents
+    pub fn new() -> RpcServer {
+        RpcServer {
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Gets an open document
+    pub fn get_document(&self, name: &str) -> Option<&Document> {
+        self.documents.get(name)
+    }
+
+    /// Handles a single JSON-RPC request, returning the response.  Notifications (requests
+    /// without an `id`) get no response.
+    pub fn handle(&mut self, request: &Json) -> Option<Json> {
+        let id = request.find("id").cloned();
+        let result = match (request.find("method").and_then(Json::as_string), request.find("params")) {
+            (Some(method), Some(params)) => self.call(method, params),
+            (Some(method), None) => self.call(method, &Json::Object(Object::new())),
+            (None, _) => Err(RpcError::new(INVALID_REQUEST, "Request has no method".to_string()))
+        };
+        id.map(|id| response(id, result))
+    }
+
+    /// Handles a single JSON-RPC request in text form
+    pub fn handle_text(&mut self, request: &str) -> Option<String> {
+        match Json::from_str(request) {
+            Ok(request) => self.handle(&request),
+            Err(error) => Some(response(Json::Null, Err(RpcError::new(PARSE_ERROR, error.to_string()))))
+        }.map(|response| response.to_string())
+    }
+
+    /// Reads requests from `reader` and writes responses to `writer` until `reader` is
+    /// exhausted
+    pub fn serve<R: BufRead, W: Write>(&mut self, reader: &mut R, writer: &mut W) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if try!(reader.read_line(&mut line)) == 0 {
+                return Ok(());
+            }
+            let header_length = {
+                let trimmed = line.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+                if trimmed.to_lowercase().starts_with("content-length:") {
+                    Some(try!(trimmed["content-length:".len()..].trim().parse::<usize>()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length"))))
+                } else {
+                    None
+                }
+            };
+            if let Some(length) = header_length {
+                // Skip any other headers, up to the blank line before the body
+                loop {
+                    line.clear();
+                    if try!(reader.read_line(&mut line)) == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                try!(reader.read_exact(&mut body));
+                let response = match String::from_utf8(body) {
+                    Ok(body) => self.handle_text(&body),
+                    Err(_) => Some(response(Json::Null, Err(RpcError::new(PARSE_ERROR, "The request is not valid UTF-8".to_string()))).to_string())
+                };
+                if let Some(response) = response {
+                    try!(write!(writer, "Content-Length: {}\r\n\r\n{}", response.len(), response));
+                    try!(writer.flush());
+                }
+            } else if let Some(response) = self.handle_text(&line) {
+                try!(writeln!(writer, "{}", response));
+                try!(writer.flush());
+            }
+        }
+    }
+
+    fn call(&mut self, method: &str, params: &Json) -> Result<Json, RpcError> {
+        let name = try!(get_string(params, "document").map_err(RpcError::params)).to_string();
+        match method {
+            "open" => {
+                if self.documents.contains_key(&name) {
+                    return Err(RpcError::params(format!("A document named {} is already open", name)));
+                }
+                let site_id = try!(get_u32(params, "site_id").map_err(RpcError::params));
+                let content = if params.find("content").is_some() {
+                    try!(get_bytes(params, "content").map_err(RpcError::params))
+                } else {
+                    Vec::new()
+                };
+                let mut engine = Engine::new(site_id);
+                engine.set_conflict_policy(match params.find("policy").map(|policy| policy.as_string()) {
+                    None | Some(Some("keep")) => ConflictPolicy::KeepInsert,
+                    Some(Some("drop")) => ConflictPolicy::DropInsert,
+                    Some(_) => return Err(RpcError::params("Expected the policy to be \"keep\" or \"drop\"".to_string()))
+                });
+                self.documents.insert(name, Document::from_parts(site_id, content, engine, TimeStamper::new()));
+                Ok(Json::Null)
+            },
+            "close" => {
+                try!(self.documents.remove(&name).ok_or(no_document(&name)));
+                Ok(Json::Null)
+            },
+            "local_edit" => {
+                let document = try!(self.documents.get_mut(&name).ok_or(no_document(&name)));
+                let position = try!(get_u64(params, "position").map_err(RpcError::params));
+                let delete = params.find("delete").and_then(Json::as_u64).unwrap_or(0);
+                let text = if params.find("text").is_some() {
+                    try!(get_bytes(params, "text").map_err(RpcError::params))
+                } else {
+                    Vec::new()
+                };
+                let transaction = try!(document.local_edit(position, delete, text).map_err(sync_error));
+                let mut object = Object::new();
+                object.insert("transaction".to_string(), match transaction {
+                    Some((transaction, lookup)) => transaction_to_json(&transaction, &lookup),
+                    None => Json::Null
+                });
+                Ok(Json::Object(object))
+            },
+            "remote" => {
+                let document = try!(self.documents.get_mut(&name).ok_or(no_document(&name)));
+                let transaction = try!(params.find("transaction").ok_or(RpcError::params("Expected a transaction".to_string())));
+                let (mut transaction, lookup) = try!(transaction_from_json(transaction).map_err(RpcError::params));
+                let edits = try!(document.integrate(&mut transaction, &lookup).map_err(sync_error));
+                let diverged = edits.is_some() && transaction.verify(document.get_content(), None).is_err();
+                let mut object = Object::new();
+                object.insert("duplicate".to_string(), Json::Boolean(edits.is_none()));
+                object.insert("diverged".to_string(), Json::Boolean(diverged));
+                object.insert("edits".to_string(), edits_to_json(&edits.unwrap_or(Vec::new())));
+                Ok(Json::Object(object))
+            },
+            "content" => {
+                let document = try!(self.documents.get(&name).ok_or(no_document(&name)));
+                let mut object = Object::new();
+                object.insert("content".to_string(), bytes_to_json(document.get_content()));
+                Ok(Json::Object(object))
+            },
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method)))
+        }
+    }
+}
+
+fn no_document(name: &str) -> RpcError {
+    RpcError::params(format!("No open document named {}", name))
+}
+
+fn sync_error(error: OTError) -> RpcError {
+    RpcError::new(SYNC_ERROR, format!("{:?}", error.kind))
+}
+
+fn response(id: Json, result: Result<Json, RpcError>) -> Json {
+    let mut object = Object::new();
+    object.insert("jsonrpc".to_string(), Json::String("2.0".to_string()));
+    object.insert("id".to_string(), id);
+    match result {
+        Ok(result) => {
+            object.insert("result".to_string(), result);
+        },
+        Err(error) => {
+            let mut error_object = Object::new();
+            error_object.insert("code".to_string(), Json::I64(error.code));
+            error_object.insert("message".to_string(), Json::String(error.message));
+            object.insert("error".to_string(), Json::Object(error_object));
+        }
+    }
+    Json::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RpcServer, Edit, transaction_to_json, transaction_from_json, get_bytes};
+    use rustc_serialize::json::Json;
+    use std::collections::BTreeMap;
+    use engine::TransactionSequence;
+    use fingerprint::Fingerprint;
+    use metadata::Metadata;
+    use operations::{InsertOperation, DeleteOperation};
+
+    fn request(server: &mut RpcServer, request: &str) -> Json {
+        let response = Json::from_str(&server.handle_text(request).unwrap()).unwrap();
+        response.find("result").cloned().unwrap_or_else(|| panic!("Request failed: {}", response))
+    }
+
+    fn edit(server: &mut RpcServer, buffer: &mut Vec<u8>, position: u64, delete: u64, text: &str) -> Json {
+        *buffer = Edit { position: position, delete: delete, text: text.bytes().collect() }.apply_to_bytes(buffer).unwrap();
+        let result = request(server, &format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "local_edit",
+            "params": {{"document": "doc", "position": {}, "delete": {}, "text": "{}"}}}}"#, position, delete, text));
+        result.find("transaction").unwrap().clone()
+    }
+
+    fn receive(server: &mut RpcServer, buffer: &mut Vec<u8>, transaction: &Json) {
+        let result = request(server, &format!(r#"{{"jsonrpc": "2.0", "id": 2, "method": "remote",
+            "params": {{"document": "doc", "transaction": {}}}}}"#, transaction));
+        assert_eq!(result.find("diverged").and_then(Json::as_boolean), Some(false));
+        for edit in result.find("edits").unwrap().as_array().unwrap() {
+            let edit = Edit {
+                position: edit.find("position").unwrap().as_u64().unwrap(),
+                delete: edit.find("delete").unwrap().as_u64().unwrap(),
+                text: get_bytes(edit, "text").unwrap(),
+            };
+            *buffer = edit.apply_to_bytes(buffer).unwrap();
+        }
+    }
+
+    #[test]
+    fn transactions_round_trip() {
+        let mut lookup = BTreeMap::new();
+        lookup.insert(3, (1, 5));
+        let mut transaction = TransactionSequence::new(Some((2, 1)),
+            vec![InsertOperation::new(2, "Some text".bytes().collect(), 3, 1)].into_iter().collect(),
+            vec![DeleteOperation::new(4, 2, 3, 1), DeleteOperation::new(9, 2, 3, 1)].into_iter().collect());
+        transaction.set_fingerprint(Some(Fingerprint::new(11, 0x12345678)));
+        let mut metadata = Metadata::new();
+        metadata.set_author(Some("Ada".to_string()));
+        metadata.set_time(Some(1500000000000));
+        metadata.set_property("ticket", Some("OPT-12".to_string()));
+        transaction.set_metadata(Some(metadata));
+        transaction.deletes.front_mut().unwrap().set_content(Some(b"me".to_vec()));
+        let json = transaction_to_json(&transaction, &lookup);
+        let (expanded, expanded_lookup) = transaction_from_json(&Json::from_str(&json.to_string()).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", expanded), format!("{:?}", transaction));
+        assert_eq!(expanded.deletes.iter().map(|delete| delete.get_content()).collect::<Vec<_>>(), vec![Some(&b"me"[..]), None]);
+        assert_eq!(expanded_lookup, lookup);
+        assert!(transaction_from_json(&Json::from_str(r#"{"inserts": []}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn editors_converge() {
+        let mut server1 = RpcServer::new();
+        let mut server2 = RpcServer::new();
+        request(&mut server1, r#"{"id": 0, "method": "open", "params": {"document": "doc", "site_id": 1, "content": "Hello world"}}"#);
+        request(&mut server2, r#"{"id": 0, "method": "open", "params": {"document": "doc", "site_id": 2, "content": "Hello world"}}"#);
+        let mut buffer1 = b"Hello world".to_vec();
+        let mut buffer2 = b"Hello world".to_vec();
+
+        let first = edit(&mut server1, &mut buffer1, 5, 0, ",");
+        let second = edit(&mut server1, &mut buffer1, 7, 5, "there");
+        let concurrent = edit(&mut server2, &mut buffer2, 11, 0, "!");
+        receive(&mut server2, &mut buffer2, &first);
+        receive(&mut server2, &mut buffer2, &second);
+        receive(&mut server1, &mut buffer1, &concurrent);
+        // Receiving a transaction twice doesn't change anything
+        receive(&mut server1, &mut buffer1, &concurrent);
+
+        assert_eq!(buffer1, b"Hello, there!");
+        assert_eq!(buffer1, buffer2);
+        let content = request(&mut server2, r#"{"id": 3, "method": "content", "params": {"document": "doc"}}"#);
+        assert_eq!(content.find("content").unwrap().as_string(), Some("Hello, there!"));
+    }
+
+    #[test]
+    fn failed_transactions_can_be_sent_again() {
+        let mut servers: Vec<RpcServer> = (0..3).map(|_| RpcServer::new()).collect();
+        for (index, server) in servers.iter_mut().enumerate() {
+            request(server, &format!(r#"{{"id": 0, "method": "open", "params": {{"document": "doc", "site_id": {}}}}}"#, index + 1));
+        }
+        let mut buffers = vec![Vec::new(); 3];
+        let first = edit(&mut servers[2], &mut buffers[2], 0, 0, "Hello");
+        receive(&mut servers[0], &mut buffers[0], &first);
+        let second = edit(&mut servers[0], &mut buffers[0], 5, 0, " world");
+
+        // Site 2 gets the second edit before the one it was made on, so it can't be integrated yet
+        let remote = format!(r#"{{"id": 2, "method": "remote", "params": {{"document": "doc", "transaction": {}}}}}"#, second);
+        let response = Json::from_str(&servers[1].handle_text(&remote).unwrap()).unwrap();
+        assert!(response.find("error").is_some());
+        receive(&mut servers[1], &mut buffers[1], &first);
+        receive(&mut servers[1], &mut buffers[1], &second);
+        assert_eq!(buffers[1], b"Hello world");
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let mut server = RpcServer::new();
+        let error = |server: &mut RpcServer, text: &str| {
+            Json::from_str(&server.handle_text(text).unwrap()).unwrap().find_path(&["error", "code"]).and_then(Json::as_i64)
+        };
+        assert_eq!(error(&mut server, "{not json"), Some(-32700));
+        assert_eq!(error(&mut server, r#"{"id": 1, "method": "open", "params": {"document": "doc"}}"#), Some(-32602));
+        assert_eq!(error(&mut server, r#"{"id": 1, "method": "open", "params": {"document": "doc", "site_id": 1, "policy": "merge"}}"#), Some(-32602));
+        assert_eq!(error(&mut server, r#"{"id": 1, "method": "content", "params": {"document": "doc"}}"#), Some(-32602));
+        assert_eq!(error(&mut server, r#"{"id": 1, "method": "frobnicate", "params": {"document": "doc"}}"#), Some(-32601));
+        // Notifications don't get a response
+        assert!(server.handle_text(r#"{"method": "open", "params": {"document": "doc", "site_id": 1}}"#).is_none());
+        assert!(server.get_document("doc").is_some());
+    }
+
+    #[test]
+    fn text_is_sent_intact() {
+        // An edit made in the middle of "é" leaves bytes that aren't valid UTF-8
+        let mut server = RpcServer::new();
+        request(&mut server, r#"{"id": 0, "method": "open", "params": {"document": "doc", "site_id": 1, "content": "caf\u00e9"}}"#);
+        let transaction = request(&mut server, r#"{"id": 1, "method": "local_edit", "params": {"document": "doc", "position": 4, "delete": 1, "text": [169, 33]}}"#);
+        let transaction = transaction.find("transaction").unwrap();
+        assert_eq!(get_bytes(&transaction.find("inserts").unwrap()[0], "value").unwrap(), vec![169, 33]);
+        assert_eq!(get_bytes(&transaction.find("deletes").unwrap()[0], "content").unwrap(), vec![169]);
+        let content = request(&mut server, r#"{"id": 2, "method": "content", "params": {"document": "doc"}}"#);
+        assert_eq!(get_bytes(&content, "content").unwrap(), b"caf\xc3\xa9!");
+
+        let (decoded, _) = transaction_from_json(transaction).unwrap();
+        assert_eq!(decoded.inserts.front().unwrap().get_value(), &[169, 33]);
+        assert_eq!(decoded.deletes.front().unwrap().get_content(), Some(&[169][..]));
+    }
+
+    #[test]
+    fn bad_input_is_rejected() {
+        let mut server = RpcServer::new();
+        let error = |server: &mut RpcServer, text: &str| {
+            Json::from_str(&server.handle_text(text).unwrap()).unwrap().find_path(&["error", "code"]).and_then(Json::as_i64)
+        };
+        assert_eq!(error(&mut server, r#"{"id": 1, "method": "open", "params": {"document": "doc", "site_id": 4294967296}}"#), Some(-32602));
+        request(&mut server, r#"{"id": 1, "method": "open", "params": {"document": "doc", "site_id": 1, "content": "Hello"}}"#);
+        // Opening the document again would throw away its history
+        assert_eq!(error(&mut server, r#"{"id": 2, "method": "open", "params": {"document": "doc", "site_id": 1}}"#), Some(-32602));
+        assert_eq!(error(&mut server, r#"{"id": 3, "method": "local_edit", "params": {"document": "doc", "position": 4, "delete": 18446744073709551615}}"#), Some(-32000));
+        assert_eq!(error(&mut server, r#"{"id": 4, "method": "local_edit", "params": {"document": "doc", "position": 0, "text": [256]}}"#), Some(-32602));
+        assert_eq!(server.get_document("doc").unwrap().get_content(), b"Hello");
+
+        assert!(transaction_from_json(&Json::from_str(r#"{"lookup": [{"timestamp": 0, "site_id": 4294967296, "remote_timestamp": 0}], "inserts": [], "deletes": []}"#).unwrap()).is_err());
+        assert!(transaction_from_json(&Json::from_str(r#"{"last_timestamp": [1, 4294967296], "lookup": [], "inserts": [], "deletes": []}"#).unwrap()).is_err());
+        assert!(Edit { position: 4, delete: 2, text: Vec::new() }.apply_to_bytes(b"Hello").is_err());
+    }
+
+    #[test]
+    fn serves_both_framings() {
+        let mut server = RpcServer::new();
+        let open = r#"{"id": 1, "method": "open", "params": {"document": "doc", "site_id": 1}}"#;
+        let open_other = r#"{"id": 1, "method": "open", "params": {"document": "other", "site_id": 1}}"#;
+        let input = format!("{}\nContent-Length: {}\r\n\r\n{}", open, open_other.len(), open_other);
+        let mut output = Vec::new();
+        server.serve(&mut input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let expected = r#"{"id":1,"jsonrpc":"2.0","result":null}"#;
+        assert_eq!(output, format!("{}\nContent-Length: {}\r\n\r\n{}", expected, expected.len(), expected));
+    }
+}
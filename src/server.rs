@@ -0,0 +1,570 @@
+use std::collections::hash_map::HashMap;
+use std::collections::btree_map::BTreeMap;
+use std::collections::linked_list::LinkedList;
+use std::sync::Arc;
+use operations::{Operation, InsertOperation, DeleteOperation, MoveOperation};
+use engine::{Engine, TransactionSequence, TimeStamper};
+use fingerprint::Fingerprint;
+use policy::ConflictPolicy;
+use tiebreak::{TieBreak, SiteOrder};
+use ::{OTError, ErrorKind as Kind, Position};
+
+/// Sequences the transactions of many clients through a central site.
+///
+/// ABST does not require a server, but a server-ordered deployment keeps every client
+/// simple: each client only ever synchronizes with the server, exactly as if there were
+/// just two sites.  The server keeps one such two-site link for every connected client.
+/// When a transaction arrives from a client it is integrated on that client's link,
+/// applied to the server's copy of the document, and then run through every other link
+/// as if the server had made the edit itself, which produces an outbound transaction
+/// that has been transformed for what that client has seen so far.
+///
+/// Each link forgets the history its client has acknowledged, either by sending a transaction
+/// made after seeing the server's edits, or through `acknowledge()`.
+///
+/// Clients use a plain [`Engine`](struct.Engine.html) and [`TimeStamper`](struct.TimeStamper.html),
+/// and treat the server as their only remote site.  The server's copy of the document is the
+/// authoritative one, and every relayed transaction carries its fingerprint so that clients can
+/// check their own copy with `TransactionSequence::verify()`.  Since fingerprints are taken
+/// over bytes, the server and its clients only synchronize bytes.
+#[derive(Debug)]
+pub struct Server {
+    /// The site id the server uses for the edits it relays
+    site_id: u32,
+
+    /// The server's copy of the document
+    document: Vec<u8>,
+
+    /// The two-site link to each connected client, keyed by the client's site id
+    links: HashMap<u32, ClientLink>,
+
+    /// The conflict policy every link uses, which the clients must use as well
+    policy: ConflictPolicy,
+
+    /// The tie-breaking strategy every link uses, which the clients must use as well
+    tie_break: Arc<dyn TieBreak>,
+}
+
+/// The history shared between the server and a single client
+#[derive(Debug)]
+struct ClientLink {
+    engine: Engine,
+    stamper: TimeStamper,
+
+    /// For each of the client's timestamps, the last server edit the client had seen
+    /// when it made that transaction
+    seen: HashMap<u32, Option<(u32, u32)>>,
+
+    /// The most recent of the client's transactions the server has integrated
+    last_integrated: Option<(u32, u32)>,
+}
+
+/// A transaction the server needs to send to a particular client
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    /// The site id of the client this transaction should be sent to
+    pub client_id: u32,
+
+    /// The transaction, transformed for the state the client is known to have
+    pub transaction: TransactionSequence,
+
+    /// The timestamp lookup to send along with `transaction`
+    pub lookup: BTreeMap<u32, (u32, u32)>,
+}
+
+/// Tells a client that the server has integrated its transactions.
+///
+/// Every transaction the client sent up to and including `state` has been sequenced by the
+/// server and relayed to the other clients, so the client no longer needs to hold on to
+/// them in case they must be sent again.  Every transaction the server sends the client from
+/// then on knows about them, so once the client has integrated the transactions the server
+/// sent before the acknowledgement, it can pass `state` to `Engine::discard_history()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Acknowledgement {
+    /// The site id of the client whose transactions were integrated
+    pub client_id: u32,
+
+    /// The `(site id, timestamp)` state of the most recent transaction integrated
+    pub state: (u32, u32),
+}
+
+impl Server {
+    /// Creates a new server for an empty document.  `site_id` must be distinct from the
+    /// site id of every client.
+    #[inline]
+    pub fn new(site_id: u32) -> Server {
+        Server::with_document(site_id, Vec::new())
+    }
+
+    /// Creates a new server whose document starts out containing `document`
+    #[inline]
+    pub fn with_document(site_id: u32, document: Vec<u8>) -> Server {
+        Server {
+            site_id: site_id,
+            document: document,
+            links: HashMap::new(),
+            policy: ConflictPolicy::default(),
+            tie_break: Arc::new(SiteOrder),
+        }
+    }
+
+    /// Gets the conflict policy that the server and its clients use
+    #[inline]
+    pub fn get_conflict_policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+
+    /// Sets the conflict policy that the server and its clients use.  It should be chosen
+    /// before any client connects, since every client's engine must be given the same policy.
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.policy = policy;
+        for link in self.links.values_mut() {
+            link.engine.set_conflict_policy(policy);
+        }
+    }
+
+    /// Gets the strategy the server and its clients use to order concurrent inserts at the same position
+    #[inline]
+    pub fn get_tie_break(&self) -> &dyn TieBreak {
+        &*self.tie_break
+    }
+
+    /// Sets the strategy the server and its clients use to order concurrent inserts at the same
+    /// position.  Like the conflict policy, it should be chosen before any client connects.
+    pub fn set_tie_break(&mut self, tie_break: Arc<dyn TieBreak>) {
+        for link in self.links.values_mut() {
+            link.engine.set_tie_break(tie_break.clone());
+        }
+        self.tie_break = tie_break;
+    }
+
+    /// Gets the server's current copy of the document
+    pub fn get_document(&self) -> &[u8] {
+        &self.document
+    }
+
+    /// Gets the site ids of the connected clients
+    pub fn get_client_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.links.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Connects a new client with an empty engine.  Returns the transaction which brings the
+    /// client up to date with the current document, or `None` if the document is empty.
+    pub fn add_client(&mut self, client_id: u32) -> Result<Option<Outbound>, OTError> {
+        if client_id == self.site_id || self.links.contains_key(&client_id) {
+            return Err(OTError::new(Kind::DuplicateSite));
+        }
+        let mut link = ClientLink {
+            engine: Engine::new(self.site_id),
+            stamper: TimeStamper::new(),
+            seen: HashMap::new(),
+            last_integrated: None,
+        };
+        link.engine.set_conflict_policy(self.policy);
+        link.engine.set_tie_break(self.tie_break.clone());
+        let outbound = if self.document.is_empty() {
+            None
+        } else {
+            let mut inserts = LinkedList::new();
+            inserts.push_back(InsertOperation::new(0, self.document.clone(), 0, self.site_id));
+            let initial = TransactionSequence::new(None, inserts, LinkedList::new());
+            Some(Server::relay(self.site_id, client_id, &mut link, &initial, &self.document))
+        };
+        self.links.insert(client_id, link);
+        Ok(outbound)
+    }
+
+    /// Disconnects a client.  Any transactions it sends afterwards will be rejected.
+    pub fn remove_client(&mut self, client_id: u32) -> Result<(), OTError> {
+        self.links.remove(&client_id).map(|_| ()).ok_or(OTError::new(Kind::NoSuchSite))
+    }
+
+    /// Integrates a transaction received from `client_id`.  Transactions must be passed in
+    /// the order they arrive.
+    ///
+    /// Returns an acknowledgement for the sending client, along with the transactions that
+    /// have to be sent to each of the other clients.
+    pub fn receive(&mut self, client_id: u32, transaction: &mut TransactionSequence, lookup: &BTreeMap<u32, (u32, u32)>) -> Result<(Acknowledgement, Vec<Outbound>), OTError> {
+        let server_id = self.site_id;
+        let state = {
+            let link = try!(self.links.get_mut(&client_id).ok_or(OTError::new(Kind::NoSuchSite)));
+            let state = try!(lookup.values().max_by_key(|&&(_, timestamp)| timestamp).cloned().ok_or(OTError::new(Kind::NoSuchState)));
+            // The client states the last timestamp it had assigned, which may be one of its own.
+            // The server's edits are the only ones that can be concurrent with the client's, so
+            // work out which of them the client had actually seen.
+            let seen = match transaction.get_last_timestamp() {
+                Some((site_id, timestamp)) if site_id == client_id => {
+                    *try!(link.seen.get(&timestamp).ok_or(OTError::new(Kind::NoSuchState)))
+                },
+                last_timestamp => last_timestamp
+            };
+            transaction.set_last_timestamp(seen);
+            // Make sure every remote timestamp has a local counterpart before integrating
+            for (_, &(site_id, timestamp)) in lookup.iter() {
+                link.stamper.stamp_remote(site_id, timestamp);
+                link.seen.insert(timestamp, seen);
+            }
+            try!(link.engine.integrate_remote(transaction, lookup, &mut link.stamper));
+            link.last_integrated = Some(state);
+            // The client's later transactions are made on what it has seen now, and refer to
+            // their own site by this transaction at the earliest
+            if let Some(seen) = seen {
+                try!(link.engine.discard_history(seen, &link.stamper));
+            }
+            link.seen.retain(|&timestamp, _| timestamp >= state.1);
+            state
+        };
+        transaction.capture_deleted(&self.document);
+        self.document = transaction.apply_to_bytes(&self.document);
+
+        let mut outbound = Vec::new();
+        if !transaction.inserts.is_empty() || !transaction.deletes.is_empty() || !transaction.get_formats().is_empty() || !transaction.get_anchors().is_empty() {
+            for (&other_id, link) in self.links.iter_mut() {
+                if other_id != client_id {
+                    outbound.push(Server::relay(server_id, other_id, link, transaction, &self.document));
+                }
+            }
+        }
+        outbound.sort_by_key(|o| o.client_id);
+        trace!("Server document is now {:?}", String::from_utf8_lossy(&self.document));
+        Ok((Acknowledgement {
+            client_id: client_id,
+            state: state,
+        }, outbound))
+    }
+
+    /// Records that `client_id` has integrated the server's transactions up to and including
+    /// `state`, so that the server can forget that part of their shared history.  Clients that
+    /// send transactions acknowledge the server's edits with them, so this is only needed for
+    /// clients that mostly watch.  The client must send it after any transactions it made before
+    /// integrating `state`.
+    pub fn acknowledge(&mut self, client_id: u32, state: (u32, u32)) -> Result<(), OTError> {
+        let link = try!(self.links.get_mut(&client_id).ok_or(OTError::new(Kind::NoSuchSite)));
+        link.engine.discard_history(state, &link.stamper)
+    }
+
+    /// Records `transaction`, which has already been applied to the server's document, as an
+    /// edit made by the server on the given link, and produces the transaction to send over it.
+    /// The transaction carries the fingerprint of `document` so the client can check its copy.
+    fn relay(server_id: u32, client_id: u32, link: &mut ClientLink, transaction: &TransactionSequence, document: &[u8]) -> Outbound {
+        // The client has seen every edit the server sent before, so only the client's
+        // edits that the server has not yet integrated are concurrent with this one.
+        let last_timestamp = link.last_integrated;
+        let timestamp = link.stamper.stamp_local(server_id);
+        let inserts = transaction.inserts.iter().map(|insert| {
+            InsertOperation::new(insert.get_position(), insert.get_value().to_vec(), timestamp, server_id)
+        }).collect();
+        let deletes = transaction.deletes.iter().map(|delete| {
+            let mut relayed = DeleteOperation::new(delete.get_position() as Position, delete.get_length(), timestamp, server_id);
+            relayed.set_content(delete.get_content().map(|content| content.to_vec()));
+            relayed
+        }).collect();
+        let mut relayed = TransactionSequence::new(last_timestamp, inserts, deletes);
+        for format in transaction.get_formats() {
+            let mut format = format.clone();
+            format.set_timestamp(timestamp);
+            format.set_site_id(server_id);
+            relayed.add_format(format);
+        }
+        for anchor in transaction.get_anchors() {
+            let mut anchor = anchor.clone();
+            anchor.set_timestamp(timestamp);
+            anchor.set_site_id(server_id);
+            relayed.add_anchor(anchor);
+        }
+        if let Some(operation) = Server::relayed_move(transaction, timestamp, server_id) {
+            relayed.add_move(operation);
+        }
+        relayed.set_fingerprint(Some(Fingerprint::of(document)));
+        relayed.set_metadata(transaction.get_metadata().cloned());
+        link.engine.process_transaction(&mut relayed);
+        let lookup = link.stamper.get_timestamps_for(&relayed);
+        Outbound {
+            client_id: client_id,
+            transaction: relayed,
+            lookup: lookup,
+        }
+    }
+
+    /// Works out the move made by `transaction`, which has been integrated, as a move made on the
+    /// server's document.  A transaction made by `TransactionSequence::from_move()` has the copy as
+    /// its only insert, and its deletes take the moved range away, wherever the server's
+    /// transformations have left them.
+    fn relayed_move(transaction: &TransactionSequence, timestamp: u32, server_id: u32) -> Option<MoveOperation> {
+        if transaction.get_moves().len() != 1 || transaction.inserts.len() != 1 {
+            return None;
+        }
+        let copy = transaction.inserts.front().unwrap();
+        // Each delete is placed as if the ones before it had already been applied
+        let mut removed = 0;
+        let mut range = None;
+        for delete in transaction.deletes.iter() {
+            let start = delete.get_position() + removed;
+            let end = start + delete.get_length();
+            range = Some(range.map_or((start, end), |(first, _)| (first, end)));
+            removed += delete.get_length();
+        }
+        let (start, end) = range?;
+        Some(MoveOperation::new(start, end - start, copy.get_position(), timestamp, server_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Server, Outbound};
+    use engine::{Engine, TransactionSequence, TimeStamper};
+    use operations::{FormatOperation, AnchorOperation, MoveOperation};
+    use test_utils::local_edit;
+    use std::collections::BTreeMap;
+    use std::collections::linked_list::LinkedList;
+    use ::Position;
+
+    struct Client {
+        site_id: u32,
+        engine: Engine,
+        stamper: TimeStamper,
+        document: Vec<u8>,
+    }
+
+    impl Client {
+        fn new(site_id: u32) -> Client {
+            Client {
+                site_id: site_id,
+                engine: Engine::new(site_id),
+                stamper: TimeStamper::new(),
+                document: Vec::new(),
+            }
+        }
+
+        fn edit(&mut self, inserts: Vec<(Position, &'static str)>, deletes: Vec<(Position, Position)>) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
+            local_edit(&mut self.engine, &mut self.stamper, self.site_id, &mut self.document, inserts, deletes)
+        }
+
+        fn receive(&mut self, mut outbound: Outbound) {
+            assert_eq!(outbound.client_id, self.site_id);
+            for (_, &(site_id, timestamp)) in outbound.lookup.iter() {
+                self.stamper.stamp_remote(site_id, timestamp);
+            }
+            self.engine.integrate_remote(&mut outbound.transaction, &outbound.lookup, &mut self.stamper).unwrap();
+            self.document = outbound.transaction.apply_to_bytes(&self.document);
+            outbound.transaction.verify(&self.document, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn relays_in_arrival_order() {
+        let mut server = Server::with_document(0, "The quick brown fox".bytes().collect());
+        let mut client1 = Client::new(1);
+        let mut client2 = Client::new(2);
+        let mut client3 = Client::new(3);
+        for client in [&mut client1, &mut client2, &mut client3] {
+            let initial = server.add_client(client.site_id).unwrap().unwrap();
+            client.receive(initial);
+            assert_eq!(client.document, server.get_document());
+        }
+
+        // All three clients edit concurrently
+        let (mut t1, l1) = client1.edit(vec![(4, "very ")], vec![(19, 3)]);
+        let (mut t2, l2) = client2.edit(vec![(19, " jumps")], vec![(0, 4)]);
+        let (mut t3, l3) = client3.edit(vec![(9, "est")], vec![(4, 5)]);
+
+        let (ack, outbound) = server.receive(1, &mut t1, &l1).unwrap();
+        assert_eq!(ack.client_id, 1);
+        assert_eq!(ack.state, (1, 1));
+        assert_eq!(outbound.iter().map(|o| o.client_id).collect::<Vec<_>>(), vec![2, 3]);
+        let mut to_2 = vec![];
+        let mut to_3 = vec![];
+        for o in outbound {
+            if o.client_id == 2 { to_2.push(o) } else { to_3.push(o) }
+        }
+
+        let (_, outbound) = server.receive(2, &mut t2, &l2).unwrap();
+        let mut to_1 = vec![];
+        for o in outbound {
+            if o.client_id == 1 { to_1.push(o) } else { to_3.push(o) }
+        }
+        let (_, outbound) = server.receive(3, &mut t3, &l3).unwrap();
+        for o in outbound {
+            if o.client_id == 1 { to_1.push(o) } else { to_2.push(o) }
+        }
+
+        for o in to_1 { client1.receive(o); }
+        for o in to_2 { client2.receive(o); }
+        for o in to_3 { client3.receive(o); }
+
+        assert_eq!(client1.document, server.get_document());
+        assert_eq!(client2.document, server.get_document());
+        assert_eq!(client3.document, server.get_document());
+    }
+
+    #[test]
+    fn relays_formats() {
+        let mut server = Server::with_document(0, "The quick brown fox".bytes().collect());
+        let mut client1 = Client::new(1);
+        let mut client2 = Client::new(2);
+        for client in [&mut client1, &mut client2] {
+            let initial = server.add_client(client.site_id).unwrap().unwrap();
+            client.receive(initial);
+        }
+
+        // One client makes "quick" bold while the other writes inside it
+        let last_timestamp = client1.stamper.get_last_timestamp().map(|(_, remote)| remote);
+        let timestamp = client1.stamper.stamp_local(1);
+        let mut t1 = TransactionSequence::new(last_timestamp, LinkedList::new(), LinkedList::new());
+        t1.add_format(FormatOperation::new(4, 5, "bold", Some("true".to_string()), timestamp, 1));
+        client1.engine.process_transaction(&mut t1);
+        let l1 = client1.stamper.get_timestamps_for(&t1);
+        let (mut t2, l2) = client2.edit(vec![(6, "-i-")], vec![]);
+
+        let (_, to_1) = server.receive(2, &mut t2, &l2).unwrap();
+        let (_, to_2) = server.receive(1, &mut t1, &l1).unwrap();
+        assert_eq!(to_2.len(), 1);
+        for o in to_1 { client1.receive(o); }
+        for o in to_2 { client2.receive(o); }
+
+        assert_eq!(client1.document, b"The qu-i-ick brown fox");
+        assert_eq!(client2.document, client1.document);
+        let runs = |client: &Client| client.engine.get_formatting().get_runs(client.document.len() as Position).into_iter().map(|(start, end, attributes)| {
+            (start, end, attributes.get("bold").cloned())
+        }).collect::<Vec<_>>();
+        assert_eq!(runs(&client1), vec![(0, 4, None), (4, 12, Some("true".to_string())), (12, 22, None)]);
+        assert_eq!(runs(&client2), runs(&client1));
+    }
+
+    #[test]
+    fn relays_anchors() {
+        let mut server = Server::with_document(0, "The quick brown fox".bytes().collect());
+        let mut client1 = Client::new(1);
+        let mut client2 = Client::new(2);
+        for client in [&mut client1, &mut client2] {
+            let initial = server.add_client(client.site_id).unwrap().unwrap();
+            client.receive(initial);
+        }
+
+        // One client starts a thread on "quick brown" while the other writes inside it
+        let thread = client1.engine.get_comments_mut().next_id(1);
+        let last_timestamp = client1.stamper.get_last_timestamp().map(|(_, remote)| remote);
+        let timestamp = client1.stamper.stamp_local(1);
+        let mut t1 = TransactionSequence::new(last_timestamp, LinkedList::new(), LinkedList::new());
+        t1.add_anchor(AnchorOperation::new(4, 11, thread, timestamp, 1));
+        client1.engine.process_transaction(&mut t1);
+        let l1 = client1.stamper.get_timestamps_for(&t1);
+        let (mut t2, l2) = client2.edit(vec![(10, "red ")], vec![]);
+
+        let (_, to_1) = server.receive(2, &mut t2, &l2).unwrap();
+        let (_, to_2) = server.receive(1, &mut t1, &l1).unwrap();
+        assert_eq!(to_2.len(), 1);
+        for o in to_1 { client1.receive(o); }
+        for o in to_2 { client2.receive(o); }
+
+        assert_eq!(client1.document, b"The quick red brown fox");
+        assert_eq!(client2.document, client1.document);
+        for client in [&client1, &client2].iter() {
+            assert_eq!(client.engine.get_comments().get_thread(thread).unwrap().get_range(), Some((4, 19)));
+        }
+    }
+
+    #[test]
+    fn relays_moves() {
+        let mut server = Server::with_document(0, "one two three".bytes().collect());
+        let mut client1 = Client::new(1);
+        let mut client2 = Client::new(2);
+        for client in [&mut client1, &mut client2] {
+            let initial = server.add_client(client.site_id).unwrap().unwrap();
+            client.receive(initial);
+        }
+
+        // One client moves "two " to the front, while the other puts an "X" in it and deletes its "o"
+        let last_timestamp = client1.stamper.get_last_timestamp().map(|(_, remote)| remote);
+        let timestamp = client1.stamper.stamp_local(1);
+        let mut t1 = TransactionSequence::from_move(last_timestamp, &MoveOperation::new(4, 4, 0, timestamp, 1), &client1.document).unwrap();
+        client1.document = t1.apply_to_bytes(&client1.document);
+        client1.engine.process_transaction(&mut t1);
+        let l1 = client1.stamper.get_timestamps_for(&t1);
+        let (mut t2, l2) = client2.edit(vec![(5, "X")], vec![(7, 1)]);
+
+        let (_, mut to_2) = server.receive(1, &mut t1, &l1).unwrap();
+        let (_, to_1) = server.receive(2, &mut t2, &l2).unwrap();
+        for o in to_1 { client1.receive(o); }
+        assert_eq!(client1.document, b"two one Xthree");
+
+        // The client that made the edits carries them over to where the range went
+        let Outbound { mut transaction, lookup, .. } = to_2.remove(0);
+        assert_eq!(transaction.get_moves().len(), 1);
+        for (_, &(site_id, timestamp)) in lookup.iter() {
+            client2.stamper.stamp_remote(site_id, timestamp);
+        }
+        let (mut carried, lookup) = client2.engine.integrate_remote_with_moves(&mut transaction, &lookup, &mut client2.document, &mut client2.stamper).unwrap().unwrap();
+        assert_eq!(client2.document, b"tXw one three");
+
+        let (_, to_1) = server.receive(2, &mut carried, &lookup).unwrap();
+        for o in to_1 { client1.receive(o); }
+        assert_eq!(client1.document, client2.document);
+        assert_eq!(server.get_document(), &client2.document[..]);
+    }
+
+    #[test]
+    fn acknowledged_history_is_discarded() {
+        let mut server = Server::with_document(0, "The quick brown fox".bytes().collect());
+        let mut client1 = Client::new(1);
+        let mut client2 = Client::new(2);
+        let mut watcher = Client::new(3);
+        for client in [&mut client1, &mut client2, &mut watcher] {
+            let initial = server.add_client(client.site_id).unwrap().unwrap();
+            client.receive(initial);
+        }
+
+        #[allow(clippy::type_complexity)]
+        let rounds: Vec<(Vec<(Position, &'static str)>, Vec<(Position, Position)>, Vec<(Position, &'static str)>, Vec<(Position, Position)>)> = vec![
+            (vec![(4, "very ")], vec![(14, 6)], vec![(19, " jumps")], vec![(0, 4)]),
+            (vec![(0, "A ")], vec![(2, 5)], vec![(3, "ish")], vec![(5, 3)]),
+            (vec![], vec![(0, 2)], vec![(0, "Oh, ")], vec![]),
+        ];
+        for (inserts1, deletes1, inserts2, deletes2) in rounds {
+            let (mut t1, l1) = client1.edit(inserts1, deletes1);
+            let (mut t2, l2) = client2.edit(inserts2, deletes2);
+            let (ack1, to_others) = server.receive(1, &mut t1, &l1).unwrap();
+            let mut outbound = to_others;
+            let (ack2, to_others) = server.receive(2, &mut t2, &l2).unwrap();
+            outbound.extend(to_others);
+            for o in outbound {
+                match o.client_id {
+                    1 => client1.receive(o),
+                    2 => client2.receive(o),
+                    _ => watcher.receive(o),
+                }
+            }
+            // Each acknowledgement arrives after what the server sent before it
+            client1.engine.discard_history(ack1.state, &client1.stamper).unwrap();
+            client2.engine.discard_history(ack2.state, &client2.stamper).unwrap();
+            let seen = watcher.stamper.get_last_remote_timestamp(watcher.site_id).unwrap();
+            server.acknowledge(3, seen).unwrap();
+
+            assert_eq!(client1.document, server.get_document());
+            assert_eq!(client2.document, server.get_document());
+            assert_eq!(watcher.document, server.get_document());
+        }
+
+        // Nothing from before the last round is left to resend
+        for client_id in server.get_client_ids() {
+            let link = server.links.get(&client_id).unwrap();
+            assert!(link.engine.get_operations_since(None, &link.stamper).is_err());
+        }
+        assert!(client1.engine.get_operations_since(None, &client1.stamper).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_clients() {
+        let mut server = Server::new(0);
+        assert!(server.add_client(1).unwrap().is_none());
+        assert!(server.add_client(1).is_err());
+        assert!(server.add_client(0).is_err());
+        let mut client = Client::new(2);
+        let (mut transaction, lookup) = client.edit(vec![(0, "Hi")], vec![]);
+        assert!(server.receive(2, &mut transaction, &lookup).is_err());
+        server.remove_client(1).unwrap();
+        assert!(server.remove_client(1).is_err());
+    }
+}
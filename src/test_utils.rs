@@ -0,0 +1,32 @@
+//! Helpers shared by the tests of the other modules
+use std::collections::BTreeMap;
+use engine::{Engine, TransactionSequence, TimeStamper};
+use operations::{InsertOperation, DeleteOperation};
+use Position;
+
+/// Makes a local edit at `site_id` on the state `stamper` last saw, applies it to `document`
+/// and runs it through `engine`.  The deletes record what they removed.  Returns the
+/// transaction and its lookup, ready to be integrated by another site.
+pub fn local_edit(engine: &mut Engine, stamper: &mut TimeStamper, site_id: u32, document: &mut Vec<u8>, inserts: Vec<(Position, &str)>, deletes: Vec<(Position, Position)>) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
+    edit(engine, stamper, site_id, document, inserts, deletes, true)
+}
+
+/// Like `local_edit()`, but the deletes don't record what they removed
+pub fn local_edit_without_content(engine: &mut Engine, stamper: &mut TimeStamper, site_id: u32, document: &mut Vec<u8>, inserts: Vec<(Position, &str)>, deletes: Vec<(Position, Position)>) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
+    edit(engine, stamper, site_id, document, inserts, deletes, false)
+}
+
+fn edit(engine: &mut Engine, stamper: &mut TimeStamper, site_id: u32, document: &mut Vec<u8>, inserts: Vec<(Position, &str)>, deletes: Vec<(Position, Position)>, capture: bool) -> (TransactionSequence, BTreeMap<u32, (u32, u32)>) {
+    let last_timestamp = stamper.get_last_timestamp().map(|(_, remote)| remote);
+    let timestamp = stamper.stamp_local(site_id);
+    let inserts = inserts.into_iter().map(|(position, text)| InsertOperation::new(position, text.bytes().collect(), timestamp, site_id)).collect();
+    let deletes = deletes.into_iter().map(|(position, length)| DeleteOperation::new(position, length, timestamp, site_id)).collect();
+    let mut transaction = TransactionSequence::new(last_timestamp, inserts, deletes);
+    if capture {
+        transaction.capture_deleted(document);
+    }
+    *document = transaction.apply_to_bytes(document);
+    engine.process_transaction(&mut transaction);
+    let lookup = stamper.get_timestamps_for(&transaction);
+    (transaction, lookup)
+}
@@ -0,0 +1,327 @@
+//! Helpers for documents that hold UTF-8 text.
+//!
+//! The engine always works in byte offsets, and a diff of two versions of a file can cut a
+//! character in half, which lets concurrent edits leave invalid UTF-8 behind.  An engine in
+//! text mode (see `Engine::set_text_mode()`) widens the local edits from
+//! `process_diffs_with_base()` to whole characters, and transactions built some other way can
+//! be widened with `TransactionSequence::align_to_chars()`.  As long as every site only
+//! sends whole characters, the transformed operations will only ever fall between characters too.
+//!
+//! The functions here convert between the byte offsets the engine uses and the character
+//! (Unicode scalar value) offsets an editor might use.  Editors more often address text by
+//! line and column, with columns counted in UTF-8 or UTF-16 code units or in characters,
+//! so a [`TextEdit`](struct.TextEdit.html) can be turned into operations for the engine, and
+//! an integrated transaction can be turned back into the `TextEdit`s to make to the buffer.
+//! Lines end at each `\n`.
+use std::collections::LinkedList;
+use std::str;
+use engine::TransactionSequence;
+use operations::{InsertOperation, DeleteOperation, Operation};
+use {OTError, ErrorKind, Position};
+
+/// What the columns of a [`LineColumn`](struct.LineColumn.html) count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnUnit {
+    /// Bytes of UTF-8
+    Utf8,
+    /// UTF-16 code units, as in the Language Server Protocol and JavaScript strings
+    Utf16,
+    /// Characters, or Unicode scalar values
+    Utf32,
+}
+
+/// A position in a text document, given by a line and a column, both counted from zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineColumn {
+    /// The line, counted from zero
+    pub line: u64,
+    /// The column, counted from zero in some [`ColumnUnit`](enum.ColumnUnit.html)
+    pub column: u64,
+}
+
+/// A change to a text document, which replaces the text from `start` up to `end` with `text`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    /// The start of the replaced range
+    pub start: LineColumn,
+    /// The end of the replaced range, which is the same as `start` if nothing is replaced
+    pub end: LineColumn,
+    /// The text to put in place of the range
+    pub text: String,
+}
+
+impl LineColumn {
+    /// Creates a position at the given line and column
+    #[inline]
+    pub fn new(line: u64, column: u64) -> LineColumn {
+        LineColumn {
+            line: line,
+            column: column,
+        }
+    }
+}
+
+impl TextEdit {
+    /// Creates an edit that replaces the text from `start` up to `end` with `text`
+    #[inline]
+    pub fn new(start: LineColumn, end: LineColumn, text: String) -> TextEdit {
+        TextEdit {
+            start: start,
+            end: end,
+            text: text,
+        }
+    }
+
+    /// Turns this edit, made to `document`, into the operations that make the same change.  The
+    /// operations are in effect order, ready to be put in a
+    /// [`TransactionSequence`](../struct.TransactionSequence.html).  Fails with `OutOfRange` if
+    /// either end of the edit isn't in the document, or if the end comes before the start.
+    pub fn to_operations(&self, document: &[u8], unit: ColumnUnit, timestamp: u32, site_id: u32) -> Result<(LinkedList<InsertOperation>, LinkedList<DeleteOperation>), OTError> {
+        let start = try!(to_offset(document, self.start, unit).ok_or(OTError::new(ErrorKind::OutOfRange)));
+        let end = try!(to_offset(document, self.end, unit).ok_or(OTError::new(ErrorKind::OutOfRange)));
+        if end < start {
+            return Err(OTError::new(ErrorKind::OutOfRange));
+        }
+        let mut inserts = LinkedList::new();
+        let mut deletes = LinkedList::new();
+        // Deletes happen after inserts, so the deleted text follows the inserted text
+        if end > start {
+            deletes.push_back(DeleteOperation::new(start + self.text.len() as Position, end - start, timestamp, site_id));
+        }
+        if !self.text.is_empty() {
+            inserts.push_back(InsertOperation::new(start, self.text.as_bytes().to_vec(), timestamp, site_id));
+        }
+        Ok((inserts, deletes))
+    }
+
+    /// Gets the edits that will make the same change to `document` as an integrated
+    /// transaction.  `document` is the buffer before the transaction is applied, and the edits
+    /// must be made in the order they are given, since each takes the edits before it into
+    /// account.  Returns `None` if an edit would start or end inside a character, or inserts
+    /// text that isn't UTF-8.
+    pub fn from_transaction(document: &[u8], transaction: &TransactionSequence, unit: ColumnUnit) -> Option<Vec<TextEdit>> {
+        let mut document = document.to_vec();
+        let mut edits = Vec::new();
+        // The inserts are in effect order, and the deletes are positioned as though every
+        // insert and every earlier delete has already been made
+        for insert in transaction.inserts.iter() {
+            let position = to_line_column(&document, insert.get_position(), unit)?;
+            let text = match str::from_utf8(insert.get_value()) {
+                Ok(text) => text.to_string(),
+                Err(_) => return None
+            };
+            let offset = insert.get_position() as usize;
+            document = [&document[..offset], insert.get_value(), &document[offset..]].concat();
+            edits.push(TextEdit::new(position, position, text));
+        }
+        for delete in transaction.deletes.iter().filter(|delete| delete.get_length() > 0) {
+            let start = to_line_column(&document, delete.get_position(), unit);
+            let end = to_line_column(&document, delete.get_position() + delete.get_length(), unit);
+            match (start, end) {
+                (Some(start), Some(end)) => edits.push(TextEdit::new(start, end, String::new())),
+                _ => return None
+            }
+            let offset = delete.get_position() as usize;
+            document = [&document[..offset], &document[offset + delete.get_length() as usize..]].concat();
+        }
+        Some(edits)
+    }
+}
+
+/// Checks whether `offset` falls between two characters of `document`, or at either end of it
+pub fn is_char_boundary(document: &[u8], offset: Position) -> bool {
+    match document.get(offset as usize) {
+        Some(&byte) => !is_continuation(byte),
+        None => offset == document.len() as Position
+    }
+}
+
+/// Converts a byte offset into `document` into the number of characters before it.  Returns
+/// `None` if the offset is past the end of the document or in the middle of a character.
+pub fn to_char_offset(document: &[u8], byte_offset: Position) -> Option<Position> {
+    if !is_char_boundary(document, byte_offset) {
+        return None;
+    }
+    Some(document[..byte_offset as usize].iter().filter(|&&byte| !is_continuation(byte)).count() as Position)
+}
+
+/// Converts a number of characters from the start of `document` into a byte offset.  Returns
+/// `None` if the document doesn't have that many characters.
+pub fn to_byte_offset(document: &[u8], char_offset: Position) -> Option<Position> {
+    let mut chars = 0;
+    for (index, &byte) in document.iter().enumerate() {
+        if !is_continuation(byte) {
+            if chars == char_offset {
+                return Some(index as Position);
+            }
+            chars += 1;
+        }
+    }
+    if chars == char_offset {
+        Some(document.len() as Position)
+    } else {
+        None
+    }
+}
+
+/// Converts a line and column in `document` into a byte offset.  As in the Language Server
+/// Protocol, a column past the end of its line means the end of the line.  Returns `None` if
+/// the document doesn't have the line, or if the column is in the middle of a character.
+pub fn to_offset(document: &[u8], position: LineColumn, unit: ColumnUnit) -> Option<Position> {
+    let (start, end) = line_bounds(document, position.line)?;
+    let line = &document[start..end];
+    let column = match unit {
+        ColumnUnit::Utf8 => {
+            let column = if position.column > line.len() as u64 { line.len() } else { position.column as usize };
+            if !is_char_boundary(line, column as Position) {
+                return None;
+            }
+            column
+        },
+        ColumnUnit::Utf16 | ColumnUnit::Utf32 => {
+            let line = match str::from_utf8(line) {
+                Ok(line) => line,
+                Err(_) => return None
+            };
+            let mut units = 0;
+            let mut column = line.len();
+            for (index, character) in line.char_indices() {
+                if units == position.column {
+                    column = index;
+                    break;
+                } else if units > position.column {
+                    return None;
+                }
+                units += unit_length(character, unit);
+            }
+            if column == line.len() && units > position.column {
+                return None;
+            }
+            column
+        }
+    };
+    Some((start + column) as Position)
+}
+
+/// Converts a byte offset into `document` into a line and column.  Returns `None` if the
+/// offset is past the end of the document or in the middle of a character.
+pub fn to_line_column(document: &[u8], offset: Position, unit: ColumnUnit) -> Option<LineColumn> {
+    if offset > document.len() as Position || !is_char_boundary(document, offset) {
+        return None;
+    }
+    let before = &document[..offset as usize];
+    let line = before.iter().filter(|&&byte| byte == b'\n').count() as u64;
+    let start = before.iter().rposition(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+    let column = match unit {
+        ColumnUnit::Utf8 => (before.len() - start) as u64,
+        ColumnUnit::Utf16 | ColumnUnit::Utf32 => {
+            match str::from_utf8(&before[start..]) {
+                Ok(text) => text.chars().map(|character| unit_length(character, unit)).sum(),
+                Err(_) => return None
+            }
+        }
+    };
+    Some(LineColumn::new(line, column))
+}
+
+/// Finds where the given line starts and ends in `document`, not counting its `\n`
+fn line_bounds(document: &[u8], line: u64) -> Option<(usize, usize)> {
+    let mut start = 0;
+    for _ in 0..line {
+        match document[start..].iter().position(|&byte| byte == b'\n') {
+            Some(index) => start += index + 1,
+            None => return None
+        }
+    }
+    let end = document[start..].iter().position(|&byte| byte == b'\n').map_or(document.len(), |index| start + index);
+    Some((start, end))
+}
+
+/// How many of `unit` it takes to hold `character`
+#[inline]
+fn unit_length(character: char, unit: ColumnUnit) -> u64 {
+    match unit {
+        ColumnUnit::Utf8 => character.len_utf8() as u64,
+        ColumnUnit::Utf16 => character.len_utf16() as u64,
+        ColumnUnit::Utf32 => 1,
+    }
+}
+
+/// Whether `byte` continues a character rather than starting one
+#[inline]
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_char_boundary, to_char_offset, to_byte_offset, to_offset, to_line_column, LineColumn, ColumnUnit, TextEdit};
+    use engine::TransactionSequence;
+
+    #[test]
+    fn offsets() {
+        let document = "añ€𝄞!".as_bytes();
+        assert!(is_char_boundary(document, 0));
+        assert!(is_char_boundary(document, 1));
+        assert!(!is_char_boundary(document, 2));
+        assert!(is_char_boundary(document, 3));
+        assert!(!is_char_boundary(document, 8));
+        assert!(is_char_boundary(document, 11));
+        assert!(!is_char_boundary(document, 12));
+
+        assert_eq!(to_char_offset(document, 3), Some(2));
+        assert_eq!(to_char_offset(document, 6), Some(3));
+        assert_eq!(to_char_offset(document, 11), Some(5));
+        assert_eq!(to_char_offset(document, 4), None);
+        assert_eq!(to_char_offset(document, 12), None);
+
+        assert_eq!(to_byte_offset(document, 0), Some(0));
+        assert_eq!(to_byte_offset(document, 3), Some(6));
+        assert_eq!(to_byte_offset(document, 4), Some(10));
+        assert_eq!(to_byte_offset(document, 5), Some(11));
+        assert_eq!(to_byte_offset(document, 6), None);
+    }
+
+    #[test]
+    fn line_columns() {
+        // "𝄞" takes two UTF-16 code units, and "é" takes two bytes
+        let document = "first\nsé𝄞x\n\nlast".as_bytes();
+        assert_eq!(to_offset(document, LineColumn::new(0, 2), ColumnUnit::Utf8), Some(2));
+        assert_eq!(to_offset(document, LineColumn::new(1, 3), ColumnUnit::Utf8), Some(9));
+        assert_eq!(to_offset(document, LineColumn::new(1, 2), ColumnUnit::Utf8), None);
+        assert_eq!(to_offset(document, LineColumn::new(1, 4), ColumnUnit::Utf16), Some(13));
+        assert_eq!(to_offset(document, LineColumn::new(1, 3), ColumnUnit::Utf16), None);
+        assert_eq!(to_offset(document, LineColumn::new(1, 3), ColumnUnit::Utf32), Some(13));
+        assert_eq!(to_offset(document, LineColumn::new(1, 40), ColumnUnit::Utf16), Some(14));
+        assert_eq!(to_offset(document, LineColumn::new(2, 0), ColumnUnit::Utf16), Some(15));
+        assert_eq!(to_offset(document, LineColumn::new(3, 4), ColumnUnit::Utf16), Some(20));
+        assert_eq!(to_offset(document, LineColumn::new(4, 0), ColumnUnit::Utf16), None);
+
+        assert_eq!(to_line_column(document, 13, ColumnUnit::Utf8), Some(LineColumn::new(1, 7)));
+        assert_eq!(to_line_column(document, 13, ColumnUnit::Utf16), Some(LineColumn::new(1, 4)));
+        assert_eq!(to_line_column(document, 13, ColumnUnit::Utf32), Some(LineColumn::new(1, 3)));
+        assert_eq!(to_line_column(document, 6, ColumnUnit::Utf16), Some(LineColumn::new(1, 0)));
+        assert_eq!(to_line_column(document, 20, ColumnUnit::Utf16), Some(LineColumn::new(3, 4)));
+        assert_eq!(to_line_column(document, 8, ColumnUnit::Utf16), None);
+        assert_eq!(to_line_column(document, 21, ColumnUnit::Utf16), None);
+    }
+
+    #[test]
+    fn text_edits() {
+        let document = "let x = 1;\nlet é = 2;\n".as_bytes();
+        // Replace "é = 2" with "y = 3"
+        let edit = TextEdit::new(LineColumn::new(1, 4), LineColumn::new(1, 9), "y = 3".to_string());
+        let (inserts, deletes) = edit.to_operations(document, ColumnUnit::Utf16, 0, 1).unwrap();
+        let transaction = TransactionSequence::new(None, inserts, deletes);
+        assert_eq!(transaction.apply_to_bytes(document), "let x = 1;\nlet y = 3;\n".as_bytes());
+        assert_eq!(TextEdit::from_transaction(document, &transaction, ColumnUnit::Utf16), Some(vec![
+            TextEdit::new(LineColumn::new(1, 4), LineColumn::new(1, 4), "y = 3".to_string()),
+            TextEdit::new(LineColumn::new(1, 9), LineColumn::new(1, 14), String::new()),
+        ]));
+
+        let backwards = TextEdit::new(LineColumn::new(1, 4), LineColumn::new(0, 9), String::new());
+        assert!(backwards.to_operations(document, ColumnUnit::Utf16, 0, 1).is_err());
+        let split = TextEdit::new(LineColumn::new(1, 4), LineColumn::new(1, 5), String::new());
+        assert!(split.to_operations(document, ColumnUnit::Utf8, 0, 1).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::btree_map::BTreeMap;
+use std::collections::hash_map::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use byteorder::{NetworkEndian, ByteOrder};
+use operations::{Operation, InsertOperation};
+use engine::TimeStamper;
+use element::Element;
+
+/// Decides which of two concurrent inserts at the same position goes first.
+///
+/// Each insert is identified by the id of the site that made it and the timestamp its
+/// transaction was given there, which are the same on every site.  The strategy is only asked
+/// about inserts from different sites, and must give the same answer on every site, or their
+/// documents will diverge.  For any two inserts, exactly one of them must go first.
+pub trait TieBreak: fmt::Debug + Send + Sync {
+    /// Whether the insert made by `first` at site `first.0` and timestamp `first.1` goes before
+    /// the one made by `second`
+    fn goes_first(&self, first: (u32, u32), second: (u32, u32)) -> bool;
+
+    /// Describes the strategy, so that engines can save it and sessions can check that both
+    /// sides use the same one.  Strategies with the same description must put inserts in the
+    /// same order.  The strategies in this crate start their description with a tag below 128,
+    /// so others should start theirs with a tag of 128 or more.
+    fn describe(&self) -> Vec<u8>;
+}
+
+/// The insert from the site with the lower id goes first.  This is the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SiteOrder;
+
+/// The insert that was stamped earlier at the site that made it goes first, and the site with
+/// the lower id goes first if they were stamped at the same time.
+///
+/// A site's timestamps count every transaction it has seen, so this favours the site that had
+/// seen less of the history when it made its insert, rather than always the same site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimestampOrder;
+
+/// The insert from the site with the higher priority goes first, and the site with the lower
+/// id goes first if they have the same priority.  Sites that haven't been given a priority
+/// have a priority of 0.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PriorityOrder {
+    priorities: HashMap<u32, u32>,
+}
+
+const SITE_ORDER: u8 = 0;
+const TIMESTAMP_ORDER: u8 = 1;
+const PRIORITY_ORDER: u8 = 2;
+
+/// Where the timestamps of the operations in a sequence came from
+#[derive(Clone, Copy)]
+pub enum Clock<'a> {
+    /// The timestamps are local ones, given out by this stamper
+    Local(&'a TimeStamper),
+    /// The timestamps are the sender's, and can be found in the lookup sent along with them
+    Remote(&'a BTreeMap<u32, (u32, u32)>),
+    /// The timestamps are used as they are
+    Own,
+}
+
+/// Breaks ties between an incoming sequence of inserts and an existing one
+pub struct Ties<'a> {
+    strategy: &'a dyn TieBreak,
+    incoming: Clock<'a>,
+    existing: Clock<'a>,
+}
+
+impl TieBreak for SiteOrder {
+    #[inline]
+    fn goes_first(&self, first: (u32, u32), second: (u32, u32)) -> bool {
+        first.0 < second.0
+    }
+
+    fn describe(&self) -> Vec<u8> {
+        vec![SITE_ORDER]
+    }
+}
+
+impl TieBreak for TimestampOrder {
+    #[inline]
+    fn goes_first(&self, first: (u32, u32), second: (u32, u32)) -> bool {
+        first.1 < second.1 || first.1 == second.1 && first.0 < second.0
+    }
+
+    fn describe(&self) -> Vec<u8> {
+        vec![TIMESTAMP_ORDER]
+    }
+}
+
+impl PriorityOrder {
+    /// Creates a priority order in which every site has a priority of 0
+    pub fn new() -> PriorityOrder {
+        PriorityOrder::default()
+    }
+
+    /// Gets the priority of the given site
+    pub fn get_priority(&self, site_id: u32) -> u32 {
+        self.priorities.get(&site_id).cloned().unwrap_or(0)
+    }
+
+    /// Sets the priority of the given site
+    pub fn set_priority(&mut self, site_id: u32, priority: u32) {
+        self.priorities.insert(site_id, priority);
+    }
+}
+
+impl TieBreak for PriorityOrder {
+    fn goes_first(&self, first: (u32, u32), second: (u32, u32)) -> bool {
+        let first_priority = self.get_priority(first.0);
+        let second_priority = self.get_priority(second.0);
+        first_priority > second_priority || first_priority == second_priority && first.0 < second.0
+    }
+
+    fn describe(&self) -> Vec<u8> {
+        // Sites with a priority of 0 are left out, so that orders that rank sites the same
+        // way are described the same way
+        let mut priorities: Vec<_> = self.priorities.iter().filter(|&(_, &priority)| priority != 0).collect();
+        priorities.sort();
+        let mut description = vec![PRIORITY_ORDER];
+        let mut int_buf = [0;4];
+        for (&site_id, &priority) in priorities {
+            NetworkEndian::write_u32(&mut int_buf, site_id);
+            description.extend_from_slice(&int_buf);
+            NetworkEndian::write_u32(&mut int_buf, priority);
+            description.extend_from_slice(&int_buf);
+        }
+        description
+    }
+}
+
+/// Finds the strategy from this crate with the given description
+pub fn restore(description: &[u8]) -> Option<Arc<dyn TieBreak>> {
+    match description.split_first() {
+        Some((&SITE_ORDER, [])) => Some(Arc::new(SiteOrder)),
+        Some((&TIMESTAMP_ORDER, [])) => Some(Arc::new(TimestampOrder)),
+        Some((&PRIORITY_ORDER, priorities)) if priorities.len() % 8 == 0 => {
+            let mut order = PriorityOrder::new();
+            for pair in priorities.chunks(8) {
+                order.set_priority(NetworkEndian::read_u32(&pair[..4]), NetworkEndian::read_u32(&pair[4..]));
+            }
+            Some(Arc::new(order))
+        },
+        _ => None
+    }
+}
+
+/// Writes the description of a strategy to `writer`, so that it can be read back with `expand_from()`
+pub fn compress_to<W: Write>(description: &[u8], writer: &mut W) -> io::Result<()> {
+    let mut int_buf = [0;4];
+    NetworkEndian::write_u32(&mut int_buf, description.len() as u32);
+    try!(writer.write_all(&int_buf));
+    writer.write_all(description)
+}
+
+/// Reads the description of a strategy written by `compress_to()`
+pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut int_buf = [0;4];
+    try!(reader.read_exact(&mut int_buf));
+    u8::read_from(reader, NetworkEndian::read_u32(&int_buf) as usize)
+}
+
+impl<'a> Clock<'a> {
+    /// Finds the timestamp `operation` was given at the site that made it
+    fn origin_of<T: Element>(&self, operation: &InsertOperation<T>) -> u32 {
+        let timestamp = operation.get_timestamp();
+        let origin = match *self {
+            Clock::Local(stamper) => stamper.get_remote_timestamp_for(timestamp),
+            Clock::Remote(lookup) => lookup.get(&timestamp).cloned(),
+            Clock::Own => None
+        };
+        origin.map_or(timestamp, |(_site_id, timestamp)| timestamp)
+    }
+}
+
+impl<'a> Ties<'a> {
+    /// Breaks ties with `strategy`, finding the timestamps of the incoming and existing
+    /// inserts from the given clocks
+    #[inline]
+    pub fn new(strategy: &'a dyn TieBreak, incoming: Clock<'a>, existing: Clock<'a>) -> Ties<'a> {
+        Ties {
+            strategy: strategy,
+            incoming: incoming,
+            existing: existing,
+        }
+    }
+
+    /// Breaks ties by site id, which needs no timestamps
+    #[inline]
+    pub fn by_site() -> Ties<'static> {
+        Ties::new(&SiteOrder, Clock::Own, Clock::Own)
+    }
+
+    /// Whether the incoming insert goes before the existing one, if they are at the same
+    /// position.  An insert never goes before one from its own site.
+    pub fn incoming_first<I: Element, E: Element>(&self, incoming: &InsertOperation<I>, existing: &InsertOperation<E>) -> bool {
+        if incoming.get_site_id() == existing.get_site_id() {
+            return false;
+        }
+        self.strategy.goes_first((incoming.get_site_id(), self.incoming.origin_of(incoming)),
+                                 (existing.get_site_id(), self.existing.origin_of(existing)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TieBreak, SiteOrder, TimestampOrder, PriorityOrder, restore, compress_to, expand_from};
+    use byteorder::{NetworkEndian, ByteOrder};
+
+    #[test]
+    fn site_order() {
+        assert!(SiteOrder.goes_first((1, 8), (2, 3)));
+        assert!(!SiteOrder.goes_first((2, 3), (1, 8)));
+    }
+
+    #[test]
+    fn timestamp_order() {
+        assert!(TimestampOrder.goes_first((2, 3), (1, 8)));
+        assert!(!TimestampOrder.goes_first((1, 8), (2, 3)));
+        assert!(TimestampOrder.goes_first((1, 4), (2, 4)));
+        assert!(!TimestampOrder.goes_first((2, 4), (1, 4)));
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut order = PriorityOrder::new();
+        order.set_priority(3, 5);
+        assert!(order.goes_first((3, 0), (1, 0)));
+        assert!(!order.goes_first((1, 0), (3, 0)));
+        assert!(order.goes_first((1, 0), (2, 0)));
+        assert_eq!(order.get_priority(2), 0);
+    }
+
+    #[test]
+    fn strategies_are_restored_from_their_description() {
+        let mut order = PriorityOrder::new();
+        order.set_priority(3, 5);
+        order.set_priority(4, 0);
+        assert_eq!(restore(&order.describe()).unwrap().describe(), order.describe());
+        assert_eq!(order.describe(), {
+            let mut same = PriorityOrder::new();
+            same.set_priority(3, 5);
+            same.describe()
+        });
+        assert_eq!(restore(&SiteOrder.describe()).unwrap().describe(), SiteOrder.describe());
+        assert_eq!(restore(&TimestampOrder.describe()).unwrap().describe(), vec![1]);
+        assert!(restore(&[200]).is_none());
+    }
+
+    #[test]
+    fn descriptions_longer_than_what_was_written_fail() {
+        let mut buffer = Vec::new();
+        compress_to(&SiteOrder.describe(), &mut buffer).unwrap();
+        assert_eq!(expand_from(&mut &buffer[..]).unwrap(), SiteOrder.describe());
+        NetworkEndian::write_u32(&mut buffer[..4], u32::MAX);
+        assert!(expand_from(&mut &buffer[..]).is_err());
+    }
+}
@@ -1,5 +1,9 @@
-use super::operations::{Operation, DeleteOperation, OverlapResult, CrossResult, OperationInternal, Advance};
-use Offset;
+use std::collections::LinkedList;
+use super::operations::{self, Operation, InsertOperation, DeleteOperation, OverlapResult, CrossResult, OperationInternal, Advance};
+use policy::ConflictPolicy;
+use tiebreak::Ties;
+use element::Element;
+use {Offset, Position};
 
 pub struct SequenceSwapper {
     incoming_offset: Offset,
@@ -11,28 +15,40 @@ pub struct SequenceSplitter {
     existing_offset: Offset,
 }
 
-pub struct SequenceTransformer {
+pub struct SequenceTransformer<'a> {
     incoming_offset: Offset,
     existing_offset: Offset,
     total_overlap: Offset,
+    policy: ConflictPolicy,
+    ties: &'a Ties<'a>,
 }
 
-impl SequenceTransformer {
+impl<'a> SequenceTransformer<'a> {
+    /// Creates a transformer that settles inserts made inside concurrently deleted ranges
+    /// according to `policy`, and orders inserts made at the same position with `ties`
     #[inline]
-    pub fn new() -> SequenceTransformer {
+    pub fn new(policy: ConflictPolicy, ties: &'a Ties<'a>) -> SequenceTransformer<'a> {
         SequenceTransformer {
             incoming_offset: 0,
             existing_offset: 0,
             total_overlap: 0,
+            policy: policy,
+            ties: ties,
         }
     }
 
-    pub fn transform_operations<O1: OperationInternal, O2: OperationInternal>(&mut self, incoming_operation: &mut O1, exisiting_operation: &O2) -> Advance<O1> {
+    /// Transforms the incoming operation by the existing one.  If they overlapped, also returns
+    /// how, along with the incoming operation as it was beforehand.
+    pub fn transform_operations<O1: OperationInternal, O2: OperationInternal>(&mut self, incoming_operation: &mut O1, exisiting_operation: &O2) -> (Advance<O1>, Option<(O1, OverlapResult)>) {
         trace!("Before: Existing: {:?}, Offset: {:?}. Incoming: {:?}, Offset: {:?}, overlap: {}", exisiting_operation, self.existing_offset, incoming_operation, self.incoming_offset, self.total_overlap);
-        let overlap_result = incoming_operation.check_overlap(exisiting_operation, self.incoming_offset, self.existing_offset);
+        let overlap_result = incoming_operation.check_overlap(exisiting_operation, self.incoming_offset, self.existing_offset, self.ties);
+        let collision = match overlap_result {
+            OverlapResult::Precedes | OverlapResult::Follows => None,
+            overlap => Some((incoming_operation.clone(), overlap))
+        };
         let r = self.update_with(overlap_result, incoming_operation, exisiting_operation);
         trace!("After: Existing: {:?}, Offset: {:?}. Incoming: {:?}, Offset: {:?}, overlap: {}", exisiting_operation, self.existing_offset, incoming_operation, self.incoming_offset, self.total_overlap);
-        r
+        (r, collision)
     }
 
     pub fn transform_single<O: OperationInternal>(&self, operation: &mut O) {
@@ -55,9 +71,14 @@ impl SequenceTransformer {
                 self.incoming_offset += incoming_operation.get_increment();
                 //move to front of the other operation
                 incoming_operation.update_position_by(self.existing_offset + self.total_overlap - front_difference as Offset);
-                self.total_overlap -= incoming_operation.get_increment() as Offset;
-                // remove its length
-                incoming_operation.set_length_to_zero();
+                // remove its length, and account for whatever it no longer changes
+                let increment = incoming_operation.get_increment();
+                if self.policy == ConflictPolicy::DropInsert {
+                    incoming_operation.discard();
+                } else {
+                    incoming_operation.set_length_to_zero();
+                }
+                self.total_overlap -= increment - incoming_operation.get_increment();
                 Advance::Incoming
             },
             OverlapResult::Encloses(front_difference) => {
@@ -70,7 +91,8 @@ impl SequenceTransformer {
                 self.existing_offset += exisiting_operation.get_increment();
                 self.total_overlap += amount as Offset;
                 self.incoming_offset -= amount as Offset;
-                incoming_operation.update_size_by(-(amount as Offset));
+                // The front of the incoming operation is the part that was already changed
+                incoming_operation.trim_front(amount);
                 //incoming_operation.update_position_by(amount as Offset);
 
                 Advance::Existing
@@ -96,7 +118,7 @@ impl SequenceSwapper {
         }
     }
 
-    pub fn swap_operations<O: OperationInternal>(&mut self, incoming_operation: &mut O, exisiting_operation: &mut DeleteOperation) -> bool {
+    pub fn swap_operations<O: OperationInternal, T: Element>(&mut self, incoming_operation: &mut O, exisiting_operation: &mut DeleteOperation<T>) -> bool {
         trace!("Before: Existing: {:?}, Offset: {:?}. Incoming: {:?}, Offset: {:?}", exisiting_operation, self.existing_offset, incoming_operation, self.incoming_offset);
         let r = if incoming_operation.get_position() as Offset - self.incoming_offset - self.existing_offset < exisiting_operation.get_position() as Offset - self.existing_offset {
             self.incoming_offset += incoming_operation.get_increment();
@@ -116,7 +138,7 @@ impl SequenceSwapper {
         operation.update_position_by(-self.existing_offset);
     }
 
-    pub fn swap_existing(&self, operation: &mut DeleteOperation) {
+    pub fn swap_existing<T: Element>(&self, operation: &mut DeleteOperation<T>) {
         operation.update_position_by(self.incoming_offset);
     }
 }
@@ -130,9 +152,9 @@ impl SequenceSplitter {
         }
     }
 
-    pub fn split_operations(&mut self, incoming_operation: &mut DeleteOperation, exisiting_operation: &DeleteOperation) -> Advance<DeleteOperation> {
+    pub fn split_operations<T: Element>(&mut self, incoming_operation: &mut DeleteOperation<T>, exisiting_operation: &DeleteOperation<T>) -> Advance<DeleteOperation<T>> {
         trace!("Before: Existing: {:?}, Offset: {:?}. Incoming: {:?}, Offset: {:?}", exisiting_operation, self.existing_offset, incoming_operation, self.incoming_offset);
-        let overlap_result = exisiting_operation.crossed_by(incoming_operation, self.existing_offset, self.incoming_offset + self.existing_offset);
+        let overlap_result = operations::delete_crosses(incoming_operation, exisiting_operation, self.incoming_offset + self.existing_offset, self.existing_offset);
         trace!("Cross: {:?}", overlap_result);
         let r = match overlap_result {
             CrossResult::Precedes => {
@@ -161,3 +183,78 @@ impl SequenceSplitter {
     //     operation.update_position_by(self.incoming_offset);
     // }
 }
+
+/// Applies the inserts and then the deletes of a transaction to `document`, pairing each byte
+/// of the result with the index of the byte of `document` it came from, if any.  Returns `None`
+/// if an operation reaches past the end of the document it is applied to.
+pub fn trace_origins(document: &[u8], inserts: &LinkedList<InsertOperation>, deletes: &LinkedList<DeleteOperation>) -> Option<Vec<(u8, Option<usize>)>> {
+    let mut bytes = Vec::with_capacity(document.len());
+    let mut next = 0;
+    for insert in inserts.iter() {
+        while (bytes.len() as Position) < insert.get_position() {
+            match document.get(next) {
+                Some(&byte) => bytes.push((byte, Some(next))),
+                None => return None
+            }
+            next += 1;
+        }
+        bytes.extend(insert.get_value().iter().map(|&byte| (byte, None)));
+    }
+    bytes.extend(document[next..].iter().enumerate().map(|(index, &byte)| (byte, Some(next + index))));
+    let mut kept = vec![true; bytes.len()];
+    let mut removed = 0;
+    for delete in deletes.iter() {
+        let start = delete.get_position() as usize + removed;
+        let end = start + delete.get_length() as usize;
+        match kept.get_mut(start..end) {
+            Some(range) => for keep in range.iter_mut() {
+                *keep = false;
+            },
+            None => return None
+        }
+        removed += delete.get_length() as usize;
+    }
+    Some(bytes.into_iter().zip(kept).filter(|&(_, keep)| keep).map(|(byte, _)| byte).collect())
+}
+
+/// Finds each region that was changed to get from a document of `document_length` bytes to
+/// `result`, whose bytes are paired with where they came from as in `trace_origins()`.  Each
+/// region is a range of the document followed by a range of the result, and the regions are
+/// in order.  Between two regions, the document and the result hold the same bytes.
+pub fn changed_regions(document_length: usize, result: &[(u8, Option<usize>)]) -> Vec<(usize, usize, usize, usize)> {
+    let mut regions = Vec::new();
+    let (mut document_front, mut result_front) = (0, 0);
+    for (index, &(_, origin)) in result.iter().enumerate() {
+        if let Some(origin) = origin {
+            if origin > document_front || index > result_front {
+                regions.push((document_front, origin, result_front, index));
+            }
+            document_front = origin + 1;
+            result_front = index + 1;
+        }
+    }
+    if document_length > document_front || result.len() > result_front {
+        regions.push((document_front, document_length, result_front, result.len()));
+    }
+    regions
+}
+
+/// Turns changed regions, as found by `changed_regions()`, into operations in effect order.
+/// Each region becomes an insert of its new contents from `result`, and a delete of its old
+/// contents, which have been pushed along by the insert.
+pub fn operations_for_regions(regions: &[(usize, usize, usize, usize)], result: &[u8], timestamp: u32, site_id: u32) -> (LinkedList<InsertOperation>, LinkedList<DeleteOperation>) {
+    let mut inserts = LinkedList::new();
+    let mut deletes = LinkedList::new();
+    let (mut inserted, mut removed) = (0, 0);
+    for &(document_front, document_back, result_front, result_back) in regions {
+        if result_back > result_front {
+            inserts.push_back(InsertOperation::new((document_front + inserted) as Position, result[result_front..result_back].to_vec(), timestamp, site_id));
+            inserted += result_back - result_front;
+        }
+        if document_back > document_front {
+            deletes.push_back(DeleteOperation::new((document_front + inserted - removed) as Position, (document_back - document_front) as Position, timestamp, site_id));
+            removed += document_back - document_front;
+        }
+    }
+    (inserts, deletes)
+}
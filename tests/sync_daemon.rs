@@ -0,0 +1,131 @@
+//! Runs two `optra-sync` daemons against each other on localhost
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn daemon_path() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("optra-sync{}", env::consts::EXE_SUFFIX))
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("optra-sync-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("127.0.0.1:{}", listener.local_addr().unwrap().port())
+}
+
+fn start(file: &PathBuf, site_id: u32, mode: &str, address: &str) -> Child {
+    start_with_policy(file, site_id, mode, address, "keep")
+}
+
+fn start_with_policy(file: &PathBuf, site_id: u32, mode: &str, address: &str, policy: &str) -> Child {
+    Command::new(daemon_path())
+        .arg(file)
+        .args(["--site", &site_id.to_string(), mode, address, "--interval", "20", "--policy", policy])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+fn stop(mut child: Child) {
+    // Closing standard input asks the daemon to save its state and exit
+    drop(child.stdin.take());
+    assert!(child.wait().unwrap().success());
+}
+
+fn read(file: &PathBuf) -> String {
+    let mut contents = String::new();
+    // The daemon creates the file if it doesn't exist yet
+    if let Ok(mut file) = File::open(file) {
+        file.read_to_string(&mut contents).unwrap();
+    }
+    contents
+}
+
+fn write(file: &PathBuf, contents: &str) {
+    File::create(file).unwrap().write_all(contents.as_bytes()).unwrap();
+}
+
+fn wait_for(file: &PathBuf, expected: &str) {
+    let start = Instant::now();
+    while read(file) != expected {
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("{:?} contains {:?}, expected {:?}", file, read(file), expected);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn daemons_synchronize_a_file() {
+    let dir = scratch_dir("synchronize");
+    let file1 = dir.join("one.txt");
+    let file2 = dir.join("two.txt");
+    let address = free_address();
+    write(&file1, "Hello world");
+
+    let daemon1 = start(&file1, 1, "--listen", &address);
+    let daemon2 = start(&file2, 2, "--connect", &address);
+    wait_for(&file2, "Hello world");
+
+    write(&file1, "Hello there world");
+    wait_for(&file2, "Hello there world");
+    write(&file2, "Hello there world!");
+    wait_for(&file1, "Hello there world!");
+
+    stop(daemon1);
+    stop(daemon2);
+    assert!(dir.join("one.txt.optra").exists());
+    assert!(dir.join("two.txt.optra").exists());
+
+    // Changes made while stopped are picked up from the saved state
+    write(&file2, "Goodbye there world!");
+    let daemon1 = start(&file1, 1, "--listen", &address);
+    let daemon2 = start(&file2, 2, "--connect", &address);
+    wait_for(&file1, "Goodbye there world!");
+    stop(daemon1);
+    stop(daemon2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn daemons_mark_conflicting_changes() {
+    let dir = scratch_dir("markers");
+    let file1 = dir.join("one.txt");
+    let file2 = dir.join("two.txt");
+    let address = free_address();
+    write(&file1, "name = optra\nport = 8080\n");
+
+    let daemon1 = start_with_policy(&file1, 1, "--listen", &address, "markers");
+    let daemon2 = start_with_policy(&file2, 2, "--connect", &address, "markers");
+    wait_for(&file2, "name = optra\nport = 8080\n");
+    stop(daemon2);
+
+    // Both sides change the same line while they are apart
+    write(&file1, "name = optra\nport = 443\n");
+    write(&file2, "name = optra\nport = 8443\n");
+    thread::sleep(Duration::from_millis(100));
+    let daemon2 = start_with_policy(&file2, 2, "--connect", &address, "markers");
+    let expected = "name = optra\n<<<<<<< site 1\nport = 443\n=======\nport = 8443\n>>>>>>> site 2\n";
+    wait_for(&file1, expected);
+    wait_for(&file2, expected);
+    stop(daemon1);
+    stop(daemon2);
+    fs::remove_dir_all(&dir).unwrap();
+}